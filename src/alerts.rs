@@ -0,0 +1,82 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::BackendAlertConfig;
+
+/// Tracks recent giftcard backend failures to decide when the admins should hear about them.
+pub struct BackendAlerts {
+    max_failures: usize,
+    window: Duration,
+    state: Mutex<AlertState>,
+}
+
+#[derive(Default)]
+struct AlertState {
+    failures: VecDeque<Instant>,
+    last_alert: Option<Instant>,
+    last_auth_alert: Option<Instant>,
+}
+
+impl BackendAlerts {
+    pub fn new(config: &BackendAlertConfig) -> Self {
+        Self {
+            max_failures: config.max_failures,
+            window: Duration::from_secs(config.window_minutes * 60),
+            state: Mutex::new(AlertState::default()),
+        }
+    }
+
+    /// Records a failure and returns how many happened within the window, if that is enough to
+    /// alert. Alerts are sent at most once per window, so an outage doesn't flood the admins.
+    pub fn record_failure(&self) -> Option<usize> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.failures.push_back(now);
+        while state
+            .failures
+            .front()
+            .is_some_and(|t| now.duration_since(*t) > self.window)
+        {
+            state.failures.pop_front();
+        }
+
+        let recently_alerted = state
+            .last_alert
+            .is_some_and(|t| now.duration_since(t) < self.window);
+        if state.failures.len() > self.max_failures && !recently_alerted {
+            state.last_alert = Some(now);
+            Some(state.failures.len())
+        } else {
+            None
+        }
+    }
+
+    /// How many failures happened within the window.
+    pub fn recent_failures(&self) -> usize {
+        let now = Instant::now();
+        let state = self.state.lock().unwrap();
+        state
+            .failures
+            .iter()
+            .filter(|t| now.duration_since(**t) <= self.window)
+            .count()
+    }
+
+    /// Whether the admins should hear that the backend refused the secret. Unlike other failures
+    /// one is enough, but they are still told at most once per window.
+    pub fn record_auth_failure(&self) -> bool {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if state
+            .last_auth_alert
+            .is_some_and(|t| now.duration_since(t) < self.window)
+        {
+            return false;
+        }
+        state.last_auth_alert = Some(now);
+        true
+    }
+}
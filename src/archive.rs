@@ -0,0 +1,108 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, ErrorKind, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::Context;
+use flate2::{Compression, read::MultiGzDecoder, write::GzEncoder};
+use serde::{Deserialize, Serialize};
+
+use crate::{config::BotConfig, store::Storage};
+
+/// one line of the archive
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ArchivedRedemption {
+    pub user_id: i64,
+    pub redeemed_at: u64,
+}
+
+/// Held while a run is appended to an archive, so that copies never end in a half-written run.
+static APPENDING: Mutex<()> = Mutex::new(());
+
+/// Where the bot's old redemptions go: a gzipped json-lines file next to its store.
+pub fn archive_path(config: &BotConfig) -> PathBuf {
+    PathBuf::from(format!("{}.archive.gz", config.store_path))
+}
+
+/// Moves the redemptions made before `before` from the store to the archive, returning how many
+/// were moved. Their users still count as redeemed.
+///
+/// Each run appends a gzip member of its own, so that earlier runs are never rewritten. The
+/// archive is synced before the store forgets the redemptions, so a crash in between leaves
+/// duplicates in the archive rather than losing history.
+pub fn archive_redemptions(store: &dyn Storage, path: &Path, before: u64) -> anyhow::Result<usize> {
+    let old: Vec<ArchivedRedemption> = store
+        .redemptions()?
+        .into_iter()
+        .filter(|(_, redeemed_at)| *redeemed_at < before)
+        .map(|(user_id, redeemed_at)| ArchivedRedemption {
+            user_id,
+            redeemed_at,
+        })
+        .collect();
+    if old.is_empty() {
+        return Ok(0);
+    }
+
+    let appending = APPENDING.lock().unwrap();
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("cannot open archive {path:?}"))?;
+    let mut out = GzEncoder::new(BufWriter::new(file), Compression::default());
+    for redemption in &old {
+        serde_json::to_writer(&mut out, redemption)?;
+        writeln!(out)?;
+    }
+    let file = out.finish()?.into_inner().map_err(|err| err.into_error())?;
+    file.sync_all()?;
+    drop(appending);
+
+    let user_ids: Vec<i64> = old.iter().map(|redemption| redemption.user_id).collect();
+    store.archive_redemptions(&user_ids)?;
+    Ok(old.len())
+}
+
+/// Copies the archive to `to`, returning whether there was one to copy.
+pub fn copy_archive(path: &Path, to: &Path) -> anyhow::Result<bool> {
+    let _appending = APPENDING.lock().unwrap();
+    match fs::copy(path, to) {
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err).with_context(|| format!("cannot copy archive {path:?}")),
+    }
+}
+
+/// Every archived redemption, in the order they were archived. A missing archive is empty.
+pub fn read_archive(path: &Path) -> anyhow::Result<Vec<ArchivedRedemption>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err).with_context(|| format!("cannot open archive {path:?}")),
+    };
+    let mut redemptions = vec![];
+    for (i, line) in BufReader::new(MultiGzDecoder::new(BufReader::new(file)))
+        .lines()
+        .enumerate()
+    {
+        let line = line.with_context(|| format!("cannot read archive {path:?}"))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        redemptions.push(
+            serde_json::from_str(&line)
+                .with_context(|| format!("line {} of archive {path:?} is invalid", i + 1))?,
+        );
+    }
+    Ok(redemptions)
+}
+
+/// The user's archived redemption, if they have one.
+pub fn find_archived(path: &Path, user_id: i64) -> anyhow::Result<Option<ArchivedRedemption>> {
+    Ok(read_archive(path)?
+        .into_iter()
+        .find(|redemption| redemption.user_id == user_id))
+}
@@ -0,0 +1,174 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Instant, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{config::AuditConfig, unix_now};
+
+/// What the bot ended up doing with a request.
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Issued,
+    AlreadyRedeemed,
+    Banned,
+    ReviewPending,
+    ReviewRequested,
+    /// the request needed review, but no admins are configured to review it
+    Unreviewable,
+    NotInGroup,
+    MembershipCheckFailed,
+    Cooldown,
+    DailyCapReached,
+    /// the backend failed, and the user was offered the waitlist
+    BackendUnavailable,
+    JoinedWaitlist,
+    ContactRequested,
+    PhoneInUse,
+    Approved,
+    Rejected,
+    Granted,
+    MilestoneBonus,
+    /// an admin ran a command, recorded under the admin's id
+    AdminCommand,
+    /// an admin was asked to confirm a command
+    AwaitingConfirmation,
+    /// an admin didn't confirm a command, so it was not run
+    NotConfirmed,
+    /// support staff acted on the user in the dashboard
+    DashboardAction,
+}
+
+/// One line of the audit log, describing a single issuance decision or admin action.
+#[derive(Serialize)]
+pub struct AuditEntry {
+    #[serde(skip)]
+    started: Instant,
+    timestamp: u64,
+    bot: String,
+    user_id: i64,
+    checks: BTreeMap<&'static str, Value>,
+    pub outcome: Option<Outcome>,
+    /// the last characters of each issued code, enough to match it against a disputed code
+    code_suffix: Option<String>,
+    error: Option<String>,
+    latency_ms: u64,
+}
+
+impl AuditEntry {
+    pub fn new(bot: &str, user_id: i64) -> Self {
+        Self {
+            started: Instant::now(),
+            timestamp: unix_now(),
+            bot: bot.to_owned(),
+            user_id,
+            checks: BTreeMap::new(),
+            outcome: None,
+            code_suffix: None,
+            error: None,
+            latency_ms: 0,
+        }
+    }
+
+    pub fn check(&mut self, name: &'static str, result: impl Into<Value>) {
+        self.checks.insert(name, result.into());
+    }
+
+    /// Records the suffixes of the issued codes, separated by commas if there are several.
+    pub fn issued_codes(&mut self, codes: &[String]) {
+        let suffixes: Vec<String> = codes
+            .iter()
+            .map(|code| {
+                let chars: Vec<char> = code.chars().collect();
+                chars[chars.len().saturating_sub(4)..].iter().collect()
+            })
+            .collect();
+        self.code_suffix = Some(suffixes.join(","));
+    }
+}
+
+/// Append-only JSONL log of issuance decisions, kept apart from the stdout logs.
+pub struct AuditLog(Option<Mutex<AuditFile>>);
+
+struct AuditFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// days since the unix epoch when the current file was started
+    day: u64,
+    max_bytes: u64,
+    rotate_daily: bool,
+}
+
+impl AuditLog {
+    /// Opens the configured log, or returns a log that discards everything if none is configured.
+    pub fn open(config: Option<&AuditConfig>) -> anyhow::Result<Self> {
+        let Some(config) = config else {
+            return Ok(Self(None));
+        };
+        let file = open_append(&config.path)?;
+        let metadata = file.metadata()?;
+        let day = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(unix_now(), |d| d.as_secs())
+            / 86400;
+
+        Ok(Self(Some(Mutex::new(AuditFile {
+            path: config.path.clone(),
+            file,
+            size: metadata.len(),
+            day,
+            max_bytes: config.max_bytes,
+            rotate_daily: config.rotate_daily,
+        }))))
+    }
+
+    /// Finishes the entry and appends it, noting the error if the request failed.
+    pub fn record(&self, mut entry: AuditEntry, result: &anyhow::Result<()>) {
+        let Some(file) = &self.0 else {
+            return;
+        };
+        entry.latency_ms = entry.started.elapsed().as_millis() as u64;
+        if let Err(err) = result {
+            entry.error = Some(format!("{err:#}"));
+        }
+
+        let mut line = serde_json::to_vec(&entry).expect("audit entries always serialize");
+        line.push(b'\n');
+        if let Err(err) = file.lock().unwrap().append(&line) {
+            eprintln!("failed to write audit log: {err:?}");
+        }
+    }
+}
+
+impl AuditFile {
+    fn append(&mut self, line: &[u8]) -> std::io::Result<()> {
+        let today = unix_now() / 86400;
+        let too_big = self.size + line.len() as u64 > self.max_bytes;
+        let new_day = self.rotate_daily && today != self.day;
+        if self.size > 0 && (too_big || new_day) {
+            let rotated = PathBuf::from(format!("{}.{}", self.path.display(), unix_now()));
+            std::fs::rename(&self.path, rotated)?;
+            self.file = open_append(&self.path)?;
+            self.size = 0;
+        }
+        self.day = today;
+
+        self.file.write_all(line)?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn open_append(path: &PathBuf) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
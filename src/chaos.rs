@@ -0,0 +1,205 @@
+//! Failure injection for rehearsing outages in staging, see [`ChaosConfig`].
+//!
+//! Calls are delayed or failed before they reach telegram or the backend, with the errors a real
+//! outage produces, so that the retries in [`crate::telegram::Outbox`] and
+//! [`crate::giftcard::GephBackend`], the waitlist and the backend alerts all see them.
+
+use std::time::Duration;
+
+use ring::rand::{SecureRandom, SystemRandom};
+use teloxide::types::{ChatId, MessageId, UserId};
+
+use crate::{
+    BoxFuture,
+    config::{ChaosConfig, ChaosRates},
+    giftcard::GiftcardError,
+    telegram::{DeliveryError, OutgoingMessage, TelegramApi},
+};
+
+/// Decides, call by call, whether to delay or fail it.
+pub struct Chaos {
+    rates: ChaosRates,
+    random: SystemRandom,
+}
+
+impl Chaos {
+    pub fn new(rates: ChaosRates) -> Self {
+        Self {
+            rates,
+            random: SystemRandom::new(),
+        }
+    }
+
+    /// Sits out a random delay if this call is to be delayed, then returns whether it is to fail.
+    pub async fn strike(&self) -> bool {
+        if self.chance(self.rates.delay_rate) {
+            let delay = (self.sample() * self.rates.max_delay_ms as f64) as u64;
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+        }
+        self.chance(self.rates.failure_rate)
+    }
+
+    /// The error the backend gives when it is down or overloaded.
+    pub fn backend_error(&self) -> GiftcardError {
+        if self.chance(0.5) {
+            GiftcardError::Timeout
+        } else {
+            GiftcardError::Status {
+                status: 503,
+                message: "injected by chaos mode".into(),
+            }
+        }
+    }
+
+    /// The error telegram gives when it is down or flood control kicks in.
+    fn delivery_error(&self) -> anyhow::Error {
+        if self.chance(0.5) {
+            DeliveryError::RetryAfter(Duration::from_secs(1)).into()
+        } else {
+            DeliveryError::Transient("injected by chaos mode".into()).into()
+        }
+    }
+
+    fn chance(&self, rate: f64) -> bool {
+        rate > 0.0 && self.sample() < rate
+    }
+
+    /// A random number from 0 up to, but not including, 1.
+    fn sample(&self) -> f64 {
+        let mut random = [0; 4];
+        // the system rng doesn't fail on any platform we run on, and chaos mode can do without
+        // randomness if it ever does
+        let _ = self.random.fill(&mut random);
+        u32::from_le_bytes(random) as f64 / (u32::MAX as f64 + 1.0)
+    }
+}
+
+/// Wraps the telegram client below the [`crate::telegram::Outbox`], so that injected failures are
+/// retried like real ones.
+pub struct ChaosTelegram<T> {
+    inner: T,
+    chaos: Chaos,
+}
+
+impl<T> ChaosTelegram<T> {
+    pub fn new(inner: T, config: &ChaosConfig) -> Self {
+        Self {
+            inner,
+            chaos: Chaos::new(config.telegram),
+        }
+    }
+
+    async fn disturb(&self) -> anyhow::Result<()> {
+        if self.chaos.strike().await {
+            return Err(self.chaos.delivery_error());
+        }
+        Ok(())
+    }
+}
+
+impl<T: TelegramApi> TelegramApi for ChaosTelegram<T> {
+    fn send_message(&self, msg: OutgoingMessage) -> BoxFuture<'_, anyhow::Result<MessageId>> {
+        Box::pin(async move {
+            self.disturb().await?;
+            self.inner.send_message(msg).await
+        })
+    }
+
+    fn send_photo(
+        &self,
+        chat_id: ChatId,
+        png: Vec<u8>,
+    ) -> BoxFuture<'_, anyhow::Result<MessageId>> {
+        Box::pin(async move {
+            self.disturb().await?;
+            self.inner.send_photo(chat_id, png).await
+        })
+    }
+
+    fn is_group_member(
+        &self,
+        group_id: ChatId,
+        user_id: UserId,
+    ) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(async move {
+            self.disturb().await?;
+            self.inner.is_group_member(group_id, user_id).await
+        })
+    }
+
+    fn member_count(&self, group_id: ChatId) -> BoxFuture<'_, anyhow::Result<u64>> {
+        Box::pin(async move {
+            self.disturb().await?;
+            self.inner.member_count(group_id).await
+        })
+    }
+
+    fn answer_callback_query(&self, query_id: String) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.disturb().await?;
+            self.inner.answer_callback_query(query_id).await
+        })
+    }
+
+    fn edit_message_text(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+    ) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.disturb().await?;
+            self.inner
+                .edit_message_text(chat_id, message_id, text)
+                .await
+        })
+    }
+
+    fn delete_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            self.disturb().await?;
+            self.inner.delete_message(chat_id, message_id).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chaos(failure_rate: f64, delay_rate: f64) -> Chaos {
+        Chaos::new(ChaosRates {
+            failure_rate,
+            delay_rate,
+            max_delay_ms: 1000,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_and_delays_at_the_configured_rates() {
+        assert!(chaos(1.0, 0.0).strike().await);
+        assert!(!chaos(0.0, 0.0).strike().await);
+
+        let start = tokio::time::Instant::now();
+        let chaos = chaos(0.0, 1.0);
+        for _ in 0..20 {
+            assert!(!chaos.strike().await);
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed > Duration::ZERO && elapsed < Duration::from_secs(20));
+    }
+
+    #[test]
+    fn injects_errors_that_are_retried() {
+        let chaos = chaos(1.0, 0.0);
+        for _ in 0..20 {
+            assert!(chaos.backend_error().is_transient());
+            let err = chaos.delivery_error();
+            assert!(err.downcast_ref::<DeliveryError>().unwrap().is_transient());
+        }
+    }
+}
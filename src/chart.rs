@@ -0,0 +1,147 @@
+//! Line charts of the daily statistics, sent to admins by `#Chart`.
+//!
+//! The image has no text in it, which would need a font; the message sent along with it names
+//! the series and says what the gridlines stand for.
+
+use crate::png::{self, ColorType};
+
+pub type Rgb = [u8; 3];
+
+pub const ISSUED_COLOR: Rgb = [46, 160, 67];
+pub const DENIED_COLOR: Rgb = [245, 140, 30];
+pub const ERRORS_COLOR: Rgb = [214, 39, 40];
+
+const WIDTH: i64 = 800;
+const HEIGHT: i64 = 400;
+const MARGIN: i64 = 24;
+/// Horizontal gridlines above the axis, the highest of which is the top of the chart.
+const GRID_LINES: u64 = 4;
+const BACKGROUND: Rgb = [255, 255, 255];
+const GRID_COLOR: Rgb = [228, 228, 228];
+const AXIS_COLOR: Rgb = [110, 110, 110];
+
+/// Draws a line for each series of daily values, oldest day on the left. Returns the png and the
+/// value each gridline adds.
+pub fn trend_png(series: &[(&[u64], Rgb)]) -> anyhow::Result<(Vec<u8>, u64)> {
+    let days = series
+        .iter()
+        .map(|(values, _)| values.len())
+        .max()
+        .unwrap_or_default() as i64;
+    anyhow::ensure!(days > 0, "a chart needs at least one day");
+    let highest = series
+        .iter()
+        .flat_map(|(values, _)| values.iter())
+        .copied()
+        .max()
+        .unwrap_or_default();
+    let step = grid_step(highest);
+
+    let (left, right, top, bottom) = (MARGIN, WIDTH - MARGIN, MARGIN, HEIGHT - MARGIN);
+    let mut canvas = Canvas::new();
+    for line in 1..=GRID_LINES as i64 {
+        let y = bottom - (bottom - top) * line / GRID_LINES as i64;
+        canvas.line((left, y), (right, y), 0, GRID_COLOR);
+    }
+    canvas.line((left, bottom), (right, bottom), 0, AXIS_COLOR);
+    canvas.line((left, top), (left, bottom), 0, AXIS_COLOR);
+
+    let x = |day: i64| match days {
+        1 => (left + right) / 2,
+        _ => left + (right - left) * day / (days - 1),
+    };
+    let y = |value: u64| bottom - (bottom - top) * value as i64 / (step * GRID_LINES) as i64;
+    for (values, color) in series {
+        let points: Vec<(i64, i64)> = (0..)
+            .zip(values.iter())
+            .map(|(day, value)| (x(day), y(*value)))
+            .collect();
+        for pair in points.windows(2) {
+            canvas.line(pair[0], pair[1], 1, *color);
+        }
+        for point in points {
+            canvas.line(point, point, 3, *color);
+        }
+    }
+    let png = png::encode(
+        WIDTH as usize,
+        HEIGHT as usize,
+        ColorType::Rgb,
+        &canvas.pixels,
+    )?;
+    Ok((png, step))
+}
+
+/// The smallest of 1, 2 or 5 times a power of ten that leaves room for the highest value below
+/// the top gridline.
+fn grid_step(highest: u64) -> u64 {
+    let mut magnitude = 1;
+    loop {
+        for factor in [1, 2, 5] {
+            if factor * magnitude * GRID_LINES >= highest {
+                return factor * magnitude;
+            }
+        }
+        magnitude *= 10;
+    }
+}
+
+struct Canvas {
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new() -> Self {
+        Self {
+            pixels: BACKGROUND.repeat((WIDTH * HEIGHT) as usize),
+        }
+    }
+
+    /// Draws a straight line with square dots reaching `radius` pixels around each point of it.
+    fn line(&mut self, from: (i64, i64), to: (i64, i64), radius: i64, color: Rgb) {
+        let steps = (to.0 - from.0).abs().max((to.1 - from.1).abs()).max(1);
+        for step in 0..=steps {
+            let x = from.0 + (to.0 - from.0) * step / steps;
+            let y = from.1 + (to.1 - from.1) * step / steps;
+            for dy in -radius..=radius {
+                for dx in -radius..=radius {
+                    self.set(x + dx, y + dy, color);
+                }
+            }
+        }
+    }
+
+    fn set(&mut self, x: i64, y: i64, color: Rgb) {
+        if (0..WIDTH).contains(&x) && (0..HEIGHT).contains(&y) {
+            let index = ((y * WIDTH + x) * 3) as usize;
+            self.pixels[index..index + 3].copy_from_slice(&color);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gridlines_step_in_round_numbers() {
+        assert_eq!(grid_step(0), 1);
+        assert_eq!(grid_step(4), 1);
+        assert_eq!(grid_step(5), 2);
+        assert_eq!(grid_step(37), 10);
+        assert_eq!(grid_step(1234), 500);
+    }
+
+    #[test]
+    fn draws_a_png_of_the_chart_size() {
+        let issued = [3, 10, 7];
+        let (png, step) =
+            trend_png(&[(&issued, ISSUED_COLOR), (&[0, 1, 0], ERRORS_COLOR)]).unwrap();
+
+        assert_eq!(step, 5);
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(png[16..20], (WIDTH as u32).to_be_bytes());
+        assert_eq!(png[20..24], (HEIGHT as u32).to_be_bytes());
+        assert!(trend_png(&[]).is_err());
+    }
+}
@@ -0,0 +1,316 @@
+use std::{
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    archive::{archive_path, read_archive},
+    backup,
+    config::{BotConfig, Command, Config},
+    store::{Storage, lock_store, open_storage, open_storage_read_only},
+    unix_now,
+};
+
+/// one line of `export` output, and of `import` input
+#[derive(Serialize, Deserialize)]
+struct ExportedRedemption {
+    user_id: i64,
+    redeemed_at: u64,
+}
+
+/// Runs an administration subcommand against a store, without starting any bot.
+pub fn run(command: &Command, config: &Config) -> anyhow::Result<()> {
+    match command {
+        Command::Stats(args) => stats(&*open_read_only(config, args.bot.as_deref())?),
+        Command::Export(args) => export(&*open_read_only(config, args.bot.as_deref())?, args.csv),
+        Command::ResetUser(args) => {
+            let bot = select_bot(config, args.bot.as_deref())?;
+            let _lock = lock_store(bot)?;
+            ensure_exists(bot)?;
+            let store = open_storage(bot)?;
+            if store.reset_user(args.user_id)? {
+                println!("reset user {}", args.user_id);
+            } else {
+                println!("user {} was not in the store", args.user_id);
+            }
+            Ok(())
+        }
+        Command::Import(args) => {
+            let bot = select_bot(config, args.bot.as_deref())?;
+            let _lock = lock_store(bot)?;
+            import(&*open_storage(bot)?, &args.file)
+        }
+        Command::VerifyStore(args) => verify(&*open_read_only(config, args.bot.as_deref())?),
+        Command::Restore(args) => {
+            let bot = select_bot(config, args.bot.as_deref())?;
+            let _lock = lock_store(bot)?;
+            let redemptions = backup::restore(bot, &args.restore_from)?;
+            println!(
+                "restored {} from {:?}, which has {redemptions} redemptions; the replaced files \
+                 were kept next to {} with a .before-restore suffix",
+                bot.bot_uname, args.restore_from, bot.store_path
+            );
+            Ok(())
+        }
+        Command::QueryArchive(args) => {
+            let bot = select_bot(config, args.bot.as_deref())?;
+            let redemptions = read_archive(&archive_path(bot))?
+                .into_iter()
+                .filter(|redemption| args.user_id.is_none_or(|id| id == redemption.user_id))
+                .map(|redemption| (redemption.user_id, redemption.redeemed_at))
+                .collect();
+            write_redemptions(redemptions, args.csv)
+        }
+    }
+}
+
+fn select_bot<'a>(config: &'a Config, bot: Option<&str>) -> anyhow::Result<&'a BotConfig> {
+    let bots: Vec<&BotConfig> = config.all_bots().collect();
+    match (bot, bots.as_slice()) {
+        (Some(uname), _) => bots
+            .iter()
+            .find(|bot| bot.bot_uname == uname)
+            .copied()
+            .with_context(|| format!("no bot named {uname} is configured")),
+        (None, [bot]) => Ok(bot),
+        (None, []) => anyhow::bail!("no bots configured"),
+        (None, _) => anyhow::bail!("several bots are configured, choose one with --bot"),
+    }
+}
+
+/// Opens the bot's store for reading, without migrating or otherwise changing it.
+fn open_read_only(config: &Config, bot: Option<&str>) -> anyhow::Result<Box<dyn Storage>> {
+    let bot = select_bot(config, bot)?;
+    ensure_exists(bot)?;
+    open_storage_read_only(bot)
+}
+
+/// Refuses to create an empty store where an existing store was meant.
+fn ensure_exists(bot: &BotConfig) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        Path::new(&bot.store_path).exists(),
+        "there is no store at {}",
+        bot.store_path
+    );
+    Ok(())
+}
+
+fn stats(store: &dyn Storage) -> anyhow::Result<()> {
+    let now = unix_now();
+    let redemptions = store.redemptions()?;
+    let since = |secs: u64| {
+        redemptions
+            .iter()
+            .filter(|(_, redeemed_at)| *redeemed_at > now.saturating_sub(secs))
+            .count()
+    };
+
+    let total = store.redemption_count()?;
+
+    println!("redemptions: {total}");
+    println!("  in the last day: {}", since(86400));
+    println!("  in the last week: {}", since(7 * 86400));
+    println!("  archived: {}", total - redemptions.len());
+    println!(
+        "  without a timestamp: {}",
+        redemptions.iter().filter(|(_, at)| *at == 0).count()
+    );
+    println!("pending reviews: {}", store.pending_reviews()?.len());
+    println!("banned users: {}", store.banned_users()?.len());
+    Ok(())
+}
+
+fn export(store: &dyn Storage, csv: bool) -> anyhow::Result<()> {
+    write_redemptions(store.redemptions()?, csv)
+}
+
+fn write_redemptions(redemptions: Vec<(i64, u64)>, csv: bool) -> anyhow::Result<()> {
+    let redemptions: Vec<ExportedRedemption> = redemptions
+        .into_iter()
+        .map(|(user_id, redeemed_at)| ExportedRedemption {
+            user_id,
+            redeemed_at,
+        })
+        .collect();
+
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    if csv {
+        writeln!(out, "user_id,redeemed_at")?;
+        for redemption in &redemptions {
+            writeln!(out, "{},{}", redemption.user_id, redemption.redeemed_at)?;
+        }
+    } else {
+        serde_json::to_writer_pretty(&mut out, &redemptions)?;
+        writeln!(out)?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Adds the exported redemptions, keeping the existing record of users who already redeemed.
+fn import(store: &dyn Storage, file: &Path) -> anyhow::Result<()> {
+    let raw = std::fs::read_to_string(file).with_context(|| format!("cannot read {file:?}"))?;
+    let redemptions = if file.extension().is_some_and(|ext| ext == "csv") {
+        parse_csv(&raw)?
+    } else {
+        serde_json::from_str(&raw).with_context(|| format!("{file:?} is not an export"))?
+    };
+
+    let mut imported = 0;
+    let mut skipped = 0;
+    for ExportedRedemption {
+        user_id,
+        redeemed_at,
+    } in redemptions
+    {
+        if store.is_redeemed(user_id)? {
+            skipped += 1;
+        } else {
+            store.record_redemption(user_id, redeemed_at)?;
+            imported += 1;
+        }
+    }
+    println!("imported {imported} redemptions, skipped {skipped} users who already redeemed");
+    Ok(())
+}
+
+fn parse_csv(raw: &str) -> anyhow::Result<Vec<ExportedRedemption>> {
+    raw.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with("user_id"))
+        .map(|(i, line)| {
+            let parse = || {
+                let (user_id, redeemed_at) = line.split_once(',')?;
+                Some(ExportedRedemption {
+                    user_id: user_id.trim().parse().ok()?,
+                    redeemed_at: redeemed_at.trim().parse().ok()?,
+                })
+            };
+            parse().with_context(|| format!("line {} is not `user_id,redeemed_at`", i + 1))
+        })
+        .collect()
+}
+
+fn verify(store: &dyn Storage) -> anyhow::Result<()> {
+    let now = unix_now();
+    let mut problems = vec![];
+
+    let redemptions = store.redemptions()?;
+    for (user_id, redeemed_at) in &redemptions {
+        if *redeemed_at > now {
+            problems.push(format!(
+                "user {user_id} redeemed in the future, at {redeemed_at}"
+            ));
+        }
+    }
+    for (user_id, _) in store.pending_reviews()? {
+        if store.is_redeemed(user_id)? {
+            problems.push(format!("user {user_id} awaits review but already redeemed"));
+        }
+        if store.is_banned(user_id)? {
+            problems.push(format!("user {user_id} awaits review but is banned"));
+        }
+    }
+
+    for problem in &problems {
+        println!("{problem}");
+    }
+    anyhow::ensure!(
+        problems.is_empty(),
+        "found {} problems in the store",
+        problems.len()
+    );
+    println!("store is consistent, {} redemptions", redemptions.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::config::{ResetUserArgs, StatsArgs, test_config};
+
+    /// A config whose one bot keeps its store in a new directory.
+    fn config() -> (TempDir, Config) {
+        let dir = tempfile::tempdir().unwrap();
+        let store_path = dir.path().join("store.json");
+        let config = test_config(&store_path.to_string_lossy(), "");
+        (dir, config)
+    }
+
+    fn bot(config: &Config) -> &BotConfig {
+        config.all_bots().next().unwrap()
+    }
+
+    #[test]
+    fn parses_exported_csv() {
+        let redemptions = parse_csv("user_id,redeemed_at\n1,100\n\n 2 , 200\n").unwrap();
+        let redemptions: Vec<(i64, u64)> = redemptions
+            .iter()
+            .map(|redemption| (redemption.user_id, redemption.redeemed_at))
+            .collect();
+        assert_eq!(redemptions, vec![(1, 100), (2, 200)]);
+
+        let err = parse_csv("user_id,redeemed_at\n1,100\n2;200\n")
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("line 3"), "{err}");
+    }
+
+    #[test]
+    fn imports_only_users_who_did_not_redeem() {
+        let (dir, config) = config();
+        let store = open_storage(bot(&config)).unwrap();
+        store.record_redemption(1, 50).unwrap();
+
+        let csv = dir.path().join("redemptions.csv");
+        std::fs::write(&csv, "user_id,redeemed_at\n1,100\n2,200\n").unwrap();
+        import(&*store, &csv).unwrap();
+        let json = dir.path().join("redemptions.json");
+        std::fs::write(&json, r#"[{"user_id": 3, "redeemed_at": 300}]"#).unwrap();
+        import(&*store, &json).unwrap();
+
+        assert_eq!(
+            store.redemptions().unwrap(),
+            vec![(1, 50), (2, 200), (3, 300)]
+        );
+    }
+
+    #[test]
+    fn writes_wait_for_the_bot_to_stop() {
+        let (_dir, config) = config();
+        open_storage(bot(&config))
+            .unwrap()
+            .record_redemption(1, 100)
+            .unwrap();
+        let reset = Command::ResetUser(ResetUserArgs {
+            bot: None,
+            user_id: 1,
+        });
+
+        let lock = lock_store(bot(&config)).unwrap();
+        let err = run(&reset, &config).err().unwrap();
+        assert!(err.to_string().contains("in use"), "{err}");
+        drop(lock);
+        run(&reset, &config).unwrap();
+        assert!(!open_storage(bot(&config)).unwrap().is_redeemed(1).unwrap());
+    }
+
+    #[test]
+    fn reading_leaves_an_old_store_as_it_is() {
+        let (_dir, config) = config();
+        let old = r#"{"redemptions": {"1": {"redeemed_at": 100}}}"#;
+        std::fs::write(&bot(&config).store_path, old).unwrap();
+
+        let stats = Command::Stats(StatsArgs { bot: None });
+        assert!(run(&stats, &config).is_err());
+        assert_eq!(
+            std::fs::read_to_string(&bot(&config).store_path).unwrap(),
+            old
+        );
+    }
+}
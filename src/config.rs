@@ -0,0 +1,1224 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+use anyhow::Context;
+use argh::FromArgs;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, de::Error as _};
+use teloxide::types::ParseMode;
+
+use crate::{
+    messages::{TemplateKey, Templates},
+    schedule::CronSchedule,
+};
+
+/// configuration yaml file for geph telegram giftcard bot
+#[derive(FromArgs, PartialEq, Debug)]
+pub struct Args {
+    /// configuration yaml file path
+    #[argh(option, short = 'c', long = "config")]
+    pub config: PathBuf,
+    /// runs the bots if no subcommand is given
+    #[argh(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// offline administration of a bot's store; commands that change it refuse to while the bot runs
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand)]
+pub enum Command {
+    Stats(StatsArgs),
+    Export(ExportArgs),
+    ResetUser(ResetUserArgs),
+    Import(ImportArgs),
+    VerifyStore(VerifyStoreArgs),
+    QueryArchive(QueryArchiveArgs),
+    Restore(RestoreArgs),
+}
+
+/// print how many users redeemed, await review or are banned
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "stats")]
+pub struct StatsArgs {
+    /// bot whose store to use, required when several bots are configured
+    #[argh(option)]
+    pub bot: Option<String>,
+}
+
+/// print every redemption to stdout, as json unless --csv is given
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "export")]
+pub struct ExportArgs {
+    /// bot whose store to use, required when several bots are configured
+    #[argh(option)]
+    pub bot: Option<String>,
+    /// write `user_id,redeemed_at` csv instead of json
+    #[argh(switch)]
+    pub csv: bool,
+}
+
+/// forget a user's redemption, pending review and ban
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "reset-user")]
+pub struct ResetUserArgs {
+    /// bot whose store to use, required when several bots are configured
+    #[argh(option)]
+    pub bot: Option<String>,
+    /// telegram user id
+    #[argh(positional)]
+    pub user_id: i64,
+}
+
+/// add redemptions from a file written by `export`; a .csv extension selects csv
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "import")]
+pub struct ImportArgs {
+    /// bot whose store to use, required when several bots are configured
+    #[argh(option)]
+    pub bot: Option<String>,
+    /// exported redemptions
+    #[argh(positional)]
+    pub file: PathBuf,
+}
+
+/// print archived redemptions to stdout, as json unless --csv is given
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "query-archive")]
+pub struct QueryArchiveArgs {
+    /// bot whose archive to use, required when several bots are configured
+    #[argh(option)]
+    pub bot: Option<String>,
+    /// only print the redemption of this telegram user id
+    #[argh(option)]
+    pub user_id: Option<i64>,
+    /// write `user_id,redeemed_at` csv instead of json
+    #[argh(switch)]
+    pub csv: bool,
+}
+
+/// check that the store can be read and is consistent
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "verify-store")]
+pub struct VerifyStoreArgs {
+    /// bot whose store to use, required when several bots are configured
+    #[argh(option)]
+    pub bot: Option<String>,
+}
+
+/// replace the store with a backup taken by the `backup_store` task; download backups kept in a
+/// bucket first
+#[derive(FromArgs, PartialEq, Debug)]
+#[argh(subcommand, name = "restore")]
+pub struct RestoreArgs {
+    /// bot whose store to replace, required when several bots are configured
+    #[argh(option)]
+    pub bot: Option<String>,
+    /// backup file to restore, which must be on this machine, as `s3://` urls aren't supported; an
+    /// archive backed up with it, named like it plus `.archive.gz`, is restored too
+    #[argh(option)]
+    pub restore_from: PathBuf,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Config {
+    /// a single bot configured at the top level, as in configs predating `bots`; any of
+    /// `store_path`, `telegram_token`, `bot_uname` or `geph_group_id` there configures one
+    #[serde(flatten, deserialize_with = "deserialize_top_level_bot")]
+    pub bot: Option<BotConfig>,
+    /// further bots run by the same process, e.g. one per language community
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    pub bots: Vec<BotConfig>,
+    pub admin_uname: String,
+    pub create_giftcard_secret: String,
+    pub days_per_giftcard: u32,
+    /// how many codes each user receives, each lasting `days_per_giftcard`, e.g. to share some
+    /// with friends
+    #[serde(default = "default_num_cards")]
+    pub num_cards: u32,
+    /// telegram user ids of the admins, who receive manual review requests; only they can use
+    /// admin commands, and only with `admin_uname` as their username. Without any, requests that
+    /// need review are refused
+    #[serde(default)]
+    pub admin_ids: Vec<i64>,
+    #[serde(default)]
+    pub fraud: FraudConfig,
+    #[serde(default)]
+    pub workers: WorkerConfig,
+    #[serde(default)]
+    pub delivery: DeliveryConfig,
+    /// where to write the audit log of issuance decisions, if anywhere
+    #[serde(default)]
+    pub audit_log: Option<AuditConfig>,
+    #[serde(default)]
+    pub error_reporting: Option<ErrorReportingConfig>,
+    #[serde(default)]
+    pub backend_alert: BackendAlertConfig,
+    #[serde(default)]
+    pub giftcard_backend: GiftcardBackendConfig,
+    #[serde(default)]
+    pub card_usage: CardUsageConfig,
+    #[serde(default)]
+    pub archive: ArchiveConfig,
+    #[serde(default)]
+    pub membership_recheck: MembershipRecheckConfig,
+    #[serde(default)]
+    pub repeat_attempts: RepeatAttemptsConfig,
+    /// where to post signed notifications about issued giftcards and flagged users
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+    #[serde(default)]
+    pub proxy: ProxyConfig,
+    /// base url of a self-hosted telegram-bot-api server to use instead of api.telegram.org;
+    /// bots must have logged out of api.telegram.org before moving to it
+    #[serde(default)]
+    pub telegram_api_url: Option<String>,
+    /// language of messages to users whose own language has no translation, unless their bot sets
+    /// its own; without one they get the multilingual `templates`
+    #[serde(default)]
+    pub default_language: Option<String>,
+    /// a web ui for support staff, served only if configured
+    #[serde(default)]
+    pub dashboard: Option<DashboardConfig>,
+    /// asks users to share their phone number, so that one person can't collect a giftcard on
+    /// each of their accounts
+    #[serde(default)]
+    pub phone_check: Option<PhoneCheckConfig>,
+    /// where the `backup_store` task copies each bot's store to
+    #[serde(default)]
+    pub backup: Option<BackupConfig>,
+    /// randomly delays and fails calls to telegram and the giftcard backend, to check in staging
+    /// that retries, redelivery and alerts hold up; never configure it in production
+    #[serde(default)]
+    pub chaos: Option<ChaosConfig>,
+}
+
+impl Config {
+    /// Lets the environment supply the secrets, so that the yaml can be committed without them.
+    ///
+    /// `TELEGRAM_TOKEN` only applies to the bot configured at the top level; bots under `bots`
+    /// can take their tokens from the environment through `${VAR}` interpolation instead.
+    fn apply_env_overrides(&mut self, env: &impl Fn(&str) -> Option<String>) {
+        if let Some(token) = env("TELEGRAM_TOKEN")
+            && let Some(bot) = &mut self.bot
+        {
+            bot.telegram_token = token;
+        }
+        if let Some(secret) = env("GIFTCARD_SECRET") {
+            self.create_giftcard_secret = secret;
+        }
+    }
+
+    /// Parses a config file, filling in `${VAR}`s and the overrides from the environment `env`
+    /// looks variables up in.
+    fn from_yaml(yaml: &str, env: impl Fn(&str) -> Option<String>) -> anyhow::Result<Self> {
+        let mut value: serde_yaml::Value = serde_yaml::from_str(yaml)?;
+        interpolate_env(&mut value, &env)?;
+        let mut config: Config = serde_yaml::from_value(value)?;
+        config.apply_env_overrides(&env);
+        Ok(config)
+    }
+
+    /// Checks what can be checked without talking to telegram.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.all_bots().next().is_some(),
+            "no bots configured, set telegram_token, bot_uname, etc. at the top level or under bots"
+        );
+        anyhow::ensure!(
+            self.days_per_giftcard > 0,
+            "days_per_giftcard must be at least 1"
+        );
+        anyhow::ensure!(
+            (1..=MAX_NUM_CARDS).contains(&self.num_cards),
+            "num_cards must be between 1 and {MAX_NUM_CARDS}"
+        );
+        anyhow::ensure!(
+            self.workers.timeout_secs > 0,
+            "workers.timeout_secs must be at least 1"
+        );
+        Regex::new(&self.giftcard_backend.code_pattern)
+            .context("giftcard_backend.code_pattern is not a valid regex")?;
+        anyhow::ensure!(
+            self.giftcard_backend.attempts > 0,
+            "giftcard_backend.attempts must be at least 1"
+        );
+        for secret in &self.giftcard_backend.secrets {
+            let (from, until) = secret
+                .window()
+                .context("invalid validity of a giftcard_backend secret")?;
+            anyhow::ensure!(
+                from < until,
+                "a giftcard_backend secret is valid_until before it is valid_from"
+            );
+        }
+        anyhow::ensure!(
+            self.archive.after_days > 0,
+            "archive.after_days must be at least 1"
+        );
+        for proxy in [&self.proxy.all, &self.proxy.telegram, &self.proxy.backend]
+            .into_iter()
+            .flatten()
+        {
+            reqwest::Proxy::all(proxy).with_context(|| format!("invalid proxy {proxy}"))?;
+        }
+        if let Some(url) = &self.telegram_api_url {
+            let url = reqwest::Url::parse(url)
+                .with_context(|| format!("invalid telegram_api_url {url}"))?;
+            anyhow::ensure!(
+                matches!(url.scheme(), "http" | "https"),
+                "telegram_api_url must be an http or https url"
+            );
+        }
+        for webhook in &self.webhooks {
+            reqwest::Url::parse(&webhook.url)
+                .with_context(|| format!("invalid webhook url {}", webhook.url))?;
+            anyhow::ensure!(
+                !webhook.secret.is_empty(),
+                "the webhook {} needs a secret",
+                webhook.url
+            );
+        }
+        if let Some(dashboard) = &self.dashboard {
+            anyhow::ensure!(
+                dashboard.token.len() >= 16,
+                "dashboard.token must be at least 16 characters"
+            );
+        }
+        if let Some(phone_check) = &self.phone_check {
+            anyhow::ensure!(
+                phone_check.salt.len() >= 16,
+                "phone_check.salt must be at least 16 characters"
+            );
+        }
+        if let Some(backup) = &self.backup {
+            anyhow::ensure!(
+                backup.directory.is_some() || backup.s3.is_some(),
+                "backup needs a directory or s3 bucket to copy stores to"
+            );
+            anyhow::ensure!(backup.keep > 0, "backup.keep must be at least 1");
+            if let Some(s3) = &backup.s3 {
+                let url = reqwest::Url::parse(&s3.endpoint)
+                    .with_context(|| format!("invalid backup.s3.endpoint {}", s3.endpoint))?;
+                anyhow::ensure!(
+                    matches!(url.scheme(), "http" | "https"),
+                    "backup.s3.endpoint must be an http or https url"
+                );
+            }
+        }
+        if let Some(chaos) = &self.chaos {
+            for (name, rates) in [("telegram", &chaos.telegram), ("backend", &chaos.backend)] {
+                anyhow::ensure!(
+                    (0.0..=1.0).contains(&rates.failure_rate)
+                        && (0.0..=1.0).contains(&rates.delay_rate),
+                    "chaos.{name} rates must be between 0 and 1"
+                );
+            }
+        }
+
+        let mut store_paths = BTreeSet::new();
+        for bot in self.all_bots() {
+            anyhow::ensure!(
+                !bot.bot_uname.starts_with('@'),
+                "bot_uname {} must be written without the @",
+                bot.bot_uname
+            );
+            anyhow::ensure!(
+                bot.days_per_giftcard != Some(0),
+                "days_per_giftcard of {} must be at least 1",
+                bot.bot_uname
+            );
+            anyhow::ensure!(
+                bot.num_cards
+                    .is_none_or(|num_cards| (1..=MAX_NUM_CARDS).contains(&num_cards)),
+                "num_cards of {} must be between 1 and {MAX_NUM_CARDS}",
+                bot.bot_uname
+            );
+            anyhow::ensure!(
+                store_paths.insert(&bot.store_path),
+                "bots must not share the store path {}",
+                bot.store_path
+            );
+            // nothing else keeps banned users, e.g. those rejected in review, from getting cards
+            anyhow::ensure!(
+                bot.eligibility
+                    .iter()
+                    .any(|rule| matches!(rule, RuleConfig::Blacklist)),
+                "the eligibility rules of {} must include blacklist",
+                bot.bot_uname
+            );
+            for rule in &bot.eligibility {
+                match rule {
+                    RuleConfig::Cooldown { days: 0 } => {
+                        anyhow::bail!("cooldown of {} must be at least 1 day", bot.bot_uname)
+                    }
+                    RuleConfig::DailyCap { max: 0 } => {
+                        anyhow::bail!("daily_cap of {} must be at least 1", bot.bot_uname)
+                    }
+                    _ => {}
+                }
+            }
+            if let Some(milestones) = &bot.milestones {
+                anyhow::ensure!(
+                    milestones.every > 0,
+                    "milestones.every of {} must be at least 1",
+                    bot.bot_uname
+                );
+            }
+            for language in bot.translations.keys() {
+                anyhow::ensure!(
+                    *language == language.to_lowercase(),
+                    "the translation language {language} of {} must be lowercase",
+                    bot.bot_uname
+                );
+            }
+            for (language, group) in &bot.language_groups {
+                anyhow::ensure!(
+                    !group.invite_link.is_empty(),
+                    "the {language} group of {} needs an invite_link",
+                    bot.bot_uname
+                );
+            }
+            for job in &bot.schedule {
+                job.cron.parse::<CronSchedule>().with_context(|| {
+                    format!(
+                        "invalid cron expression in the schedule of {}",
+                        bot.bot_uname
+                    )
+                })?;
+                anyhow::ensure!(
+                    self.backup.is_some()
+                        || !matches!(
+                            job.action,
+                            ScheduledAction::Maintenance(MaintenanceTask::BackupStore)
+                        ),
+                    "{} schedules backup_store, but no backup is configured",
+                    bot.bot_uname
+                );
+            }
+            let processes_waitlist = bot.schedule.iter().any(|job| {
+                matches!(
+                    job.action,
+                    ScheduledAction::Maintenance(MaintenanceTask::ProcessWaitlist)
+                )
+            });
+            if let Some(waitlist) = &bot.waitlist {
+                anyhow::ensure!(
+                    waitlist.per_run > 0,
+                    "waitlist.per_run of {} must be at least 1",
+                    bot.bot_uname
+                );
+                anyhow::ensure!(
+                    processes_waitlist,
+                    "{} has a waitlist, but doesn't schedule process_waitlist to serve it",
+                    bot.bot_uname
+                );
+            } else {
+                anyhow::ensure!(
+                    !processes_waitlist,
+                    "{} schedules process_waitlist, but has no waitlist",
+                    bot.bot_uname
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns every bot this process should run.
+    pub fn all_bots(&self) -> impl Iterator<Item = &BotConfig> {
+        self.bot.iter().chain(&self.bots)
+    }
+}
+
+/// Keys that only a bot has, so that a config with any of them at the top level has a bot there.
+const TOP_LEVEL_BOT_KEYS: &[&str] = &["store_path", "telegram_token", "bot_uname", "geph_group_id"];
+
+/// Parses the bot configured at the top level, if there is one. Without this, a flattened
+/// `Option` would take a bot with a mistake in it for no bot at all, hiding the mistake.
+///
+/// Like `bots`, it reads enums written as `{variant: ...}` maps, such as eligibility rules, which
+/// serde_yaml otherwise only accepts as `!variant` tags.
+fn deserialize_top_level_bot<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<BotConfig>, D::Error> {
+    let keys = serde_yaml::Mapping::deserialize(deserializer)?;
+    if !TOP_LEVEL_BOT_KEYS.iter().any(|key| keys.contains_key(*key)) {
+        return Ok(None);
+    }
+    serde_yaml::with::singleton_map_recursive::deserialize(serde_yaml::Value::Mapping(keys))
+        .map(Some)
+        .map_err(|err| D::Error::custom(format!("bot at the top level: {err}")))
+}
+
+/// settings specific to one telegram bot
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BotConfig {
+    pub store_path: String,
+    #[serde(default)]
+    pub store_backend: StoreBackend,
+    pub telegram_token: String,
+    pub bot_uname: String,
+    pub geph_group_id: i64,
+    /// overrides the global `days_per_giftcard` for this bot
+    #[serde(default)]
+    pub days_per_giftcard: Option<u32>,
+    /// overrides the global `num_cards` for this bot
+    #[serde(default)]
+    pub num_cards: Option<u32>,
+    /// the group users are asked to join, in place of `{link}` in templates
+    #[serde(default = "default_group_link")]
+    pub group_link: String,
+    #[serde(default)]
+    pub templates: Templates,
+    /// templates by language code and template name, e.g. `{fa: {congrats: ...}}`, taking
+    /// precedence over the translations the bot ships with
+    #[serde(default)]
+    pub translations: BTreeMap<String, BTreeMap<TemplateKey, String>>,
+    /// overrides the global `default_language` for this bot
+    #[serde(default)]
+    pub default_language: Option<String>,
+    /// celebrating round member counts of `geph_group_id`, see `check_milestones`
+    #[serde(default)]
+    pub milestones: Option<MilestoneConfig>,
+    /// the rules giftcard requests must pass, in order, see [`crate::eligibility`]; `blacklist` is
+    /// required, since nothing else refuses banned users
+    #[serde(default = "default_eligibility")]
+    pub eligibility: Vec<RuleConfig>,
+    #[serde(default)]
+    pub formatting: Formatting,
+    /// also send giftcard codes as QR images, for users who redeem on another device
+    #[serde(default)]
+    pub qr_code: bool,
+    /// letting users wait for a giftcard when the daily cap is reached or the backend fails,
+    /// served by the `process_waitlist` task
+    #[serde(default)]
+    pub waitlist: Option<WaitlistConfig>,
+    #[serde(default)]
+    pub greeting: GreetingConfig,
+    #[serde(default)]
+    pub group_replies: GroupRepliesConfig,
+    /// recurring announcements and maintenance
+    #[serde(default)]
+    pub schedule: Vec<ScheduledJob>,
+    /// groups to require instead of `geph_group_id`, by telegram language code such as `zh` or
+    /// `fa`; regional codes like `zh-hans` fall back to their language
+    #[serde(default)]
+    pub language_groups: BTreeMap<String, LanguageGroup>,
+}
+
+impl BotConfig {
+    /// The group a user with this telegram language must join, if it isn't `geph_group_id`.
+    pub fn language_group(&self, language_code: Option<&str>) -> Option<&LanguageGroup> {
+        let code = language_code?.to_lowercase();
+        let language = code.split('-').next().unwrap_or_default();
+        self.language_groups
+            .get(&code)
+            .or_else(|| self.language_groups.get(language))
+    }
+
+    /// Every group users may be required to join.
+    pub fn required_groups(&self) -> impl Iterator<Item = i64> + '_ {
+        std::iter::once(self.geph_group_id)
+            .chain(self.language_groups.values().map(|group| group.group_id))
+    }
+}
+
+/// a waitlist users can join while the bot cannot give out giftcards
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct WaitlistConfig {
+    /// how many users each run of `process_waitlist` serves at most, so that a long waitlist
+    /// doesn't run into telegram's rate limits
+    pub per_run: usize,
+}
+
+impl Default for WaitlistConfig {
+    fn default() -> Self {
+        Self { per_run: 50 }
+    }
+}
+
+/// announcing the group's member count every `every` members, with a bonus giftcard for a random
+/// recent recipient
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MilestoneConfig {
+    pub every: u64,
+    /// duration of the bonus card, the bot's `days_per_giftcard` if unset
+    #[serde(default)]
+    pub bonus_days: Option<u32>,
+    /// how recently the winner must have received their giftcard
+    #[serde(default = "default_milestone_recent_days")]
+    pub recent_days: u64,
+}
+
+fn default_milestone_recent_days() -> u64 {
+    30
+}
+
+/// a check giftcard requests must pass, e.g. `group_membership` or `{daily_cap: {max: 500}}`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleConfig {
+    /// refuse banned users
+    Blacklist,
+    /// require membership of `geph_group_id`, or of the user's language group
+    GroupMembership,
+    /// send accounts flagged by the `fraud` heuristics to manual review
+    AccountHeuristics,
+    /// refuse users who were given a card less than `days` ago, e.g. before a reset
+    Cooldown { days: u64 },
+    /// refuse requests once `max` cards were issued since midnight UTC
+    DailyCap { max: usize },
+}
+
+/// The most codes a user can receive at once.
+const MAX_NUM_CARDS: u32 = 10;
+
+fn default_num_cards() -> u32 {
+    1
+}
+
+fn default_group_link() -> String {
+    "https://t.me/gephusers".to_owned()
+}
+
+fn default_eligibility() -> Vec<RuleConfig> {
+    vec![
+        RuleConfig::Blacklist,
+        RuleConfig::GroupMembership,
+        RuleConfig::AccountHeuristics,
+    ]
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LanguageGroup {
+    pub group_id: i64,
+    /// link sent to users who haven't joined, in place of `{link}` in `join_language_group`
+    pub invite_link: String,
+}
+
+/// something the bot does whenever `cron` matches, e.g. `{cron: "0 12 * * 1", announce: ...}`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ScheduledJob {
+    /// five-field cron expression, in UTC
+    pub cron: String,
+    #[serde(flatten)]
+    pub action: ScheduledAction,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduledAction {
+    /// post this text to the group, with `{days}` replaced as in templates
+    Announce(String),
+    Maintenance(MaintenanceTask),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceTask {
+    /// reclaim the space left by deleted data, for backends that need it
+    CompactStore,
+    /// send codes again to users who haven't redeemed them, see `card_usage`
+    RemindUnusedCards,
+    /// move old redemptions out of the store into a gzipped archive next to it, see `archive`
+    ArchiveRedemptions,
+    /// flag recent recipients who have left the group, see `membership_recheck`
+    RecheckMembership,
+    /// announce member count milestones of the group, see `milestones`
+    CheckMilestones,
+    /// copy the store to the `backup` directory or bucket
+    BackupStore,
+    /// give giftcards to users on the `waitlist`, in the order they joined it, while the bot can
+    ProcessWaitlist,
+}
+
+/// welcoming people who join the group with the `welcome` template
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct GreetingConfig {
+    pub enabled: bool,
+    /// the greeting is deleted after this long, or kept if 0
+    pub delete_after_secs: u64,
+    /// joins within this long after a greeting go unwelcomed, so mass joins don't flood the group
+    pub min_interval_secs: u64,
+}
+
+/// cleaning up after the bot's replies to mentions in the group, so that they don't clutter it
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct GroupRepliesConfig {
+    /// replies are deleted after this long, or kept if 0
+    pub delete_after_secs: u64,
+    /// also delete the message that mentioned the bot, which the bot can only do as a group admin
+    /// allowed to delete messages
+    pub delete_mention: bool,
+}
+
+impl Default for GreetingConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            delete_after_secs: 60,
+            min_interval_secs: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StoreBackend {
+    #[default]
+    Json,
+    /// requires building with the `sqlite` feature
+    Sqlite,
+}
+
+/// how the templates are rendered by telegram
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Formatting {
+    /// `HTML` or `MarkdownV2`, in which case every template must be escaped for that syntax;
+    /// templates are sent as plain text if unset
+    #[serde(default)]
+    pub parse_mode: Option<ParseMode>,
+    #[serde(default)]
+    pub disable_link_preview: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+    /// the log is rotated once it would grow beyond this size
+    #[serde(default = "default_audit_max_bytes")]
+    pub max_bytes: u64,
+    /// whether the log is also rotated when the (UTC) day changes
+    #[serde(default = "default_true")]
+    pub rotate_daily: bool,
+}
+
+/// proxies for outbound connections, as urls like `socks5h://127.0.0.1:9909` or
+/// `http://proxy:3128`
+///
+/// Webhooks and error reports don't use these, but honor the usual `HTTPS_PROXY` variables.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ProxyConfig {
+    /// used for both telegram and the giftcard backend, unless they have their own
+    pub all: Option<String>,
+    pub telegram: Option<String>,
+    pub backend: Option<String>,
+}
+
+impl ProxyConfig {
+    pub fn telegram(&self) -> Option<&str> {
+        self.telegram.as_deref().or(self.all.as_deref())
+    }
+
+    pub fn backend(&self) -> Option<&str> {
+        self.backend.as_deref().or(self.all.as_deref())
+    }
+}
+
+/// following up on giftcards that were issued but never redeemed
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct CardUsageConfig {
+    /// how long after issuance the `remind_unused_cards` task reminds users
+    pub remind_after_days: u64,
+}
+
+impl Default for CardUsageConfig {
+    fn default() -> Self {
+        Self {
+            remind_after_days: 3,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ArchiveConfig {
+    /// how old a redemption must be for the `archive_redemptions` task to archive it
+    pub after_days: u64,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self { after_days: 365 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MembershipRecheckConfig {
+    /// how long after redeeming leaving the group gets a user flagged
+    pub window_days: u64,
+    /// whether to have the backend cancel the unused codes of flagged users, rather than only
+    /// telling the admins
+    pub cancel_codes: bool,
+}
+
+impl Default for MembershipRecheckConfig {
+    fn default() -> Self {
+        Self {
+            window_days: 30,
+            cancel_codes: false,
+        }
+    }
+}
+
+/// answering users who keep asking after they received their giftcard
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct RepeatAttemptsConfig {
+    /// on which repeated request to send `support_contact` instead of `already_redeemed`; later
+    /// requests go unanswered, so they don't use up the bot's telegram rate limits. 0 never
+    /// escalates
+    pub escalate_after: u32,
+    /// where users are sent for help, in place of `{support}` in `support_contact`
+    pub support_link: String,
+}
+
+impl Default for RepeatAttemptsConfig {
+    fn default() -> Self {
+        Self {
+            escalate_after: 3,
+            support_link: "https://t.me/gephusers".to_owned(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WebhookConfig {
+    pub url: String,
+    /// key of the hmac-sha256 signature sent along with each event
+    pub secret: String,
+    /// which events to send, all of them if empty
+    #[serde(default)]
+    pub events: Vec<WebhookEventKind>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    /// a user received a giftcard, including after a review or through #Grant
+    Issued,
+    /// the anti-fraud heuristics sent a user to manual review
+    FraudFlagged,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DashboardConfig {
+    /// address to serve on; put a tls-terminating proxy in front if it isn't localhost
+    #[serde(default = "default_dashboard_listen")]
+    pub listen: String,
+    /// the password for http basic auth (with any username), or a bearer token
+    pub token: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PhoneCheckConfig {
+    /// key of the hmac under which phone numbers are stored; changing it forgets every number
+    pub salt: String,
+}
+
+/// copies of each bot's store, named `<bot_uname>-<UTC time>.<json|sqlite>`, taken whenever a
+/// `backup_store` task is scheduled
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BackupConfig {
+    /// local directory to copy stores into, ideally on another disk
+    #[serde(default)]
+    pub directory: Option<PathBuf>,
+    #[serde(default)]
+    pub s3: Option<S3Config>,
+    /// how many backups of each bot to keep in each place, older ones are deleted
+    #[serde(default = "default_backup_keep")]
+    pub keep: usize,
+}
+
+/// an S3-compatible bucket, such as AWS S3, Backblaze B2 or MinIO
+#[derive(Serialize, Deserialize, Clone)]
+pub struct S3Config {
+    /// e.g. `https://s3.eu-central-1.amazonaws.com`; buckets are addressed path-style under it
+    pub endpoint: String,
+    pub bucket: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    /// prepended to the backup names, e.g. `giftcard-bot/`
+    #[serde(default)]
+    pub prefix: String,
+}
+
+fn default_backup_keep() -> usize {
+    14
+}
+
+fn default_s3_region() -> String {
+    "us-east-1".to_owned()
+}
+
+fn default_dashboard_listen() -> String {
+    "127.0.0.1:8080".to_owned()
+}
+
+fn default_audit_max_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// where to send alerts about panics, handler errors and backend outages
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ErrorReportingConfig {
+    pub sentry_dsn: Option<String>,
+    /// receives a JSON POST for every alert
+    pub webhook_url: Option<String>,
+    /// how many giftcard backend calls in a row must fail before alerting
+    pub backend_failure_threshold: u32,
+}
+
+impl Default for ErrorReportingConfig {
+    fn default() -> Self {
+        Self {
+            sentry_dsn: None,
+            webhook_url: None,
+            backend_failure_threshold: 3,
+        }
+    }
+}
+
+/// how the geph web backend is used and its answers are checked
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct GiftcardBackendConfig {
+    /// answers not matching this regex, like html error pages, are failures rather than codes
+    pub code_pattern: String,
+    /// how many times to ask for a code before giving up
+    pub attempts: u32,
+    /// secrets tried before `create_giftcard_secret` while they are valid, the latest `valid_from`
+    /// first; each one the backend refuses falls back to the next
+    pub secrets: Vec<GiftcardSecretConfig>,
+}
+
+impl Default for GiftcardBackendConfig {
+    fn default() -> Self {
+        Self {
+            code_pattern: "^[A-Za-z0-9-]{6,64}$".to_owned(),
+            attempts: 2,
+            secrets: vec![],
+        }
+    }
+}
+
+/// a secret staged ahead of a rotation, or kept for a while after one
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GiftcardSecretConfig {
+    pub secret: String,
+    /// RFC 3339 time from which the secret is used, e.g. `2025-01-01T00:00:00Z`
+    #[serde(default)]
+    pub valid_from: Option<String>,
+    /// RFC 3339 time from which the secret is no longer used
+    #[serde(default)]
+    pub valid_until: Option<String>,
+}
+
+impl GiftcardSecretConfig {
+    /// The unix times from which and until which the secret is used.
+    pub fn window(&self) -> anyhow::Result<(u64, u64)> {
+        let parse = |time: &Option<String>, unset: u64| match time {
+            Some(time) => chrono::DateTime::parse_from_rfc3339(time)
+                .map(|time| time.timestamp().max(0) as u64)
+                .with_context(|| {
+                    format!("invalid time {time}, expected e.g. 2025-01-01T00:00:00Z")
+                }),
+            None => Ok(unset),
+        };
+        Ok((
+            parse(&self.valid_from, 0)?,
+            parse(&self.valid_until, u64::MAX)?,
+        ))
+    }
+}
+
+/// when to warn the admins over telegram that the giftcard backend keeps failing
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct BackendAlertConfig {
+    /// alert once more than this many calls failed within the window
+    pub max_failures: usize,
+    pub window_minutes: u64,
+}
+
+impl Default for BackendAlertConfig {
+    fn default() -> Self {
+        Self {
+            max_failures: 5,
+            window_minutes: 10,
+        }
+    }
+}
+
+/// how many updates are processed at once, shared by all bots
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct WorkerConfig {
+    pub count: usize,
+    /// updates each worker buffers before the dispatcher has to wait
+    pub queue_size: usize,
+    /// how long handling one update may take before it is abandoned and the user is asked to try
+    /// again, so that a hung call can't hold up the worker
+    pub timeout_secs: u64,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            count: 8,
+            queue_size: 64,
+            timeout_secs: 60,
+        }
+    }
+}
+
+/// retrying messages telegram didn't take, see [`crate::telegram::Outbox`]
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct DeliveryConfig {
+    /// how many times to try sending a message
+    pub attempts: u32,
+    /// the longest flood control wait to sit out rather than give up on the message
+    pub max_retry_after_secs: u64,
+}
+
+impl Default for DeliveryConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            max_retry_after_secs: 30,
+        }
+    }
+}
+
+/// failure injection, see [`crate::chaos`]
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ChaosConfig {
+    pub telegram: ChaosRates,
+    pub backend: ChaosRates,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub struct ChaosRates {
+    /// share of calls that fail, from 0 to 1
+    pub failure_rate: f64,
+    /// share of calls that are held up first, from 0 to 1
+    pub delay_rate: f64,
+    /// the longest a call is held up
+    pub max_delay_ms: u64,
+}
+
+impl Default for ChaosRates {
+    fn default() -> Self {
+        Self {
+            failure_rate: 0.0,
+            delay_rate: 0.0,
+            max_delay_ms: 5000,
+        }
+    }
+}
+
+/// heuristics that send a request to manual review instead of issuing a card directly
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct FraudConfig {
+    /// flag users without a telegram username
+    #[serde(default)]
+    pub flag_no_username: bool,
+    /// flag users whose id is above this value, since recently created accounts get higher ids
+    #[serde(default)]
+    pub flag_user_id_above: Option<u64>,
+}
+
+pub static ARGS: Lazy<Args> = Lazy::new(argh::from_env);
+
+pub static CONFIG: Lazy<Config> = Lazy::new(|| {
+    let s = std::fs::read_to_string(&ARGS.config).expect("cannot read config file");
+    Config::from_yaml(&s, |name| std::env::var(name).ok()).expect("cannot parse config file")
+});
+
+/// Replaces every `${VAR}` in the string values of the parsed yaml with the value of the
+/// environment variable `VAR`, which must be set, so that values can't change the structure of
+/// the yaml around them. A value that is only a variable holding a number or `true`/`false` is
+/// read as that, e.g. for group ids. `$${` stands for a literal `${`.
+fn interpolate_env(
+    value: &mut serde_yaml::Value,
+    env: &impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<()> {
+    use serde_yaml::Value;
+    match value {
+        Value::String(s) => {
+            let whole_variable = s.starts_with("${") && s.find('}') == Some(s.len() - 1);
+            let interpolated = interpolate_str(s, env)?;
+            *value = match serde_yaml::from_str(&interpolated) {
+                Ok(typed @ (Value::Number(_) | Value::Bool(_))) if whole_variable => typed,
+                _ => Value::String(interpolated),
+            };
+        }
+        Value::Sequence(values) => {
+            for value in values {
+                interpolate_env(value, env)?;
+            }
+        }
+        Value::Mapping(map) => {
+            for (_, value) in map.iter_mut() {
+                interpolate_env(value, env)?;
+            }
+        }
+        Value::Tagged(tagged) => interpolate_env(&mut tagged.value, env)?,
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+    Ok(())
+}
+
+fn interpolate_str(raw: &str, env: &impl Fn(&str) -> Option<String>) -> anyhow::Result<String> {
+    let mut out = String::with_capacity(raw.len());
+    let mut rest = raw;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        if let Some(escaped) = rest.strip_prefix("$${") {
+            out.push_str("${");
+            rest = escaped;
+        } else if let Some(var) = rest.strip_prefix("${") {
+            let end = var
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("unterminated ${{ in config"))?;
+            let name = &var[..end];
+            let value = env(name)
+                .ok_or_else(|| anyhow::anyhow!("config refers to ${{{name}}}, which is not set"))?;
+            out.push_str(&value);
+            rest = &var[end + 1..];
+        } else {
+            out.push('$');
+            rest = &rest[1..];
+        }
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Parses a config with a single bot at the top level, storing into `store_path` and with the
+/// given yaml lines added to the settings every bot needs.
+#[cfg(test)]
+pub fn test_config(store_path: &str, extra_yaml: &str) -> Config {
+    let yaml = format!(
+        "store_path: '{store_path}'
+telegram_token: '123:secret'
+bot_uname: GephGiftcardBot
+geph_group_id: -100123
+admin_uname: admin
+create_giftcard_secret: secret
+days_per_giftcard: 3
+{extra_yaml}"
+    );
+    serde_yaml::from_str(&yaml).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = "
+# telegram_token: ${COMMENTED_OUT}
+store_path: store.json
+telegram_token: ${TOKEN}
+bot_uname: GephGiftcardBot
+geph_group_id: ${GROUP_ID}
+admin_uname: admin
+create_giftcard_secret: secret-${SECRET}
+days_per_giftcard: 3
+group_link: https://t.me/$${literal}
+";
+
+    fn env<'a>(vars: &'a [(&str, &str)]) -> impl Fn(&str) -> Option<String> + 'a {
+        |name| {
+            vars.iter()
+                .find(|(var, _)| *var == name)
+                .map(|(_, value)| value.to_string())
+        }
+    }
+
+    #[test]
+    fn interpolates_variables_into_values() {
+        let config = Config::from_yaml(
+            YAML,
+            env(&[
+                ("TOKEN", "*123: abc #def"),
+                ("GROUP_ID", "-100123"),
+                ("SECRET", "&x"),
+            ]),
+        )
+        .unwrap();
+
+        let bot = config.bot.unwrap();
+        assert_eq!(bot.telegram_token, "*123: abc #def");
+        assert_eq!(bot.geph_group_id, -100123);
+        assert_eq!(config.create_giftcard_secret, "secret-&x");
+        assert_eq!(bot.group_link, "https://t.me/${literal}");
+    }
+
+    #[test]
+    fn refuses_unset_variables() {
+        let err = Config::from_yaml(YAML, env(&[("TOKEN", "123:abc")]))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("${GROUP_ID}"));
+    }
+
+    #[test]
+    fn environment_overrides_the_secrets() {
+        let vars = [
+            ("TOKEN", "123:abc"),
+            ("GROUP_ID", "-100123"),
+            ("SECRET", "x"),
+            ("TELEGRAM_TOKEN", "456:def"),
+            ("GIFTCARD_SECRET", "from-env"),
+        ];
+        let config = Config::from_yaml(YAML, env(&vars)).unwrap();
+
+        assert_eq!(config.bot.unwrap().telegram_token, "456:def");
+        assert_eq!(config.create_giftcard_secret, "from-env");
+    }
+
+    #[test]
+    fn reports_mistakes_in_the_top_level_bot() {
+        const BOTS: &str = "
+admin_uname: admin
+create_giftcard_secret: secret
+days_per_giftcard: 3
+bots:
+  - store_path: other.json
+    telegram_token: '456:def'
+    bot_uname: OtherBot
+    geph_group_id: -100456
+    eligibility: [blacklist, {cooldown: {days: 30}}]
+";
+        let config = Config::from_yaml(BOTS, env(&[])).unwrap();
+        assert!(config.bot.is_none());
+        assert!(matches!(
+            config.bots[0].eligibility.as_slice(),
+            [RuleConfig::Blacklist, RuleConfig::Cooldown { days: 30 }]
+        ));
+
+        let mistaken = format!("{BOTS}store_path: store.json\ntelegram_token: '123:abc'\n");
+        let err = Config::from_yaml(&mistaken, env(&[])).err().unwrap();
+        assert!(err.to_string().contains("bot_uname"), "{err}");
+    }
+}
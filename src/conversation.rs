@@ -0,0 +1,81 @@
+//! Multi-message conversations, such as asking an admin for the details of a grant one at a time.
+//!
+//! A [`Flow`] is a named list of steps. While a user is in a flow, each of their private messages
+//! goes to the handler of their current step instead of the usual commands, and the handler's
+//! [`Transition`] decides where they go next. Where a user is, along with what earlier steps
+//! collected, is kept in the store as a [`ConversationState`](crate::store::ConversationState),
+//! so conversations survive restarts. A conversation ends when its last step says so, when the
+//! user sends `/cancel`, or when the user stays silent for the flow's TTL.
+
+use std::collections::BTreeMap;
+
+use teloxide::types::Message;
+
+use crate::{BoxFuture, service::BotService};
+
+/// What happens after a step has handled a message.
+pub enum Transition {
+    /// go on to the named step
+    Next(&'static str),
+    /// ask for the same step again, e.g. after invalid input
+    Repeat,
+    /// the conversation is over
+    Done,
+}
+
+/// Handles one message in a step, given its text and the data collected so far.
+pub type StepHandler = for<'a> fn(
+    &'a BotService,
+    &'a Message,
+    &'a str,
+    &'a mut BTreeMap<String, String>,
+) -> BoxFuture<'a, anyhow::Result<Transition>>;
+
+pub struct Flow {
+    pub name: &'static str,
+    /// how long the user may take to answer each step
+    pub ttl_secs: u64,
+    steps: Vec<(&'static str, StepHandler)>,
+}
+
+impl Flow {
+    pub fn new(name: &'static str, ttl_secs: u64) -> Self {
+        Self {
+            name,
+            ttl_secs,
+            steps: vec![],
+        }
+    }
+
+    /// Adds a step. The first step added is where the flow starts.
+    pub fn step(mut self, name: &'static str, handler: StepHandler) -> Self {
+        self.steps.push((name, handler));
+        self
+    }
+
+    pub fn first_step(&self) -> Option<&'static str> {
+        self.steps.first().map(|(name, _)| *name)
+    }
+
+    pub fn handler(&self, step: &str) -> Option<StepHandler> {
+        self.steps
+            .iter()
+            .find(|(name, _)| *name == step)
+            .map(|(_, handler)| *handler)
+    }
+}
+
+/// Every flow the bot knows, looked up by name.
+#[derive(Default)]
+pub struct Flows(Vec<Flow>);
+
+impl Flows {
+    pub fn flow(mut self, flow: Flow) -> Self {
+        self.0.push(flow);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Flow> {
+        self.0.iter().find(|flow| flow.name == name)
+    }
+}
@@ -0,0 +1,418 @@
+//! A web ui for support staff who would rather not use the admin commands in telegram.
+//!
+//! It shows each bot's statistics, recent redemptions, the backend's recent failures and the
+//! queue of pending reviews, with buttons to approve, reject, ban, unban and reset users, each of
+//! which is recorded in the audit log. Rejecting, banning and resetting a user first ask for
+//! confirmation on a page of their own. Every request needs the configured token, either as the
+//! password of http basic auth or as a bearer token, and the page is plain html without scripts,
+//! so any browser can use it.
+
+use std::{
+    convert::Infallible,
+    fmt::Write,
+    net::SocketAddr,
+    sync::{Arc, atomic::Ordering},
+};
+
+use anyhow::Context;
+use base64::{Engine, prelude::BASE64_STANDARD};
+use chrono::DateTime;
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::{
+    Method, Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header::{AUTHORIZATION, CONTENT_TYPE, HOST, LOCATION, ORIGIN, WWW_AUTHENTICATE},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use serde::Serialize;
+use tokio::net::TcpListener;
+
+use crate::{
+    audit::{AuditEntry, Outcome},
+    config::DashboardConfig,
+    service::BotService,
+};
+
+/// How many of the latest redemptions each bot lists.
+const RECENT_REDEMPTIONS: usize = 20;
+
+struct Dashboard {
+    token: String,
+    services: Vec<Arc<BotService>>,
+}
+
+/// Everything the page shows, also served as JSON at `/api/stats`.
+#[derive(Serialize)]
+struct Stats {
+    /// giftcard backend failures within the alert window
+    backend_failures: usize,
+    backend_window_minutes: u64,
+    bots: Vec<BotStats>,
+}
+
+#[derive(Serialize)]
+struct BotStats {
+    bot: String,
+    redemptions: usize,
+    redemptions_last_day: usize,
+    banned: usize,
+    /// updates abandoned for taking too long since the bot started
+    timed_out_updates: u64,
+    pending_reviews: Vec<PendingRow>,
+    /// `(user_id, redeemed_at)`, newest first
+    recent_redemptions: Vec<(i64, u64)>,
+}
+
+#[derive(Serialize)]
+struct PendingRow {
+    user_id: i64,
+    reasons: Vec<String>,
+    requested_at: u64,
+}
+
+/// Binds the configured address and serves the dashboard in the background.
+pub async fn spawn(
+    config: &DashboardConfig,
+    services: Vec<Arc<BotService>>,
+) -> anyhow::Result<SocketAddr> {
+    let listener = TcpListener::bind(&config.listen)
+        .await
+        .with_context(|| format!("cannot serve the dashboard on {}", config.listen))?;
+    let addr = listener.local_addr()?;
+    let dashboard = Arc::new(Dashboard {
+        token: config.token.clone(),
+        services,
+    });
+
+    tokio::spawn(async move {
+        loop {
+            let stream = match listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(err) => {
+                    eprintln!("dashboard cannot accept a connection: {err:?}");
+                    continue;
+                }
+            };
+            let dashboard = dashboard.clone();
+            tokio::spawn(async move {
+                let service = service_fn(move |req| handle(dashboard.clone(), req));
+                let _ = http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .await;
+            });
+        }
+    });
+    Ok(addr)
+}
+
+async fn handle(
+    dashboard: Arc<Dashboard>,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    if !dashboard.authorized(&req) {
+        let mut response = text(StatusCode::UNAUTHORIZED, "unauthorized");
+        response.headers_mut().insert(
+            WWW_AUTHENTICATE,
+            "Basic realm=\"giftcard bot\"".parse().unwrap(),
+        );
+        return Ok(response);
+    }
+
+    let path: Vec<&str> = req
+        .uri()
+        .path()
+        .split('/')
+        .filter(|s| !s.is_empty())
+        .collect();
+    let result = match (req.method(), path.as_slice()) {
+        (&Method::GET, []) => dashboard.stats().map(|stats| {
+            let mut response = Response::new(Full::new(Bytes::from(render(&stats))));
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "text/html; charset=utf-8".parse().unwrap());
+            response
+        }),
+        (&Method::GET, ["api", "stats"]) => dashboard.stats().map(|stats| {
+            let mut response = Response::new(Full::new(Bytes::from(
+                serde_json::to_vec(&stats).expect("stats always serialize"),
+            )));
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+            response
+        }),
+        (&Method::POST, ["bots", bot, "users"]) => {
+            let bot = bot.to_string();
+            if !same_origin(&req) {
+                return Ok(text(StatusCode::FORBIDDEN, "cross-origin request refused"));
+            }
+            match Limited::new(req.into_body(), 4096).collect().await {
+                Ok(body) => {
+                    let body = String::from_utf8_lossy(&body.to_bytes()).into_owned();
+                    dashboard.act(&bot, &body).await
+                }
+                Err(_) => Ok(text(StatusCode::BAD_REQUEST, "unreadable form")),
+            }
+        }
+        _ => Ok(text(StatusCode::NOT_FOUND, "not found")),
+    };
+
+    Ok(result.unwrap_or_else(|err| {
+        eprintln!("dashboard request failed: {err:?}");
+        text(StatusCode::INTERNAL_SERVER_ERROR, &format!("{err:#}"))
+    }))
+}
+
+impl Dashboard {
+    fn authorized(&self, req: &Request<Incoming>) -> bool {
+        let Some(header) = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+        else {
+            return false;
+        };
+        let given = if let Some(token) = header.strip_prefix("Bearer ") {
+            token.trim().to_owned()
+        } else if let Some(credentials) = header.strip_prefix("Basic ") {
+            let decoded = BASE64_STANDARD
+                .decode(credentials.trim())
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok());
+            // the username is not checked, so support staff can use their own names
+            match decoded.as_deref().and_then(|d| d.split_once(':')) {
+                Some((_, password)) => password.to_owned(),
+                None => return false,
+            }
+        } else {
+            return false;
+        };
+        constant_time_eq(given.as_bytes(), self.token.as_bytes())
+    }
+
+    fn stats(&self) -> anyhow::Result<Stats> {
+        let Some(first) = self.services.first() else {
+            anyhow::bail!("no bots are running");
+        };
+        let mut bots = vec![];
+        for service in &self.services {
+            let now = service.clock.unix_now();
+            let mut redemptions = service.store.redemptions()?;
+            redemptions.sort_by_key(|(_, redeemed_at)| std::cmp::Reverse(*redeemed_at));
+            let mut pending_reviews: Vec<PendingRow> = service
+                .store
+                .pending_reviews()?
+                .into_iter()
+                .map(|(user_id, pending)| PendingRow {
+                    user_id,
+                    reasons: pending.reasons,
+                    requested_at: pending.requested_at,
+                })
+                .collect();
+            pending_reviews.sort_by_key(|row| row.requested_at);
+
+            bots.push(BotStats {
+                bot: service.config.bot_uname.clone(),
+                redemptions: redemptions.len(),
+                redemptions_last_day: redemptions
+                    .iter()
+                    .filter(|(_, redeemed_at)| *redeemed_at > now.saturating_sub(86400))
+                    .count(),
+                banned: service.store.banned_users()?.len(),
+                timed_out_updates: service.timed_out_updates.load(Ordering::Relaxed),
+                pending_reviews,
+                recent_redemptions: redemptions.into_iter().take(RECENT_REDEMPTIONS).collect(),
+            });
+        }
+        Ok(Stats {
+            backend_failures: first.backend_alerts.recent_failures(),
+            backend_window_minutes: first.global.backend_alert.window_minutes,
+            bots,
+        })
+    }
+
+    /// Carries out a form submission, then sends the browser back to the page.
+    async fn act(&self, bot: &str, form: &str) -> anyhow::Result<Response<Full<Bytes>>> {
+        let Some(service) = self.services.iter().find(|s| s.config.bot_uname == bot) else {
+            return Ok(text(StatusCode::NOT_FOUND, "no such bot"));
+        };
+        let field = |name: &str| {
+            form.split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value.trim())
+        };
+        let Some(Ok(user_id)) = field("user_id").map(str::parse::<i64>) else {
+            return Ok(text(StatusCode::BAD_REQUEST, "user_id must be a number"));
+        };
+        let action = field("action").unwrap_or_default();
+        if DESTRUCTIVE_ACTIONS.contains(&action) && field("confirm") != Some("yes") {
+            let mut response = text(StatusCode::OK, &render_confirmation(bot, user_id, action));
+            response
+                .headers_mut()
+                .insert(CONTENT_TYPE, "text/html; charset=utf-8".parse()?);
+            return Ok(response);
+        }
+
+        let result = match action {
+            "approve" => service.resolve_review(user_id, true).await.map(drop),
+            "reject" => service.resolve_review(user_id, false).await.map(drop),
+            "ban" => service.ban_user(user_id),
+            "unban" => service.store.unban(user_id).map(drop),
+            "reset" => service.store.reset_user(user_id).map(drop),
+            _ => return Ok(text(StatusCode::BAD_REQUEST, "unknown action")),
+        };
+        let mut audit = AuditEntry::new(bot, user_id);
+        audit.outcome = Some(Outcome::DashboardAction);
+        audit.check("action", action);
+        service.audit.record(audit, &result);
+        result?;
+        eprintln!("[{bot}] dashboard: {action} user {user_id}");
+
+        let mut response = text(StatusCode::SEE_OTHER, "done");
+        response.headers_mut().insert(LOCATION, "/".parse()?);
+        Ok(response)
+    }
+}
+
+/// Actions that can't be undone with another button, so they are only carried out once the form
+/// comes back with `confirm=yes`.
+const DESTRUCTIVE_ACTIONS: &[&str] = &["reject", "ban", "reset"];
+
+/// Refuses forms posted from other sites, which the browser would send with the basic auth
+/// credentials it remembers.
+fn same_origin(req: &Request<Incoming>) -> bool {
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    match header(ORIGIN) {
+        None => true,
+        Some(origin) => {
+            let origin_host = origin.split_once("://").map(|(_, host)| host);
+            origin_host.is_some() && origin_host == header(HOST)
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn text(status: StatusCode, body: &str) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::new(Bytes::from(body.to_owned())));
+    *response.status_mut() = status;
+    response
+}
+
+fn render(stats: &Stats) -> String {
+    let mut html = String::from(
+        "<!doctype html><html><head><meta charset=utf-8>\
+         <meta http-equiv=refresh content=30><title>Giftcard bot</title>\
+         <style>body{font-family:sans-serif;margin:2em}table{border-collapse:collapse}\
+         td,th{border:1px solid #ccc;padding:.3em .6em;text-align:left}\
+         form{display:inline}</style></head><body><h1>Giftcard bot</h1>",
+    );
+    let _ = write!(
+        html,
+        "<p>Giftcard backend failures in the last {} minutes: <b>{}</b></p>",
+        stats.backend_window_minutes, stats.backend_failures
+    );
+
+    for bot in &stats.bots {
+        let name = escape(&bot.bot);
+        let _ = write!(
+            html,
+            "<h2>@{name}</h2><p>{} redemptions, {} in the last day, {} banned users, \
+             {} timed out updates</p>",
+            bot.redemptions, bot.redemptions_last_day, bot.banned, bot.timed_out_updates
+        );
+        let _ = write!(
+            html,
+            "<form method=post action=\"/bots/{name}/users\">\
+             <input name=user_id placeholder=\"user id\" required> \
+             <button name=action value=ban>Ban</button> \
+             <button name=action value=unban>Unban</button> \
+             <button name=action value=reset>Reset</button></form>"
+        );
+
+        let _ = write!(
+            html,
+            "<h3>Pending reviews ({})</h3>",
+            bot.pending_reviews.len()
+        );
+        if !bot.pending_reviews.is_empty() {
+            html.push_str(
+                "<table><tr><th>User</th><th>Requested</th><th>Reasons</th><th></th></tr>",
+            );
+            for row in &bot.pending_reviews {
+                let _ = write!(
+                    html,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    row.user_id,
+                    time(row.requested_at),
+                    escape(&row.reasons.join(", ")),
+                    buttons(&name, row.user_id, &["approve", "reject"]),
+                );
+            }
+            html.push_str("</table>");
+        }
+
+        html.push_str(
+            "<h3>Recent redemptions</h3><table><tr><th>User</th><th>Redeemed</th><th></th></tr>",
+        );
+        for (user_id, redeemed_at) in &bot.recent_redemptions {
+            let _ = write!(
+                html,
+                "<tr><td>{user_id}</td><td>{}</td><td>{}</td></tr>",
+                time(*redeemed_at),
+                buttons(&name, *user_id, &["reset", "ban"]),
+            );
+        }
+        html.push_str("</table>");
+    }
+    html.push_str("</body></html>");
+    html
+}
+
+fn render_confirmation(bot: &str, user_id: i64, action: &str) -> String {
+    let bot = escape(bot);
+    format!(
+        "<!doctype html><html><head><meta charset=utf-8><title>Giftcard bot</title>\
+         <style>body{{font-family:sans-serif;margin:2em}}</style></head><body>\
+         <p>Really {action} user {user_id} of @{bot}?</p>\
+         <form method=post action=\"/bots/{bot}/users\">\
+         <input type=hidden name=user_id value={user_id}>\
+         <input type=hidden name=action value={action}>\
+         <button name=confirm value=yes>Yes, {action}</button></form> \
+         <a href=\"/\">Cancel</a></body></html>"
+    )
+}
+
+fn buttons(bot: &str, user_id: i64, actions: &[&str]) -> String {
+    let mut html = format!(
+        "<form method=post action=\"/bots/{bot}/users\">\
+         <input type=hidden name=user_id value={user_id}>"
+    );
+    for action in actions {
+        let _ = write!(
+            html,
+            "<button name=action value={action}>{action}</button> "
+        );
+    }
+    html.push_str("</form>");
+    html
+}
+
+fn time(unix: u64) -> String {
+    match DateTime::from_timestamp(unix as i64, 0) {
+        Some(t) if unix > 0 => t.format("%Y-%m-%d %H:%M UTC").to_string(),
+        _ => "unknown".to_owned(),
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
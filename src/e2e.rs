@@ -0,0 +1,656 @@
+//! End-to-end tests: JSON updates go through the real dispatcher schema, worker pool, store and
+//! HTTP clients, with the Bot API and the giftcard backend answered by [`MockServer`].
+//!
+//! To cover a new command, feed its update with [`Harness::feed`] and assert on the requests the
+//! mock server recorded.
+
+mod mock_server;
+
+use std::sync::{Arc, atomic::AtomicU64};
+
+use serde_json::{Value, json};
+use teloxide::types::Update;
+use tempfile::TempDir;
+use tokio::sync::oneshot;
+
+use self::mock_server::MockServer;
+use crate::{
+    alerts::BackendAlerts,
+    audit::AuditLog,
+    backup,
+    config::{Config, DashboardConfig, GiftcardBackendConfig, MaintenanceTask, ScheduledAction},
+    dashboard,
+    giftcard::{GephBackend, GiftcardError, GiftcardProvider},
+    reporting::ErrorReporter,
+    service::{BotService, SystemClock},
+    store::open_storage,
+    telegram::{Outbox, build_bot},
+    webhooks::{self, Webhooks},
+    workers::WorkerPool,
+};
+
+const ADMIN_ID: u64 = 42;
+const USER_ID: u64 = 1000;
+const GROUP_ID: i64 = -100123;
+const TOKEN: &str = "123:secret";
+const DATE: u64 = 1_700_000_000;
+
+struct Harness {
+    server: MockServer,
+    service: Arc<BotService>,
+    workers: Arc<WorkerPool>,
+    next_update_id: i32,
+    dir: TempDir,
+}
+
+impl Harness {
+    async fn new(extra_yaml: &str) -> Self {
+        let server = MockServer::start().await;
+        let dir = tempfile::tempdir().unwrap();
+        let yaml = format!(
+            "store_path: {store_path}
+telegram_token: '{TOKEN}'
+bot_uname: GephGiftcardBot
+geph_group_id: {GROUP_ID}
+admin_uname: admin
+admin_ids: [{ADMIN_ID}]
+telegram_api_url: {api_url}
+create_giftcard_secret: backend-secret
+days_per_giftcard: 3
+audit_log: {{ path: {audit_path} }}
+{extra_yaml}",
+            store_path = dir.path().join("store.json").display(),
+            audit_path = dir.path().join("audit.jsonl").display(),
+            api_url = server.url,
+            extra_yaml = extra_yaml.replace("{mock_url}", &server.url),
+        );
+        let global: Config = serde_yaml::from_str(&yaml).unwrap();
+        let config = global.bot.clone().unwrap();
+
+        let bot = build_bot(&config, &global).unwrap();
+        let workers = Arc::new(WorkerPool::new(2, 8));
+        let service = Arc::new(BotService {
+            store: open_storage(&config).unwrap(),
+            config,
+            telegram: Arc::new(Outbox::new(Arc::new(bot), &global.delivery)),
+            giftcards: Arc::new(
+                GephBackend::new(
+                    &server.url,
+                    &global.create_giftcard_secret,
+                    &global.giftcard_backend,
+                    None,
+                )
+                .unwrap(),
+            ),
+            clock: Arc::new(SystemClock),
+            audit: Arc::new(AuditLog::open(global.audit_log.as_ref()).unwrap()),
+            reporter: Arc::new(ErrorReporter::new(&global).unwrap()),
+            backend_alerts: Arc::new(BackendAlerts::new(&global.backend_alert)),
+            webhooks: Arc::new(Webhooks::new(&global.webhooks).unwrap()),
+            global: Arc::new(global),
+            last_greeting: AtomicU64::new(0),
+            timed_out_updates: AtomicU64::new(0),
+            workers: workers.clone(),
+        });
+
+        Self {
+            server,
+            service,
+            workers,
+            next_update_id: 1,
+            dir,
+        }
+    }
+
+    /// Runs one update through the dispatcher schema and waits until its handler has finished.
+    async fn feed(&mut self, update: Value) {
+        let mut update = update;
+        update["update_id"] = json!(self.next_update_id);
+        self.next_update_id += 1;
+        // Update only parses the message kind from a string, not from a Value
+        let update: Update = serde_json::from_str(&update.to_string()).unwrap();
+        let key = update.from().map(|user| user.id.0).unwrap_or_default();
+
+        let result = crate::schema()
+            .dispatch(teloxide::dptree::deps![
+                self.service.clone(),
+                self.workers.clone(),
+                update
+            ])
+            .await;
+        assert!(result.is_break(), "update was not handled");
+
+        // jobs with the same key run in order, so once this one runs the handler is done
+        let (done, finished) = oneshot::channel();
+        self.workers
+            .submit(key, async move {
+                let _ = done.send(());
+            })
+            .await;
+        finished.await.unwrap();
+    }
+}
+
+fn user(id: u64, username: Option<&str>) -> Value {
+    json!({ "id": id, "is_bot": false, "first_name": "Test", "username": username })
+}
+
+fn private_message(from: Value, text: &str) -> Value {
+    json!({
+        "message": {
+            "message_id": 7,
+            "date": DATE,
+            "chat": { "id": from["id"], "type": "private", "first_name": "Test" },
+            "from": from,
+            "text": text,
+        }
+    })
+}
+
+fn group_message(text: &str) -> Value {
+    json!({
+        "message": {
+            "message_id": 99,
+            "date": DATE,
+            "chat": { "id": GROUP_ID, "type": "supergroup", "title": "Geph" },
+            "from": user(USER_ID, Some("alice")),
+            "text": text,
+        }
+    })
+}
+
+fn callback(from: Value, data: &str) -> Value {
+    json!({
+        "callback_query": {
+            "id": "query",
+            "from": from,
+            "chat_instance": "instance",
+            "data": data,
+            "message": {
+                "message_id": 5,
+                "date": DATE,
+                "chat": { "id": ADMIN_ID, "type": "private", "first_name": "Admin" },
+                "text": "🔎 Manual review requested",
+            },
+        }
+    })
+}
+
+#[tokio::test]
+async fn group_member_receives_giftcard() {
+    let mut h = Harness::new("").await;
+    h.server.set_member(USER_ID, "member");
+    h.server.set_giftcard_code("GIFT-E2E-0001");
+
+    h.feed(private_message(user(USER_ID, Some("alice")), "hi"))
+        .await;
+
+    let membership = h.server.calls("getChatMember");
+    assert_eq!(membership[0]["chat_id"], json!(GROUP_ID));
+    assert_eq!(membership[0]["user_id"], json!(USER_ID));
+
+    let backend = h.server.calls("create-giftcards");
+    assert_eq!(
+        backend,
+        vec![json!({ "days_per_card": 3, "num_cards": 1, "secret": "backend-secret" })]
+    );
+
+    let texts = h.server.texts_to(USER_ID as i64);
+    assert_eq!(texts.len(), 3);
+    assert!(texts[0].contains("3-day"));
+    assert_eq!(texts[1], "GIFT-E2E-0001");
+
+    // a second request is refused without calling the backend again
+    h.feed(private_message(user(USER_ID, Some("alice")), "hi again"))
+        .await;
+    assert_eq!(h.server.calls("create-giftcards").len(), 1);
+    assert_eq!(
+        h.server.texts_to(USER_ID as i64)[3],
+        h.service.config.templates.already_redeemed
+    );
+}
+
+#[tokio::test]
+async fn qr_code_is_uploaded_with_the_giftcard() {
+    let mut h = Harness::new("qr_code: true").await;
+    h.server.set_member(USER_ID, "member");
+
+    h.feed(private_message(user(USER_ID, Some("alice")), "hi"))
+        .await;
+
+    assert_eq!(h.server.calls("sendPhoto").len(), 1);
+    assert!(h.service.store.is_redeemed(USER_ID as i64).unwrap());
+}
+
+#[tokio::test]
+async fn bundles_arrive_in_one_message() {
+    let mut h = Harness::new("num_cards: 3").await;
+    h.server.set_member(USER_ID, "member");
+
+    h.feed(private_message(user(USER_ID, Some("alice")), "hi"))
+        .await;
+
+    let calls = h.server.calls("create-giftcards");
+    assert_eq!(calls.len(), 1);
+    assert_eq!(calls[0]["num_cards"], 3);
+    assert_eq!(calls[0]["days_per_card"], 3);
+    let texts = h.server.texts_to(USER_ID as i64);
+    assert_eq!(texts.len(), 3);
+    assert!(texts[0].contains("3 Geph Plus giftcards of 3 days each"));
+    assert_eq!(
+        texts[1],
+        "GIFT-ABCD-1234-1\nGIFT-ABCD-1234-2\nGIFT-ABCD-1234-3"
+    );
+    assert_eq!(h.service.store.cards().unwrap().len(), 3);
+    assert_eq!(h.service.store.redemption_count().unwrap(), 1);
+}
+
+#[tokio::test]
+async fn non_member_is_asked_to_join() {
+    let mut h = Harness::new("").await;
+
+    h.feed(private_message(user(USER_ID, Some("alice")), "hi"))
+        .await;
+
+    assert!(h.server.calls("create-giftcards").is_empty());
+    assert_eq!(
+        h.server.texts_to(USER_ID as i64),
+        vec![
+            h.service
+                .config
+                .templates
+                .join_group
+                .replace("{link}", "https://t.me/gephusers")
+        ]
+    );
+}
+
+#[tokio::test]
+async fn users_who_blocked_the_bot_are_flagged() {
+    let mut h = Harness::new("").await;
+    h.server.block(USER_ID as i64);
+
+    h.feed(private_message(user(USER_ID, Some("alice")), "hi"))
+        .await;
+
+    assert_eq!(h.server.calls("sendMessage").len(), 1);
+    assert_eq!(
+        h.service.store.undeliverable_users().unwrap(),
+        vec![USER_ID as i64]
+    );
+}
+
+#[tokio::test]
+async fn flagged_user_is_approved_by_admin() {
+    let mut h = Harness::new("fraud:\n  flag_no_username: true").await;
+    h.server.set_member(USER_ID, "member");
+
+    h.feed(private_message(user(USER_ID, None), "hi")).await;
+
+    let review = &h.server.calls("sendMessage")[0];
+    assert_eq!(review["chat_id"], json!(ADMIN_ID));
+    let buttons = &review["reply_markup"]["inline_keyboard"][0];
+    assert_eq!(
+        buttons[0]["callback_data"],
+        json!(format!("approve:{USER_ID}"))
+    );
+    assert!(h.server.calls("create-giftcards").is_empty());
+
+    h.feed(callback(
+        user(ADMIN_ID, Some("admin")),
+        &format!("approve:{USER_ID}"),
+    ))
+    .await;
+
+    assert_eq!(h.server.calls("answerCallbackQuery").len(), 1);
+    let edit = &h.server.calls("editMessageText")[0];
+    assert!(edit["text"].as_str().unwrap().ends_with("✅ approved"));
+    assert_eq!(h.server.calls("create-giftcards").len(), 1);
+    assert_eq!(h.server.texts_to(USER_ID as i64)[2], "GIFT-ABCD-1234");
+}
+
+#[tokio::test]
+async fn admin_command_replies_to_admin() {
+    let mut h = Harness::new("").await;
+    h.server.set_member(USER_ID, "member");
+    h.feed(private_message(user(USER_ID, Some("alice")), "hi"))
+        .await;
+
+    h.feed(private_message(
+        user(ADMIN_ID, Some("admin")),
+        "#RecipientCount",
+    ))
+    .await;
+
+    assert_eq!(
+        h.server.texts_to(ADMIN_ID as i64),
+        vec!["🌸 1 users received giftcards!"]
+    );
+}
+
+#[tokio::test]
+async fn group_mention_gets_a_reply() {
+    let mut h = Harness::new("").await;
+
+    h.feed(group_message("hello everyone")).await;
+    assert!(h.server.requests().is_empty());
+
+    h.feed(group_message("@GephGiftcardBot where do I get a card?"))
+        .await;
+
+    let reply = &h.server.calls("sendMessage")[0];
+    assert_eq!(reply["chat_id"], json!(GROUP_ID));
+    assert_eq!(reply["reply_parameters"]["message_id"], json!(99));
+}
+
+#[tokio::test]
+async fn error_pages_are_not_sent_as_codes() {
+    let mut h = Harness::new("").await;
+    h.server.set_member(USER_ID, "member");
+    h.server
+        .set_giftcard_code("<html><body>502 Bad Gateway</body></html>");
+
+    h.feed(private_message(user(USER_ID, Some("alice")), "hi"))
+        .await;
+
+    // the default config asks twice before giving up
+    assert_eq!(h.server.calls("create-giftcards").len(), 2);
+    assert!(h.server.texts_to(USER_ID as i64).is_empty());
+    assert!(!h.service.store.is_redeemed(USER_ID as i64).unwrap());
+}
+
+#[tokio::test]
+async fn rejected_secret_alerts_admins_at_once() {
+    let mut h = Harness::new("").await;
+    h.server.set_member(USER_ID, "member");
+    h.server.fail_giftcard(403, r#"{"error":"bad secret"}"#);
+
+    h.feed(private_message(user(USER_ID, Some("alice")), "hi"))
+        .await;
+
+    // retrying can't fix the secret
+    assert_eq!(h.server.calls("create-giftcards").len(), 1);
+    assert!(!h.service.store.is_redeemed(USER_ID as i64).unwrap());
+    let alert = &h.server.texts_to(ADMIN_ID as i64)[0];
+    assert!(alert.contains("bad secret"), "{alert}");
+}
+
+#[tokio::test]
+async fn rate_limit_is_waited_out() {
+    let mut h = Harness::new("").await;
+    h.server.set_member(USER_ID, "member");
+    h.server
+        .fail_giftcard(429, r#"{"error":"slow down","retry_after":0}"#);
+
+    h.feed(private_message(user(USER_ID, Some("alice")), "hi"))
+        .await;
+
+    assert_eq!(h.server.calls("create-giftcards").len(), 2);
+    assert!(h.service.store.is_redeemed(USER_ID as i64).unwrap());
+    assert!(
+        h.server
+            .texts_to(USER_ID as i64)
+            .iter()
+            .any(|text| text.contains("GIFT-ABCD-1234"))
+    );
+}
+
+#[tokio::test]
+async fn dashboard_shows_stats_and_bans_users() {
+    let mut h = Harness::new("").await;
+    h.server.set_member(USER_ID, "member");
+    h.feed(private_message(user(USER_ID, Some("alice")), "hi"))
+        .await;
+
+    let config = DashboardConfig {
+        listen: "127.0.0.1:0".to_owned(),
+        token: "dashboard-token-1234".to_owned(),
+    };
+    let addr = dashboard::spawn(&config, vec![h.service.clone()])
+        .await
+        .unwrap();
+    let client = reqwest::Client::new();
+
+    let response = client.get(format!("http://{addr}/")).send().await.unwrap();
+    assert_eq!(response.status(), 401);
+
+    let stats: Value = client
+        .get(format!("http://{addr}/api/stats"))
+        .basic_auth("support", Some(&config.token))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(stats["bots"][0]["redemptions"], json!(1));
+    assert_eq!(stats["bots"][0]["recent_redemptions"][0][0], json!(USER_ID));
+
+    let ban = |form: &'static str| {
+        client
+            .post(format!("http://{addr}/bots/GephGiftcardBot/users"))
+            .bearer_auth(&config.token)
+            .header("content-type", "application/x-www-form-urlencoded")
+            .body(form)
+            .send()
+    };
+    let response = ban("user_id=555&action=ban").await.unwrap();
+    assert_eq!(response.status(), 200);
+    assert!(
+        response
+            .text()
+            .await
+            .unwrap()
+            .contains("name=confirm value=yes")
+    );
+    assert!(!h.service.store.is_banned(555).unwrap());
+
+    let response = ban("user_id=555&action=ban&confirm=yes").await.unwrap();
+    // the redirect back to the page is followed
+    assert_eq!(response.status(), 200);
+    assert!(response.text().await.unwrap().contains("1 banned users"));
+    assert!(h.service.store.is_banned(555).unwrap());
+    let audit = std::fs::read_to_string(h.dir.path().join("audit.jsonl")).unwrap();
+    let entry: Value = serde_json::from_str(audit.lines().last().unwrap()).unwrap();
+    assert_eq!(entry["outcome"], "dashboard_action");
+    assert_eq!(entry["user_id"], 555);
+    assert_eq!(entry["checks"]["action"], "ban");
+}
+
+#[tokio::test]
+async fn issuance_and_fraud_flags_reach_webhooks() {
+    let mut h = Harness::new(
+        "webhooks: [{ url: '{mock_url}/webhook', secret: hook-secret }]
+fraud: { flag_no_username: true }",
+    )
+    .await;
+    h.server.set_member(USER_ID, "member");
+    h.server.set_member(USER_ID + 1, "member");
+
+    h.feed(private_message(user(USER_ID, Some("alice")), "hi"))
+        .await;
+    h.feed(private_message(user(USER_ID + 1, None), "hi")).await;
+
+    let hooks = h.server.wait_for("webhook", 2).await;
+    let events: Vec<&Value> = hooks.iter().map(|hook| &hook.body["event"]).collect();
+    assert!(events.contains(&&json!("issued")));
+    assert!(events.contains(&&json!("fraud_flagged")));
+    for hook in &hooks {
+        let timestamp: u64 = hook.headers["x-webhook-timestamp"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let expected = webhooks::signature("hook-secret", timestamp, &hook.body.to_string());
+        assert_eq!(hook.headers["x-webhook-signature"], expected.as_str());
+    }
+}
+
+#[tokio::test]
+async fn unused_cards_are_checked_with_the_backend() {
+    let mut h = Harness::new("").await;
+    h.server.set_member(USER_ID, "member");
+    h.feed(private_message(user(USER_ID, Some("alice")), "hi"))
+        .await;
+
+    h.feed(private_message(
+        user(ADMIN_ID, Some("admin")),
+        "#UnusedCards",
+    ))
+    .await;
+    h.server.set_used("GIFT-ABCD-1234");
+    h.feed(private_message(
+        user(ADMIN_ID, Some("admin")),
+        "#UnusedCards",
+    ))
+    .await;
+
+    let status = h.server.calls("giftcard-status");
+    assert_eq!(status[0]["code"], json!("GIFT-ABCD-1234"));
+    assert_eq!(status[0]["secret"], json!("backend-secret"));
+    assert_eq!(
+        h.server.texts_to(ADMIN_ID as i64),
+        vec![
+            "🃏 1 of 1 issued giftcards have not been redeemed yet",
+            "🃏 0 of 1 issued giftcards have not been redeemed yet",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn backend_requests_go_through_the_proxy() {
+    let server = MockServer::start().await;
+    // the backend's own host doesn't resolve, so only the proxy can answer
+    let backend = GephBackend::new(
+        "http://giftcards.invalid",
+        "backend-secret",
+        &Default::default(),
+        Some(&server.url),
+    )
+    .unwrap();
+
+    let codes = backend.create_giftcards(3, 1).await.unwrap();
+
+    assert_eq!(codes, vec!["GIFT-ABCD-1234"]);
+    assert_eq!(server.calls("create-giftcards").len(), 1);
+}
+
+#[tokio::test]
+async fn stores_are_backed_up_to_a_bucket_and_can_be_restored() {
+    let h = Harness::new(
+        "backup:
+  keep: 2
+  s3:
+    endpoint: {mock_url}
+    bucket: backups
+    access_key_id: backup-key
+    secret_access_key: backup-secret
+    prefix: bots/",
+    )
+    .await;
+    for key in [
+        "bots/GephGiftcardBot-20230101T000000Z.json",
+        "bots/GephGiftcardBot-20230102T000000Z.json",
+        "bots/OtherBot-20230101T000000Z.json",
+    ] {
+        h.server.put_object(key, b"old");
+    }
+    h.service
+        .store
+        .record_redemption(USER_ID as i64, DATE)
+        .unwrap();
+
+    let task = ScheduledAction::Maintenance(MaintenanceTask::BackupStore);
+    h.service.run_scheduled(&task).await.unwrap();
+
+    let objects = h.server.objects();
+    let (new_key, backup) = objects
+        .iter()
+        .find(|(_, contents)| contents.as_slice() != b"old")
+        .unwrap();
+    assert!(new_key.starts_with("bots/GephGiftcardBot-20"));
+    let mut keys: Vec<&str> = objects.keys().map(String::as_str).collect();
+    keys.retain(|key| key != new_key);
+    assert_eq!(
+        keys,
+        vec![
+            "bots/GephGiftcardBot-20230102T000000Z.json",
+            "bots/OtherBot-20230101T000000Z.json",
+        ]
+    );
+    let put = &h.server.wait_for("s3 PUT", 1).await[0];
+    let authorization = put.headers["authorization"].to_str().unwrap();
+    assert!(authorization.starts_with("AWS4-HMAC-SHA256 Credential=backup-key/"));
+    assert!(!authorization.contains("backup-secret"));
+
+    let dir = tempfile::tempdir().unwrap();
+    let mut config = h.service.config.clone();
+    config.store_path = dir.path().join("store.json").display().to_string();
+    let file = dir.path().join("backup.json");
+    std::fs::write(&file, "not a store").unwrap();
+    assert!(backup::restore(&config, &file).is_err());
+    assert!(!dir.path().join("store.json").exists());
+
+    std::fs::write(&file, backup).unwrap();
+    assert_eq!(backup::restore(&config, &file).unwrap(), 1);
+    assert!(
+        open_storage(&config)
+            .unwrap()
+            .is_redeemed(USER_ID as i64)
+            .unwrap()
+    );
+}
+
+#[tokio::test]
+async fn giftcard_secrets_rotate_and_fall_back_when_refused() {
+    let server = MockServer::start().await;
+    let config: GiftcardBackendConfig = serde_yaml::from_str(
+        "secrets:
+  - secret: expired-secret
+    valid_until: 2020-01-01T00:00:00Z
+  - secret: staged-secret
+    valid_from: 2999-01-01T00:00:00Z
+  - secret: new-secret
+    valid_from: 2021-01-01T00:00:00Z",
+    )
+    .unwrap();
+    let secrets = |server: &MockServer| -> Vec<Value> {
+        let calls = server.calls("create-giftcards");
+        calls
+            .into_iter()
+            .map(|call| call["secret"].clone())
+            .collect()
+    };
+
+    // the backend hasn't switched yet, so the old secret is used until the new one is retried
+    server.accept_secrets(&["old-secret"]);
+    let backend = GephBackend::new(&server.url, "old-secret", &config, None).unwrap();
+    backend.create_giftcards(3, 1).await.unwrap();
+    backend.create_giftcards(3, 1).await.unwrap();
+    assert_eq!(
+        secrets(&server),
+        vec![
+            json!("new-secret"),
+            json!("old-secret"),
+            json!("old-secret")
+        ]
+    );
+
+    // once it has, the new secret is used right away
+    server.accept_secrets(&["new-secret"]);
+    let backend = GephBackend::new(&server.url, "old-secret", &config, None).unwrap();
+    backend.create_giftcards(3, 1).await.unwrap();
+    assert_eq!(secrets(&server)[3..], [json!("new-secret")]);
+
+    // and if it refuses every secret, that is the error
+    server.accept_secrets(&["unknown"]);
+    let err = backend.create_giftcards(3, 1).await.unwrap_err();
+    assert!(matches!(
+        err.downcast_ref(),
+        Some(GiftcardError::Unauthorized(_))
+    ));
+    assert_eq!(
+        secrets(&server)[4..],
+        [json!("new-secret"), json!("old-secret")]
+    );
+}
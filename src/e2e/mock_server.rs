@@ -0,0 +1,328 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    convert::Infallible,
+    sync::{Arc, Mutex},
+};
+
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    HeaderMap, Method, Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    server::conn::http1,
+    service::service_fn,
+};
+use hyper_util::rt::TokioIo;
+use serde_json::{Value, json};
+use tokio::net::TcpListener;
+
+/// A request the bot made to the mock server.
+#[derive(Clone, Debug)]
+pub struct Recorded {
+    /// Bot API method name, `create-giftcards` or `giftcard-status` for the giftcard backend, or
+    /// `webhook`
+    pub method: String,
+    pub body: Value,
+    pub headers: HeaderMap,
+}
+
+#[derive(Default)]
+struct State {
+    requests: Mutex<Vec<Recorded>>,
+    /// chat member status by user id; unknown users have left the group
+    members: Mutex<BTreeMap<u64, &'static str>>,
+    giftcard_code: Mutex<String>,
+    /// codes the app has redeemed
+    used_codes: Mutex<Vec<String>>,
+    /// error responses the giftcard backend gives before it answers with codes again
+    giftcard_errors: Mutex<VecDeque<(StatusCode, String)>>,
+    next_message_id: Mutex<i32>,
+    /// chats whose user blocked the bot, which `sendMessage` refuses
+    blocked: Mutex<Vec<i64>>,
+    /// the only secrets `create-giftcards` accepts, if any are set
+    accepted_secrets: Mutex<Vec<String>>,
+    /// objects in the `backups` bucket by key
+    objects: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+/// Answers Bot API calls, giftcard backend requests and requests to an S3 bucket named `backups`
+/// on a local port, recording every request.
+pub struct MockServer {
+    pub url: String,
+    state: Arc<State>,
+}
+
+impl MockServer {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let state = Arc::new(State::default());
+        *state.giftcard_code.lock().unwrap() = "GIFT-ABCD-1234".to_owned();
+
+        let server_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = server_state.clone();
+                tokio::spawn(async move {
+                    let service = service_fn(move |req| handle(state.clone(), req));
+                    let _ = http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+
+        Self { url, state }
+    }
+
+    pub fn set_member(&self, user_id: u64, status: &'static str) {
+        self.state.members.lock().unwrap().insert(user_id, status);
+    }
+
+    pub fn set_giftcard_code(&self, code: &str) {
+        *self.state.giftcard_code.lock().unwrap() = code.to_owned();
+    }
+
+    pub fn block(&self, chat_id: i64) {
+        self.state.blocked.lock().unwrap().push(chat_id);
+    }
+
+    /// Makes `create-giftcards` refuse every other secret.
+    pub fn accept_secrets(&self, secrets: &[&str]) {
+        let mut accepted = self.state.accepted_secrets.lock().unwrap();
+        *accepted = secrets.iter().map(|secret| secret.to_string()).collect();
+    }
+
+    pub fn put_object(&self, key: &str, contents: &[u8]) {
+        let mut objects = self.state.objects.lock().unwrap();
+        objects.insert(key.to_owned(), contents.to_vec());
+    }
+
+    pub fn objects(&self) -> BTreeMap<String, Vec<u8>> {
+        self.state.objects.lock().unwrap().clone()
+    }
+
+    pub fn set_used(&self, code: &str) {
+        self.state.used_codes.lock().unwrap().push(code.to_owned());
+    }
+
+    /// Makes the next giftcard request fail with this status and body.
+    pub fn fail_giftcard(&self, status: u16, body: &str) {
+        let status = StatusCode::from_u16(status).unwrap();
+        let mut errors = self.state.giftcard_errors.lock().unwrap();
+        errors.push_back((status, body.to_owned()));
+    }
+
+    pub fn requests(&self) -> Vec<Recorded> {
+        self.state.requests.lock().unwrap().clone()
+    }
+
+    /// Bodies of the recorded calls to one method, in the order they were made.
+    pub fn calls(&self, method: &str) -> Vec<Value> {
+        self.requests()
+            .into_iter()
+            .filter(|req| req.method == method)
+            .map(|req| req.body)
+            .collect()
+    }
+
+    /// Waits for requests the bot makes in the background, such as webhooks.
+    pub async fn wait_for(&self, method: &str, count: usize) -> Vec<Recorded> {
+        for _ in 0..500 {
+            let requests: Vec<Recorded> = self
+                .requests()
+                .into_iter()
+                .filter(|req| req.method == method)
+                .collect();
+            if requests.len() >= count {
+                return requests;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("expected {count} {method} requests");
+    }
+
+    /// Texts sent to a chat with `sendMessage`.
+    pub fn texts_to(&self, chat_id: i64) -> Vec<String> {
+        self.calls("sendMessage")
+            .into_iter()
+            .filter(|body| body["chat_id"] == json!(chat_id))
+            .map(|body| body["text"].as_str().unwrap_or_default().to_owned())
+            .collect()
+    }
+}
+
+async fn handle(
+    state: Arc<State>,
+    req: Request<Incoming>,
+) -> Result<Response<Full<Bytes>>, Infallible> {
+    let path = req.uri().path().to_owned();
+    let query = req.uri().query().unwrap_or_default().to_owned();
+    let http_method = req.method().clone();
+    let headers = req.headers().clone();
+    let raw = match req.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => Bytes::new(),
+    };
+    let body = serde_json::from_slice(&raw).unwrap_or(Value::Null);
+
+    if let Some(key) = path.strip_prefix("/backups") {
+        state.requests.lock().unwrap().push(Recorded {
+            method: format!("s3 {http_method}"),
+            body,
+            headers,
+        });
+        let key = key.trim_start_matches('/').to_owned();
+        let mut objects = state.objects.lock().unwrap();
+        let response = match http_method {
+            Method::PUT => {
+                objects.insert(key, raw.to_vec());
+                String::new()
+            }
+            Method::DELETE => {
+                objects.remove(&key);
+                String::new()
+            }
+            _ => {
+                let url = reqwest::Url::parse(&format!("http://bucket/?{query}")).unwrap();
+                let prefix = url
+                    .query_pairs()
+                    .find(|(name, _)| name == "prefix")
+                    .map(|(_, prefix)| prefix.into_owned())
+                    .unwrap_or_default();
+                let contents: String = objects
+                    .keys()
+                    .filter(|key| key.starts_with(&prefix))
+                    .map(|key| format!("<Contents><Key>{key}</Key></Contents>"))
+                    .collect();
+                format!("<ListBucketResult><Name>backups</Name>{contents}</ListBucketResult>")
+            }
+        };
+        return Ok(Response::new(Full::new(Bytes::from(response))));
+    }
+
+    if path == "/webhook" {
+        state.requests.lock().unwrap().push(Recorded {
+            method: "webhook".to_owned(),
+            body,
+            headers,
+        });
+        return Ok(Response::new(Full::new(Bytes::from("ok"))));
+    }
+    if path == "/support/giftcard-status" {
+        let code = body["code"].as_str().unwrap_or_default().to_owned();
+        state.requests.lock().unwrap().push(Recorded {
+            method: "giftcard-status".to_owned(),
+            body,
+            headers,
+        });
+        let redeemed = state.used_codes.lock().unwrap().contains(&code);
+        let response = json!({ "redeemed": redeemed });
+        return Ok(Response::new(Full::new(Bytes::from(response.to_string()))));
+    }
+    if path == "/support/create-giftcards" {
+        let secret = body["secret"].as_str().unwrap_or_default().to_owned();
+        let num_cards = body["num_cards"].as_u64().unwrap_or(1);
+        state.requests.lock().unwrap().push(Recorded {
+            method: "create-giftcards".to_owned(),
+            body,
+            headers,
+        });
+        let accepted = state.accepted_secrets.lock().unwrap().clone();
+        if !accepted.is_empty() && !accepted.contains(&secret) {
+            let mut response = Response::new(Full::new(Bytes::from(r#"{"error":"bad secret"}"#)));
+            *response.status_mut() = StatusCode::UNAUTHORIZED;
+            return Ok(response);
+        }
+        if let Some((status, body)) = state.giftcard_errors.lock().unwrap().pop_front() {
+            let mut response = Response::new(Full::new(Bytes::from(body)));
+            *response.status_mut() = status;
+            return Ok(response);
+        }
+        // a bundle is answered one code per line, each with its number appended
+        let code = state.giftcard_code.lock().unwrap().clone();
+        let codes = match num_cards {
+            1 => code,
+            count => (1..=count)
+                .map(|n| format!("{code}-{n}"))
+                .collect::<Vec<_>>()
+                .join("\n"),
+        };
+        return Ok(Response::new(Full::new(Bytes::from(codes))));
+    }
+
+    // bot API paths look like /bot<token>/<Method>, and method names are case-insensitive
+    let method = path.rsplit('/').next().unwrap_or_default();
+    let mut chars = method.chars();
+    let method = match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect::<String>(),
+        None => String::new(),
+    };
+    state.requests.lock().unwrap().push(Recorded {
+        method: method.clone(),
+        body: body.clone(),
+        headers,
+    });
+
+    let chat_id = body["chat_id"].as_i64().unwrap_or_default();
+    if method == "sendMessage" && state.blocked.lock().unwrap().contains(&chat_id) {
+        let response = json!({
+            "ok": false,
+            "error_code": 403,
+            "description": "Forbidden: bot was blocked by the user",
+        });
+        let mut response = Response::new(Full::new(Bytes::from(response.to_string())));
+        *response.status_mut() = StatusCode::FORBIDDEN;
+        return Ok(response);
+    }
+
+    let result = match method.as_str() {
+        "getChatMember" => {
+            let user_id = body["user_id"].as_u64().unwrap_or_default();
+            let status = state
+                .members
+                .lock()
+                .unwrap()
+                .get(&user_id)
+                .copied()
+                .unwrap_or("left");
+            json!({
+                "status": status,
+                "user": { "id": user_id, "is_bot": false, "first_name": "Test" },
+            })
+        }
+        "sendMessage" | "editMessageText" => {
+            let mut next_id = state.next_message_id.lock().unwrap();
+            *next_id += 1;
+            json!({
+                "message_id": *next_id,
+                "date": 0,
+                "chat": { "id": body["chat_id"], "type": "private", "first_name": "Test" },
+                "text": body["text"],
+            })
+        }
+        "sendPhoto" => {
+            let mut next_id = state.next_message_id.lock().unwrap();
+            *next_id += 1;
+            json!({
+                "message_id": *next_id,
+                "date": 0,
+                "chat": { "id": 0, "type": "private", "first_name": "Test" },
+                "photo": [{ "file_id": "qr", "file_unique_id": "qr", "width": 1, "height": 1 }],
+            })
+        }
+        "answerCallbackQuery" | "deleteMessage" => json!(true),
+        _ => {
+            let response = json!({
+                "ok": false,
+                "error_code": 404,
+                "description": format!("Not Found: method {method} is not mocked"),
+            });
+            let mut response = Response::new(Full::new(Bytes::from(response.to_string())));
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(response);
+        }
+    };
+
+    let response = json!({ "ok": true, "result": result });
+    Ok(Response::new(Full::new(Bytes::from(response.to_string()))))
+}
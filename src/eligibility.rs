@@ -0,0 +1,299 @@
+//! The rules a giftcard request must pass, configured per bot in `eligibility`.
+//!
+//! Each [`EligibilityRule`] looks at the request and returns a [`Verdict`]. The rules run in the
+//! configured order until one denies the request, and the user is sent that rule's denial in
+//! their language. Rules may instead ask for a manual review, in which case the reasons of every
+//! such rule are collected. Whether the user already redeemed, waits for a review or still has to
+//! share their phone number is checked before the rules, since those are steps of every request
+//! rather than reasons to refuse one.
+
+use teloxide::types::{ChatId, User};
+
+use crate::{
+    BoxFuture,
+    audit::{AuditEntry, Outcome},
+    config::RuleConfig,
+    i18n::Arg,
+    messages::TemplateKey,
+    service::BotService,
+};
+
+/// A giftcard request, as the rules see it.
+pub struct Request<'a> {
+    pub sender: &'a User,
+    pub sender_id: i64,
+    pub language: Option<&'a str>,
+}
+
+/// What a rule decided about a request.
+pub enum Verdict<'a> {
+    Eligible,
+    /// let an admin decide, for these reasons
+    Review(Vec<String>),
+    Deny(Denial<'a>),
+}
+
+/// Why a request was refused, and how to tell the user.
+pub struct Denial<'a> {
+    pub outcome: Outcome,
+    pub template: TemplateKey,
+    /// values for the template's placeholders
+    pub args: Vec<(&'static str, Arg<'a>)>,
+}
+
+impl<'a> Denial<'a> {
+    fn new(outcome: Outcome, template: TemplateKey) -> Self {
+        Self {
+            outcome,
+            template,
+            args: vec![],
+        }
+    }
+
+    fn arg(mut self, name: &'static str, value: Arg<'a>) -> Self {
+        self.args.push((name, value));
+        self
+    }
+}
+
+pub trait EligibilityRule: Send + Sync {
+    /// Decides on a request, recording what it looked at in the audit entry.
+    fn check<'a, 'b>(
+        &'b self,
+        service: &'a BotService,
+        request: &'a Request<'a>,
+        audit: &'b mut AuditEntry,
+    ) -> BoxFuture<'b, anyhow::Result<Verdict<'a>>>
+    where
+        'a: 'b;
+}
+
+/// The rule a config entry describes.
+pub fn rule(config: &RuleConfig) -> Box<dyn EligibilityRule> {
+    match *config {
+        RuleConfig::Blacklist => Box::new(Blacklist),
+        RuleConfig::GroupMembership => Box::new(GroupMembership),
+        RuleConfig::AccountHeuristics => Box::new(AccountHeuristics),
+        RuleConfig::Cooldown { days } => Box::new(Cooldown { days }),
+        RuleConfig::DailyCap { max } => Box::new(DailyCap { max }),
+    }
+}
+
+/// Runs the bot's rules in order, stopping at the first denial. If any rules ask for a review,
+/// their reasons are combined into one.
+pub async fn evaluate<'a>(
+    service: &'a BotService,
+    request: &'a Request<'a>,
+    audit: &mut AuditEntry,
+) -> anyhow::Result<Verdict<'a>> {
+    let mut reasons = vec![];
+    for config in &service.config.eligibility {
+        match rule(config).check(service, request, audit).await? {
+            Verdict::Eligible => {}
+            Verdict::Review(more) => reasons.extend(more),
+            denied @ Verdict::Deny(_) => return Ok(denied),
+        }
+    }
+    if reasons.is_empty() {
+        Ok(Verdict::Eligible)
+    } else {
+        Ok(Verdict::Review(reasons))
+    }
+}
+
+/// Refuses banned users.
+struct Blacklist;
+
+impl EligibilityRule for Blacklist {
+    fn check<'a, 'b>(
+        &'b self,
+        service: &'a BotService,
+        request: &'a Request<'a>,
+        audit: &'b mut AuditEntry,
+    ) -> BoxFuture<'b, anyhow::Result<Verdict<'a>>>
+    where
+        'a: 'b,
+    {
+        Box::pin(async move {
+            let banned = service.store.is_banned(request.sender_id)?;
+            audit.check("banned", banned);
+            if !banned {
+                return Ok(Verdict::Eligible);
+            }
+            eprintln!("banned user {} tried to get a giftcard", request.sender_id);
+            Ok(Verdict::Deny(Denial::new(
+                Outcome::Banned,
+                TemplateKey::Refused,
+            )))
+        })
+    }
+}
+
+/// Requires membership of `geph_group_id`, or of the group of the user's language.
+struct GroupMembership;
+
+impl EligibilityRule for GroupMembership {
+    fn check<'a, 'b>(
+        &'b self,
+        service: &'a BotService,
+        request: &'a Request<'a>,
+        audit: &'b mut AuditEntry,
+    ) -> BoxFuture<'b, anyhow::Result<Verdict<'a>>>
+    where
+        'a: 'b,
+    {
+        Box::pin(async move {
+            let language_group = service.config.language_group(request.language);
+            let group_id =
+                ChatId(language_group.map_or(service.config.geph_group_id, |g| g.group_id));
+            audit.check("group", group_id.0);
+
+            match service
+                .telegram
+                .is_group_member(group_id, request.sender.id)
+                .await
+            {
+                Ok(true) => {
+                    audit.check("group_member", true);
+                    Ok(Verdict::Eligible)
+                }
+                Ok(false) => {
+                    audit.check("group_member", false);
+                    Ok(Verdict::Deny(match language_group {
+                        Some(group) => {
+                            Denial::new(Outcome::NotInGroup, TemplateKey::JoinLanguageGroup)
+                                .arg("link", Arg::Ltr(&group.invite_link))
+                        }
+                        None => Denial::new(Outcome::NotInGroup, TemplateKey::JoinGroup),
+                    }))
+                }
+                Err(err) => {
+                    eprintln!(
+                        "failed to check group membership for user {}: {err:?}",
+                        request.sender_id
+                    );
+                    audit.check("group_member", format!("{err:#}"));
+                    Ok(Verdict::Deny(Denial::new(
+                        Outcome::MembershipCheckFailed,
+                        TemplateKey::MembershipCheckFailed,
+                    )))
+                }
+            }
+        })
+    }
+}
+
+/// Sends accounts that look suspicious by the `fraud` heuristics to manual review.
+struct AccountHeuristics;
+
+impl EligibilityRule for AccountHeuristics {
+    fn check<'a, 'b>(
+        &'b self,
+        service: &'a BotService,
+        request: &'a Request<'a>,
+        audit: &'b mut AuditEntry,
+    ) -> BoxFuture<'b, anyhow::Result<Verdict<'a>>>
+    where
+        'a: 'b,
+    {
+        Box::pin(async move {
+            let fraud = &service.global.fraud;
+            let mut reasons = vec![];
+            if fraud.flag_no_username && request.sender.username.is_none() {
+                reasons.push("no username".to_owned());
+            }
+            if let Some(threshold) = fraud.flag_user_id_above
+                && request.sender.id.0 > threshold
+            {
+                reasons.push(format!("user id above {threshold}"));
+            }
+            audit.check("fraud_reasons", reasons.clone());
+            if reasons.is_empty() {
+                Ok(Verdict::Eligible)
+            } else {
+                Ok(Verdict::Review(reasons))
+            }
+        })
+    }
+}
+
+/// Refuses users who were given a card less than `days` ago, such as users whose redemption an
+/// admin reset.
+struct Cooldown {
+    days: u64,
+}
+
+impl EligibilityRule for Cooldown {
+    fn check<'a, 'b>(
+        &'b self,
+        service: &'a BotService,
+        request: &'a Request<'a>,
+        audit: &'b mut AuditEntry,
+    ) -> BoxFuture<'b, anyhow::Result<Verdict<'a>>>
+    where
+        'a: 'b,
+    {
+        Box::pin(async move {
+            let last_issued = service.store.last_card_issued_at(request.sender_id)?;
+            audit.check("last_card_issued_at", last_issued);
+            let Some(last_issued) = last_issued else {
+                return Ok(Verdict::Eligible);
+            };
+            let ends_at = last_issued + self.days * 86400;
+            let now = service.clock.unix_now();
+            if now >= ends_at {
+                return Ok(Verdict::Eligible);
+            }
+            let remaining_days = (ends_at - now).div_ceil(86400);
+            Ok(Verdict::Deny(
+                Denial::new(Outcome::Cooldown, TemplateKey::Cooldown)
+                    .arg("remaining_days", Arg::Number(remaining_days)),
+            ))
+        })
+    }
+}
+
+/// Refuses requests once `max` cards were issued since midnight UTC.
+struct DailyCap {
+    max: usize,
+}
+
+impl EligibilityRule for DailyCap {
+    fn check<'a, 'b>(
+        &'b self,
+        service: &'a BotService,
+        _request: &'a Request<'a>,
+        audit: &'b mut AuditEntry,
+    ) -> BoxFuture<'b, anyhow::Result<Verdict<'a>>>
+    where
+        'a: 'b,
+    {
+        Box::pin(async move {
+            let issued_today = issued_today(service)?;
+            audit.check("issued_today", issued_today);
+            if issued_today < self.max {
+                return Ok(Verdict::Eligible);
+            }
+            Ok(Verdict::Deny(Denial::new(
+                Outcome::DailyCapReached,
+                TemplateKey::DailyCapReached,
+            )))
+        })
+    }
+}
+
+/// Whether a `daily_cap` rule of the bot would refuse requests right now.
+pub fn daily_cap_reached(service: &BotService) -> anyhow::Result<bool> {
+    let issued_today = issued_today(service)?;
+    Ok(service
+        .config
+        .eligibility
+        .iter()
+        .any(|rule| matches!(*rule, RuleConfig::DailyCap { max } if issued_today >= max)))
+}
+
+/// How many cards were issued since midnight UTC.
+fn issued_today(service: &BotService) -> anyhow::Result<usize> {
+    let now = service.clock.unix_now();
+    service.store.redemptions_since(now - now % 86400)
+}
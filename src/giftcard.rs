@@ -0,0 +1,394 @@
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use regex::Regex;
+use reqwest::{Client, Proxy, StatusCode, header::RETRY_AFTER};
+use serde_json::{Value, json};
+
+use crate::{
+    BoxFuture,
+    chaos::Chaos,
+    config::{ChaosRates, GiftcardBackendConfig},
+    unix_now,
+};
+
+/// Where giftcard codes come from.
+pub trait GiftcardProvider: Send + Sync {
+    /// Creates `count` codes, each lasting `days`.
+    fn create_giftcards(&self, days: u32, count: u32)
+    -> BoxFuture<'_, anyhow::Result<Vec<String>>>;
+    /// Whether the code was already redeemed in the app.
+    fn is_used<'a>(&'a self, code: &'a str) -> BoxFuture<'a, anyhow::Result<bool>>;
+    /// Makes the code unusable, if it wasn't redeemed yet.
+    fn cancel_giftcard<'a>(&'a self, code: &'a str) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+pub const GEPH_BACKEND_URL: &str = "https://web-backend.geph.io";
+
+/// The longest we wait on a rate limit before trying again within the same request.
+const MAX_RETRY_WAIT: Duration = Duration::from_secs(5);
+
+/// How long a secret the backend refused is tried after the others rather than first.
+const REFUSED_SECRET_PAUSE: Duration = Duration::from_secs(300);
+
+/// Why the backend did not give us a giftcard code.
+#[derive(Debug)]
+pub enum GiftcardError {
+    /// the backend refused our secret; retrying won't help until the config is fixed
+    Unauthorized(String),
+    /// the backend wants us to slow down for a while
+    RateLimited { retry_after: Duration },
+    /// the backend did not answer in time
+    Timeout,
+    /// the backend answered with some other error status
+    Status { status: u16, message: String },
+    /// the backend could not be reached at all
+    Network(reqwest::Error),
+    /// the backend answered successfully, but not with the giftcard codes asked for
+    InvalidCode(String),
+}
+
+impl GiftcardError {
+    /// Whether asking again soon could succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Unauthorized(_) => false,
+            Self::Status { status, .. } => *status >= 500,
+            Self::RateLimited { .. } | Self::Timeout | Self::Network(_) | Self::InvalidCode(_) => {
+                true
+            }
+        }
+    }
+}
+
+impl fmt::Display for GiftcardError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unauthorized(message) => write!(f, "backend refused the secret: {message}"),
+            Self::RateLimited { retry_after } => {
+                write!(f, "backend is rate limiting, retry after {retry_after:?}")
+            }
+            Self::Timeout => write!(f, "backend timed out"),
+            Self::Status { status, message } => write!(f, "backend answered {status}: {message}"),
+            Self::Network(err) => write!(f, "cannot reach backend: {err}"),
+            Self::InvalidCode(code) => {
+                write!(
+                    f,
+                    "backend answered {code:?}, which are not the giftcard codes asked for"
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for GiftcardError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Network(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for GiftcardError {
+    fn from(err: reqwest::Error) -> Self {
+        if err.is_timeout() {
+            Self::Timeout
+        } else {
+            Self::Network(err)
+        }
+    }
+}
+
+/// A secret the backend may accept.
+struct Secret {
+    value: String,
+    /// unix times from which and until which it is used
+    valid_from: u64,
+    valid_until: u64,
+    /// when the backend last refused it
+    refused_at: Mutex<Option<Instant>>,
+}
+
+/// Creates giftcards through the geph web backend.
+pub struct GephBackend {
+    client: Client,
+    url: String,
+    /// the latest `valid_from` first, ending with `create_giftcard_secret`
+    secrets: Vec<Secret>,
+    code_pattern: Regex,
+    attempts: u32,
+    /// set after a 429, so that other users' requests don't hammer the backend meanwhile
+    rate_limited_until: Mutex<Option<Instant>>,
+    chaos: Option<Chaos>,
+}
+
+impl GephBackend {
+    /// Connects to the backend at `url`, through `proxy` if there is one.
+    pub fn new(
+        url: &str,
+        secret: &str,
+        config: &GiftcardBackendConfig,
+        proxy: Option<&str>,
+    ) -> anyhow::Result<Self> {
+        let mut client = Client::builder().timeout(Duration::from_secs(10));
+        if let Some(proxy) = proxy {
+            client = client.proxy(Proxy::all(proxy).context("invalid backend proxy")?);
+        }
+        let mut secrets = vec![];
+        for secret in &config.secrets {
+            let (valid_from, valid_until) = secret.window()?;
+            secrets.push(Secret {
+                value: secret.secret.clone(),
+                valid_from,
+                valid_until,
+                refused_at: Mutex::new(None),
+            });
+        }
+        secrets.sort_by_key(|secret| std::cmp::Reverse(secret.valid_from));
+        secrets.push(Secret {
+            value: secret.to_owned(),
+            valid_from: 0,
+            valid_until: u64::MAX,
+            refused_at: Mutex::new(None),
+        });
+        Ok(Self {
+            client: client.build()?,
+            url: url.to_owned(),
+            secrets,
+            code_pattern: Regex::new(&config.code_pattern)?,
+            attempts: config.attempts.max(1),
+            rate_limited_until: Mutex::new(None),
+            chaos: None,
+        })
+    }
+
+    /// Delays and fails requests at the given rates, see [`crate::chaos`].
+    pub fn with_chaos(mut self, rates: ChaosRates) -> Self {
+        self.chaos = Some(Chaos::new(rates));
+        self
+    }
+
+    async fn try_create(&self, days: u32, count: u32) -> Result<Vec<String>, GiftcardError> {
+        let until = *self.rate_limited_until.lock().unwrap();
+        if let Some(until) = until {
+            let now = Instant::now();
+            if now < until {
+                return Err(GiftcardError::RateLimited {
+                    retry_after: until - now,
+                });
+            }
+        }
+        let result = self
+            .with_secret(|secret| create_giftcards(&self.client, &self.url, days, count, secret))
+            .await;
+        if let Err(GiftcardError::RateLimited { retry_after }) = &result {
+            *self.rate_limited_until.lock().unwrap() = Some(Instant::now() + *retry_after);
+        }
+        let response = result?;
+        let codes = parse_codes(&response);
+        if codes.len() == count as usize
+            && codes.iter().all(|code| self.code_pattern.is_match(code))
+        {
+            Ok(codes)
+        } else {
+            Err(GiftcardError::InvalidCode(
+                response.chars().take(100).collect(),
+            ))
+        }
+    }
+
+    /// Makes a request with each secret that is valid now until the backend accepts one, so that
+    /// the backend can switch to a new secret before or after the bot does. Secrets it refused
+    /// recently are tried last, so that every request doesn't pay for a rotation still pending.
+    async fn with_secret<T, F>(&self, request: impl Fn(String) -> F) -> Result<T, GiftcardError>
+    where
+        F: Future<Output = Result<T, GiftcardError>>,
+    {
+        let now = unix_now();
+        let mut candidates: Vec<&Secret> = self
+            .secrets
+            .iter()
+            .filter(|secret| secret.valid_from <= now && now < secret.valid_until)
+            .collect();
+        candidates.sort_by_key(|secret| {
+            secret
+                .refused_at
+                .lock()
+                .unwrap()
+                .is_some_and(|at| at.elapsed() < REFUSED_SECRET_PAUSE)
+        });
+
+        let mut refusal = None;
+        for secret in candidates {
+            if let Some(chaos) = &self.chaos
+                && chaos.strike().await
+            {
+                return Err(chaos.backend_error());
+            }
+            match request(secret.value.clone()).await {
+                Err(GiftcardError::Unauthorized(message)) => {
+                    eprintln!("the giftcard backend refused a secret: {message}");
+                    *secret.refused_at.lock().unwrap() = Some(Instant::now());
+                    refusal = Some(GiftcardError::Unauthorized(message));
+                }
+                result => {
+                    if result.is_ok() {
+                        *secret.refused_at.lock().unwrap() = None;
+                    }
+                    return result;
+                }
+            }
+        }
+        // create_giftcard_secret is always valid, so there was at least one refusal
+        Err(refusal.unwrap_or_else(|| GiftcardError::Unauthorized("no valid secret".to_owned())))
+    }
+}
+
+impl GiftcardProvider for GephBackend {
+    /// Asks the backend for a code until it answers with something that looks like one, so that
+    /// users never receive an error page in place of their giftcard. Only transient errors are
+    /// retried, and a rate limit is waited out if it is short.
+    fn create_giftcards(
+        &self,
+        days: u32,
+        count: u32,
+    ) -> BoxFuture<'_, anyhow::Result<Vec<String>>> {
+        Box::pin(async move {
+            let mut attempt = 1;
+            loop {
+                let err = match self.try_create(days, count).await {
+                    Ok(codes) => return Ok(codes),
+                    Err(err) => err,
+                };
+                let wait = match &err {
+                    GiftcardError::RateLimited { retry_after } => *retry_after,
+                    _ => Duration::ZERO,
+                };
+                if attempt >= self.attempts || !err.is_transient() || wait > MAX_RETRY_WAIT {
+                    return Err(err.into());
+                }
+                eprintln!(
+                    "giftcard attempt {attempt} of {} failed: {err}",
+                    self.attempts
+                );
+                tokio::time::sleep(wait).await;
+                attempt += 1;
+            }
+        })
+    }
+
+    fn is_used<'a>(&'a self, code: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let response = self
+                .with_secret(|secret| giftcard_status(&self.client, &self.url, code, secret))
+                .await?;
+            let status: Value =
+                serde_json::from_str(&response).context("backend answered with invalid json")?;
+            status["redeemed"]
+                .as_bool()
+                .context("backend did not say whether the giftcard was redeemed")
+        })
+    }
+
+    fn cancel_giftcard<'a>(&'a self, code: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        Box::pin(async move {
+            let url = format!("{}/support/cancel-giftcard", self.url);
+            self.with_secret(|secret| {
+                let body = json!({
+                    "code": code,
+                    "secret": secret,
+                });
+                let url = &url;
+                async move { post(&self.client, url, &body).await }
+            })
+            .await?;
+            Ok(())
+        })
+    }
+}
+
+pub async fn create_giftcards(
+    client: &Client,
+    url: &str,
+    days: u32,
+    count: u32,
+    secret: String,
+) -> Result<String, GiftcardError> {
+    let body = json!({
+        "days_per_card": days,
+        "num_cards": count,
+        "secret": secret,
+    });
+    post(client, &format!("{url}/support/create-giftcards"), &body).await
+}
+
+/// The codes in a `create-giftcards` response, which is either a JSON array of them or the codes
+/// themselves, separated by whitespace or commas.
+fn parse_codes(response: &str) -> Vec<String> {
+    if let Ok(codes) = serde_json::from_str::<Vec<String>>(response) {
+        return codes;
+    }
+    response
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|code| !code.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Asks the backend whether a giftcard was redeemed, answered as `{"redeemed": bool}`.
+pub async fn giftcard_status(
+    client: &Client,
+    url: &str,
+    code: &str,
+    secret: String,
+) -> Result<String, GiftcardError> {
+    let body = json!({
+        "code": code,
+        "secret": secret,
+    });
+    post(client, &format!("{url}/support/giftcard-status"), &body).await
+}
+
+/// Posts to the backend, turning error statuses into the matching [`GiftcardError`].
+async fn post(client: &Client, url: &str, body: &Value) -> Result<String, GiftcardError> {
+    let response = client.post(url).json(body).send().await?;
+    let status = response.status();
+    let header_retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok());
+    let text = response.text().await?;
+
+    if status.is_success() {
+        return Ok(text);
+    }
+    // the backend explains errors as `{"error": ..}`, but proxies in front of it answer in html
+    let parsed: Option<Value> = serde_json::from_str(&text).ok();
+    let message = parsed
+        .as_ref()
+        .and_then(|body| body["error"].as_str().or(body["message"].as_str()))
+        .map(str::to_owned)
+        .unwrap_or_else(|| text.trim().chars().take(200).collect());
+    Err(match status {
+        StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => GiftcardError::Unauthorized(message),
+        StatusCode::TOO_MANY_REQUESTS => {
+            let secs = header_retry_after
+                .or_else(|| parsed.as_ref()?["retry_after"].as_u64())
+                .unwrap_or(1);
+            GiftcardError::RateLimited {
+                retry_after: Duration::from_secs(secs),
+            }
+        }
+        StatusCode::REQUEST_TIMEOUT | StatusCode::GATEWAY_TIMEOUT => GiftcardError::Timeout,
+        _ => GiftcardError::Status {
+            status: status.as_u16(),
+            message,
+        },
+    })
+}
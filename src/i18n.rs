@@ -0,0 +1,224 @@
+use crate::messages::TemplateKey;
+
+/// Marks the start of a right-to-left paragraph, so that a message opening with an emoji or a
+/// latin word still reads right to left.
+const RLM: char = '\u{200F}';
+/// Isolates text of unknown direction, such as a user's name.
+const FSI: char = '\u{2068}';
+/// Isolates left-to-right text, such as links and codes, inside right-to-left text.
+const LRI: char = '\u{2066}';
+/// Ends an isolate started by [`FSI`] or [`LRI`].
+const PDI: char = '\u{2069}';
+
+/// A language messages can be written in, as a lowercase telegram language code such as `fa` or
+/// `zh-hans`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Locale(String);
+
+impl Locale {
+    pub fn new(code: &str) -> Self {
+        Self(code.trim().to_lowercase().replace('_', "-"))
+    }
+
+    pub fn code(&self) -> &str {
+        &self.0
+    }
+
+    /// The language without its region, e.g. `zh` for `zh-hans`.
+    pub fn language(&self) -> &str {
+        self.0.split('-').next().unwrap_or_default()
+    }
+
+    pub fn is_rtl(&self) -> bool {
+        matches!(self.language(), "fa" | "ar" | "he" | "ur" | "ps" | "ckb")
+    }
+
+    /// The digits numbers are written with, if not ascii ones.
+    fn digits(&self) -> Option<[char; 10]> {
+        match self.language() {
+            "fa" | "ps" | "ur" => Some(['۰', '۱', '۲', '۳', '۴', '۵', '۶', '۷', '۸', '۹']),
+            "ar" => Some(['٠', '١', '٢', '٣', '٤', '٥', '٦', '٧', '٨', '٩']),
+            _ => None,
+        }
+    }
+}
+
+/// The locales to try for a user, most preferred first: the user's own language, the bot's
+/// default, then the default of the whole process. Regional codes are followed by their
+/// language, so `zh-hans` falls back to `zh` before the defaults.
+pub fn fallback_chain(
+    user: Option<&str>,
+    bot_default: Option<&str>,
+    global_default: Option<&str>,
+) -> Vec<Locale> {
+    let mut chain: Vec<Locale> = vec![];
+    for code in [user, bot_default, global_default].into_iter().flatten() {
+        let locale = Locale::new(code);
+        let language = Locale::new(locale.language());
+        for locale in [locale, language] {
+            if !locale.code().is_empty() && !chain.contains(&locale) {
+                chain.push(locale);
+            }
+        }
+    }
+    chain
+}
+
+/// A value for a placeholder in a template, formatted to suit the message's locale.
+#[derive(Clone, Copy)]
+pub enum Arg<'a> {
+    Number(u64),
+    /// text that may be written in either direction, such as names
+    Text(&'a str),
+    /// text that always reads left to right, such as links
+    Ltr(&'a str),
+}
+
+/// Fills in the `{name}` placeholders of a template written in `locale`, or in several languages
+/// at once if `None`. In right-to-left locales, embedded names and links are isolated so that
+/// they don't reorder the text around them, and numbers use the locale's digits.
+pub fn render(template: &str, locale: Option<&Locale>, args: &[(&str, Arg)]) -> String {
+    let mut text = template.to_owned();
+    for (name, arg) in args {
+        let value = match (arg, locale) {
+            (Arg::Number(n), Some(locale)) => localize_number(*n, locale),
+            (Arg::Number(n), None) => n.to_string(),
+            (Arg::Text(s), Some(_)) => format!("{FSI}{s}{PDI}"),
+            (Arg::Ltr(s), Some(locale)) if locale.is_rtl() => format!("{LRI}{s}{PDI}"),
+            (Arg::Text(s) | Arg::Ltr(s), _) => s.to_string(),
+        };
+        text = text.replace(&format!("{{{name}}}"), &value);
+    }
+    if locale.is_some_and(Locale::is_rtl) && !text.starts_with(RLM) {
+        text.insert(0, RLM);
+    }
+    text
+}
+
+fn localize_number(n: u64, locale: &Locale) -> String {
+    let n = n.to_string();
+    match locale.digits() {
+        Some(digits) => n
+            .chars()
+            .map(|c| c.to_digit(10).map_or(c, |d| digits[d as usize]))
+            .collect(),
+        None => n,
+    }
+}
+
+/// The translation shipped with the bot, used unless the bot's config has one of its own.
+pub fn builtin(locale: &Locale, key: TemplateKey) -> Option<&'static str> {
+    match locale.code() {
+        "fa" => Some(farsi(key)),
+        _ => None,
+    }
+}
+
+fn farsi(key: TemplateKey) -> &'static str {
+    match key {
+        TemplateKey::AlreadyRedeemed => {
+            "🎁 شما قبلاً یک گیفت‌کارت دریافت کرده‌اید! هر کاربر فقط ۱ گیفت‌کارت دریافت می‌کند"
+        }
+        TemplateKey::SupportContact => {
+            "🙋 شما قبلاً گیفت‌کارت خود را دریافت کرده‌اید و هر کاربر فقط ۱ گیفت‌کارت دریافت می‌کند. اگر مشکلی در آن وجود دارد، لطفاً در {support} کمک بخواهید"
+        }
+        TemplateKey::Congrats => "🎉 تبریک! این یک گیفت‌کارت {days} روزه‌ی Geph Plus برای شماست:",
+        TemplateKey::BundleCongrats => {
+            "🎉 تبریک! این {count} گیفت‌کارت {days} روزه‌ی Geph Plus برای شماست. یکی را نگه دارید و بقیه را با دوستانتان به اشتراک بگذارید:"
+        }
+        TemplateKey::RedeemSteps => {
+            "💳 برای استفاده از گیفت‌کارت: برنامه‌ی Geph را باز کنید ← «Buy Plus» یا «Extend» در گوشه‌ی بالا ← «Redeem voucher»"
+        }
+        TemplateKey::JoinGroup => "⛔ برای دریافت گیفت‌کارت باید عضو گروه رسمی ما شوید: {link}",
+        TemplateKey::JoinLanguageGroup => {
+            "⛔ برای دریافت گیفت‌کارت باید عضو گروه رسمی ما شوید: {link}"
+        }
+        TemplateKey::MembershipCheckFailed => {
+            "⚠️ در حال حاضر نمی‌توانم عضویت شما در گروه را بررسی کنم. لطفاً بعداً دوباره تلاش کنید."
+        }
+        TemplateKey::TemporaryProblem => {
+            "⚠️ متأسفیم، مشکلی موقت پیش آمده است. لطفاً چند دقیقه‌ی دیگر دوباره تلاش کنید."
+        }
+        TemplateKey::Cooldown => {
+            "⏳ شما به‌تازگی یک گیفت‌کارت دریافت کرده‌اید. لطفاً {remaining_days} روز دیگر دوباره تلاش کنید."
+        }
+        TemplateKey::DailyCapReached => {
+            "🌙 همه‌ی گیفت‌کارت‌های امروز داده شده‌اند. لطفاً فردا دوباره تلاش کنید!"
+        }
+        TemplateKey::WaitlistOffer => {
+            "⏳ متأسفیم، در حال حاضر نمی‌توانم گیفت‌کارت بدهم. با دکمه‌ی زیر به فهرست انتظار بپیوندید تا در اولین فرصت گیفت‌کارت شما را بفرستم."
+        }
+        TemplateKey::WaitlistJoined => {
+            "✅ شما نفر {position} در فهرست انتظار هستید. گیفت‌کارت شما همین‌جا فرستاده می‌شود و نیازی به درخواست دوباره نیست."
+        }
+        TemplateKey::ReviewPending => {
+            "🔎 درخواست شما باید توسط یک مدیر بررسی شود. پس از تأیید، گیفت‌کارت خود را همین‌جا دریافت خواهید کرد."
+        }
+        TemplateKey::Refused => "🚫 متأسفیم، نمی‌توانیم به شما گیفت‌کارت بدهیم.",
+        TemplateKey::GroupReply => {
+            "لطفاً برای دریافت گیفت‌کارت به من پیام خصوصی بدهید: \u{2066}https://t.me/GephGiftcardBot\u{2069}"
+        }
+        TemplateKey::SendText => "✍️ لطفاً برای دریافت گیفت‌کارت یک پیام متنی برایم بفرستید.",
+        TemplateKey::Welcome => {
+            "👋 {names} خوش آمدید! برای دریافت گیفت‌کارت رایگان {days} روزه‌ی Geph Plus به \u{2066}https://t.me/GephGiftcardBot\u{2069} پیام خصوصی بدهید."
+        }
+        TemplateKey::UnusedReminder => {
+            "⏰ هنوز از گیفت‌کارت Geph Plus خود استفاده نکرده‌اید! دوباره اینجاست:"
+        }
+        TemplateKey::Milestone => {
+            "🥳 گروه ما به {count} عضو رسید! به این مناسبت، یکی از دریافت‌کنندگان اخیر گیفت‌کارت یک گیفت‌کارت {days} روزه‌ی Geph Plus هدیه می‌گیرد."
+        }
+        TemplateKey::MilestoneBonus => {
+            "🎊 گروه ما به {count} عضو رسید و شما برای دریافت یک گیفت‌کارت هدیه انتخاب شدید!"
+        }
+        TemplateKey::ShareContact => {
+            "📱 برای اینکه هر نفر فقط یک گیفت‌کارت دریافت کند، لطفاً با دکمه‌ی زیر شماره تلفن خود را به اشتراک بگذارید. فقط اثر انگشتی از آن نگه داشته می‌شود."
+        }
+        TemplateKey::ContactVerified => "✅ ممنون، شماره تلفن شما تأیید شد.",
+        TemplateKey::PhoneInUse => {
+            "🚫 این شماره تلفن قبلاً توسط حساب دیگری استفاده شده است. هر نفر فقط ۱ گیفت‌کارت دریافت می‌کند"
+        }
+        TemplateKey::ChooseLanguage => "🌐 به چه زبانی برایتان بنویسم؟",
+        TemplateKey::LanguageChanged => "✅ باشه، از این به بعد به این زبان برایتان می‌نویسم.",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codes(chain: Vec<Locale>) -> Vec<String> {
+        chain.into_iter().map(|locale| locale.0).collect()
+    }
+
+    #[test]
+    fn falls_back_from_region_to_language_to_defaults() {
+        assert_eq!(
+            codes(fallback_chain(Some("zh-Hans"), Some("fa"), Some("zh"))),
+            vec!["zh-hans", "zh", "fa"]
+        );
+        assert_eq!(codes(fallback_chain(None, None, Some("en"))), vec!["en"]);
+        assert!(fallback_chain(None, None, None).is_empty());
+    }
+
+    #[test]
+    fn isolates_embedded_text_in_rtl_locales() {
+        let farsi = Locale::new("fa");
+        let args = [
+            ("days", Arg::Number(30)),
+            ("link", Arg::Ltr("https://t.me/gephfa")),
+        ];
+        assert_eq!(
+            render("{days} روز: {link}", Some(&farsi), &args),
+            "\u{200F}۳۰ روز: \u{2066}https://t.me/gephfa\u{2069}"
+        );
+        assert_eq!(
+            render("{days} days: {link}", Some(&Locale::new("en")), &args),
+            "30 days: https://t.me/gephfa"
+        );
+        assert_eq!(
+            render("hi {names}", None, &[("names", Arg::Text("دارا"))]),
+            "hi دارا"
+        );
+    }
+}
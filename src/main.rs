@@ -1,199 +1,222 @@
+mod alerts;
+mod archive;
+mod audit;
+mod backup;
+mod chaos;
+mod chart;
+mod cli;
+mod config;
+mod conversation;
+mod dashboard;
+#[cfg(test)]
+mod e2e;
+mod eligibility;
+mod giftcard;
+mod i18n;
+mod messages;
+mod png;
+mod qr;
+mod reporting;
+mod router;
+mod schedule;
+mod service;
+mod store;
+mod telegram;
+mod webhooks;
+mod workers;
+
 use std::{
-    collections::BTreeSet,
-    path::{Path, PathBuf},
+    future::Future,
+    pin::Pin,
+    sync::{Arc, atomic::AtomicU64},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-use acidjson::AcidJson;
-use anyhow::Context;
-use argh::FromArgs;
 use once_cell::sync::Lazy;
-use reqwest::Client;
-use serde::{Deserialize, Serialize};
 use serde_json::json;
 use teloxide::{
-    dispatching::UpdateFilterExt,
-    payloads::SendMessageSetters,
+    dispatching::{UpdateFilterExt, UpdateHandler},
     prelude::*,
-    types::{ChatId, Message, ReplyParameters, User, UserId},
+    types::{CallbackQuery, Message},
 };
 
-/// configuration yaml file for geph telegram giftcard bot
-#[derive(FromArgs, PartialEq, Debug)]
-struct Args {
-    /// configuration yaml file path
-    #[argh(option, short = 'c', long = "config")]
-    config: PathBuf,
-}
-
-#[derive(Serialize, Deserialize, Clone)]
-struct Config {
-    store_path: String,
-    telegram_token: String,
-    admin_uname: String,
-    bot_uname: String,
-    geph_group_id: i64,
-    create_giftcard_secret: String,
-    days_per_giftcard: u32,
-}
-
-static ARGS: Lazy<Args> = Lazy::new(argh::from_env);
-
-static CONFIG: Lazy<Config> = Lazy::new(|| {
-    let s = &std::fs::read(&ARGS.config).expect("cannot read config file");
-    serde_yaml::from_slice(s).expect("cannot parse config file")
-});
-
-#[derive(Serialize, Deserialize, Clone)]
-struct Store {
-    redeemed_users: BTreeSet<i64>,
-}
-
-const MSG_RECIPIENT_COUNT: &str = "🌸 {count} users received giftcards!";
-const MSG_ALREADY_REDEEMED: &str = "🎁 You have already received a giftcard! Each user will only receive 1 giftcard\n\n🧧 您已经获得了一张礼品卡！每名用户可以得到一张礼品卡";
-const MSG_CONGRATS: &str = "🎉 Congratulations! Here's a 3-day Geph Plus giftcard for you:\n\n恭喜您！这里是一张3天迷雾通 Plus 礼品卡:";
-const MSG_REDEEM_STEPS: &str = "💳 To redeem the giftcard: open the Geph app --> \"Buy Plus\" / \"Extend\" in the top right corner --> \"Redeem voucher\"\n\n💝 如何兑换礼品卡：打开迷雾通 APP --> 点击右上角的“购买 Plus”或“延长” --> “兑换礼品卡”";
-const MSG_JOIN_GROUP: &str = "⛔ You must join our official group to get a giftcard:\n🚦 您必须加入迷雾通官方群组才能获得礼品卡： https://t.me/gephusers";
-const MSG_MEMBERSHIP_CHECK_FAILED: &str = "⚠️ I couldn't verify your group membership right now. Please try again later.\n\n⚠️ 暂时无法验证您的群组成员身份。请稍后重试。";
-const MSG_GROUP_REPLY: &str = "Please private message https://t.me/GephGiftcardBot to get your giftcard\n\n请私信 https://t.me/GephGiftcardBot 来领取礼品卡\n\nلطفاً برای دریافت گیفت‌کارت به من پیام خصوصی بدهید: https://t.me/GephGiftcardBot";
+use crate::{
+    alerts::BackendAlerts,
+    audit::AuditLog,
+    chaos::ChaosTelegram,
+    config::{ARGS, CONFIG},
+    giftcard::{GEPH_BACKEND_URL, GephBackend},
+    reporting::ErrorReporter,
+    service::{BotService, SystemClock},
+    store::{lock_store, open_storage},
+    telegram::{Outbox, TelegramApi, build_bot, check_bot},
+    webhooks::Webhooks,
+    workers::WorkerPool,
+};
 
-static STORE: Lazy<AcidJson<Store>> = Lazy::new(|| {
-    AcidJson::open_or_else(Path::new(&CONFIG.store_path), || Store {
-        redeemed_users: BTreeSet::new(),
-    })
-    .unwrap()
-});
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     Lazy::force(&CONFIG);
-    Lazy::force(&STORE);
-
-    let bot = Bot::new(CONFIG.telegram_token.clone());
-    let handler = Update::filter_message().endpoint(dispatch_message);
-
-    Dispatcher::builder(bot, handler)
-        .enable_ctrlc_handler()
-        .build()
-        .dispatch()
-        .await;
-
-    Ok(())
-}
-
-async fn dispatch_message(bot: Bot, msg: Message) -> ResponseResult<()> {
-    if let Err(err) = handle_message(bot, msg).await {
-        eprintln!("failed to process message: {err:?}");
+    if let Some(command) = &ARGS.command {
+        return cli::run(command, &CONFIG);
     }
-
-    Ok(())
-}
-
-async fn handle_message(bot: Bot, msg: Message) -> anyhow::Result<()> {
-    let Some(sender) = msg.from.clone() else {
-        return Ok(());
-    };
-    let text = msg.text().unwrap_or_default().to_owned();
-
-    if msg.chat.is_private() {
-        handle_private_message(&bot, &msg, &sender, &text).await?;
-    } else if msg.chat.is_group() || msg.chat.is_supergroup() {
-        handle_group_message(&bot, &msg, &text).await?;
+    CONFIG.validate()?;
+    if CONFIG.admin_ids.is_empty() {
+        eprintln!(
+            "no admin_ids are configured, so nobody can use admin commands, and requests that \
+             need review are refused"
+        );
     }
-
-    Ok(())
-}
-
-async fn handle_private_message(
-    bot: &Bot,
-    msg: &Message,
-    sender: &User,
-    text: &str,
-) -> anyhow::Result<()> {
-    let chat_id = msg.chat.id;
-    let sender_uname = sender.username.clone().unwrap_or_default();
-    let sender_id: i64 = sender
-        .id
-        .0
-        .try_into()
-        .context("sender id does not fit into i64")?;
-
-    if sender_uname == CONFIG.admin_uname {
-        if text == "#RecipientCount" {
-            let count = STORE.read().redeemed_users.len();
-            let msg = MSG_RECIPIENT_COUNT.replace("{count}", &count.to_string());
-            bot.send_message(chat_id, msg).await?;
-        }
-        return Ok(());
+    let global = Arc::new(CONFIG.clone());
+
+    let workers = Arc::new(WorkerPool::new(
+        CONFIG.workers.count,
+        CONFIG.workers.queue_size,
+    ));
+    let audit = Arc::new(AuditLog::open(CONFIG.audit_log.as_ref())?);
+    let reporter = Arc::new(ErrorReporter::new(&CONFIG)?);
+    reporter.install_panic_hook();
+    let backend_alerts = Arc::new(BackendAlerts::new(&CONFIG.backend_alert));
+    let webhooks = Arc::new(Webhooks::new(&CONFIG.webhooks)?);
+    let mut giftcards = GephBackend::new(
+        GEPH_BACKEND_URL,
+        &CONFIG.create_giftcard_secret,
+        &CONFIG.giftcard_backend,
+        CONFIG.proxy.backend(),
+    )?;
+    if let Some(chaos) = &CONFIG.chaos {
+        eprintln!("CHAOS MODE: calls to telegram and the giftcard backend will randomly fail");
+        giftcards = giftcards.with_chaos(chaos.backend);
     }
-
-    if STORE.read().redeemed_users.contains(&sender_id) {
-        bot.send_message(chat_id, MSG_ALREADY_REDEEMED).await?;
-        return Ok(());
+    let giftcards = Arc::new(giftcards);
+
+    let mut services = vec![];
+    let mut dispatchers = vec![];
+    let mut store_locks = vec![];
+    for bot_config in CONFIG.all_bots() {
+        store_locks.push(lock_store(bot_config)?);
+        let bot = build_bot(bot_config, &CONFIG)?;
+        check_bot(&bot, bot_config).await?;
+        let telegram: Arc<dyn TelegramApi> = match &CONFIG.chaos {
+            Some(chaos) => Arc::new(ChaosTelegram::new(bot.clone(), chaos)),
+            None => Arc::new(bot.clone()),
+        };
+        let service = Arc::new(BotService {
+            config: bot_config.clone(),
+            global: global.clone(),
+            telegram: Arc::new(Outbox::new(telegram, &CONFIG.delivery)),
+            giftcards: giftcards.clone(),
+            clock: Arc::new(SystemClock),
+            store: open_storage(bot_config)?,
+            audit: audit.clone(),
+            reporter: reporter.clone(),
+            backend_alerts: backend_alerts.clone(),
+            webhooks: webhooks.clone(),
+            last_greeting: AtomicU64::new(0),
+            timed_out_updates: AtomicU64::new(0),
+            workers: workers.clone(),
+        });
+        schedule::spawn_jobs(service.clone());
+        services.push(service.clone());
+        let workers = workers.clone();
+
+        dispatchers.push(tokio::spawn(async move {
+            Dispatcher::builder(bot, schema())
+                .dependencies(dptree::deps![service, workers])
+                .enable_ctrlc_handler()
+                .build()
+                .dispatch()
+                .await;
+        }));
     }
-
-    let group_id = ChatId(CONFIG.geph_group_id);
-
-    match user_in_group(bot, sender.id, group_id).await {
-        Ok(true) => {
-            let gc =
-                create_giftcards(CONFIG.days_per_giftcard, &CONFIG.create_giftcard_secret).await?;
-            STORE.write().redeemed_users.insert(sender_id);
-
-            bot.send_message(chat_id, MSG_CONGRATS).await?;
-            bot.send_message(chat_id, &gc).await?;
-            bot.send_message(chat_id, MSG_REDEEM_STEPS).await?;
-        }
-        Ok(false) => {
-            bot.send_message(chat_id, MSG_JOIN_GROUP).await?;
-        }
-        Err(err) => {
-            eprintln!("failed to check group membership for user {sender_id}: {err:?}");
-            bot.send_message(chat_id, MSG_MEMBERSHIP_CHECK_FAILED)
-                .await?;
-        }
+    if let Some(config) = &CONFIG.dashboard {
+        let addr = dashboard::spawn(config, services).await?;
+        eprintln!("dashboard listening on http://{addr}");
+    }
+    for dispatcher in dispatchers {
+        dispatcher.await?;
     }
 
     Ok(())
 }
 
-async fn handle_group_message(bot: &Bot, msg: &Message, text: &str) -> anyhow::Result<()> {
-    let bot_mention = format!("@{}", CONFIG.bot_uname);
-    if text.contains(&bot_mention) {
-        bot.send_message(msg.chat.id, MSG_GROUP_REPLY)
-            .reply_parameters(ReplyParameters::new(msg.id))
-            .await?;
-    }
-
-    Ok(())
+fn schema() -> UpdateHandler<teloxide::RequestError> {
+    dptree::entry()
+        .branch(Update::filter_message().endpoint(dispatch_message))
+        .branch(Update::filter_edited_message().endpoint(dispatch_message))
+        .branch(Update::filter_callback_query().endpoint(dispatch_callback))
 }
 
-async fn user_in_group(bot: &Bot, user_id: UserId, group_id: ChatId) -> anyhow::Result<bool> {
-    let member = bot
-        .get_chat_member(group_id, user_id)
-        .await
-        .with_context(|| format!("get_chat_member failed for user {}", user_id.0))?;
+async fn dispatch_message(
+    service: Arc<BotService>,
+    workers: Arc<WorkerPool>,
+    msg: Message,
+) -> ResponseResult<()> {
+    let key = msg
+        .from
+        .as_ref()
+        .map_or(msg.chat.id.0 as u64, |user| user.id.0);
+    workers
+        .submit(key, async move {
+            let context = json!({
+                "bot": service.config.bot_uname,
+                "chat_id": msg.chat.id.0,
+                "user_id": msg.from.as_ref().map(|user| user.id.0),
+                "text": msg.text(),
+            });
+            // only private chats are told to try again, so that a slow group doesn't get spammed
+            let user = msg.from.clone().filter(|_| msg.chat.is_private());
+            if let Err(err) = service
+                .within_timeout(user.as_ref(), service.handle_message(msg))
+                .await
+            {
+                eprintln!(
+                    "[{}] failed to process message: {err:?}",
+                    service.config.bot_uname
+                );
+                service.reporter.handler_error(&err, context);
+            }
+        })
+        .await;
 
-    Ok(member.is_present())
+    Ok(())
 }
 
-pub async fn create_giftcards(days: u32, secret: &str) -> Result<String, reqwest::Error> {
-    let client = Client::builder()
-        .timeout(std::time::Duration::from_secs(10))
-        .build()?;
-
-    let body = json!({
-        "days_per_card": days,
-        "num_cards": 1,
-        "secret": secret,
-    });
+async fn dispatch_callback(
+    service: Arc<BotService>,
+    workers: Arc<WorkerPool>,
+    query: CallbackQuery,
+) -> ResponseResult<()> {
+    let key = query.from.id.0;
+    workers
+        .submit(key, async move {
+            let context = json!({
+                "bot": service.config.bot_uname,
+                "user_id": query.from.id.0,
+                "data": query.data,
+            });
+            let user = query.from.clone();
+            if let Err(err) = service
+                .within_timeout(Some(&user), service.handle_callback(query))
+                .await
+            {
+                eprintln!(
+                    "[{}] failed to process callback query: {err:?}",
+                    service.config.bot_uname
+                );
+                service.reporter.handler_error(&err, context);
+            }
+        })
+        .await;
 
-    let response = client
-        .post("https://web-backend.geph.io/support/create-giftcards")
-        .json(&body)
-        .send()
-        .await?
-        .text()
-        .await?;
+    Ok(())
+}
 
-    Ok(response.trim().to_string())
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
 }
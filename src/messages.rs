@@ -0,0 +1,235 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+pub const MSG_RECIPIENT_COUNT: &str = "🌸 {count} users received giftcards!";
+pub const MSG_BANNED: &str = "🚫 User {id} is now banned";
+pub const MSG_UNBANNED: &str = "✅ User {id} is no longer banned";
+pub const MSG_NOT_BANNED: &str = "ℹ️ User {id} was not banned";
+pub const MSG_REJECTED: &str = "❌ Rejected user {id}, who is now banned";
+pub const MSG_NO_PENDING_REVIEW: &str = "ℹ️ User {id} has no pending review";
+pub const MSG_INVALID_USER_ID: &str = "⚠️ Not a valid user id: {id}";
+pub const MSG_GRANTED: &str = "🎁 Sent a {days}-day giftcard to user {id}";
+pub const MSG_GRANT_USAGE: &str = "⚠️ Usage: #Grant <user_id> <days>";
+pub const MSG_GRANT_ASK_USER: &str =
+    "👤 Which user id should receive the giftcard? Send /cancel to stop.";
+pub const MSG_GRANT_ASK_DAYS: &str = "📅 How many days should the giftcard last?";
+pub const MSG_SET_USAGE: &str = "⚠️ Usage: #Set days <days> or #Set group_link <https://t.me/...>";
+pub const MSG_SETTING_UPDATED: &str = "⚙️ {name} is now {value}";
+pub const MSG_INVALID_DAYS: &str = "⚠️ Please send a number of days greater than 0";
+pub const MSG_CANCELLED: &str = "👌 Cancelled";
+pub const MSG_CONFIRM: &str =
+    "⚠️ Send {phrase} within {secs} seconds to run {command}, or anything else to cancel it";
+pub const MSG_BACKEND_FAILING: &str = "🚨 The giftcard backend failed {count} times in the last {minutes} minutes. Latest error:\n\n{error}";
+pub const MSG_BACKEND_UNAUTHORIZED: &str = "🔑 The giftcard backend refused create_giftcard_secret and every other valid secret, so no giftcards can be sent until it is fixed:\n\n{error}";
+pub const MSG_UNUSED_CARDS: &str =
+    "🃏 {unused} of {total} issued giftcards have not been redeemed yet";
+pub const MSG_LEFT_GROUP: &str =
+    "👋 User {id} left the group {days} days after receiving a giftcard";
+pub const MSG_CODES_CANCELLED: &str = "🗑️ Cancelled {count} of their codes";
+pub const MSG_CODES_NOT_CANCELLED: &str = "⚠️ {count} of their codes could not be cancelled";
+pub const MSG_REPEAT_REQUESTERS: &str = "🔁 Users who asked again after receiving a giftcard:";
+pub const MSG_REPEAT_REQUESTER: &str = "{id}: {count} times";
+pub const MSG_NO_REPEAT_REQUESTERS: &str = "🔁 Nobody asked again after receiving a giftcard";
+pub const MSG_ARCHIVED: &str = "🗄️ User {id} redeemed on {date}, and was archived";
+pub const MSG_NOT_ARCHIVED: &str = "ℹ️ User {id} is not in the archive";
+pub const MSG_CARDS_UNCHECKED: &str = "⚠️ {count} could not be checked right now";
+pub const MSG_CHART: &str = "📈 Giftcard requests per day, {from} to {to} (UTC)\n🟩 issued: {issued}\n🟧 denied: {denied}\n🟥 errors: {errors}\n\nGridlines are {step} apart";
+pub const MSG_CHART_USAGE: &str = "⚠️ Usage: #Chart <days>d, e.g. #Chart 30d, for up to {max} days";
+pub const MSG_SHARE_CONTACT_BUTTON: &str = "📱 Share my phone number / 分享我的手机号";
+pub const MSG_JOIN_WAITLIST_BUTTON: &str = "⏳ Join the waitlist / 加入候补名单";
+pub const MSG_REVIEW_REQUEST: &str =
+    "🔎 Manual review requested\n\nuser: {name} (@{uname}, id {id})\nreasons: {reasons}";
+
+const MSG_ALREADY_REDEEMED: &str = "🎁 You have already received a giftcard! Each user will only receive 1 giftcard\n\n🧧 您已经获得了一张礼品卡！每名用户可以得到一张礼品卡";
+const MSG_CONGRATS: &str = "🎉 Congratulations! Here's a {days}-day Geph Plus giftcard for you:\n\n恭喜您！这里是一张{days}天迷雾通 Plus 礼品卡:";
+const MSG_BUNDLE_CONGRATS: &str = "🎉 Congratulations! Here are {count} Geph Plus giftcards of {days} days each. Keep one and share the others with friends:\n\n🎉 恭喜！这是{count}张{days}天的迷雾通 Plus 礼品卡。请自己留一张，其余的分享给朋友：";
+const MSG_REDEEM_STEPS: &str = "💳 To redeem the giftcard: open the Geph app --> \"Buy Plus\" / \"Extend\" in the top right corner --> \"Redeem voucher\"\n\n💝 如何兑换礼品卡：打开迷雾通 APP --> 点击右上角的“购买 Plus”或“延长” --> “兑换礼品卡”";
+const MSG_JOIN_GROUP: &str = "⛔ You must join our official group to get a giftcard:\n🚦 您必须加入迷雾通官方群组才能获得礼品卡： {link}";
+const MSG_JOIN_LANGUAGE_GROUP: &str = "⛔ You must join our official group to get a giftcard:\n🚦 您必须加入迷雾通官方群组才能获得礼品卡： {link}";
+const MSG_SUPPORT_CONTACT: &str = "🙋 You already received your giftcard, and each user only gets 1. If something is wrong with it, please ask for help in {support}\n\n🙋 您已经领取过礼品卡，每名用户只能领取一张。如果礼品卡有问题，请在 {support} 寻求帮助";
+const MSG_MILESTONE: &str = "🥳 Our group just reached {count} members! To celebrate, one recent giftcard recipient gets a bonus {days}-day Geph Plus giftcard.\n\n🥳 我们的群组成员刚刚达到 {count} 人！为了庆祝，一位最近领取礼品卡的用户将获得额外的{days}天迷雾通 Plus 礼品卡。";
+const MSG_MILESTONE_BONUS: &str = "🎊 Our group reached {count} members, and you were picked for a bonus giftcard!\n\n🎊 我们的群组达到了 {count} 名成员，您被选中获得一张额外的礼品卡！";
+const MSG_COOLDOWN: &str = "⏳ You received a giftcard recently. Please try again in {remaining_days} days.\n\n⏳ 您最近已经领取过礼品卡。请在 {remaining_days} 天后再试。";
+const MSG_DAILY_CAP_REACHED: &str = "🌙 All of today's giftcards have been given out. Please try again tomorrow!\n\n🌙 今天的礼品卡已经全部发完了。请明天再来！";
+const MSG_WAITLIST_OFFER: &str = "⏳ Sorry, I can't give out giftcards right now. Join the waitlist with the button below, and I will send you yours as soon as I can.\n\n⏳ 抱歉，我暂时无法发放礼品卡。点击下方按钮加入候补名单，我会尽快把礼品卡发给您。";
+const MSG_WAITLIST_JOINED: &str = "✅ You are number {position} on the waitlist. Your giftcard will be sent to you here, no need to ask again.\n\n✅ 您在候补名单中排第 {position} 位。礼品卡会发送到这里，无需再次申请。";
+const MSG_TEMPORARY_PROBLEM: &str = "⚠️ Sorry, we're having a temporary problem. Please try again in a few minutes.\n\n⚠️ 抱歉，我们遇到了暂时的问题。请过几分钟再试。";
+const MSG_MEMBERSHIP_CHECK_FAILED: &str = "⚠️ I couldn't verify your group membership right now. Please try again later.\n\n⚠️ 暂时无法验证您的群组成员身份。请稍后重试。";
+const MSG_REVIEW_PENDING: &str = "🔎 Your request needs to be reviewed by an admin. You will receive your giftcard here once it is approved.\n\n🔎 您的请求需要管理员审核。审核通过后，您将在这里收到礼品卡。";
+const MSG_REFUSED: &str =
+    "🚫 Sorry, we cannot give you a giftcard.\n\n🚫 抱歉，我们无法为您提供礼品卡。";
+const MSG_SEND_TEXT: &str =
+    "✍️ Please send me a text message to get your giftcard.\n\n✍️ 请给我发送文字消息来领取礼品卡。";
+const MSG_UNUSED_REMINDER: &str = "⏰ You haven't redeemed your Geph Plus giftcard yet! Here it is again:\n\n⏰ 您还没有兑换迷雾通 Plus 礼品卡！这是您的礼品卡：";
+const MSG_SHARE_CONTACT: &str = "📱 To make sure everyone gets only one giftcard, please share your phone number with the button below. Only a fingerprint of it is kept.\n\n📱 为确保每人只领取一张礼品卡，请点击下方按钮分享您的手机号。我们只保存其指纹。";
+const MSG_CONTACT_VERIFIED: &str =
+    "✅ Thanks, your phone number is verified.\n\n✅ 谢谢，您的手机号已验证。";
+const MSG_PHONE_IN_USE: &str = "🚫 This phone number was already used by another account. Each person will only receive 1 giftcard\n\n🚫 该手机号已被另一个账号使用。每人只能获得一张礼品卡";
+const MSG_CHOOSE_LANGUAGE: &str =
+    "🌐 Which language should I write to you in?\n\n🌐 您希望我用哪种语言和您交流？";
+const MSG_LANGUAGE_CHANGED: &str = "✅ Got it, I will write to you in this language from now on.\n\n✅ 好的，今后我将用这种语言和您交流。";
+const MSG_WELCOME: &str = "👋 Welcome {names}! Private message https://t.me/GephGiftcardBot to get a free {days}-day Geph Plus giftcard.\n\n👋 欢迎 {names}！私信 https://t.me/GephGiftcardBot 即可领取{days}天迷雾通 Plus 礼品卡。";
+const MSG_GROUP_REPLY: &str = "Please private message https://t.me/GephGiftcardBot to get your giftcard\n\n请私信 https://t.me/GephGiftcardBot 来领取礼品卡\n\nلطفاً برای دریافت گیفت‌کارت به من پیام خصوصی بدهید: https://t.me/GephGiftcardBot";
+
+/// user-facing messages, which each bot can override in its config
+///
+/// `{days}` in any template is replaced with the duration of the bot's giftcards, or of the card
+/// being sent in the case of `congrats`. These are the texts for users whose language has no
+/// translation, see `translations` and [`crate::i18n`].
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct Templates {
+    pub already_redeemed: String,
+    /// sent instead of `already_redeemed` once a user keeps asking, see `repeat_attempts`;
+    /// `{support}` is replaced with its `support_link`
+    pub support_contact: String,
+    pub congrats: String,
+    /// sent instead of `congrats` when users receive several codes, see `num_cards`; `{count}`
+    /// is replaced with how many
+    pub bundle_congrats: String,
+    pub redeem_steps: String,
+    /// `{link}` is replaced with `group_link`
+    pub join_group: String,
+    /// sent instead of `join_group` to users whose language has its own group, see
+    /// `language_groups`; `{link}` is replaced with the group's invite link
+    pub join_language_group: String,
+    pub membership_check_failed: String,
+    /// sent when handling a message took longer than `workers.timeout_secs`
+    pub temporary_problem: String,
+    /// sent by the `cooldown` rule; `{remaining_days}` is replaced with the days left to wait
+    pub cooldown: String,
+    /// sent by the `daily_cap` rule, with a button to join the `waitlist` if there is one
+    pub daily_cap_reached: String,
+    /// sent with a button to join the `waitlist` when the giftcard backend fails
+    pub waitlist_offer: String,
+    /// sent to users joining the waitlist; `{position}` is replaced with their place on it
+    pub waitlist_joined: String,
+    pub review_pending: String,
+    /// sent to banned users, including those rejected in manual review
+    pub refused: String,
+    pub group_reply: String,
+    pub send_text: String,
+    /// greets people joining the group; `{names}` is replaced with their first names
+    pub welcome: String,
+    /// sent with the code again to users who haven't redeemed it after a while
+    pub unused_reminder: String,
+    /// posted in the group when it passes a member count milestone; `{count}` is replaced with
+    /// the milestone and `{days}` with the duration of the bonus card
+    pub milestone: String,
+    /// sent to the winner of a milestone's bonus card, before the card
+    pub milestone_bonus: String,
+    /// asks for the user's phone number when `phone_check` is configured
+    pub share_contact: String,
+    pub contact_verified: String,
+    /// sent when the shared phone number belongs to another account
+    pub phone_in_use: String,
+    /// sent for `/language`, above a button for each language users can choose
+    pub choose_language: String,
+    /// sent in the language a user just chose
+    pub language_changed: String,
+}
+
+static DEFAULT_TEMPLATES: Lazy<Templates> = Lazy::new(Templates::default);
+
+/// names a template, in `translations` and wherever the bot sends one
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum TemplateKey {
+    AlreadyRedeemed,
+    SupportContact,
+    Congrats,
+    BundleCongrats,
+    RedeemSteps,
+    JoinGroup,
+    JoinLanguageGroup,
+    MembershipCheckFailed,
+    TemporaryProblem,
+    Cooldown,
+    DailyCapReached,
+    WaitlistOffer,
+    WaitlistJoined,
+    ReviewPending,
+    Refused,
+    GroupReply,
+    SendText,
+    Welcome,
+    UnusedReminder,
+    Milestone,
+    MilestoneBonus,
+    ShareContact,
+    ContactVerified,
+    PhoneInUse,
+    ChooseLanguage,
+    LanguageChanged,
+}
+
+impl Templates {
+    pub fn get(&self, key: TemplateKey) -> &str {
+        match key {
+            TemplateKey::AlreadyRedeemed => &self.already_redeemed,
+            TemplateKey::SupportContact => &self.support_contact,
+            TemplateKey::Congrats => &self.congrats,
+            TemplateKey::BundleCongrats => &self.bundle_congrats,
+            TemplateKey::RedeemSteps => &self.redeem_steps,
+            TemplateKey::JoinGroup => &self.join_group,
+            TemplateKey::JoinLanguageGroup => &self.join_language_group,
+            TemplateKey::MembershipCheckFailed => &self.membership_check_failed,
+            TemplateKey::TemporaryProblem => &self.temporary_problem,
+            TemplateKey::Cooldown => &self.cooldown,
+            TemplateKey::DailyCapReached => &self.daily_cap_reached,
+            TemplateKey::WaitlistOffer => &self.waitlist_offer,
+            TemplateKey::WaitlistJoined => &self.waitlist_joined,
+            TemplateKey::ReviewPending => &self.review_pending,
+            TemplateKey::Refused => &self.refused,
+            TemplateKey::GroupReply => &self.group_reply,
+            TemplateKey::SendText => &self.send_text,
+            TemplateKey::Welcome => &self.welcome,
+            TemplateKey::UnusedReminder => &self.unused_reminder,
+            TemplateKey::Milestone => &self.milestone,
+            TemplateKey::MilestoneBonus => &self.milestone_bonus,
+            TemplateKey::ShareContact => &self.share_contact,
+            TemplateKey::ContactVerified => &self.contact_verified,
+            TemplateKey::PhoneInUse => &self.phone_in_use,
+            TemplateKey::ChooseLanguage => &self.choose_language,
+            TemplateKey::LanguageChanged => &self.language_changed,
+        }
+    }
+
+    /// Whether the template is the one the bot ships with, rather than one set in the config.
+    pub fn is_default(&self, key: TemplateKey) -> bool {
+        self.get(key) == DEFAULT_TEMPLATES.get(key)
+    }
+}
+
+impl Default for Templates {
+    fn default() -> Self {
+        Self {
+            already_redeemed: MSG_ALREADY_REDEEMED.to_owned(),
+            support_contact: MSG_SUPPORT_CONTACT.to_owned(),
+            congrats: MSG_CONGRATS.to_owned(),
+            bundle_congrats: MSG_BUNDLE_CONGRATS.to_owned(),
+            redeem_steps: MSG_REDEEM_STEPS.to_owned(),
+            join_group: MSG_JOIN_GROUP.to_owned(),
+            join_language_group: MSG_JOIN_LANGUAGE_GROUP.to_owned(),
+            membership_check_failed: MSG_MEMBERSHIP_CHECK_FAILED.to_owned(),
+            temporary_problem: MSG_TEMPORARY_PROBLEM.to_owned(),
+            cooldown: MSG_COOLDOWN.to_owned(),
+            daily_cap_reached: MSG_DAILY_CAP_REACHED.to_owned(),
+            waitlist_offer: MSG_WAITLIST_OFFER.to_owned(),
+            waitlist_joined: MSG_WAITLIST_JOINED.to_owned(),
+            review_pending: MSG_REVIEW_PENDING.to_owned(),
+            refused: MSG_REFUSED.to_owned(),
+            group_reply: MSG_GROUP_REPLY.to_owned(),
+            send_text: MSG_SEND_TEXT.to_owned(),
+            welcome: MSG_WELCOME.to_owned(),
+            unused_reminder: MSG_UNUSED_REMINDER.to_owned(),
+            milestone: MSG_MILESTONE.to_owned(),
+            milestone_bonus: MSG_MILESTONE_BONUS.to_owned(),
+            share_contact: MSG_SHARE_CONTACT.to_owned(),
+            contact_verified: MSG_CONTACT_VERIFIED.to_owned(),
+            phone_in_use: MSG_PHONE_IN_USE.to_owned(),
+            choose_language: MSG_CHOOSE_LANGUAGE.to_owned(),
+            language_changed: MSG_LANGUAGE_CHANGED.to_owned(),
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Just enough of the png format to encode the images the bot draws itself, see [`crate::qr`]
+//! and [`crate::chart`].
+
+use std::io::Write;
+
+use flate2::{Compression, Crc, write::ZlibEncoder};
+
+/// How the bytes of each pixel are read.
+#[derive(Clone, Copy)]
+pub enum ColorType {
+    /// one byte per pixel, from black to white
+    Grayscale,
+    /// three bytes per pixel: red, green and blue
+    Rgb,
+}
+
+impl ColorType {
+    fn bytes_per_pixel(self) -> usize {
+        match self {
+            ColorType::Grayscale => 1,
+            ColorType::Rgb => 3,
+        }
+    }
+
+    /// The number the png header gives the color type.
+    fn code(self) -> u8 {
+        match self {
+            ColorType::Grayscale => 0,
+            ColorType::Rgb => 2,
+        }
+    }
+}
+
+/// Encodes 8 bit pixels, given row by row starting at the top left corner.
+pub fn encode(
+    width: usize,
+    height: usize,
+    color: ColorType,
+    pixels: &[u8],
+) -> anyhow::Result<Vec<u8>> {
+    let row_len = width * color.bytes_per_pixel();
+    anyhow::ensure!(width > 0 && height > 0, "images must not be empty");
+    anyhow::ensure!(
+        pixels.len() == row_len * height,
+        "a {width}x{height} image needs {} bytes of pixels, not {}",
+        row_len * height,
+        pixels.len()
+    );
+
+    let mut scanlines = Vec::with_capacity((row_len + 1) * height);
+    for row in pixels.chunks(row_len) {
+        // each scanline starts with its filter type, none here
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+    let mut compressed = ZlibEncoder::new(Vec::new(), Compression::default());
+    compressed.write_all(&scanlines)?;
+
+    let mut header = vec![];
+    header.extend((width as u32).to_be_bytes());
+    header.extend((height as u32).to_be_bytes());
+    // 8 bits per channel, default compression and filtering, not interlaced
+    header.extend([8, color.code(), 0, 0, 0]);
+
+    let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &compressed.finish()?);
+    write_chunk(&mut png, b"IEND", &[]);
+    Ok(png)
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend((data.len() as u32).to_be_bytes());
+    png.extend(kind);
+    png.extend(data);
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    png.extend(crc.sum().to_be_bytes());
+}
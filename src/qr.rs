@@ -0,0 +1,55 @@
+//! Giftcard codes as QR images, for users who redeem on another device than the one telegram
+//! runs on.
+
+use anyhow::Context;
+use qrcode::{Color, QrCode};
+
+use crate::png::{self, ColorType};
+
+/// How many pixels wide each module of the code is drawn.
+const SCALE: usize = 8;
+/// The blank modules scanners need around a code to find it.
+const QUIET_ZONE: usize = 4;
+
+/// Renders the text as a black on white QR code, encoded as a grayscale png.
+pub fn qr_png(text: &str) -> anyhow::Result<Vec<u8>> {
+    let code = QrCode::new(text.as_bytes()).context("cannot encode the text as a qr code")?;
+    let modules = code.width();
+    let colors = code.to_colors();
+    let module_at = |pixel: usize| {
+        (pixel / SCALE)
+            .checked_sub(QUIET_ZONE)
+            .filter(|module| *module < modules)
+    };
+
+    let size = (modules + 2 * QUIET_ZONE) * SCALE;
+    let mut pixels = Vec::with_capacity(size * size);
+    for y in 0..size {
+        for x in 0..size {
+            let dark = match (module_at(y), module_at(x)) {
+                (Some(row), Some(column)) => colors[row * modules + column] == Color::Dark,
+                _ => false,
+            };
+            pixels.push(if dark { 0 } else { 255 });
+        }
+    }
+    png::encode(size, size, ColorType::Grayscale, &pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_a_png_with_a_quiet_zone() {
+        let png = qr_png("GIFT-ABCD-1234").unwrap();
+        let modules = QrCode::new("GIFT-ABCD-1234").unwrap().width();
+        let size = ((modules + 2 * QUIET_ZONE) * SCALE) as u32;
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(png[16..20], size.to_be_bytes());
+        assert_eq!(png[20..24], size.to_be_bytes());
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+    }
+}
@@ -0,0 +1,267 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU32, Ordering},
+};
+
+use regex::Regex;
+use reqwest::{Client, Url};
+use serde_json::{Value, json};
+
+use crate::{config::Config, unix_now};
+
+/// Sends alerts about errors to Sentry and/or a webhook, if configured.
+pub struct ErrorReporter {
+    client: Client,
+    sentry: Option<SentryTarget>,
+    webhook_url: Option<String>,
+    backend_failure_threshold: u32,
+    consecutive_backend_failures: AtomicU32,
+    /// values that must never leave the process, such as bot tokens and the giftcard secret
+    secrets: Vec<String>,
+    /// what giftcard codes look like, so that codes in error messages are left out too
+    code_pattern: Regex,
+}
+
+struct SentryTarget {
+    store_url: Url,
+    auth_header: String,
+}
+
+impl ErrorReporter {
+    pub fn new(global: &Config) -> anyhow::Result<Self> {
+        let config = global.error_reporting.clone().unwrap_or_default();
+        let secrets = global
+            .all_bots()
+            .map(|bot| bot.telegram_token.clone())
+            .chain([global.create_giftcard_secret.clone()])
+            .chain(
+                global
+                    .giftcard_backend
+                    .secrets
+                    .iter()
+                    .map(|secret| secret.secret.clone()),
+            )
+            .chain(
+                global
+                    .dashboard
+                    .iter()
+                    .map(|dashboard| dashboard.token.clone()),
+            )
+            .chain(global.webhooks.iter().map(|webhook| webhook.secret.clone()))
+            .chain(global.phone_check.iter().map(|check| check.salt.clone()))
+            .chain(
+                global
+                    .backup
+                    .iter()
+                    .flat_map(|backup| &backup.s3)
+                    .map(|s3| s3.secret_access_key.clone()),
+            )
+            .filter(|secret| !secret.is_empty())
+            .collect();
+
+        Ok(Self {
+            client: Client::builder()
+                .timeout(std::time::Duration::from_secs(10))
+                .build()?,
+            sentry: config.sentry_dsn.as_deref().map(parse_dsn).transpose()?,
+            webhook_url: config.webhook_url,
+            backend_failure_threshold: config.backend_failure_threshold,
+            consecutive_backend_failures: AtomicU32::new(0),
+            secrets,
+            code_pattern: Regex::new(&global.giftcard_backend.code_pattern)?,
+        })
+    }
+
+    /// Reports panics in addition to printing them as usual.
+    pub fn install_panic_hook(self: &Arc<Self>) {
+        let reporter = self.clone();
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            default_hook(info);
+            let location = info.location().map(|l| l.to_string()).unwrap_or_default();
+            reporter.report(
+                "fatal",
+                &format!("panic: {info}"),
+                json!({ "location": location }),
+            );
+        }));
+    }
+
+    pub fn handler_error(&self, err: &anyhow::Error, context: Value) {
+        self.report("error", &format!("{err:?}"), context);
+    }
+
+    /// Counts a failed giftcard backend call, alerting once the failures pile up.
+    pub fn backend_failure(&self, err: &anyhow::Error) {
+        let failures = self
+            .consecutive_backend_failures
+            .fetch_add(1, Ordering::SeqCst)
+            + 1;
+        if failures == self.backend_failure_threshold {
+            self.report(
+                "error",
+                &format!("giftcard backend failed {failures} times in a row: {err:?}"),
+                json!({ "consecutive_failures": failures }),
+            );
+        }
+    }
+
+    pub fn backend_success(&self) {
+        self.consecutive_backend_failures.store(0, Ordering::SeqCst);
+    }
+
+    /// Sends the report in the background, so that reporting never holds up the caller.
+    fn report(&self, level: &str, message: &str, mut context: Value) {
+        if self.sentry.is_none() && self.webhook_url.is_none() {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+        let message = self.redact(message);
+        self.redact_value(&mut context);
+
+        let mut requests = vec![];
+        if let Some(sentry) = &self.sentry {
+            let event = json!({
+                "message": { "formatted": message },
+                "level": level,
+                "platform": "other",
+                "logger": env!("CARGO_PKG_NAME"),
+                "timestamp": unix_now(),
+                "extra": context,
+            });
+            requests.push(
+                self.client
+                    .post(sentry.store_url.clone())
+                    .header("X-Sentry-Auth", &sentry.auth_header)
+                    .json(&event),
+            );
+        }
+        if let Some(url) = &self.webhook_url {
+            let payload = json!({
+                "level": level,
+                "message": message,
+                "timestamp": unix_now(),
+                "context": context,
+            });
+            requests.push(self.client.post(url).json(&payload));
+        }
+
+        runtime.spawn(async move {
+            for request in requests {
+                if let Err(err) = request.send().await.and_then(|r| r.error_for_status()) {
+                    eprintln!("failed to send error report: {err}");
+                }
+            }
+        });
+    }
+
+    fn redact(&self, s: &str) -> String {
+        let s = self
+            .secrets
+            .iter()
+            .fold(s.to_owned(), |s, secret| s.replace(secret, "[redacted]"));
+        self.redact_codes(&s)
+    }
+
+    /// Replaces the words that look like giftcard codes, e.g. in an answer of the backend that
+    /// had the wrong number of them. Words of only letters or only digits are taken to be
+    /// ordinary words, ids and timestamps even if they match `code_pattern`.
+    fn redact_codes(&self, s: &str) -> String {
+        let is_delimiter = |c: char| c.is_whitespace() || "\"'`,.;:=()[]{}<>/".contains(c);
+        let mut redacted = String::with_capacity(s.len());
+        let mut rest = s;
+        while !rest.is_empty() {
+            let (word, tail) = rest.split_at(rest.find(is_delimiter).unwrap_or(rest.len()));
+            let plain =
+                word.chars().all(|c| c.is_alphabetic()) || word.chars().all(|c| c.is_ascii_digit());
+            if !plain && self.code_pattern.is_match(word) {
+                redacted.push_str("[code]");
+            } else {
+                redacted.push_str(word);
+            }
+            let delimiter = tail.chars().next().map_or(0, char::len_utf8);
+            redacted.push_str(&tail[..delimiter]);
+            rest = &tail[delimiter..];
+        }
+        redacted
+    }
+
+    fn redact_value(&self, value: &mut Value) {
+        match value {
+            Value::String(s) => *s = self.redact(s),
+            Value::Array(values) => values.iter_mut().for_each(|v| self.redact_value(v)),
+            Value::Object(map) => map.values_mut().for_each(|v| self.redact_value(v)),
+            _ => {}
+        }
+    }
+}
+
+/// Turns a DSN like `https://<key>@<host>/<project>` into the event endpoint and auth header.
+fn parse_dsn(dsn: &str) -> anyhow::Result<SentryTarget> {
+    let url = Url::parse(dsn)?;
+    let key = url.username();
+    anyhow::ensure!(!key.is_empty(), "sentry dsn has no public key");
+    let project = url
+        .path_segments()
+        .and_then(|mut segments| segments.next_back())
+        .filter(|project| !project.is_empty())
+        .ok_or_else(|| anyhow::anyhow!("sentry dsn has no project id"))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("sentry dsn has no host"))?;
+    let port = url.port().map(|p| format!(":{p}")).unwrap_or_default();
+
+    Ok(SentryTarget {
+        store_url: Url::parse(&format!(
+            "{}://{host}{port}/api/{project}/store/",
+            url.scheme()
+        ))?,
+        auth_header: format!(
+            "Sentry sentry_version=7, sentry_client={}/{}, sentry_key={key}",
+            env!("CARGO_PKG_NAME"),
+            env!("CARGO_PKG_VERSION")
+        ),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reporter() -> ErrorReporter {
+        let config: Config = serde_yaml::from_str(
+            "store_path: unused.json
+telegram_token: '123:token'
+bot_uname: GephGiftcardBot
+geph_group_id: -100123
+admin_uname: admin
+create_giftcard_secret: backend-secret
+days_per_giftcard: 3",
+        )
+        .unwrap();
+        ErrorReporter::new(&config).unwrap()
+    }
+
+    #[test]
+    fn redacts_secrets_and_giftcard_codes() {
+        let reporter = reporter();
+        let mut context = json!({
+            "user_id": 1000,
+            "error": "invalid giftcard code: [\"GIFT-ABCD-1234\",\"GIFT-EFGH-5678\"]",
+            "nested": ["sent with 123:token and backend-secret at 1700000000"],
+        });
+
+        reporter.redact_value(&mut context);
+
+        assert_eq!(
+            context,
+            json!({
+                "user_id": 1000,
+                "error": "invalid giftcard code: [\"[code]\",\"[code]\"]",
+                "nested": ["sent with [redacted] and [redacted] at 1700000000"],
+            })
+        );
+    }
+}
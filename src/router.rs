@@ -0,0 +1,110 @@
+use teloxide::types::Message;
+
+use crate::{BoxFuture, service::BotService};
+
+/// Which chats a command is available in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scope {
+    Private,
+    Group,
+}
+
+/// Who may use a command. Admins may also use every command for users.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Role {
+    Admin,
+    User,
+}
+
+/// Handles a command, given the message and the words following the command name.
+pub type Handler =
+    for<'a> fn(&'a BotService, &'a Message, Vec<&'a str>) -> BoxFuture<'a, anyhow::Result<()>>;
+
+struct Command {
+    names: &'static [&'static str],
+    scope: Scope,
+    role: Role,
+    args: usize,
+    handler: Handler,
+    confirm: bool,
+}
+
+/// A command a message invokes.
+pub struct Route<'a> {
+    pub handler: Handler,
+    pub args: Vec<&'a str>,
+    /// whether the admin must confirm the command before it runs
+    pub confirm: bool,
+}
+
+/// Maps the first word of a message to the command it names.
+#[derive(Default)]
+pub struct Router {
+    commands: Vec<Command>,
+}
+
+impl Router {
+    /// Registers a command under each of `names`, e.g. an english and a localized alias.
+    ///
+    /// Messages with a different number of arguments than `args` don't match the command.
+    pub fn command(
+        mut self,
+        names: &'static [&'static str],
+        scope: Scope,
+        role: Role,
+        args: usize,
+        handler: Handler,
+    ) -> Self {
+        self.commands.push(Command {
+            names,
+            scope,
+            role,
+            args,
+            handler,
+            confirm: false,
+        });
+        self
+    }
+
+    /// Makes the command registered last ask the admin for confirmation before it runs, for
+    /// commands that are hard to undo.
+    pub fn confirmed(mut self) -> Self {
+        if let Some(command) = self.commands.last_mut() {
+            command.confirm = true;
+        }
+        self
+    }
+
+    /// Finds the command a message invokes, along with its arguments.
+    ///
+    /// A `@bot_uname` suffix on the command name, as telegram adds in groups, is ignored.
+    pub fn route<'a>(
+        &self,
+        scope: Scope,
+        role: Role,
+        bot_uname: &str,
+        text: &'a str,
+    ) -> Option<Route<'a>> {
+        let mut words = text.split_whitespace();
+        let first = words.next()?;
+        let name = match first.split_once('@') {
+            Some((name, uname)) if uname.eq_ignore_ascii_case(bot_uname) => name,
+            _ => first,
+        };
+        let args: Vec<&str> = words.collect();
+
+        self.commands
+            .iter()
+            .find(|command| {
+                command.scope == scope
+                    && (command.role == Role::User || role == Role::Admin)
+                    && command.args == args.len()
+                    && command.names.contains(&name)
+            })
+            .map(|command| Route {
+                handler: command.handler,
+                args,
+                confirm: command.confirm,
+            })
+    }
+}
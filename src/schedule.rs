@@ -0,0 +1,216 @@
+use std::{str::FromStr, sync::Arc};
+
+use anyhow::Context;
+use chrono::{DateTime, Datelike, Duration, DurationRound, TimeZone, Timelike, Utc};
+use serde_json::json;
+
+use crate::service::BotService;
+
+/// A standard five-field cron expression (`minute hour day-of-month month day-of-week`),
+/// evaluated in UTC.
+///
+/// Fields accept `*`, numbers, ranges like `1-5`, steps like `*/15` or `0-30/10`, and
+/// comma-separated lists of those. Day of week runs from 0 (Sunday) to 7 (Sunday again). As in
+/// cron, when both day fields are restricted, a day matching either of them fires.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CronSchedule {
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+impl FromStr for CronSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minute, hour, day, month, weekday] = fields[..] else {
+            anyhow::bail!(
+                "cron expression {s:?} must have 5 fields, but has {}",
+                fields.len()
+            );
+        };
+        let mut weekdays = parse_field(weekday, 0, 7).context("day of week")?;
+        // 7 is another name for sunday
+        if weekdays & (1 << 7) != 0 {
+            weekdays |= 1;
+        }
+        Ok(Self {
+            minutes: parse_field(minute, 0, 59).context("minute")?,
+            hours: parse_field(hour, 0, 23).context("hour")?,
+            days: parse_field(day, 1, 31).context("day of month")?,
+            months: parse_field(month, 1, 12).context("month")?,
+            weekdays,
+            any_day: day == "*",
+            any_weekday: weekday == "*",
+        })
+    }
+}
+
+/// Parses one field into a bitmask of the values it matches.
+fn parse_field(field: &str, min: u32, max: u32) -> anyhow::Result<u64> {
+    let mut mask = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().context("invalid step")?),
+            None => (part, 1),
+        };
+        anyhow::ensure!(step > 0, "step must be at least 1");
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (start.parse()?, end.parse()?)
+        } else {
+            let value = range.parse()?;
+            // `5/10` means from 5 to the end in steps of 10
+            (value, if part.contains('/') { max } else { value })
+        };
+        anyhow::ensure!(
+            min <= start && start <= end && end <= max,
+            "{part:?} is outside {min}-{max}"
+        );
+        for value in (start..=end).step_by(step) {
+            mask |= 1 << value;
+        }
+    }
+    Ok(mask)
+}
+
+impl CronSchedule {
+    /// The first minute strictly after `after` that matches, if there is one within five years.
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let mut t = after.duration_trunc(Duration::minutes(1)).ok()? + Duration::minutes(1);
+        let limit = after + Duration::days(5 * 366);
+        while t < limit {
+            if self.months & (1 << t.month()) == 0 {
+                let (year, month) = if t.month() == 12 {
+                    (t.year() + 1, 1)
+                } else {
+                    (t.year(), t.month() + 1)
+                };
+                t = Utc.with_ymd_and_hms(year, month, 1, 0, 0, 0).single()?;
+            } else if !self.day_matches(t) {
+                t = t.date_naive().succ_opt()?.and_hms_opt(0, 0, 0)?.and_utc();
+            } else if self.hours & (1 << t.hour()) == 0 {
+                t = t.with_minute(0)? + Duration::hours(1);
+            } else if self.minutes & (1 << t.minute()) == 0 {
+                t += Duration::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+
+    fn day_matches(&self, t: DateTime<Utc>) -> bool {
+        let day = self.days & (1 << t.day()) != 0;
+        let weekday = self.weekdays & (1 << t.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        }
+    }
+}
+
+/// Runs each of the bot's scheduled jobs in its own task, for as long as the process lives.
+pub fn spawn_jobs(service: Arc<BotService>) {
+    for job in service.config.schedule.clone() {
+        let service = service.clone();
+        tokio::spawn(async move {
+            // the config was validated at startup
+            let Ok(schedule) = job.cron.parse::<CronSchedule>() else {
+                return;
+            };
+            while let Some(next) = schedule.next_after(now()) {
+                let wait = (next - now()).to_std().unwrap_or_default();
+                tokio::time::sleep(wait).await;
+                if let Err(err) = service.run_scheduled(&job.action).await {
+                    eprintln!(
+                        "[{}] scheduled job {:?} failed: {err:?}",
+                        service.config.bot_uname, job.cron
+                    );
+                    service.reporter.handler_error(
+                        &err,
+                        json!({ "bot": service.config.bot_uname, "cron": job.cron }),
+                    );
+                }
+            }
+        });
+    }
+}
+
+fn now() -> DateTime<Utc> {
+    DateTime::from_timestamp(crate::unix_now() as i64, 0).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().to_utc()
+    }
+
+    fn next(cron: &str, after: &str) -> String {
+        let schedule: CronSchedule = cron.parse().unwrap();
+        schedule.next_after(at(after)).unwrap().to_rfc3339()
+    }
+
+    #[test]
+    fn finds_next_matching_minute() {
+        assert_eq!(
+            next("*/15 * * * *", "2024-03-01T10:07:30Z"),
+            "2024-03-01T10:15:00+00:00"
+        );
+        assert_eq!(
+            next("0 12 * * *", "2024-03-01T12:00:00Z"),
+            "2024-03-02T12:00:00+00:00"
+        );
+        assert_eq!(
+            next("30 23 31 12 *", "2024-03-01T00:00:00Z"),
+            "2024-12-31T23:30:00+00:00"
+        );
+    }
+
+    #[test]
+    fn handles_weekdays() {
+        // 2024-03-01 is a friday
+        assert_eq!(
+            next("0 9 * * 1", "2024-03-01T10:00:00Z"),
+            "2024-03-04T09:00:00+00:00"
+        );
+        assert_eq!(
+            next("0 9 * * 7", "2024-03-01T10:00:00Z"),
+            "2024-03-03T09:00:00+00:00"
+        );
+        // either day field matches when both are restricted
+        assert_eq!(
+            next("0 0 15 * 6", "2024-03-01T10:00:00Z"),
+            "2024-03-02T00:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_expressions() {
+        for cron in [
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+        ] {
+            assert!(cron.parse::<CronSchedule>().is_err(), "{cron}");
+        }
+        assert_eq!(
+            "0 0 30 2 *"
+                .parse::<CronSchedule>()
+                .unwrap()
+                .next_after(now()),
+            None
+        );
+    }
+}
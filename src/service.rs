@@ -0,0 +1,1930 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use chrono::DateTime;
+use once_cell::sync::Lazy;
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use serde_json::json;
+use teloxide::{
+    types::{
+        ButtonRequest, CallbackQuery, ChatId, Contact, InlineKeyboardButton, InlineKeyboardMarkup,
+        KeyboardButton, KeyboardMarkup, KeyboardRemove, Message, MessageId, ParseMode, User,
+        UserId,
+    },
+    utils::{html, markdown},
+};
+
+use crate::{
+    alerts::BackendAlerts,
+    archive::{archive_path, archive_redemptions, find_archived},
+    audit::{AuditEntry, AuditLog, Outcome},
+    backup::backup_store,
+    chart,
+    config::{BotConfig, Config, MaintenanceTask, ScheduledAction, WebhookEventKind},
+    conversation::{Flow, Flows, Transition},
+    eligibility::{self, Request, Verdict},
+    giftcard::{GiftcardError, GiftcardProvider},
+    i18n::{self, Arg, Locale},
+    messages::{
+        MSG_ARCHIVED, MSG_BACKEND_FAILING, MSG_BACKEND_UNAUTHORIZED, MSG_BANNED, MSG_CANCELLED,
+        MSG_CARDS_UNCHECKED, MSG_CHART, MSG_CHART_USAGE, MSG_CODES_CANCELLED,
+        MSG_CODES_NOT_CANCELLED, MSG_CONFIRM, MSG_GRANT_ASK_DAYS, MSG_GRANT_ASK_USER,
+        MSG_GRANT_USAGE, MSG_GRANTED, MSG_INVALID_DAYS, MSG_INVALID_USER_ID,
+        MSG_JOIN_WAITLIST_BUTTON, MSG_LEFT_GROUP, MSG_NO_PENDING_REVIEW, MSG_NO_REPEAT_REQUESTERS,
+        MSG_NOT_ARCHIVED, MSG_NOT_BANNED, MSG_RECIPIENT_COUNT, MSG_REJECTED, MSG_REPEAT_REQUESTER,
+        MSG_REPEAT_REQUESTERS, MSG_REVIEW_REQUEST, MSG_SET_USAGE, MSG_SETTING_UPDATED,
+        MSG_SHARE_CONTACT_BUTTON, MSG_UNBANNED, MSG_UNUSED_CARDS, TemplateKey,
+    },
+    qr,
+    reporting::ErrorReporter,
+    router::{Role, Router, Scope},
+    store::{ConversationState, DailyStats, IssuedCard, PendingReview, Storage, WaitlistEntry},
+    telegram::{DeliveryError, OutgoingMessage, TelegramApi},
+    webhooks::Webhooks,
+    workers::WorkerPool,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Every command the bot understands. Text that isn't a command is a giftcard request in private
+/// chats, and is only answered in groups when it mentions the bot.
+static COMMANDS: Lazy<Router> = Lazy::new(|| {
+    Router::default()
+        .command(
+            &["#RecipientCount", "#领取人数"],
+            Scope::Private,
+            Role::Admin,
+            0,
+            |service, msg, _| Box::pin(service.recipient_count(msg)),
+        )
+        .command(
+            &["/language"],
+            Scope::Private,
+            Role::User,
+            0,
+            |service, msg, _| Box::pin(service.choose_language(msg)),
+        )
+        .command(
+            &["#Ban", "#封禁"],
+            Scope::Private,
+            Role::Admin,
+            1,
+            |service, msg, args| Box::pin(service.ban(msg, args[0])),
+        )
+        .confirmed()
+        .command(
+            &["#Reject", "#拒绝"],
+            Scope::Private,
+            Role::Admin,
+            1,
+            |service, msg, args| Box::pin(service.reject(msg, args[0])),
+        )
+        .confirmed()
+        .command(
+            &["#Unban", "#解封"],
+            Scope::Private,
+            Role::Admin,
+            1,
+            |service, msg, args| Box::pin(service.unban(msg, args[0])),
+        )
+        .command(
+            &["#RepeatRequesters", "#重复请求"],
+            Scope::Private,
+            Role::Admin,
+            0,
+            |service, msg, _| Box::pin(service.repeat_requesters(msg)),
+        )
+        .command(
+            &["#Archived", "#已归档"],
+            Scope::Private,
+            Role::Admin,
+            1,
+            |service, msg, args| Box::pin(service.archived(msg, args[0])),
+        )
+        .command(
+            &["#Chart", "#图表"],
+            Scope::Private,
+            Role::Admin,
+            1,
+            |service, msg, args| Box::pin(service.chart(msg, args[0])),
+        )
+        .command(
+            &["#Chart", "#图表"],
+            Scope::Private,
+            Role::Admin,
+            0,
+            |service, msg, _| Box::pin(service.chart(msg, DEFAULT_CHART_PERIOD)),
+        )
+        .command(
+            &["#UnusedCards", "#未用礼品卡"],
+            Scope::Private,
+            Role::Admin,
+            0,
+            |service, msg, _| Box::pin(service.unused_cards(msg)),
+        )
+        .command(
+            &["#Grant", "#赠送"],
+            Scope::Private,
+            Role::Admin,
+            2,
+            |service, msg, args| Box::pin(service.grant(msg, args[0], args[1])),
+        )
+        .command(
+            &["#Grant", "#赠送"],
+            Scope::Private,
+            Role::Admin,
+            0,
+            |service, msg, _| Box::pin(service.start_grant(msg)),
+        )
+        .command(
+            &["#Set", "#设置"],
+            Scope::Private,
+            Role::Admin,
+            2,
+            |service, msg, args| Box::pin(service.set(msg, args[0], args[1])),
+        )
+});
+
+/// The languages users can choose with `/language`, by code and name.
+const LANGUAGES: [(&str, &str); 3] = [("en", "English"), ("zh", "中文"), ("fa", "فارسی")];
+
+/// Names of the settings admins can change with `#Set`.
+const SETTING_DAYS: &str = "days";
+const SETTING_GROUP_LINK: &str = "group_link";
+
+/// The period `#Chart` covers unless given one, and the longest it can cover, in days.
+const DEFAULT_CHART_PERIOD: &str = "30d";
+const MAX_CHART_DAYS: u64 = 365;
+
+/// What an admin sends to run a command registered with [`Router::confirmed`].
+const CONFIRMATION_PHRASE: &str = "CONFIRM";
+/// How long an admin has to confirm a command.
+const CONFIRMATION_SECS: u64 = 60;
+
+/// Every multi-message conversation the bot can have, see [`crate::conversation`].
+static FLOWS: Lazy<Flows> = Lazy::new(|| {
+    Flows::default()
+        .flow(
+            Flow::new("grant", 300)
+                .step("user_id", |service, msg, text, data| {
+                    Box::pin(service.grant_user_step(msg, text, data))
+                })
+                .step("days", |service, msg, text, data| {
+                    Box::pin(service.grant_days_step(msg, text, data))
+                }),
+        )
+        .flow(
+            Flow::new("confirm", CONFIRMATION_SECS).step("answer", |service, msg, text, data| {
+                Box::pin(service.confirm_step(msg, text, data))
+            }),
+        )
+});
+
+/// Source of the current time, so that tests can control timestamps.
+pub trait Clock: Send + Sync {
+    fn unix_now(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn unix_now(&self) -> u64 {
+        crate::unix_now()
+    }
+}
+
+/// Everything a single bot needs to handle its updates.
+pub struct BotService {
+    pub config: BotConfig,
+    /// settings shared by all bots in this process
+    pub global: Arc<Config>,
+    pub telegram: Arc<dyn TelegramApi>,
+    pub giftcards: Arc<dyn GiftcardProvider>,
+    pub clock: Arc<dyn Clock>,
+    pub store: Box<dyn Storage>,
+    pub audit: Arc<AuditLog>,
+    pub reporter: Arc<ErrorReporter>,
+    pub backend_alerts: Arc<BackendAlerts>,
+    pub webhooks: Arc<Webhooks>,
+    /// when the group was last greeted, for rate limiting
+    pub last_greeting: AtomicU64,
+    /// updates abandoned for taking longer than `workers.timeout_secs`
+    pub timed_out_updates: AtomicU64,
+    /// the workers updates are handled on, by user; scheduled jobs acting on a user take their
+    /// turn there too
+    pub workers: Arc<WorkerPool>,
+}
+
+tokio::task_local! {
+    /// Set once the update being handled has gone too far to be cancelled, see
+    /// [`BotService::within_timeout`].
+    static COMMITTED: Arc<AtomicBool>;
+}
+
+/// Keeps [`BotService::within_timeout`] from cancelling the update being handled from now on,
+/// e.g. because giftcards are being created that the user must get.
+fn commit_to_finishing() {
+    // updates handled outside of `within_timeout`, e.g. by scheduled jobs, can't be cancelled
+    let _ = COMMITTED.try_with(|committed| committed.store(true, Ordering::Relaxed));
+}
+
+impl BotService {
+    /// Runs the handling of one update, cancelling it once it takes longer than
+    /// `workers.timeout_secs`. The user, if given, is then asked to try again rather than left
+    /// waiting for an answer that may never come.
+    ///
+    /// Handling that already started creating giftcards is left to finish however long it takes,
+    /// since cancelling it could leave a user recorded as given a card they never received.
+    pub async fn within_timeout(
+        &self,
+        user: Option<&User>,
+        handling: impl Future<Output = anyhow::Result<()>>,
+    ) -> anyhow::Result<()> {
+        let timeout_secs = self.global.workers.timeout_secs;
+        let committed = Arc::new(AtomicBool::new(false));
+        let handling = COMMITTED.scope(committed.clone(), handling);
+        tokio::pin!(handling);
+        if let Ok(result) =
+            tokio::time::timeout(Duration::from_secs(timeout_secs), &mut handling).await
+        {
+            return result;
+        }
+        if committed.load(Ordering::Relaxed) {
+            eprintln!("an update is taking longer than {timeout_secs}s to deliver a giftcard");
+            return handling.await;
+        }
+        self.timed_out_updates.fetch_add(1, Ordering::Relaxed);
+        if let Some(user) = user
+            && let Err(err) = self
+                .send_template(
+                    ChatId(user.id.0 as i64),
+                    TemplateKey::TemporaryProblem,
+                    self.user_language(user).as_deref(),
+                )
+                .await
+        {
+            eprintln!("cannot tell user {} to try again: {err:?}", user.id);
+        }
+        anyhow::bail!("handling the update took longer than {timeout_secs}s, so it was cancelled")
+    }
+
+    pub async fn handle_message(&self, msg: Message) -> anyhow::Result<()> {
+        if let Some(members) = msg.new_chat_members() {
+            if self
+                .config
+                .required_groups()
+                .any(|id| msg.chat.id == ChatId(id))
+            {
+                self.greet_new_members(&msg, members).await?;
+            }
+            return Ok(());
+        }
+        let Some(sender) = msg.from.clone() else {
+            return Ok(());
+        };
+        // writing to the bot means the user can be messaged again, e.g. after unblocking it
+        if msg.chat.is_private() && self.store.unflag_undeliverable(user_id(&sender)?)? {
+            eprintln!("user {} can receive messages again", sender.id);
+        }
+        if let Some(contact) = msg.contact()
+            && msg.chat.is_private()
+        {
+            return self.handle_contact(&msg, &sender, contact).await;
+        }
+        let Some(text) = msg.text().map(str::to_owned) else {
+            // media, stickers, joins and the like carry no command, but private chats deserve an answer
+            if msg.chat.is_private() {
+                self.send_template(
+                    msg.chat.id,
+                    TemplateKey::SendText,
+                    self.user_language(&sender).as_deref(),
+                )
+                .await?;
+            }
+            return Ok(());
+        };
+
+        let scope = if msg.chat.is_private() {
+            Scope::Private
+        } else if msg.chat.is_group() || msg.chat.is_supergroup() {
+            Scope::Group
+        } else {
+            return Ok(());
+        };
+        let role = if self.is_admin(&sender) {
+            Role::Admin
+        } else {
+            Role::User
+        };
+
+        if scope == Scope::Private && self.continue_conversation(&msg, &sender, &text).await? {
+            return Ok(());
+        }
+        if let Some(route) = COMMANDS.route(scope, role, &self.config.bot_uname, &text) {
+            if role == Role::User {
+                return (route.handler)(self, &msg, route.args).await;
+            }
+            if route.confirm {
+                return self.ask_confirmation(msg.chat.id, &sender, &text).await;
+            }
+            let handling = (route.handler)(self, &msg, route.args);
+            return self
+                .run_admin_command(&sender, &text, false, handling)
+                .await;
+        }
+        match (scope, role) {
+            // anything else users write in private is a request for a giftcard
+            (Scope::Private, Role::User) => self.handle_private_message(&msg, &sender).await,
+            (Scope::Group, _) => self.handle_group_message(&msg, &text).await,
+            (Scope::Private, Role::Admin) => Ok(()),
+        }
+    }
+
+    pub async fn handle_callback(&self, query: CallbackQuery) -> anyhow::Result<()> {
+        self.telegram
+            .answer_callback_query(query.id.clone())
+            .await?;
+
+        let Some(data) = query.data.as_deref() else {
+            return Ok(());
+        };
+        if let Some(language) = data.strip_prefix("language:") {
+            return self.set_language(&query.from, language).await;
+        }
+        if data == "waitlist:join" {
+            return self.join_waitlist(&query.from).await;
+        }
+        if !self.is_admin(&query.from) {
+            return Ok(());
+        }
+        let Some((action, user_id)) = data.split_once(':') else {
+            return Ok(());
+        };
+        let user_id: i64 = user_id
+            .parse()
+            .context("invalid user id in callback data")?;
+        match action {
+            "approve" => {}
+            // rejecting bans the user, so it is confirmed like #Ban
+            "reject" => {
+                let admin_chat = ChatId(query.from.id.0 as i64);
+                let command = format!("#Reject {user_id}");
+                return self
+                    .ask_confirmation(admin_chat, &query.from, &command)
+                    .await;
+            }
+            _ => return Ok(()),
+        }
+        let resolving = self.resolve_review(user_id, true);
+        if !self
+            .run_admin_command(&query.from, data, false, resolving)
+            .await?
+        {
+            return Ok(());
+        }
+
+        if let Some(msg) = query.regular_message() {
+            let text = format!("{}\n\n✅ approved", msg.text().unwrap_or_default());
+            self.telegram
+                .edit_message_text(msg.chat.id, msg.id, text)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the user is the admin. Besides having `admin_uname`, they must be one of
+    /// `admin_ids`, since a username can be given up and taken by someone else. Without any
+    /// `admin_ids`, nobody is.
+    fn is_admin(&self, user: &User) -> bool {
+        if user.username.as_deref() != Some(self.global.admin_uname.as_str()) {
+            return false;
+        }
+        let known = self.global.admin_ids.contains(&(user.id.0 as i64));
+        if !known {
+            eprintln!(
+                "user {} has the admin's username but is not one of admin_ids, treating them as a user",
+                user.id
+            );
+        }
+        known
+    }
+
+    /// Runs an admin's command, recording it in the audit log under the admin's id.
+    async fn run_admin_command<T>(
+        &self,
+        admin: &User,
+        command: &str,
+        confirmed: bool,
+        handling: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let mut audit = AuditEntry::new(&self.config.bot_uname, user_id(admin)?);
+        audit.outcome = Some(Outcome::AdminCommand);
+        audit.check("command", command);
+        if confirmed {
+            audit.check("confirmed", true);
+        }
+        let result = handling.await;
+        let recorded = match &result {
+            Ok(_) => Ok(()),
+            Err(err) => Err(anyhow::anyhow!("{err:#}")),
+        };
+        self.audit.record(audit, &recorded);
+        result
+    }
+
+    /// Holds back a command that is hard to undo until the admin confirms it, so that a slip of
+    /// the finger, or someone else at the admin's unlocked phone, can't run it right away.
+    async fn ask_confirmation(
+        &self,
+        chat_id: ChatId,
+        admin: &User,
+        command: &str,
+    ) -> anyhow::Result<()> {
+        let data = BTreeMap::from([("command".to_owned(), command.to_owned())]);
+        self.start_flow(admin, "confirm", data).await?;
+        let mut audit = AuditEntry::new(&self.config.bot_uname, user_id(admin)?);
+        audit.outcome = Some(Outcome::AwaitingConfirmation);
+        audit.check("command", command);
+        self.audit.record(audit, &Ok(()));
+        let text = MSG_CONFIRM
+            .replace("{phrase}", CONFIRMATION_PHRASE)
+            .replace("{secs}", &CONFIRMATION_SECS.to_string())
+            .replace("{command}", command);
+        self.send(OutgoingMessage::new(chat_id, text)).await?;
+        Ok(())
+    }
+
+    async fn confirm_step(
+        &self,
+        msg: &Message,
+        text: &str,
+        data: &mut BTreeMap<String, String>,
+    ) -> anyhow::Result<Transition> {
+        let command = data
+            .get("command")
+            .context("confirm flow lost the command")?;
+        let admin = msg.from.as_ref().context("confirmation without a sender")?;
+        if text != CONFIRMATION_PHRASE {
+            let mut audit = AuditEntry::new(&self.config.bot_uname, user_id(admin)?);
+            audit.outcome = Some(Outcome::NotConfirmed);
+            audit.check("command", command.as_str());
+            self.audit.record(audit, &Ok(()));
+            self.reply(msg, MSG_CANCELLED.to_owned()).await?;
+            return Ok(Transition::Done);
+        }
+        let route = COMMANDS
+            .route(Scope::Private, Role::Admin, &self.config.bot_uname, command)
+            .context("the confirmed command no longer exists")?;
+        let handling = (route.handler)(self, msg, route.args);
+        self.run_admin_command(admin, command, true, handling)
+            .await?;
+        Ok(Transition::Done)
+    }
+
+    /// Approves or rejects a user's pending review, returning whether there was one.
+    pub async fn resolve_review(&self, user_id: i64, approve: bool) -> anyhow::Result<bool> {
+        // removing the entry first makes sure that two admins can't both approve the same request
+        let Some(pending) = self.store.take_pending_review(user_id)? else {
+            return Ok(false);
+        };
+        commit_to_finishing();
+        let user_chat = ChatId(pending.chat_id);
+        let mut audit = AuditEntry::new(&self.config.bot_uname, user_id);
+        audit.check("fraud_reasons", pending.reasons.clone());
+
+        let language = self.chosen_language(user_id);
+        if approve {
+            let days = self.days_per_giftcard();
+            let result = match self.create_giftcards(user_id, days, self.num_cards()).await {
+                Ok(codes) => {
+                    audit.outcome = Some(Outcome::Approved);
+                    audit.issued_codes(&codes);
+                    self.deliver_giftcards(user_chat, user_id, days, &codes, language.as_deref())
+                        .await
+                }
+                Err(err) => {
+                    // no card was created, so the review can be approved again later
+                    self.store.add_pending_review(user_id, pending)?;
+                    Err(err)
+                }
+            };
+            self.audit.record(audit, &result);
+            result?;
+        } else {
+            self.store.ban(user_id)?;
+            audit.outcome = Some(Outcome::Rejected);
+            self.audit.record(audit, &Ok(()));
+            self.count_today(DailyStats {
+                denied: 1,
+                ..DailyStats::default()
+            });
+            self.send_template(user_chat, TemplateKey::Refused, language.as_deref())
+                .await?;
+        }
+        Ok(true)
+    }
+
+    /// Bans a user, dropping their pending review if they had one.
+    pub fn ban_user(&self, user_id: i64) -> anyhow::Result<()> {
+        self.store.ban(user_id)?;
+        self.store.take_pending_review(user_id)?;
+        Ok(())
+    }
+
+    fn days_per_giftcard(&self) -> u32 {
+        self.runtime_setting(SETTING_DAYS)
+            .and_then(|days| days.parse().ok())
+            .or(self.config.days_per_giftcard)
+            .unwrap_or(self.global.days_per_giftcard)
+    }
+
+    fn num_cards(&self) -> u32 {
+        self.config.num_cards.unwrap_or(self.global.num_cards)
+    }
+
+    fn group_link(&self) -> String {
+        self.runtime_setting(SETTING_GROUP_LINK)
+            .unwrap_or_else(|| self.config.group_link.clone())
+    }
+
+    /// The value an admin set with `#Set`, if any. A failure to read it is only logged, so that
+    /// messages still go out with the configured value.
+    fn runtime_setting(&self, name: &str) -> Option<String> {
+        self.store.setting(name).unwrap_or_else(|err| {
+            eprintln!("cannot read setting {name}: {err:?}");
+            None
+        })
+    }
+
+    async fn handle_private_message(&self, msg: &Message, sender: &User) -> anyhow::Result<()> {
+        let chat_id = msg.chat.id;
+        let sender_id = user_id(sender)?;
+
+        let mut audit = AuditEntry::new(&self.config.bot_uname, sender_id);
+        let result = self
+            .handle_giftcard_request(chat_id, sender, sender_id, false, &mut audit)
+            .await;
+        self.count_request(&audit, &result);
+        self.audit.record(audit, &result);
+        result
+    }
+
+    /// Decides on a giftcard request and answers it. Requests of users on the waitlist aren't
+    /// offered the waitlist again, and fail if the backend does, so that they keep their place.
+    async fn handle_giftcard_request(
+        &self,
+        chat_id: ChatId,
+        sender: &User,
+        sender_id: i64,
+        waitlisted: bool,
+        audit: &mut AuditEntry,
+    ) -> anyhow::Result<()> {
+        let language = self.user_language(sender);
+        let language = language.as_deref();
+
+        let redeemed = self.store.is_redeemed(sender_id)?;
+        audit.check("already_redeemed", redeemed);
+        if redeemed {
+            audit.outcome = Some(Outcome::AlreadyRedeemed);
+            let attempts = self.store.record_repeat_attempt(sender_id)?;
+            audit.check("repeat_attempts", attempts);
+            let escalate_after = self.global.repeat_attempts.escalate_after;
+            if escalate_after == 0 || attempts < escalate_after {
+                self.send_template(chat_id, TemplateKey::AlreadyRedeemed, language)
+                    .await?;
+            } else if attempts == escalate_after {
+                let support = &self.global.repeat_attempts.support_link;
+                let msg = self.template_message(
+                    chat_id,
+                    TemplateKey::SupportContact,
+                    language,
+                    &[("support", Arg::Ltr(support))],
+                );
+                self.send(msg).await?;
+            }
+            return Ok(());
+        }
+
+        let pending = self.store.has_pending_review(sender_id)?;
+        audit.check("pending_review", pending);
+        if pending {
+            audit.outcome = Some(Outcome::ReviewPending);
+            self.send_template(chat_id, TemplateKey::ReviewPending, language)
+                .await?;
+            return Ok(());
+        }
+
+        let request = Request {
+            sender,
+            sender_id,
+            language,
+        };
+        let reasons = match eligibility::evaluate(self, &request, audit).await? {
+            Verdict::Deny(denial) => {
+                audit.outcome = Some(denial.outcome);
+                let mut msg =
+                    self.template_message(chat_id, denial.template, language, &denial.args);
+                if matches!(denial.outcome, Outcome::DailyCapReached)
+                    && self.config.waitlist.is_some()
+                    && !waitlisted
+                {
+                    msg.keyboard = Some(waitlist_button().into());
+                }
+                self.send(msg).await?;
+                return Ok(());
+            }
+            Verdict::Review(reasons) => reasons,
+            Verdict::Eligible => vec![],
+        };
+
+        if self.global.phone_check.is_some() {
+            let verified = self.store.has_phone(sender_id)?;
+            audit.check("phone_verified", verified);
+            if !verified {
+                audit.outcome = Some(Outcome::ContactRequested);
+                self.ask_for_contact(chat_id, language).await?;
+                return Ok(());
+            }
+        }
+
+        if !reasons.is_empty() && self.global.admin_ids.is_empty() {
+            // nobody could approve the request, and letting it through would void the checks
+            eprintln!(
+                "refusing user {sender_id}, who needs review but no admin_ids are configured"
+            );
+            audit.outcome = Some(Outcome::Unreviewable);
+            self.send_template(chat_id, TemplateKey::Refused, language)
+                .await?;
+        } else if !reasons.is_empty() {
+            audit.outcome = Some(Outcome::ReviewRequested);
+            self.request_review(chat_id, sender, sender_id, reasons)
+                .await?;
+        } else {
+            let issued = self
+                .issue_giftcard(
+                    chat_id,
+                    sender_id,
+                    self.days_per_giftcard(),
+                    self.num_cards(),
+                    language,
+                    audit,
+                )
+                .await;
+            match issued {
+                Ok(()) => audit.outcome = Some(Outcome::Issued),
+                Err(err)
+                    if err.downcast_ref::<GiftcardError>().is_some()
+                        && self.config.waitlist.is_some()
+                        && !waitlisted =>
+                {
+                    eprintln!("offering user {sender_id} the waitlist: {err:?}");
+                    audit.outcome = Some(Outcome::BackendUnavailable);
+                    audit.check("backend_error", format!("{err:#}"));
+                    let mut msg =
+                        self.template_message(chat_id, TemplateKey::WaitlistOffer, language, &[]);
+                    msg.keyboard = Some(waitlist_button().into());
+                    self.send(msg).await?;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Counts a refused or failed request toward today's statistics. Issued cards are counted as
+    /// they are issued, whichever way that happens.
+    fn count_request(&self, audit: &AuditEntry, result: &anyhow::Result<()>) {
+        let stats = match (result, &audit.outcome) {
+            (Err(_), _) | (_, Some(Outcome::BackendUnavailable)) => DailyStats {
+                errors: 1,
+                ..DailyStats::default()
+            },
+            (
+                _,
+                Some(
+                    Outcome::AlreadyRedeemed
+                    | Outcome::Banned
+                    | Outcome::NotInGroup
+                    | Outcome::MembershipCheckFailed
+                    | Outcome::Cooldown
+                    | Outcome::DailyCapReached
+                    | Outcome::Unreviewable,
+                ),
+            ) => DailyStats {
+                denied: 1,
+                ..DailyStats::default()
+            },
+            _ => return,
+        };
+        self.count_today(stats);
+    }
+
+    /// Adds to today's statistics, see `#Chart`. They aren't worth failing a request over, so
+    /// errors are only logged.
+    fn count_today(&self, stats: DailyStats) {
+        let today = self.clock.unix_now() / 86400;
+        if let Err(err) = self.store.add_daily_stats(today, stats) {
+            eprintln!("cannot record today's statistics: {err:?}");
+        }
+    }
+
+    /// Puts the user on the waitlist, or tells them their place if they already are on it.
+    async fn join_waitlist(&self, user: &User) -> anyhow::Result<()> {
+        if self.config.waitlist.is_none() {
+            return Ok(());
+        }
+        let user_id = user_id(user)?;
+        let entry = WaitlistEntry {
+            user: user.clone(),
+            joined_at: self.clock.unix_now(),
+        };
+        self.store.join_waitlist(user_id, entry)?;
+        let position = self
+            .store
+            .waitlist()?
+            .iter()
+            .position(|(waiting, _)| *waiting == user_id)
+            .map_or(0, |index| index + 1);
+
+        let mut audit = AuditEntry::new(&self.config.bot_uname, user_id);
+        audit.check("position", position);
+        audit.outcome = Some(Outcome::JoinedWaitlist);
+        self.audit.record(audit, &Ok(()));
+        let msg = self.template_message(
+            ChatId(user_id),
+            TemplateKey::WaitlistJoined,
+            self.user_language(user).as_deref(),
+            &[("position", Arg::Number(position as u64))],
+        );
+        self.send(msg).await?;
+        Ok(())
+    }
+
+    /// Handles the requests of users on the waitlist in the order they joined it, as long as the
+    /// daily cap allows. Users stay on it if the backend still fails, and leave it once their
+    /// request was answered in any other way.
+    async fn process_waitlist(&self) -> anyhow::Result<()> {
+        let Some(waitlist) = &self.config.waitlist else {
+            return Ok(());
+        };
+        let mut served = 0;
+        for (user_id, entry) in self.store.waitlist()?.into_iter().take(waitlist.per_run) {
+            if eligibility::daily_cap_reached(self)? {
+                break;
+            }
+            // in turn with the user's own updates, so that a request of theirs can't race this one
+            let _turn = self.workers.turn(user_id as u64).await;
+            // e.g. granted a card by an admin in the meantime
+            if self.store.is_redeemed(user_id)? {
+                self.store.leave_waitlist(user_id)?;
+                continue;
+            }
+            let mut audit = AuditEntry::new(&self.config.bot_uname, user_id);
+            audit.check("waitlisted_at", entry.joined_at);
+            let result = self
+                .handle_giftcard_request(ChatId(user_id), &entry.user, user_id, true, &mut audit)
+                .await;
+            self.count_request(&audit, &result);
+            self.audit.record(audit, &result);
+            if let Err(err) = result {
+                eprintln!("stopped serving the waitlist at user {user_id}: {err:?}");
+                break;
+            }
+            self.store.leave_waitlist(user_id)?;
+            served += 1;
+        }
+        if served > 0 {
+            eprintln!(
+                "served {served} users on the waitlist of {}",
+                self.config.bot_uname
+            );
+        }
+        Ok(())
+    }
+
+    /// Links the phone number a user shared to their account, refusing numbers already linked to
+    /// another account, then goes on with their giftcard request.
+    async fn handle_contact(
+        &self,
+        msg: &Message,
+        sender: &User,
+        contact: &Contact,
+    ) -> anyhow::Result<()> {
+        let Some(phone_check) = &self.global.phone_check else {
+            return Ok(());
+        };
+        let sender_id = user_id(sender)?;
+        let language = self.user_language(sender);
+        let language = language.as_deref();
+        // anyone's contact can be forwarded, but only the button shares the sender's own number
+        if contact.user_id != Some(sender.id) {
+            return self.ask_for_contact(msg.chat.id, language).await;
+        }
+
+        let hash = phone_hash(&phone_check.salt, &contact.phone_number);
+        match self.store.phone_owner(&hash)? {
+            Some(owner) if owner != sender_id => {
+                eprintln!("user {sender_id} shared the phone number of user {owner}");
+                let mut audit = AuditEntry::new(&self.config.bot_uname, sender_id);
+                audit.check("phone_owner", owner);
+                audit.outcome = Some(Outcome::PhoneInUse);
+                self.audit.record(audit, &Ok(()));
+                let mut reply =
+                    self.template_message(msg.chat.id, TemplateKey::PhoneInUse, language, &[]);
+                reply.keyboard = Some(KeyboardRemove::new().into());
+                self.send(reply).await?;
+                Ok(())
+            }
+            _ => {
+                self.store.record_phone(&hash, sender_id)?;
+                let mut reply =
+                    self.template_message(msg.chat.id, TemplateKey::ContactVerified, language, &[]);
+                reply.keyboard = Some(KeyboardRemove::new().into());
+                self.send(reply).await?;
+                self.handle_private_message(msg, sender).await
+            }
+        }
+    }
+
+    async fn ask_for_contact(&self, chat_id: ChatId, language: Option<&str>) -> anyhow::Result<()> {
+        let mut msg = self.template_message(chat_id, TemplateKey::ShareContact, language, &[]);
+        msg.keyboard = Some(
+            KeyboardMarkup::new([[
+                KeyboardButton::new(MSG_SHARE_CONTACT_BUTTON).request(ButtonRequest::Contact)
+            ]])
+            .one_time_keyboard()
+            .resize_keyboard()
+            .into(),
+        );
+        self.send(msg).await?;
+        Ok(())
+    }
+
+    async fn recipient_count(&self, msg: &Message) -> anyhow::Result<()> {
+        let count = self.store.redemption_count()?;
+        self.reply(
+            msg,
+            MSG_RECIPIENT_COUNT.replace("{count}", &count.to_string()),
+        )
+        .await
+    }
+
+    async fn repeat_requesters(&self, msg: &Message) -> anyhow::Result<()> {
+        let top = self.store.repeat_attempts()?;
+        if top.is_empty() {
+            return self.reply(msg, MSG_NO_REPEAT_REQUESTERS.to_owned()).await;
+        }
+        let mut reply = MSG_REPEAT_REQUESTERS.to_owned();
+        for (user_id, attempts) in top.into_iter().take(10) {
+            reply.push('\n');
+            reply.push_str(
+                &MSG_REPEAT_REQUESTER
+                    .replace("{id}", &user_id.to_string())
+                    .replace("{count}", &attempts.to_string()),
+            );
+        }
+        self.reply(msg, reply).await
+    }
+
+    async fn ban(&self, msg: &Message, user_id: &str) -> anyhow::Result<()> {
+        let reply = match user_id.parse::<i64>() {
+            Ok(user_id) => {
+                self.ban_user(user_id)?;
+                MSG_BANNED.replace("{id}", &user_id.to_string())
+            }
+            Err(_) => MSG_INVALID_USER_ID.replace("{id}", user_id),
+        };
+        self.reply(msg, reply).await
+    }
+
+    async fn reject(&self, msg: &Message, user_id: &str) -> anyhow::Result<()> {
+        let reply = match user_id.parse::<i64>() {
+            Ok(user_id) if self.resolve_review(user_id, false).await? => {
+                MSG_REJECTED.replace("{id}", &user_id.to_string())
+            }
+            Ok(user_id) => MSG_NO_PENDING_REVIEW.replace("{id}", &user_id.to_string()),
+            Err(_) => MSG_INVALID_USER_ID.replace("{id}", user_id),
+        };
+        self.reply(msg, reply).await
+    }
+
+    async fn archived(&self, msg: &Message, user_id: &str) -> anyhow::Result<()> {
+        let Ok(user_id) = user_id.parse::<i64>() else {
+            return self
+                .reply(msg, MSG_INVALID_USER_ID.replace("{id}", user_id))
+                .await;
+        };
+        let reply = match find_archived(&archive_path(&self.config), user_id)? {
+            Some(redemption) => {
+                let date = DateTime::from_timestamp(redemption.redeemed_at as i64, 0)
+                    .filter(|_| redemption.redeemed_at > 0)
+                    .map_or("an unknown date".to_owned(), |t| {
+                        t.format("%Y-%m-%d").to_string()
+                    });
+                MSG_ARCHIVED
+                    .replace("{id}", &user_id.to_string())
+                    .replace("{date}", &date)
+            }
+            None => MSG_NOT_ARCHIVED.replace("{id}", &user_id.to_string()),
+        };
+        self.reply(msg, reply).await
+    }
+
+    async fn unban(&self, msg: &Message, user_id: &str) -> anyhow::Result<()> {
+        let reply = match user_id.parse::<i64>() {
+            Ok(user_id) if self.store.unban(user_id)? => {
+                MSG_UNBANNED.replace("{id}", &user_id.to_string())
+            }
+            Ok(user_id) => MSG_NOT_BANNED.replace("{id}", &user_id.to_string()),
+            Err(_) => MSG_INVALID_USER_ID.replace("{id}", user_id),
+        };
+        self.reply(msg, reply).await
+    }
+
+    async fn grant(&self, msg: &Message, user_id: &str, days: &str) -> anyhow::Result<()> {
+        let (Ok(user_id), Ok(days)) = (user_id.parse::<i64>(), days.parse::<u32>()) else {
+            return self.reply(msg, MSG_GRANT_USAGE.to_owned()).await;
+        };
+        if days == 0 {
+            return self.reply(msg, MSG_GRANT_USAGE.to_owned()).await;
+        }
+
+        let mut audit = AuditEntry::new(&self.config.bot_uname, user_id);
+        audit.check("granted_days", days);
+        // users have a private chat with the bot under their own id
+        let result = self
+            .issue_giftcard(
+                ChatId(user_id),
+                user_id,
+                days,
+                1,
+                self.chosen_language(user_id).as_deref(),
+                &mut audit,
+            )
+            .await
+            .map(|()| audit.outcome = Some(Outcome::Granted));
+        self.audit.record(audit, &result);
+        result?;
+
+        let reply = MSG_GRANTED
+            .replace("{days}", &days.to_string())
+            .replace("{id}", &user_id.to_string());
+        self.reply(msg, reply).await
+    }
+
+    async fn set(&self, msg: &Message, name: &str, value: &str) -> anyhow::Result<()> {
+        let valid = match name {
+            SETTING_DAYS => value.parse::<u32>().is_ok_and(|days| days > 0),
+            SETTING_GROUP_LINK => value.starts_with("https://"),
+            _ => false,
+        };
+        if !valid {
+            return self.reply(msg, MSG_SET_USAGE.to_owned()).await;
+        }
+        self.store.set_setting(name, value)?;
+        eprintln!("setting {name} changed to {value}");
+        let reply = MSG_SETTING_UPDATED
+            .replace("{name}", name)
+            .replace("{value}", value);
+        self.reply(msg, reply).await
+    }
+
+    async fn start_grant(&self, msg: &Message) -> anyhow::Result<()> {
+        let Some(sender) = &msg.from else {
+            return Ok(());
+        };
+        self.start_flow(sender, "grant", BTreeMap::new()).await?;
+        self.reply(msg, MSG_GRANT_ASK_USER.to_owned()).await
+    }
+
+    async fn grant_user_step(
+        &self,
+        msg: &Message,
+        text: &str,
+        data: &mut BTreeMap<String, String>,
+    ) -> anyhow::Result<Transition> {
+        if text.parse::<i64>().is_err() {
+            self.reply(msg, MSG_INVALID_USER_ID.replace("{id}", text))
+                .await?;
+            return Ok(Transition::Repeat);
+        }
+        data.insert("user_id".to_owned(), text.to_owned());
+        self.reply(msg, MSG_GRANT_ASK_DAYS.to_owned()).await?;
+        Ok(Transition::Next("days"))
+    }
+
+    async fn grant_days_step(
+        &self,
+        msg: &Message,
+        text: &str,
+        data: &mut BTreeMap<String, String>,
+    ) -> anyhow::Result<Transition> {
+        if !text.parse::<u32>().is_ok_and(|days| days > 0) {
+            self.reply(msg, MSG_INVALID_DAYS.to_owned()).await?;
+            return Ok(Transition::Repeat);
+        }
+        let user_id = data.get("user_id").context("grant flow lost the user id")?;
+        self.grant(msg, user_id, text).await?;
+        Ok(Transition::Done)
+    }
+
+    /// Puts the user at the first step of a flow, with the data the flow starts from. The caller
+    /// asks for the step's input.
+    async fn start_flow(
+        &self,
+        user: &User,
+        flow: &str,
+        data: BTreeMap<String, String>,
+    ) -> anyhow::Result<()> {
+        let flow = FLOWS
+            .get(flow)
+            .with_context(|| format!("no flow named {flow}"))?;
+        let state = ConversationState {
+            flow: flow.name.to_owned(),
+            step: flow.first_step().context("flow has no steps")?.to_owned(),
+            data,
+            expires_at: self.clock.unix_now() + flow.ttl_secs,
+        };
+        self.store.set_conversation(user_id(user)?, state)
+    }
+
+    /// Hands the message to the step the user is at, if they are in a conversation. Returns
+    /// whether the message was part of a conversation.
+    async fn continue_conversation(
+        &self,
+        msg: &Message,
+        sender: &User,
+        text: &str,
+    ) -> anyhow::Result<bool> {
+        let sender_id = user_id(sender)?;
+        let Some(mut state) = self.store.conversation(sender_id)? else {
+            return Ok(false);
+        };
+        let now = self.clock.unix_now();
+        let flow = FLOWS.get(&state.flow);
+        let handler = flow.and_then(|flow| flow.handler(&state.step));
+        let (Some(flow), Some(handler)) = (flow, handler) else {
+            // the flow was removed or changed by an upgrade
+            self.store.clear_conversation(sender_id)?;
+            return Ok(false);
+        };
+        if state.expires_at <= now {
+            self.store.clear_conversation(sender_id)?;
+            return Ok(false);
+        }
+        if text == "/cancel" {
+            self.store.clear_conversation(sender_id)?;
+            self.reply(msg, MSG_CANCELLED.to_owned()).await?;
+            return Ok(true);
+        }
+
+        // if the step fails, the user stays where they are and can try again
+        match handler(self, msg, text, &mut state.data).await? {
+            Transition::Next(step) => {
+                state.step = step.to_owned();
+                state.expires_at = now + flow.ttl_secs;
+                self.store.set_conversation(sender_id, state)?;
+            }
+            Transition::Repeat => {
+                state.expires_at = now + flow.ttl_secs;
+                self.store.set_conversation(sender_id, state)?;
+            }
+            Transition::Done => self.store.clear_conversation(sender_id)?,
+        }
+        Ok(true)
+    }
+
+    /// Answers in the chat the message came from, without any template formatting.
+    async fn reply(&self, msg: &Message, text: String) -> anyhow::Result<()> {
+        self.send(OutgoingMessage::new(msg.chat.id, text)).await?;
+        Ok(())
+    }
+
+    /// Creates `count` codes lasting `days` each and sends them to the user, all in one message.
+    /// The codes go into the audit entry as soon as they exist, so that it has them even if
+    /// delivery fails.
+    async fn issue_giftcard(
+        &self,
+        chat_id: ChatId,
+        user_id: i64,
+        days: u32,
+        count: u32,
+        language: Option<&str>,
+        audit: &mut AuditEntry,
+    ) -> anyhow::Result<()> {
+        let codes = self.create_giftcards(user_id, days, count).await?;
+        audit.issued_codes(&codes);
+        self.deliver_giftcards(chat_id, user_id, days, &codes, language)
+            .await
+    }
+
+    /// Creates `count` codes lasting `days` each and records them as given to the user. Once this
+    /// succeeded, the user counts as redeemed whether or not the codes reach them.
+    async fn create_giftcards(
+        &self,
+        user_id: i64,
+        days: u32,
+        count: u32,
+    ) -> anyhow::Result<Vec<String>> {
+        commit_to_finishing();
+        let codes = match self.giftcards.create_giftcards(days, count).await {
+            Ok(codes) => {
+                self.reporter.backend_success();
+                codes
+            }
+            Err(err) => {
+                // a rejected secret needs an admin right away, not after enough users failed
+                if let Some(GiftcardError::Unauthorized(message)) = err.downcast_ref()
+                    && self.backend_alerts.record_auth_failure()
+                {
+                    self.notify_admins(&MSG_BACKEND_UNAUTHORIZED.replace("{error}", message))
+                        .await;
+                }
+                let err = err.context("cannot create giftcard");
+                self.reporter.backend_failure(&err);
+                if let Some(count) = self.backend_alerts.record_failure() {
+                    let alert = MSG_BACKEND_FAILING
+                        .replace("{count}", &count.to_string())
+                        .replace(
+                            "{minutes}",
+                            &self.global.backend_alert.window_minutes.to_string(),
+                        )
+                        .replace("{error}", &format!("{err:#}"));
+                    self.notify_admins(&alert).await;
+                }
+                return Err(err);
+            }
+        };
+        // users given a card before, e.g. winners of a bonus, keep the time of their first one
+        if !self.store.is_redeemed(user_id)? {
+            self.store
+                .record_redemption(user_id, self.clock.unix_now())?;
+        }
+        for code in &codes {
+            self.store.record_card(IssuedCard {
+                user_id,
+                code: code.clone(),
+                issued_at: self.clock.unix_now(),
+                used: false,
+                reminded: false,
+            })?;
+        }
+        self.count_today(DailyStats {
+            issued: 1,
+            ..DailyStats::default()
+        });
+        self.webhooks.send(
+            WebhookEventKind::Issued,
+            &self.config.bot_uname,
+            user_id,
+            json!({ "days": days, "cards": codes.len() }),
+        );
+        Ok(codes)
+    }
+
+    /// Sends created codes to the user, all in one message.
+    async fn deliver_giftcards(
+        &self,
+        chat_id: ChatId,
+        user_id: i64,
+        days: u32,
+        codes: &[String],
+        language: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let (template, count) = match codes.len() {
+            1 => (TemplateKey::Congrats, None),
+            count => (TemplateKey::BundleCongrats, Some(count as u64)),
+        };
+        let mut args = vec![("days", Arg::Number(days.into()))];
+        args.extend(count.map(|count| ("count", Arg::Number(count))));
+        let congrats = self.template_message(chat_id, template, language, &args);
+        self.send(congrats).await?;
+        // one code per line, so that each can be copied on its own
+        self.send(OutgoingMessage::new(chat_id, codes.join("\n")))
+            .await?;
+        if self.config.qr_code {
+            for code in codes {
+                // the code already went out as text, so a missing image is only logged
+                let sent = async {
+                    let png = qr::qr_png(code)?;
+                    let result = self.telegram.send_photo(chat_id, png).await;
+                    self.flag_if_undeliverable(chat_id, &result)?;
+                    result
+                };
+                if let Err(err) = sent.await {
+                    eprintln!("cannot send the qr code of user {user_id}'s giftcard: {err:?}");
+                }
+            }
+        }
+        self.send_template(chat_id, TemplateKey::RedeemSteps, language)
+            .await?;
+        Ok(())
+    }
+
+    async fn request_review(
+        &self,
+        chat_id: ChatId,
+        sender: &User,
+        sender_id: i64,
+        reasons: Vec<String>,
+    ) -> anyhow::Result<()> {
+        let summary = MSG_REVIEW_REQUEST
+            .replace("{name}", &sender.full_name())
+            .replace("{uname}", sender.username.as_deref().unwrap_or_default())
+            .replace("{id}", &sender_id.to_string())
+            .replace("{reasons}", &reasons.join(", "));
+        let keyboard = InlineKeyboardMarkup::new([[
+            InlineKeyboardButton::callback("✅ Approve", format!("approve:{sender_id}")),
+            InlineKeyboardButton::callback("❌ Reject", format!("reject:{sender_id}")),
+        ]]);
+
+        self.webhooks.send(
+            WebhookEventKind::FraudFlagged,
+            &self.config.bot_uname,
+            sender_id,
+            json!({ "reasons": reasons }),
+        );
+        self.store.add_pending_review(
+            sender_id,
+            PendingReview {
+                chat_id: chat_id.0,
+                reasons,
+                requested_at: self.clock.unix_now(),
+            },
+        )?;
+
+        for admin_id in &self.global.admin_ids {
+            let mut msg = OutgoingMessage::new(ChatId(*admin_id), &summary);
+            msg.keyboard = Some(keyboard.clone().into());
+            self.send(msg).await?;
+        }
+        self.send_template(
+            chat_id,
+            TemplateKey::ReviewPending,
+            self.user_language(sender).as_deref(),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn handle_group_message(&self, msg: &Message, text: &str) -> anyhow::Result<()> {
+        let bot_mention = format!("@{}", self.config.bot_uname);
+        if text.contains(&bot_mention) {
+            let language = msg.from.as_ref().and_then(|user| self.user_language(user));
+            let mut reply = self.template_message(
+                msg.chat.id,
+                TemplateKey::GroupReply,
+                language.as_deref(),
+                &[],
+            );
+            reply.reply_to = Some(msg.id);
+            // in forum supergroups, replies without a thread id land in the General topic
+            if msg.is_topic_message {
+                reply.thread_id = msg.thread_id;
+            }
+            let reply_id = self.send(reply).await?;
+
+            let cleanup = &self.config.group_replies;
+            if cleanup.delete_after_secs > 0 {
+                let mut message_ids = vec![reply_id];
+                if cleanup.delete_mention {
+                    message_ids.push(msg.id);
+                }
+                self.delete_later(
+                    msg.chat.id,
+                    message_ids,
+                    Duration::from_secs(cleanup.delete_after_secs),
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Welcomes people who joined the group, unless the group was greeted only a moment ago.
+    async fn greet_new_members(&self, msg: &Message, members: &[User]) -> anyhow::Result<()> {
+        let greeting = &self.config.greeting;
+        let names: Vec<String> = members
+            .iter()
+            .filter(|user| !user.is_bot)
+            .map(|user| match self.config.formatting.parse_mode {
+                Some(ParseMode::Html) => html::escape(&user.first_name),
+                Some(ParseMode::MarkdownV2) => markdown::escape(&user.first_name),
+                _ => user.first_name.clone(),
+            })
+            .collect();
+        if !greeting.enabled || names.is_empty() {
+            return Ok(());
+        }
+
+        let now = self.clock.unix_now();
+        let last = self.last_greeting.load(Ordering::SeqCst);
+        if last != 0 && now < last + greeting.min_interval_secs {
+            return Ok(());
+        }
+        // another worker may be greeting the same wave of joins
+        if self
+            .last_greeting
+            .compare_exchange(last, now, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Ok(());
+        }
+
+        // the group is shared by speakers of many languages, so the bot's default is used
+        let names = names.join(", ");
+        let mut welcome = self.template_message(
+            msg.chat.id,
+            TemplateKey::Welcome,
+            None,
+            &[("names", Arg::Text(&names))],
+        );
+        if msg.is_topic_message {
+            welcome.thread_id = msg.thread_id;
+        }
+        let message_id = self.send(welcome).await?;
+
+        if greeting.delete_after_secs > 0 {
+            self.delete_later(
+                msg.chat.id,
+                vec![message_id],
+                Duration::from_secs(greeting.delete_after_secs),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Deletes the messages once the delay has passed, without waiting for it.
+    fn delete_later(&self, chat_id: ChatId, message_ids: Vec<MessageId>, delay: Duration) {
+        let telegram = self.telegram.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(delay).await;
+            for message_id in message_ids {
+                if let Err(err) = telegram.delete_message(chat_id, message_id).await {
+                    eprintln!(
+                        "failed to delete message {} in chat {chat_id}: {err:?}",
+                        message_id.0
+                    );
+                }
+            }
+        });
+    }
+
+    /// Carries out a job from the bot's schedule.
+    pub async fn run_scheduled(&self, action: &ScheduledAction) -> anyhow::Result<()> {
+        match action {
+            ScheduledAction::Announce(text) => {
+                let group_id = ChatId(self.config.geph_group_id);
+                let days = Arg::Number(self.days_per_giftcard().into());
+                let text = i18n::render(text, None, &[("days", days)]);
+                self.send(self.formatted_message(group_id, text)).await?;
+                Ok(())
+            }
+            ScheduledAction::Maintenance(MaintenanceTask::CompactStore) => self.store.compact(),
+            ScheduledAction::Maintenance(MaintenanceTask::RemindUnusedCards) => {
+                self.remind_unused_cards().await
+            }
+            ScheduledAction::Maintenance(MaintenanceTask::ArchiveRedemptions) => {
+                let before = self
+                    .clock
+                    .unix_now()
+                    .saturating_sub(self.global.archive.after_days * 86400);
+                let archived =
+                    archive_redemptions(&*self.store, &archive_path(&self.config), before)?;
+                eprintln!(
+                    "archived {archived} redemptions of {}",
+                    self.config.bot_uname
+                );
+                Ok(())
+            }
+            ScheduledAction::Maintenance(MaintenanceTask::RecheckMembership) => {
+                self.recheck_membership().await
+            }
+            ScheduledAction::Maintenance(MaintenanceTask::CheckMilestones) => {
+                self.check_milestones().await
+            }
+            ScheduledAction::Maintenance(MaintenanceTask::BackupStore) => {
+                let backup = self
+                    .global
+                    .backup
+                    .as_ref()
+                    .context("no backup is configured")?;
+                let name =
+                    backup_store(&self.config, backup, &*self.store, self.clock.unix_now()).await?;
+                eprintln!("backed up the store of {} as {name}", self.config.bot_uname);
+                Ok(())
+            }
+            ScheduledAction::Maintenance(MaintenanceTask::ProcessWaitlist) => {
+                self.process_waitlist().await
+            }
+        }
+    }
+
+    /// Sends a chart of the daily statistics over a period such as `30d`, ending today.
+    async fn chart(&self, msg: &Message, period: &str) -> anyhow::Result<()> {
+        let days = period
+            .strip_suffix('d')
+            .unwrap_or(period)
+            .parse::<u64>()
+            .ok()
+            .filter(|days| (1..=MAX_CHART_DAYS).contains(days));
+        let Some(days) = days else {
+            let usage = MSG_CHART_USAGE.replace("{max}", &MAX_CHART_DAYS.to_string());
+            return self.reply(msg, usage).await;
+        };
+        let today = self.clock.unix_now() / 86400;
+        let first_day = today + 1 - days;
+        let mut stats = vec![DailyStats::default(); days as usize];
+        for (day, day_stats) in self.store.daily_stats(first_day)? {
+            if let Some(slot) = stats.get_mut((day - first_day) as usize) {
+                *slot = day_stats;
+            }
+        }
+
+        let series = |count: fn(&DailyStats) -> u64| stats.iter().map(count).collect::<Vec<_>>();
+        let (issued, denied, errors) = (
+            series(|day| day.issued),
+            series(|day| day.denied),
+            series(|day| day.errors),
+        );
+        let (png, step) = chart::trend_png(&[
+            (&issued, chart::ISSUED_COLOR),
+            (&denied, chart::DENIED_COLOR),
+            (&errors, chart::ERRORS_COLOR),
+        ])?;
+        self.telegram.send_photo(msg.chat.id, png).await?;
+
+        let date = |day: u64| {
+            DateTime::from_timestamp((day * 86400) as i64, 0)
+                .unwrap_or_default()
+                .format("%Y-%m-%d")
+                .to_string()
+        };
+        let total = |series: &[u64]| series.iter().sum::<u64>().to_string();
+        let caption = MSG_CHART
+            .replace("{from}", &date(first_day))
+            .replace("{to}", &date(today))
+            .replace("{issued}", &total(&issued))
+            .replace("{denied}", &total(&denied))
+            .replace("{errors}", &total(&errors))
+            .replace("{step}", &step.to_string());
+        self.reply(msg, caption).await
+    }
+
+    async fn unused_cards(&self, msg: &Message) -> anyhow::Result<()> {
+        let cards = self.store.cards()?;
+        let total = cards.len();
+        let (unused, unchecked) = self.check_usage(cards).await?;
+        let mut reply = MSG_UNUSED_CARDS
+            .replace("{unused}", &unused.len().to_string())
+            .replace("{total}", &total.to_string());
+        if unchecked > 0 {
+            reply.push('\n');
+            reply.push_str(&MSG_CARDS_UNCHECKED.replace("{count}", &unchecked.to_string()));
+        }
+        self.reply(msg, reply).await
+    }
+
+    /// Sends their code again to users who still haven't redeemed it, once per card.
+    async fn remind_unused_cards(&self) -> anyhow::Result<()> {
+        let due_before = self
+            .clock
+            .unix_now()
+            .saturating_sub(self.global.card_usage.remind_after_days * 86400);
+        // users flagged for leaving the group are not encouraged to use their codes
+        let left_group = self.store.left_group_users()?;
+        let undeliverable = self.store.undeliverable_users()?;
+        let due: Vec<IssuedCard> = self
+            .store
+            .cards()?
+            .into_iter()
+            .filter(|card| {
+                !card.reminded
+                    && card.issued_at <= due_before
+                    && !left_group.contains(&card.user_id)
+                    && !undeliverable.contains(&card.user_id)
+            })
+            .collect();
+        let (unused, _) = self.check_usage(due).await?;
+
+        for mut card in unused {
+            let chat_id = ChatId(card.user_id);
+            let language = self.chosen_language(card.user_id);
+            let sent = async {
+                self.send_template(chat_id, TemplateKey::UnusedReminder, language.as_deref())
+                    .await?;
+                self.send(OutgoingMessage::new(chat_id, &card.code)).await?;
+                self.send_template(chat_id, TemplateKey::RedeemSteps, language.as_deref())
+                    .await
+            };
+            // users who can't be messaged would fail again every time, so they are not retried
+            if let Err(err) = sent.await {
+                eprintln!(
+                    "cannot remind user {} of their giftcard: {err:?}",
+                    card.user_id
+                );
+            }
+            card.reminded = true;
+            self.store.update_card(card)?;
+        }
+        Ok(())
+    }
+
+    /// Announces the highest milestone the group's member count passed since the last check, and
+    /// gives a bonus card to a random recent recipient. The first check only records where the
+    /// group stands, so that turning milestones on doesn't celebrate one passed long ago.
+    async fn check_milestones(&self) -> anyhow::Result<()> {
+        let Some(config) = &self.config.milestones else {
+            return Ok(());
+        };
+        let group_id = ChatId(self.config.geph_group_id);
+        let count = self.telegram.member_count(group_id).await?;
+        let milestone = count / config.every * config.every;
+        let last = self.store.last_milestone()?;
+        if milestone == 0 || last.is_some_and(|last| milestone <= last) {
+            return Ok(());
+        }
+        if last.is_none() {
+            self.store.record_milestone(milestone)?;
+            return Ok(());
+        }
+
+        let days = config
+            .bonus_days
+            .unwrap_or_else(|| self.days_per_giftcard());
+        // created before anything is announced, so that if the backend fails the milestone is
+        // celebrated at the next check instead of promising a card nobody gets
+        let bonus = match self.pick_recent_recipient(config.recent_days)? {
+            Some(winner) => {
+                let mut audit = AuditEntry::new(&self.config.bot_uname, winner);
+                audit.check("milestone", milestone);
+                match self.create_giftcards(winner, days, 1).await {
+                    Ok(codes) => {
+                        audit.issued_codes(&codes);
+                        Some((winner, codes, audit))
+                    }
+                    Err(err) => {
+                        let result = Err(err);
+                        self.audit.record(audit, &result);
+                        return result;
+                    }
+                }
+            }
+            None => None,
+        };
+        // recorded before anything is sent, so that a failure can't celebrate it twice
+        self.store.record_milestone(milestone)?;
+
+        let args = [
+            ("count", Arg::Number(milestone)),
+            ("days", Arg::Number(days.into())),
+        ];
+        let announcement = self.template_message(group_id, TemplateKey::Milestone, None, &args);
+        // the bonus card exists and the milestone won't be checked again, so the winner gets it
+        // even if the group can't be told
+        if let Err(err) = self.send(announcement).await {
+            eprintln!("failed to announce the {milestone} members milestone: {err:?}");
+        }
+        let Some((winner, codes, mut audit)) = bonus else {
+            eprintln!(
+                "nobody received a giftcard recently enough to win the {milestone} members bonus"
+            );
+            return Ok(());
+        };
+        let language = self.chosen_language(winner);
+        let result = async {
+            let bonus = self.template_message(
+                ChatId(winner),
+                TemplateKey::MilestoneBonus,
+                language.as_deref(),
+                &args,
+            );
+            self.send(bonus).await?;
+            self.deliver_giftcards(ChatId(winner), winner, days, &codes, language.as_deref())
+                .await
+        }
+        .await
+        .map(|()| audit.outcome = Some(Outcome::MilestoneBonus));
+        self.audit.record(audit, &result);
+        result
+    }
+
+    /// A random user who received a giftcard within `recent_days`, and hasn't been banned, left
+    /// the group or blocked the bot since.
+    fn pick_recent_recipient(&self, recent_days: u64) -> anyhow::Result<Option<i64>> {
+        let since = self.clock.unix_now().saturating_sub(recent_days * 86400);
+        let left_group = self.store.left_group_users()?;
+        let undeliverable = self.store.undeliverable_users()?;
+        let mut candidates = vec![];
+        for (user_id, redeemed_at) in self.store.redemptions()? {
+            if redeemed_at >= since
+                && !left_group.contains(&user_id)
+                && !undeliverable.contains(&user_id)
+                && !self.store.is_banned(user_id)?
+            {
+                candidates.push(user_id);
+            }
+        }
+        if candidates.is_empty() {
+            return Ok(None);
+        }
+        let mut random = [0; 8];
+        SystemRandom::new()
+            .fill(&mut random)
+            .map_err(|_| anyhow::anyhow!("cannot generate a random number"))?;
+        let index = u64::from_le_bytes(random) % candidates.len() as u64;
+        Ok(Some(candidates[index as usize]))
+    }
+
+    /// Flags users who redeemed within `membership_recheck.window_days` but are no longer in any
+    /// of the bot's groups, telling the admins and, if configured, cancelling their codes.
+    async fn recheck_membership(&self) -> anyhow::Result<()> {
+        let config = &self.global.membership_recheck;
+        let now = self.clock.unix_now();
+        let since = now.saturating_sub(config.window_days * 86400);
+        let flagged = self.store.left_group_users()?;
+        let recent = self
+            .store
+            .redemptions()?
+            .into_iter()
+            .filter(|(user_id, redeemed_at)| *redeemed_at >= since && !flagged.contains(user_id));
+
+        for (user_id, redeemed_at) in recent {
+            match self.is_in_any_group(user_id).await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(err) => {
+                    eprintln!("cannot recheck group membership of user {user_id}: {err:?}");
+                    continue;
+                }
+            }
+            if !self.store.flag_left_group(user_id)? {
+                continue;
+            }
+            let mut text = MSG_LEFT_GROUP
+                .replace("{id}", &user_id.to_string())
+                .replace("{days}", &((now - redeemed_at) / 86400).to_string());
+            if config.cancel_codes {
+                let (cancelled, failed) = self.cancel_cards(user_id).await?;
+                text.push('\n');
+                text.push_str(&MSG_CODES_CANCELLED.replace("{count}", &cancelled.to_string()));
+                if failed > 0 {
+                    text.push('\n');
+                    text.push_str(&MSG_CODES_NOT_CANCELLED.replace("{count}", &failed.to_string()));
+                }
+            }
+            self.notify_admins(&text).await;
+        }
+        Ok(())
+    }
+
+    async fn is_in_any_group(&self, user_id: i64) -> anyhow::Result<bool> {
+        for group_id in self.config.required_groups() {
+            if self
+                .telegram
+                .is_group_member(ChatId(group_id), UserId(user_id as u64))
+                .await?
+            {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Has the backend cancel the user's codes that aren't known to be used. Returns how many
+    /// were cancelled and how many could not be.
+    async fn cancel_cards(&self, user_id: i64) -> anyhow::Result<(usize, usize)> {
+        let mut cancelled = 0;
+        let mut failed = 0;
+        for card in self.store.cards()? {
+            if card.user_id != user_id || card.used {
+                continue;
+            }
+            match self.giftcards.cancel_giftcard(&card.code).await {
+                Ok(()) => cancelled += 1,
+                Err(err) => {
+                    eprintln!("cannot cancel a giftcard of user {user_id}: {err:?}");
+                    failed += 1;
+                }
+            }
+        }
+        Ok((cancelled, failed))
+    }
+
+    /// Asks the backend about each card not yet known to be used, remembering those that are.
+    /// Returns the cards that are still unused, and how many could not be checked.
+    async fn check_usage(
+        &self,
+        cards: Vec<IssuedCard>,
+    ) -> anyhow::Result<(Vec<IssuedCard>, usize)> {
+        let mut unused = vec![];
+        let mut unchecked = 0;
+        for mut card in cards.into_iter().filter(|card| !card.used) {
+            match self.giftcards.is_used(&card.code).await {
+                Ok(true) => {
+                    card.used = true;
+                    self.store.update_card(card)?;
+                }
+                Ok(false) => unused.push(card),
+                Err(err) => {
+                    eprintln!("cannot check whether a giftcard was used: {err:?}");
+                    unchecked += 1;
+                }
+            }
+        }
+        Ok((unused, unchecked))
+    }
+
+    /// Offers the user a button for each language they can choose.
+    async fn choose_language(&self, msg: &Message) -> anyhow::Result<()> {
+        let Some(sender) = &msg.from else {
+            return Ok(());
+        };
+        let language = self.user_language(sender);
+        let buttons = LANGUAGES
+            .map(|(code, name)| InlineKeyboardButton::callback(name, format!("language:{code}")));
+        let mut prompt = self.template_message(
+            msg.chat.id,
+            TemplateKey::ChooseLanguage,
+            language.as_deref(),
+            &[],
+        );
+        prompt.keyboard = Some(InlineKeyboardMarkup::new([buttons]).into());
+        self.send(prompt).await?;
+        Ok(())
+    }
+
+    async fn set_language(&self, user: &User, language: &str) -> anyhow::Result<()> {
+        if !LANGUAGES.iter().any(|(code, _)| *code == language) {
+            return Ok(());
+        }
+        self.store.set_language(user_id(user)?, language)?;
+        // users have a private chat with the bot under their own id
+        self.send_template(
+            ChatId(user_id(user)?),
+            TemplateKey::LanguageChanged,
+            Some(language),
+        )
+        .await
+    }
+
+    /// The language the user chose with `/language`, if they did.
+    fn chosen_language(&self, user_id: i64) -> Option<String> {
+        self.store.language(user_id).unwrap_or_else(|err| {
+            eprintln!("cannot read the language of user {user_id}: {err:?}");
+            None
+        })
+    }
+
+    /// The language to write to the user in: the one they chose, or else the one telegram
+    /// reports for them.
+    fn user_language(&self, user: &User) -> Option<String> {
+        user_id(user)
+            .ok()
+            .and_then(|id| self.chosen_language(id))
+            .or_else(|| user.language_code.clone())
+    }
+
+    /// The template in the first language of the user's fallback chain that has a translation,
+    /// along with that language, or the multilingual one from `templates` if none has.
+    ///
+    /// Translations shipped with the bot only stand in for templates the config leaves alone.
+    fn localize(&self, key: TemplateKey, language: Option<&str>) -> (&str, Option<Locale>) {
+        let chain = i18n::fallback_chain(
+            language,
+            self.config.default_language.as_deref(),
+            self.global.default_language.as_deref(),
+        );
+        let templates = &self.config.templates;
+        for locale in chain {
+            let configured = self
+                .config
+                .translations
+                .get(locale.code())
+                .and_then(|translations| translations.get(&key));
+            if let Some(text) = configured {
+                return (text, Some(locale));
+            }
+            if let Some(text) = i18n::builtin(&locale, key).filter(|_| templates.is_default(key)) {
+                return (text, Some(locale));
+            }
+        }
+        (templates.get(key), None)
+    }
+
+    /// Prepares a message with one of the bot's templates in the user's language, formatted as
+    /// configured for the bot. `{days}` is filled in unless `args` has it.
+    fn template_message(
+        &self,
+        chat_id: ChatId,
+        key: TemplateKey,
+        language: Option<&str>,
+        args: &[(&str, Arg)],
+    ) -> OutgoingMessage {
+        let (template, locale) = self.localize(key, language);
+        let days = ("days", Arg::Number(self.days_per_giftcard().into()));
+        let link = self.group_link();
+        let args: Vec<(&str, Arg)> = args
+            .iter()
+            .copied()
+            .chain([days, ("link", Arg::Ltr(&link))])
+            .collect();
+        self.formatted_message(chat_id, i18n::render(template, locale.as_ref(), &args))
+    }
+
+    /// A message formatted as configured for the bot.
+    fn formatted_message(&self, chat_id: ChatId, text: String) -> OutgoingMessage {
+        let formatting = &self.config.formatting;
+        let mut msg = OutgoingMessage::new(chat_id, text);
+        msg.parse_mode = formatting.parse_mode;
+        msg.disable_link_preview = formatting.disable_link_preview;
+        msg
+    }
+
+    async fn send_template(
+        &self,
+        chat_id: ChatId,
+        key: TemplateKey,
+        language: Option<&str>,
+    ) -> anyhow::Result<()> {
+        self.send(self.template_message(chat_id, key, language, &[]))
+            .await?;
+        Ok(())
+    }
+
+    /// Sends a message, flagging users who can never receive one, such as those who blocked the
+    /// bot, so that reminders and the like skip them until they write again.
+    async fn send(&self, msg: OutgoingMessage) -> anyhow::Result<MessageId> {
+        let chat_id = msg.chat_id;
+        let result = self.telegram.send_message(msg).await;
+        self.flag_if_undeliverable(chat_id, &result)?;
+        result
+    }
+
+    fn flag_if_undeliverable(
+        &self,
+        chat_id: ChatId,
+        result: &anyhow::Result<MessageId>,
+    ) -> anyhow::Result<()> {
+        if let Err(err) = result
+            && let Some(DeliveryError::Undeliverable(reason)) = err.downcast_ref()
+            && chat_id.is_user()
+            && self.store.flag_undeliverable(chat_id.0)?
+        {
+            eprintln!("user {chat_id} cannot be messaged anymore: {reason}");
+        }
+        Ok(())
+    }
+
+    /// Sends a message to every configured admin, logging rather than failing on delivery errors.
+    async fn notify_admins(&self, text: &str) {
+        for admin_id in &self.global.admin_ids {
+            let msg = OutgoingMessage::new(ChatId(*admin_id), text);
+            if let Err(err) = self.send(msg).await {
+                eprintln!("failed to notify admin {admin_id}: {err:?}");
+            }
+        }
+    }
+}
+
+/// The button that puts the user on the waitlist.
+fn waitlist_button() -> InlineKeyboardMarkup {
+    InlineKeyboardMarkup::new([[InlineKeyboardButton::callback(
+        MSG_JOIN_WAITLIST_BUTTON,
+        "waitlist:join",
+    )]])
+}
+
+/// The key under which a phone number is stored: an hmac of its digits, so that the store never
+/// holds the number itself and the same number always matches however it was formatted.
+fn phone_hash(salt: &str, phone_number: &str) -> String {
+    let digits: String = phone_number.chars().filter(char::is_ascii_digit).collect();
+    let key = hmac::Key::new(hmac::HMAC_SHA256, salt.as_bytes());
+    hmac::sign(&key, digits.as_bytes())
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The user's id as the store keeps it.
+fn user_id(user: &User) -> anyhow::Result<i64> {
+    user.id
+        .0
+        .try_into()
+        .context("user id does not fit into i64")
+}
@@ -0,0 +1,2427 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    path::Path,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use serde_json::{Value, json};
+use teloxide::types::{CallbackQuery, ChatId, Message, MessageId, UserId};
+
+use super::{BotService, Clock};
+use crate::{
+    BoxFuture,
+    alerts::BackendAlerts,
+    audit::AuditLog,
+    config::{AuditConfig, Config, MaintenanceTask, ScheduledAction, test_config},
+    giftcard::{GiftcardError, GiftcardProvider},
+    messages::MSG_SET_USAGE,
+    reporting::ErrorReporter,
+    store::{ConversationState, DailyStats, IssuedCard, PendingReview, Storage, WaitlistEntry},
+    telegram::{DeliveryError, Outbox, OutgoingMessage, TelegramApi},
+    webhooks::Webhooks,
+    workers::WorkerPool,
+};
+
+const ADMIN_ID: u64 = 42;
+const USER_ID: u64 = 1000;
+const GROUP_ID: i64 = -100123;
+const NOW: u64 = 1_700_000_000;
+const CODE: &str = "GIFT-ABCD-1234";
+
+#[derive(Default)]
+struct MockTelegram {
+    sent: Mutex<Vec<OutgoingMessage>>,
+    edits: Mutex<Vec<(ChatId, MessageId, String)>>,
+    answered: Mutex<Vec<String>>,
+    deleted: Mutex<Vec<(ChatId, MessageId)>>,
+    /// membership of users in the group; users not listed make the check fail
+    members: Mutex<BTreeMap<u64, bool>>,
+    /// the groups whose membership was checked
+    checked_groups: Mutex<Vec<ChatId>>,
+    member_count: AtomicU64,
+    /// errors the next messages fail with, before any are sent
+    send_failures: Mutex<VecDeque<DeliveryError>>,
+    /// how long each message takes to send
+    send_delay: Mutex<Duration>,
+    photos: Mutex<Vec<(ChatId, Vec<u8>)>>,
+}
+
+impl MockTelegram {
+    fn sent(&self) -> Vec<OutgoingMessage> {
+        self.sent.lock().unwrap().clone()
+    }
+
+    fn texts_to(&self, chat_id: i64) -> Vec<String> {
+        self.sent()
+            .into_iter()
+            .filter(|msg| msg.chat_id == ChatId(chat_id))
+            .map(|msg| msg.text)
+            .collect()
+    }
+}
+
+impl TelegramApi for MockTelegram {
+    fn send_message(&self, msg: OutgoingMessage) -> BoxFuture<'_, anyhow::Result<MessageId>> {
+        if let Some(err) = self.send_failures.lock().unwrap().pop_front() {
+            return Box::pin(async move { Err(err.into()) });
+        }
+        let mut sent = self.sent.lock().unwrap();
+        sent.push(msg);
+        let id = MessageId(sent.len() as i32);
+        let delay = *self.send_delay.lock().unwrap();
+        Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            Ok(id)
+        })
+    }
+
+    fn send_photo(
+        &self,
+        chat_id: ChatId,
+        png: Vec<u8>,
+    ) -> BoxFuture<'_, anyhow::Result<MessageId>> {
+        self.photos.lock().unwrap().push((chat_id, png));
+        Box::pin(async move { Ok(MessageId(0)) })
+    }
+
+    fn is_group_member(
+        &self,
+        group_id: ChatId,
+        user_id: UserId,
+    ) -> BoxFuture<'_, anyhow::Result<bool>> {
+        self.checked_groups.lock().unwrap().push(group_id);
+        let member = self.members.lock().unwrap().get(&user_id.0).copied();
+        Box::pin(async move { member.ok_or_else(|| anyhow::anyhow!("getChatMember failed")) })
+    }
+
+    fn member_count(&self, _group_id: ChatId) -> BoxFuture<'_, anyhow::Result<u64>> {
+        let count = self.member_count.load(Ordering::SeqCst);
+        Box::pin(async move { Ok(count) })
+    }
+
+    fn answer_callback_query(&self, query_id: String) -> BoxFuture<'_, anyhow::Result<()>> {
+        self.answered.lock().unwrap().push(query_id);
+        Box::pin(async { Ok(()) })
+    }
+
+    fn edit_message_text(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+    ) -> BoxFuture<'_, anyhow::Result<()>> {
+        self.edits.lock().unwrap().push((chat_id, message_id, text));
+        Box::pin(async { Ok(()) })
+    }
+
+    fn delete_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, anyhow::Result<()>> {
+        self.deleted.lock().unwrap().push((chat_id, message_id));
+        Box::pin(async { Ok(()) })
+    }
+}
+
+#[derive(Default)]
+struct MockGiftcards {
+    fail: AtomicBool,
+    requested_days: Mutex<Vec<u32>>,
+    /// codes the app has redeemed
+    used: Mutex<BTreeSet<String>>,
+    cancelled: Mutex<Vec<String>>,
+    /// how long creating giftcards takes
+    delay: Mutex<Duration>,
+}
+
+impl GiftcardProvider for MockGiftcards {
+    fn create_giftcards(
+        &self,
+        days: u32,
+        count: u32,
+    ) -> BoxFuture<'_, anyhow::Result<Vec<String>>> {
+        self.requested_days.lock().unwrap().push(days);
+        let fail = self.fail.load(Ordering::SeqCst);
+        let delay = *self.delay.lock().unwrap();
+        Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            if fail {
+                return Err(GiftcardError::Status {
+                    status: 503,
+                    message: "backend unavailable".to_owned(),
+                }
+                .into());
+            }
+            // the first code of a bundle is CODE, so that tests of single cards see it too
+            Ok((0..count)
+                .map(|n| match n {
+                    0 => CODE.to_owned(),
+                    n => format!("{CODE}-{n}"),
+                })
+                .collect())
+        })
+    }
+
+    fn is_used<'a>(&'a self, code: &'a str) -> BoxFuture<'a, anyhow::Result<bool>> {
+        let used = self.used.lock().unwrap().contains(code);
+        Box::pin(async move { Ok(used) })
+    }
+
+    fn cancel_giftcard<'a>(&'a self, code: &'a str) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.cancelled.lock().unwrap().push(code.to_owned());
+        Box::pin(async { Ok(()) })
+    }
+}
+
+struct FixedClock;
+
+impl Clock for FixedClock {
+    fn unix_now(&self) -> u64 {
+        NOW
+    }
+}
+
+#[derive(Default)]
+struct MemoryStorage {
+    redemptions: Mutex<BTreeMap<i64, u64>>,
+    pending_reviews: Mutex<BTreeMap<i64, PendingReview>>,
+    banned_users: Mutex<BTreeSet<i64>>,
+    conversations: Mutex<BTreeMap<i64, ConversationState>>,
+    cards: Mutex<BTreeMap<String, IssuedCard>>,
+    phones: Mutex<BTreeMap<String, i64>>,
+    archived_users: Mutex<BTreeSet<i64>>,
+    left_group: Mutex<BTreeSet<i64>>,
+    repeat_attempts: Mutex<BTreeMap<i64, u32>>,
+    milestones: Mutex<BTreeSet<u64>>,
+    settings: Mutex<BTreeMap<String, String>>,
+    languages: Mutex<BTreeMap<i64, String>>,
+    waitlist: Mutex<BTreeMap<i64, WaitlistEntry>>,
+    daily_stats: Mutex<BTreeMap<u64, DailyStats>>,
+    undeliverable: Mutex<BTreeSet<i64>>,
+}
+
+impl Storage for MemoryStorage {
+    fn is_redeemed(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self.redemptions.lock().unwrap().contains_key(&user_id)
+            || self.archived_users.lock().unwrap().contains(&user_id))
+    }
+
+    fn record_redemption(&self, user_id: i64, redeemed_at: u64) -> anyhow::Result<()> {
+        self.redemptions
+            .lock()
+            .unwrap()
+            .insert(user_id, redeemed_at);
+        Ok(())
+    }
+
+    fn redemption_count(&self) -> anyhow::Result<usize> {
+        Ok(self.redemptions.lock().unwrap().len() + self.archived_users.lock().unwrap().len())
+    }
+
+    fn redemptions(&self) -> anyhow::Result<Vec<(i64, u64)>> {
+        Ok(self
+            .redemptions
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(user_id, redeemed_at)| (*user_id, *redeemed_at))
+            .collect())
+    }
+
+    fn redemptions_since(&self, since: u64) -> anyhow::Result<usize> {
+        Ok(self
+            .redemptions
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|redeemed_at| **redeemed_at >= since)
+            .count())
+    }
+
+    fn archive_redemptions(&self, user_ids: &[i64]) -> anyhow::Result<()> {
+        let mut redemptions = self.redemptions.lock().unwrap();
+        for user_id in user_ids {
+            if redemptions.remove(user_id).is_some() {
+                self.archived_users.lock().unwrap().insert(*user_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn has_pending_review(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self.pending_reviews.lock().unwrap().contains_key(&user_id))
+    }
+
+    fn add_pending_review(&self, user_id: i64, review: PendingReview) -> anyhow::Result<()> {
+        self.pending_reviews.lock().unwrap().insert(user_id, review);
+        Ok(())
+    }
+
+    fn take_pending_review(&self, user_id: i64) -> anyhow::Result<Option<PendingReview>> {
+        Ok(self.pending_reviews.lock().unwrap().remove(&user_id))
+    }
+
+    fn pending_reviews(&self) -> anyhow::Result<Vec<(i64, PendingReview)>> {
+        Ok(self
+            .pending_reviews
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(user_id, review)| (*user_id, review.clone()))
+            .collect())
+    }
+
+    fn is_banned(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self.banned_users.lock().unwrap().contains(&user_id))
+    }
+
+    fn ban(&self, user_id: i64) -> anyhow::Result<()> {
+        self.banned_users.lock().unwrap().insert(user_id);
+        Ok(())
+    }
+
+    fn unban(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self.banned_users.lock().unwrap().remove(&user_id))
+    }
+
+    fn banned_users(&self) -> anyhow::Result<Vec<i64>> {
+        Ok(self.banned_users.lock().unwrap().iter().copied().collect())
+    }
+
+    fn conversation(&self, user_id: i64) -> anyhow::Result<Option<ConversationState>> {
+        Ok(self.conversations.lock().unwrap().get(&user_id).cloned())
+    }
+
+    fn set_conversation(&self, user_id: i64, state: ConversationState) -> anyhow::Result<()> {
+        self.conversations.lock().unwrap().insert(user_id, state);
+        Ok(())
+    }
+
+    fn clear_conversation(&self, user_id: i64) -> anyhow::Result<()> {
+        self.conversations.lock().unwrap().remove(&user_id);
+        Ok(())
+    }
+
+    fn record_card(&self, card: IssuedCard) -> anyhow::Result<()> {
+        self.cards.lock().unwrap().insert(card.code.clone(), card);
+        Ok(())
+    }
+
+    fn cards(&self) -> anyhow::Result<Vec<IssuedCard>> {
+        let mut cards: Vec<IssuedCard> = self.cards.lock().unwrap().values().cloned().collect();
+        cards.sort_by_key(|card| card.issued_at);
+        Ok(cards)
+    }
+
+    fn last_card_issued_at(&self, user_id: i64) -> anyhow::Result<Option<u64>> {
+        Ok(self
+            .cards
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|card| card.user_id == user_id)
+            .map(|card| card.issued_at)
+            .max())
+    }
+
+    fn update_card(&self, card: IssuedCard) -> anyhow::Result<()> {
+        self.record_card(card)
+    }
+
+    fn phone_owner(&self, phone_hash: &str) -> anyhow::Result<Option<i64>> {
+        Ok(self.phones.lock().unwrap().get(phone_hash).copied())
+    }
+
+    fn has_phone(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self
+            .phones
+            .lock()
+            .unwrap()
+            .values()
+            .any(|owner| *owner == user_id))
+    }
+
+    fn record_phone(&self, phone_hash: &str, user_id: i64) -> anyhow::Result<()> {
+        self.phones
+            .lock()
+            .unwrap()
+            .insert(phone_hash.to_owned(), user_id);
+        Ok(())
+    }
+
+    fn record_repeat_attempt(&self, user_id: i64) -> anyhow::Result<u32> {
+        let mut repeat_attempts = self.repeat_attempts.lock().unwrap();
+        let attempts = repeat_attempts.entry(user_id).or_default();
+        *attempts += 1;
+        Ok(*attempts)
+    }
+
+    fn repeat_attempts(&self) -> anyhow::Result<Vec<(i64, u32)>> {
+        let mut attempts: Vec<(i64, u32)> = self
+            .repeat_attempts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(user_id, attempts)| (*user_id, *attempts))
+            .collect();
+        attempts.sort_by_key(|(_, attempts)| std::cmp::Reverse(*attempts));
+        Ok(attempts)
+    }
+
+    fn last_milestone(&self) -> anyhow::Result<Option<u64>> {
+        Ok(self.milestones.lock().unwrap().last().copied())
+    }
+
+    fn record_milestone(&self, milestone: u64) -> anyhow::Result<()> {
+        self.milestones.lock().unwrap().insert(milestone);
+        Ok(())
+    }
+
+    fn flag_undeliverable(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self.undeliverable.lock().unwrap().insert(user_id))
+    }
+
+    fn unflag_undeliverable(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self.undeliverable.lock().unwrap().remove(&user_id))
+    }
+
+    fn undeliverable_users(&self) -> anyhow::Result<Vec<i64>> {
+        Ok(self.undeliverable.lock().unwrap().iter().copied().collect())
+    }
+
+    fn setting(&self, name: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.settings.lock().unwrap().get(name).cloned())
+    }
+
+    fn set_setting(&self, name: &str, value: &str) -> anyhow::Result<()> {
+        self.settings
+            .lock()
+            .unwrap()
+            .insert(name.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn language(&self, user_id: i64) -> anyhow::Result<Option<String>> {
+        Ok(self.languages.lock().unwrap().get(&user_id).cloned())
+    }
+
+    fn set_language(&self, user_id: i64, language: &str) -> anyhow::Result<()> {
+        self.languages
+            .lock()
+            .unwrap()
+            .insert(user_id, language.to_owned());
+        Ok(())
+    }
+
+    fn join_waitlist(&self, user_id: i64, entry: WaitlistEntry) -> anyhow::Result<()> {
+        self.waitlist
+            .lock()
+            .unwrap()
+            .entry(user_id)
+            .or_insert(entry);
+        Ok(())
+    }
+
+    fn waitlist(&self) -> anyhow::Result<Vec<(i64, WaitlistEntry)>> {
+        let mut waitlist: Vec<_> = self
+            .waitlist
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(user_id, entry)| (*user_id, entry.clone()))
+            .collect();
+        waitlist.sort_by_key(|(user_id, entry)| (entry.joined_at, *user_id));
+        Ok(waitlist)
+    }
+
+    fn leave_waitlist(&self, user_id: i64) -> anyhow::Result<()> {
+        self.waitlist.lock().unwrap().remove(&user_id);
+        Ok(())
+    }
+
+    fn add_daily_stats(&self, day: u64, stats: DailyStats) -> anyhow::Result<()> {
+        self.daily_stats
+            .lock()
+            .unwrap()
+            .entry(day)
+            .or_default()
+            .add(stats);
+        Ok(())
+    }
+
+    fn daily_stats(&self, first_day: u64) -> anyhow::Result<Vec<(u64, DailyStats)>> {
+        Ok(self
+            .daily_stats
+            .lock()
+            .unwrap()
+            .range(first_day..)
+            .map(|(day, stats)| (*day, *stats))
+            .collect())
+    }
+
+    fn flag_left_group(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self.left_group.lock().unwrap().insert(user_id))
+    }
+
+    fn left_group_users(&self) -> anyhow::Result<Vec<i64>> {
+        Ok(self.left_group.lock().unwrap().iter().copied().collect())
+    }
+
+    fn reset_user(&self, user_id: i64) -> anyhow::Result<bool> {
+        self.conversations.lock().unwrap().remove(&user_id);
+        self.left_group.lock().unwrap().remove(&user_id);
+        self.repeat_attempts.lock().unwrap().remove(&user_id);
+        self.phones
+            .lock()
+            .unwrap()
+            .retain(|_, owner| *owner != user_id);
+        let archived = self.archived_users.lock().unwrap().remove(&user_id);
+        let redeemed = self.redemptions.lock().unwrap().remove(&user_id).is_some() || archived;
+        let pending = self
+            .pending_reviews
+            .lock()
+            .unwrap()
+            .remove(&user_id)
+            .is_some();
+        let banned = self.banned_users.lock().unwrap().remove(&user_id);
+        Ok(redeemed || pending || banned)
+    }
+
+    fn snapshot(&self, path: &Path) -> anyhow::Result<()> {
+        let redemptions = serde_json::to_vec(&*self.redemptions.lock().unwrap())?;
+        Ok(std::fs::write(path, redemptions)?)
+    }
+}
+
+#[test]
+fn memory_storage_behaves_like_the_real_backends() {
+    crate::store::check_storage(&MemoryStorage::default());
+}
+
+struct Harness {
+    service: BotService,
+    telegram: Arc<MockTelegram>,
+    giftcards: Arc<MockGiftcards>,
+}
+
+impl Harness {
+    fn new() -> Self {
+        Self::with_config("")
+    }
+
+    /// Builds a service whose config is the defaults below plus the given yaml lines.
+    fn with_config(extra_yaml: &str) -> Self {
+        let global = test_config(
+            "unused.json",
+            &format!("admin_ids: [{ADMIN_ID}]\n{extra_yaml}"),
+        );
+        let telegram = Arc::new(MockTelegram::default());
+        let giftcards = Arc::new(MockGiftcards::default());
+        let service = BotService {
+            config: global.bot.clone().unwrap(),
+            telegram: Arc::new(Outbox::new(telegram.clone(), &global.delivery)),
+            giftcards: giftcards.clone(),
+            clock: Arc::new(FixedClock),
+            store: Box::new(MemoryStorage::default()),
+            audit: Arc::new(AuditLog::open(None).unwrap()),
+            reporter: Arc::new(ErrorReporter::new(&global).unwrap()),
+            backend_alerts: Arc::new(BackendAlerts::new(&global.backend_alert)),
+            webhooks: Arc::new(Webhooks::new(&global.webhooks).unwrap()),
+            global: Arc::new(global),
+            last_greeting: AtomicU64::new(0),
+            timed_out_updates: AtomicU64::new(0),
+            workers: Arc::new(WorkerPool::new(2, 8)),
+        };
+        Self {
+            service,
+            telegram,
+            giftcards,
+        }
+    }
+
+    fn set_member(&self, user_id: u64, member: bool) {
+        self.telegram
+            .members
+            .lock()
+            .unwrap()
+            .insert(user_id, member);
+    }
+}
+
+fn user(id: u64, username: Option<&str>) -> Value {
+    json!({
+        "id": id,
+        "is_bot": false,
+        "first_name": "Test",
+        "username": username,
+    })
+}
+
+fn private_message(from: Value, text: Option<&str>) -> Message {
+    let mut msg = json!({
+        "message_id": 7,
+        "date": NOW,
+        "chat": { "id": from["id"], "type": "private", "first_name": "Test" },
+        "from": from,
+    });
+    match text {
+        Some(text) => msg["text"] = json!(text),
+        None => msg["sticker"] = sticker(),
+    }
+    serde_json::from_value(msg).unwrap()
+}
+
+fn group_message(text: &str, thread_id: Option<i32>) -> Message {
+    let mut msg = json!({
+        "message_id": 99,
+        "date": NOW,
+        "chat": { "id": GROUP_ID, "type": "supergroup", "title": "Geph", "is_forum": thread_id.is_some() },
+        "from": user(USER_ID, Some("alice")),
+        "text": text,
+    });
+    if let Some(thread_id) = thread_id {
+        msg["message_thread_id"] = json!(thread_id);
+        msg["is_topic_message"] = json!(true);
+    }
+    serde_json::from_value(msg).unwrap()
+}
+
+fn join_message(members: Vec<Value>) -> Message {
+    serde_json::from_value(json!({
+        "message_id": 100,
+        "date": NOW,
+        "chat": { "id": GROUP_ID, "type": "supergroup", "title": "Geph" },
+        "from": members[0],
+        "new_chat_members": members,
+    }))
+    .unwrap()
+}
+
+fn sticker() -> Value {
+    json!({
+        "file_id": "f",
+        "file_unique_id": "u",
+        "type": "regular",
+        "width": 512,
+        "height": 512,
+        "is_animated": false,
+        "is_video": false,
+    })
+}
+
+fn callback(from: Value, data: &str) -> CallbackQuery {
+    serde_json::from_value(json!({
+        "id": "query",
+        "from": from,
+        "chat_instance": "instance",
+        "data": data,
+        "message": {
+            "message_id": 5,
+            "date": NOW,
+            "chat": { "id": ADMIN_ID, "type": "private", "first_name": "Admin" },
+            "text": "🔎 Manual review requested",
+        },
+    }))
+    .unwrap()
+}
+
+fn admin() -> Value {
+    user(ADMIN_ID, Some("admin"))
+}
+
+fn alice() -> Value {
+    user(USER_ID, Some("alice"))
+}
+
+#[tokio::test]
+async fn issues_giftcard_to_group_member() {
+    let h = Harness::new();
+    h.set_member(USER_ID, true);
+
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+
+    let texts = h.telegram.texts_to(USER_ID as i64);
+    assert_eq!(texts.len(), 3);
+    assert!(texts[0].contains("3-day"));
+    assert_eq!(texts[1], CODE);
+    assert_eq!(*h.giftcards.requested_days.lock().unwrap(), vec![3]);
+    assert!(h.service.store.is_redeemed(USER_ID as i64).unwrap());
+}
+
+#[tokio::test]
+async fn audit_log_has_the_codes_of_cards_that_failed_to_deliver() {
+    let h = Harness::new();
+    h.set_member(USER_ID, true);
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("audit.jsonl");
+    let config = AuditConfig {
+        path: path.clone(),
+        max_bytes: u64::MAX,
+        rotate_daily: false,
+    };
+    let h = Harness {
+        service: BotService {
+            audit: Arc::new(AuditLog::open(Some(&config)).unwrap()),
+            ..h.service
+        },
+        ..h
+    };
+    h.telegram
+        .send_failures
+        .lock()
+        .unwrap()
+        .push_back(DeliveryError::Undeliverable("bot was blocked".to_owned()));
+
+    assert!(
+        h.service
+            .handle_message(private_message(alice(), Some("hi")))
+            .await
+            .is_err()
+    );
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    let entry: Value = serde_json::from_str(log.trim()).unwrap();
+    assert_eq!(entry["code_suffix"], "1234");
+    assert!(entry["error"].as_str().unwrap().contains("bot was blocked"));
+}
+
+#[tokio::test]
+async fn records_redemption_time_from_clock() {
+    let h = Harness::new();
+    h.set_member(USER_ID, true);
+
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        h.service.store.redemptions().unwrap(),
+        vec![(USER_ID as i64, NOW)]
+    );
+}
+
+#[tokio::test]
+async fn refuses_second_giftcard() {
+    let h = Harness::new();
+    h.set_member(USER_ID, true);
+    h.service
+        .store
+        .record_redemption(USER_ID as i64, NOW)
+        .unwrap();
+
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64),
+        vec![h.service.config.templates.already_redeemed.clone()]
+    );
+    assert!(h.giftcards.requested_days.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn asks_non_members_to_join_group() {
+    let h = Harness::new();
+    h.set_member(USER_ID, false);
+
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64),
+        vec![
+            h.service
+                .config
+                .templates
+                .join_group
+                .replace("{link}", "https://t.me/gephusers")
+        ]
+    );
+    assert!(!h.service.store.is_redeemed(USER_ID as i64).unwrap());
+}
+
+#[tokio::test]
+async fn reports_failed_membership_check() {
+    let h = Harness::new();
+
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64),
+        vec![h.service.config.templates.membership_check_failed.clone()]
+    );
+}
+
+#[tokio::test]
+async fn refuses_banned_users() {
+    let h = Harness::new();
+    h.set_member(USER_ID, true);
+    h.service.store.ban(USER_ID as i64).unwrap();
+
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64),
+        vec![h.service.config.templates.refused.clone()]
+    );
+}
+
+#[tokio::test]
+async fn asks_for_text_on_media() {
+    let h = Harness::new();
+
+    h.service
+        .handle_message(private_message(alice(), None))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64),
+        vec![h.service.config.templates.send_text.clone()]
+    );
+}
+
+#[tokio::test]
+async fn backend_failure_does_not_mark_redeemed() {
+    let h = Harness::new();
+    h.set_member(USER_ID, true);
+    h.giftcards.fail.store(true, Ordering::SeqCst);
+
+    let result = h
+        .service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await;
+
+    assert!(result.is_err());
+    assert!(!h.service.store.is_redeemed(USER_ID as i64).unwrap());
+    assert!(h.telegram.texts_to(USER_ID as i64).is_empty());
+}
+
+#[tokio::test]
+async fn users_capped_out_can_wait_for_their_giftcard() {
+    let h = Harness::with_config("eligibility: [blacklist, {daily_cap: {max: 1}}]\nwaitlist: {}");
+    h.service.store.record_redemption(1, NOW).unwrap();
+    let bob = user(USER_ID + 1, Some("bob"));
+
+    for user in [alice(), bob.clone()] {
+        h.service
+            .handle_message(private_message(user, Some("hi")))
+            .await
+            .unwrap();
+    }
+    let offer = &h.telegram.sent()[0];
+    assert_eq!(offer.text, h.service.config.templates.daily_cap_reached);
+    let keyboard = serde_json::to_value(offer.keyboard.as_ref().unwrap()).unwrap();
+    assert_eq!(
+        keyboard["inline_keyboard"][0][0]["callback_data"],
+        "waitlist:join"
+    );
+    // tapping again keeps alice's place
+    for user in [alice(), bob, alice()] {
+        h.service
+            .handle_callback(callback(user, "waitlist:join"))
+            .await
+            .unwrap();
+    }
+    assert!(
+        h.telegram.texts_to(USER_ID as i64 + 1)[1]
+            .starts_with("✅ You are number 2 on the waitlist")
+    );
+
+    let process = ScheduledAction::Maintenance(MaintenanceTask::ProcessWaitlist);
+    h.service.run_scheduled(&process).await.unwrap();
+    assert_eq!(h.service.store.waitlist().unwrap().len(), 2);
+
+    // a new day, with room for one more card
+    h.service.store.reset_user(1).unwrap();
+    h.service.run_scheduled(&process).await.unwrap();
+
+    assert!(h.service.store.is_redeemed(USER_ID as i64).unwrap());
+    assert!(!h.service.store.is_redeemed(USER_ID as i64 + 1).unwrap());
+    let waiting: Vec<i64> = h
+        .service
+        .store
+        .waitlist()
+        .unwrap()
+        .into_iter()
+        .map(|(user_id, _)| user_id)
+        .collect();
+    assert_eq!(waiting, vec![USER_ID as i64 + 1]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn waitlisted_users_writing_while_served_get_one_card() {
+    let h = Harness::with_config("waitlist: {}");
+    h.set_member(USER_ID, true);
+    h.giftcards.fail.store(true, Ordering::SeqCst);
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+    h.service
+        .handle_callback(callback(alice(), "waitlist:join"))
+        .await
+        .unwrap();
+    h.giftcards.fail.store(false, Ordering::SeqCst);
+    *h.giftcards.delay.lock().unwrap() = Duration::from_secs(1);
+    h.giftcards.requested_days.lock().unwrap().clear();
+
+    // handled in the user's turn, as the dispatcher does
+    let message = async {
+        let _turn = h.service.workers.turn(USER_ID).await;
+        h.service
+            .handle_message(private_message(alice(), Some("hi")))
+            .await
+    };
+    let process = ScheduledAction::Maintenance(MaintenanceTask::ProcessWaitlist);
+    let (handled, processed) = tokio::join!(message, h.service.run_scheduled(&process));
+    handled.unwrap();
+    processed.unwrap();
+
+    assert_eq!(h.giftcards.requested_days.lock().unwrap().len(), 1);
+    assert!(h.service.store.waitlist().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn waitlisted_users_keep_their_place_while_the_backend_fails() {
+    let h = Harness::with_config("waitlist: {}");
+    h.set_member(USER_ID, true);
+    h.giftcards.fail.store(true, Ordering::SeqCst);
+
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+    h.service
+        .handle_callback(callback(alice(), "waitlist:join"))
+        .await
+        .unwrap();
+    let process = ScheduledAction::Maintenance(MaintenanceTask::ProcessWaitlist);
+    h.service.run_scheduled(&process).await.unwrap();
+
+    assert_eq!(h.service.store.waitlist().unwrap().len(), 1);
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64)[0],
+        h.service.config.templates.waitlist_offer
+    );
+
+    h.giftcards.fail.store(false, Ordering::SeqCst);
+    h.service.run_scheduled(&process).await.unwrap();
+
+    assert!(h.service.store.waitlist().unwrap().is_empty());
+    assert!(h.service.store.is_redeemed(USER_ID as i64).unwrap());
+    assert!(
+        h.telegram
+            .texts_to(USER_ID as i64)
+            .contains(&CODE.to_owned())
+    );
+}
+
+#[tokio::test]
+async fn flagged_users_go_to_review() {
+    let h = Harness::with_config("fraud:\n  flag_no_username: true");
+    h.set_member(USER_ID, true);
+
+    h.service
+        .handle_message(private_message(user(USER_ID, None), Some("hi")))
+        .await
+        .unwrap();
+
+    let to_admin = h
+        .telegram
+        .sent()
+        .into_iter()
+        .find(|msg| msg.chat_id == ChatId(ADMIN_ID as i64))
+        .unwrap();
+    assert!(to_admin.text.contains("no username"));
+    assert!(to_admin.keyboard.is_some());
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64),
+        vec![h.service.config.templates.review_pending.clone()]
+    );
+    assert!(h.service.store.has_pending_review(USER_ID as i64).unwrap());
+    assert!(h.giftcards.requested_days.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn pending_users_are_told_to_wait() {
+    let h = Harness::new();
+    h.service
+        .store
+        .add_pending_review(
+            USER_ID as i64,
+            PendingReview {
+                chat_id: USER_ID as i64,
+                reasons: vec![],
+                requested_at: NOW,
+            },
+        )
+        .unwrap();
+
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64),
+        vec![h.service.config.templates.review_pending.clone()]
+    );
+}
+
+#[tokio::test]
+async fn flagged_users_are_refused_without_admins_to_review() {
+    let h = Harness::with_config("fraud:\n  flag_no_username: true");
+    let service = BotService {
+        global: Arc::new(Config {
+            admin_ids: vec![],
+            ..(*h.service.global).clone()
+        }),
+        ..h.service
+    };
+    h.telegram.members.lock().unwrap().insert(USER_ID, true);
+
+    service
+        .handle_message(private_message(user(USER_ID, None), Some("hi")))
+        .await
+        .unwrap();
+
+    assert!(!service.store.is_redeemed(USER_ID as i64).unwrap());
+    assert!(h.giftcards.requested_days.lock().unwrap().is_empty());
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64),
+        vec![service.config.templates.refused.clone()]
+    );
+}
+
+#[tokio::test]
+async fn admin_approves_review() {
+    let h = Harness::new();
+    h.service
+        .store
+        .add_pending_review(
+            USER_ID as i64,
+            PendingReview {
+                chat_id: USER_ID as i64,
+                reasons: vec!["no username".to_owned()],
+                requested_at: NOW,
+            },
+        )
+        .unwrap();
+
+    h.service
+        .handle_callback(callback(admin(), &format!("approve:{USER_ID}")))
+        .await
+        .unwrap();
+
+    assert!(h.service.store.is_redeemed(USER_ID as i64).unwrap());
+    assert!(!h.service.store.has_pending_review(USER_ID as i64).unwrap());
+    assert_eq!(h.telegram.texts_to(USER_ID as i64)[1], CODE);
+    let edits = h.telegram.edits.lock().unwrap();
+    assert!(edits[0].2.ends_with("✅ approved"));
+}
+
+#[tokio::test]
+async fn approvals_that_created_a_card_are_not_approved_again() {
+    let h = Harness::new();
+    let review = PendingReview {
+        chat_id: USER_ID as i64,
+        reasons: vec![],
+        requested_at: NOW,
+    };
+    h.service
+        .store
+        .add_pending_review(USER_ID as i64, review.clone())
+        .unwrap();
+    h.giftcards.fail.store(true, Ordering::SeqCst);
+
+    assert!(
+        h.service
+            .resolve_review(USER_ID as i64, true)
+            .await
+            .is_err()
+    );
+    // the backend failed, so nothing was issued and the review is back for another try
+    assert!(h.service.store.has_pending_review(USER_ID as i64).unwrap());
+
+    h.giftcards.fail.store(false, Ordering::SeqCst);
+    h.telegram
+        .send_failures
+        .lock()
+        .unwrap()
+        .push_back(DeliveryError::Undeliverable("bot was blocked".to_owned()));
+    assert!(
+        h.service
+            .resolve_review(USER_ID as i64, true)
+            .await
+            .is_err()
+    );
+    // the card was created before the user turned out to be unreachable
+    assert!(h.service.store.is_redeemed(USER_ID as i64).unwrap());
+    assert!(!h.service.store.has_pending_review(USER_ID as i64).unwrap());
+    assert!(
+        !h.service
+            .resolve_review(USER_ID as i64, true)
+            .await
+            .unwrap()
+    );
+    assert_eq!(h.service.store.cards().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn admin_rejects_review() {
+    let h = Harness::new();
+    h.service
+        .store
+        .add_pending_review(
+            USER_ID as i64,
+            PendingReview {
+                chat_id: USER_ID as i64,
+                reasons: vec![],
+                requested_at: NOW,
+            },
+        )
+        .unwrap();
+
+    h.service
+        .handle_callback(callback(admin(), &format!("reject:{USER_ID}")))
+        .await
+        .unwrap();
+    // rejecting bans the user, so it waits for the admin to confirm
+    assert!(h.service.store.has_pending_review(USER_ID as i64).unwrap());
+    assert!(h.telegram.texts_to(ADMIN_ID as i64)[0].contains(&format!("run #Reject {USER_ID}")));
+    h.service
+        .handle_message(private_message(admin(), Some("CONFIRM")))
+        .await
+        .unwrap();
+
+    assert!(h.service.store.is_banned(USER_ID as i64).unwrap());
+    assert_eq!(
+        h.telegram.texts_to(ADMIN_ID as i64)[1],
+        format!("❌ Rejected user {USER_ID}, who is now banned")
+    );
+    assert!(!h.service.store.is_redeemed(USER_ID as i64).unwrap());
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64),
+        vec![h.service.config.templates.refused.clone()]
+    );
+}
+
+#[tokio::test]
+async fn ignores_review_buttons_from_non_admins() {
+    let h = Harness::new();
+    h.service
+        .store
+        .add_pending_review(
+            USER_ID as i64,
+            PendingReview {
+                chat_id: USER_ID as i64,
+                reasons: vec![],
+                requested_at: NOW,
+            },
+        )
+        .unwrap();
+
+    h.service
+        .handle_callback(callback(alice(), &format!("approve:{USER_ID}")))
+        .await
+        .unwrap();
+
+    assert!(h.service.store.has_pending_review(USER_ID as i64).unwrap());
+    assert_eq!(*h.telegram.answered.lock().unwrap(), vec!["query"]);
+    assert!(h.telegram.sent().is_empty());
+}
+
+#[tokio::test]
+async fn admin_counts_recipients() {
+    let h = Harness::new();
+    h.service.store.record_redemption(1, NOW).unwrap();
+    h.service.store.record_redemption(2, NOW).unwrap();
+
+    h.service
+        .handle_message(private_message(admin(), Some("#RecipientCount")))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        h.telegram.texts_to(ADMIN_ID as i64),
+        vec!["🌸 2 users received giftcards!"]
+    );
+}
+
+fn card(user_id: i64, code: &str, issued_at: u64) -> IssuedCard {
+    IssuedCard {
+        user_id,
+        code: code.to_owned(),
+        issued_at,
+        used: false,
+        reminded: false,
+    }
+}
+
+#[tokio::test]
+async fn admin_counts_unused_cards() {
+    let h = Harness::new();
+    h.telegram.members.lock().unwrap().insert(USER_ID, true);
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+    h.service
+        .store
+        .record_card(card(2, "USED-CODE", NOW))
+        .unwrap();
+    h.giftcards
+        .used
+        .lock()
+        .unwrap()
+        .insert("USED-CODE".to_owned());
+
+    h.service
+        .handle_message(private_message(admin(), Some("#UnusedCards")))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        h.telegram.texts_to(ADMIN_ID as i64),
+        vec!["🃏 1 of 2 issued giftcards have not been redeemed yet"]
+    );
+    let cards = h.service.store.cards().unwrap();
+    assert_eq!(cards.len(), 2);
+    assert!(cards.iter().any(|card| card.code == CODE && !card.used));
+    assert!(
+        cards
+            .iter()
+            .any(|card| card.code == "USED-CODE" && card.used)
+    );
+}
+
+#[tokio::test]
+async fn reminds_unused_cards_once() {
+    let h = Harness::new();
+    let four_days_ago = NOW - 4 * 86400;
+    let store = &h.service.store;
+    store
+        .record_card(card(1, "UNUSED-OLD", four_days_ago))
+        .unwrap();
+    store
+        .record_card(card(2, "USED-OLD", four_days_ago))
+        .unwrap();
+    store
+        .record_card(card(3, "UNUSED-NEW", NOW - 86400))
+        .unwrap();
+    h.giftcards
+        .used
+        .lock()
+        .unwrap()
+        .insert("USED-OLD".to_owned());
+
+    let remind = ScheduledAction::Maintenance(MaintenanceTask::RemindUnusedCards);
+    h.service.run_scheduled(&remind).await.unwrap();
+    h.service.run_scheduled(&remind).await.unwrap();
+
+    let texts = h.telegram.texts_to(1);
+    assert_eq!(texts.len(), 3);
+    assert_eq!(texts[1], "UNUSED-OLD");
+    assert!(h.telegram.texts_to(2).is_empty());
+    assert!(h.telegram.texts_to(3).is_empty());
+    assert!(
+        store
+            .cards()
+            .unwrap()
+            .iter()
+            .any(|c| c.code == "USED-OLD" && c.used)
+    );
+}
+
+#[tokio::test]
+async fn archives_old_redemptions() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut h = Harness::with_config("archive: { after_days: 30 }");
+    h.service.config.store_path = dir.path().join("store.json").display().to_string();
+    let store = &h.service.store;
+    store.record_redemption(1, NOW - 40 * 86400).unwrap();
+    store.record_redemption(2, NOW - 86400).unwrap();
+
+    let archive = ScheduledAction::Maintenance(MaintenanceTask::ArchiveRedemptions);
+    h.service.run_scheduled(&archive).await.unwrap();
+    h.service.run_scheduled(&archive).await.unwrap();
+    for text in ["#Archived 1", "#Archived 2"] {
+        h.service
+            .handle_message(private_message(admin(), Some(text)))
+            .await
+            .unwrap();
+    }
+
+    let store = &h.service.store;
+    assert_eq!(store.redemptions().unwrap(), vec![(2, NOW - 86400)]);
+    assert_eq!(store.redemption_count().unwrap(), 2);
+    assert!(store.is_redeemed(1).unwrap());
+    assert_eq!(
+        h.telegram.texts_to(ADMIN_ID as i64),
+        vec![
+            "🗄️ User 1 redeemed on 2023-10-05, and was archived",
+            "ℹ️ User 2 is not in the archive",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn admins_get_a_chart_of_the_daily_statistics() {
+    let h = Harness::new();
+    h.set_member(USER_ID, true);
+    h.set_member(USER_ID + 1, true);
+    h.service
+        .store
+        .add_daily_stats(
+            NOW / 86400 - 3,
+            DailyStats {
+                issued: 5,
+                denied: 2,
+                errors: 0,
+            },
+        )
+        .unwrap();
+    // too long ago to be charted
+    h.service
+        .store
+        .add_daily_stats(NOW / 86400 - 7, DailyStats::default())
+        .unwrap();
+
+    for _ in 0..2 {
+        h.service
+            .handle_message(private_message(alice(), Some("hi")))
+            .await
+            .unwrap();
+    }
+    h.giftcards.fail.store(true, Ordering::SeqCst);
+    let failed = h
+        .service
+        .handle_message(private_message(user(USER_ID + 1, Some("bob")), Some("hi")))
+        .await;
+    assert!(failed.is_err());
+    assert_eq!(
+        h.service.store.daily_stats(NOW / 86400).unwrap(),
+        vec![(
+            NOW / 86400,
+            DailyStats {
+                issued: 1,
+                denied: 1,
+                errors: 1,
+            }
+        )]
+    );
+
+    for command in ["#Chart 7d", "#Chart 0d"] {
+        h.service
+            .handle_message(private_message(admin(), Some(command)))
+            .await
+            .unwrap();
+    }
+
+    let photos = h.telegram.photos.lock().unwrap();
+    assert_eq!(photos.len(), 1);
+    assert_eq!(photos[0].0, ChatId(ADMIN_ID as i64));
+    assert!(photos[0].1.starts_with(b"\x89PNG"));
+    let replies = h.telegram.texts_to(ADMIN_ID as i64);
+    assert!(replies[0].contains("🟩 issued: 6\n🟧 denied: 3\n🟥 errors: 1"));
+    assert!(replies[1].starts_with("⚠️ Usage: #Chart"));
+}
+
+#[tokio::test]
+async fn backs_up_the_store_keeping_the_newest_copies() {
+    let dir = tempfile::tempdir().unwrap();
+    let backups = dir.path().join("backups");
+    let mut h = Harness::with_config(&format!(
+        "backup: {{ directory: '{}', keep: 2 }}",
+        backups.display()
+    ));
+    h.service.config.store_path = dir.path().join("store.json").display().to_string();
+    std::fs::create_dir(&backups).unwrap();
+    for name in [
+        "GephGiftcardBot-20230101T000000Z.json",
+        "GephGiftcardBot-20230102T000000Z.json",
+        "OtherBot-20230101T000000Z.json",
+        "notes.txt",
+    ] {
+        std::fs::write(backups.join(name), "old").unwrap();
+    }
+    h.service.store.record_redemption(1, NOW).unwrap();
+
+    let backup = ScheduledAction::Maintenance(MaintenanceTask::BackupStore);
+    h.service.run_scheduled(&backup).await.unwrap();
+
+    let mut names: Vec<String> = std::fs::read_dir(&backups)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "GephGiftcardBot-20230102T000000Z.json",
+            "GephGiftcardBot-20231114T221320Z.json",
+            "OtherBot-20230101T000000Z.json",
+            "notes.txt",
+        ]
+    );
+    assert_eq!(
+        std::fs::read_to_string(backups.join("GephGiftcardBot-20231114T221320Z.json")).unwrap(),
+        r#"{"1":1700000000}"#
+    );
+    assert!(!dir.path().join("store.json.snapshot").exists());
+}
+
+#[tokio::test]
+async fn flags_recent_recipients_who_left_the_group() {
+    let h = Harness::new();
+    let store = &h.service.store;
+    // left soon after redeeming, stayed, left long after redeeming, unknown to telegram
+    for (user_id, days_ago) in [(1, 2), (2, 2), (3, 60), (4, 2)] {
+        store
+            .record_redemption(user_id, NOW - days_ago * 86400)
+            .unwrap();
+    }
+    h.set_member(1, false);
+    h.set_member(2, true);
+    h.set_member(3, false);
+
+    let recheck = ScheduledAction::Maintenance(MaintenanceTask::RecheckMembership);
+    h.service.run_scheduled(&recheck).await.unwrap();
+    h.service.run_scheduled(&recheck).await.unwrap();
+
+    assert_eq!(store.left_group_users().unwrap(), vec![1]);
+    assert_eq!(
+        h.telegram.texts_to(ADMIN_ID as i64),
+        vec!["👋 User 1 left the group 2 days after receiving a giftcard"]
+    );
+    assert!(h.giftcards.cancelled.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn cancels_codes_of_users_who_left_when_configured() {
+    let h = Harness::with_config("membership_recheck: { cancel_codes: true }");
+    let store = &h.service.store;
+    store.record_redemption(1, NOW - 86400).unwrap();
+    store.record_card(card(1, "UNUSED", NOW - 86400)).unwrap();
+    store
+        .record_card(IssuedCard {
+            used: true,
+            ..card(1, "USED", NOW - 86400)
+        })
+        .unwrap();
+    store.record_card(card(2, "SOMEONE-ELSES", NOW)).unwrap();
+    h.set_member(1, false);
+
+    let recheck = ScheduledAction::Maintenance(MaintenanceTask::RecheckMembership);
+    h.service.run_scheduled(&recheck).await.unwrap();
+
+    assert_eq!(*h.giftcards.cancelled.lock().unwrap(), vec!["UNUSED"]);
+    assert!(h.telegram.texts_to(ADMIN_ID as i64)[0].ends_with("🗑️ Cancelled 1 of their codes"));
+}
+
+#[tokio::test]
+async fn users_must_join_the_group_of_their_language() {
+    let h = Harness::with_config(
+        "language_groups: { fa: { group_id: -200, invite_link: 'https://t.me/gephfa' } }",
+    );
+    h.telegram.members.lock().unwrap().insert(USER_ID, false);
+    h.telegram
+        .members
+        .lock()
+        .unwrap()
+        .insert(USER_ID + 1, false);
+    let mut farsi = user(USER_ID, Some("dara"));
+    farsi["language_code"] = json!("fa-IR");
+    let mut chinese = user(USER_ID + 1, Some("li"));
+    chinese["language_code"] = json!("zh-hans");
+
+    for sender in [farsi, chinese] {
+        h.service
+            .handle_message(private_message(sender, Some("hi")))
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(
+        *h.telegram.checked_groups.lock().unwrap(),
+        vec![ChatId(-200), ChatId(GROUP_ID)]
+    );
+    assert!(h.telegram.texts_to(USER_ID as i64)[0].contains("https://t.me/gephfa"));
+    assert!(h.telegram.texts_to(USER_ID as i64 + 1)[0].contains("https://t.me/gephusers"));
+}
+
+/// A private message from `from` sharing the contact of `contact_user_id`.
+fn contact_message(from: Value, phone_number: &str, contact_user_id: u64) -> Message {
+    serde_json::from_value(json!({
+        "message_id": 8,
+        "date": NOW,
+        "chat": { "id": from["id"], "type": "private", "first_name": "Test" },
+        "from": from,
+        "contact": {
+            "phone_number": phone_number,
+            "first_name": "Test",
+            "user_id": contact_user_id,
+        },
+    }))
+    .unwrap()
+}
+
+const PHONE_CHECK: &str = "phone_check: { salt: 'a salt of sixteen chars' }";
+
+#[tokio::test]
+async fn phone_check_asks_for_the_users_contact() {
+    let h = Harness::with_config(PHONE_CHECK);
+    h.set_member(USER_ID, true);
+
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+
+    let sent = h.telegram.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].text, h.service.config.templates.share_contact);
+    assert!(sent[0].keyboard.is_some());
+    assert!(h.giftcards.requested_days.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn shared_contact_leads_to_the_giftcard() {
+    let h = Harness::with_config(PHONE_CHECK);
+    h.set_member(USER_ID, true);
+
+    h.service
+        .handle_message(contact_message(alice(), "+98 912 000 0000", USER_ID))
+        .await
+        .unwrap();
+
+    let texts = h.telegram.texts_to(USER_ID as i64);
+    assert_eq!(texts[0], h.service.config.templates.contact_verified);
+    assert_eq!(texts[2], CODE);
+    assert!(h.service.store.has_phone(USER_ID as i64).unwrap());
+    assert!(h.service.store.is_redeemed(USER_ID as i64).unwrap());
+}
+
+#[tokio::test]
+async fn second_account_with_the_same_phone_is_refused() {
+    let h = Harness::with_config(PHONE_CHECK);
+    h.set_member(USER_ID, true);
+    h.set_member(USER_ID + 1, true);
+    let second = user(USER_ID + 1, Some("alice2"));
+
+    h.service
+        .handle_message(contact_message(alice(), "+98 912 000 0000", USER_ID))
+        .await
+        .unwrap();
+    h.service
+        .handle_message(contact_message(second, "989120000000", USER_ID + 1))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64 + 1),
+        vec![h.service.config.templates.phone_in_use.clone()]
+    );
+    assert!(!h.service.store.has_phone(USER_ID as i64 + 1).unwrap());
+    assert_eq!(*h.giftcards.requested_days.lock().unwrap(), vec![3]);
+}
+
+#[tokio::test]
+async fn forwarded_contacts_are_not_accepted() {
+    let h = Harness::with_config(PHONE_CHECK);
+    h.set_member(USER_ID, true);
+
+    h.service
+        .handle_message(contact_message(alice(), "+98 912 000 0000", USER_ID + 1))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64),
+        vec![h.service.config.templates.share_contact.clone()]
+    );
+    assert!(!h.service.store.has_phone(USER_ID as i64).unwrap());
+}
+
+#[tokio::test]
+async fn messages_follow_the_users_language() {
+    let h = Harness::new();
+    h.set_member(USER_ID, true);
+    h.set_member(USER_ID + 1, true);
+    let mut farsi = user(USER_ID, Some("dara"));
+    farsi["language_code"] = json!("fa");
+    let mut chinese = user(USER_ID + 1, Some("li"));
+    chinese["language_code"] = json!("zh-hans");
+
+    for sender in [farsi, chinese] {
+        h.service
+            .handle_message(private_message(sender, Some("hi")))
+            .await
+            .unwrap();
+    }
+
+    let to_farsi = h.telegram.texts_to(USER_ID as i64);
+    assert!(to_farsi[0].starts_with('\u{200F}'));
+    assert!(to_farsi[0].contains("گیفت‌کارت ۳ روزه"));
+    assert_eq!(to_farsi[1], CODE);
+    // chinese has no translation, so the multilingual default is used
+    assert!(h.telegram.texts_to(USER_ID as i64 + 1)[0].contains("3-day"));
+}
+
+#[tokio::test]
+async fn languages_without_translation_fall_back_to_the_bots_default() {
+    let h = Harness::with_config(
+        "default_language: de
+translations: { de: { send_text: 'Bitte schreib mir eine Nachricht' } }
+templates: { refused: 'custom refusal' }",
+    );
+    let mut russian = alice();
+    russian["language_code"] = json!("ru");
+    let mut farsi = user(USER_ID + 1, Some("dara"));
+    farsi["language_code"] = json!("fa");
+    h.service.store.ban(USER_ID as i64 + 1).unwrap();
+
+    h.service
+        .handle_message(private_message(russian, None))
+        .await
+        .unwrap();
+    h.service
+        .handle_message(private_message(farsi, Some("hi")))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64),
+        vec!["Bitte schreib mir eine Nachricht"]
+    );
+    // the shipped farsi translation doesn't replace a template the config changed
+    assert_eq!(
+        h.telegram.texts_to(USER_ID as i64 + 1),
+        vec!["custom refusal"]
+    );
+}
+
+#[tokio::test]
+async fn persistent_repeat_requesters_are_sent_to_support_then_ignored() {
+    let h = Harness::new();
+    h.service
+        .store
+        .record_redemption(USER_ID as i64, NOW)
+        .unwrap();
+
+    for _ in 0..5 {
+        h.service
+            .handle_message(private_message(alice(), Some("again")))
+            .await
+            .unwrap();
+    }
+    h.service
+        .handle_message(private_message(admin(), Some("#RepeatRequesters")))
+        .await
+        .unwrap();
+
+    let templates = &h.service.config.templates;
+    let texts = h.telegram.texts_to(USER_ID as i64);
+    assert_eq!(texts.len(), 3);
+    assert_eq!(
+        texts[..2],
+        [
+            templates.already_redeemed.clone(),
+            templates.already_redeemed.clone()
+        ]
+    );
+    assert!(texts[2].contains("https://t.me/gephusers"));
+    assert_eq!(
+        h.telegram.texts_to(ADMIN_ID as i64),
+        vec!["🔁 Users who asked again after receiving a giftcard:\n1000: 5 times"]
+    );
+}
+
+#[tokio::test]
+async fn users_choose_the_language_they_are_written_to_in() {
+    let h = Harness::new();
+    h.set_member(USER_ID, true);
+
+    h.service
+        .handle_message(private_message(alice(), Some("/language")))
+        .await
+        .unwrap();
+    let prompt = &h.telegram.sent()[0];
+    let keyboard = serde_json::to_value(prompt.keyboard.as_ref().unwrap()).unwrap();
+    let choices: Vec<&Value> = keyboard["inline_keyboard"][0]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|button| &button["callback_data"])
+        .collect();
+    assert_eq!(
+        choices,
+        vec![
+            &json!("language:en"),
+            &json!("language:zh"),
+            &json!("language:fa")
+        ]
+    );
+
+    for data in ["language:xx", "language:fa"] {
+        h.service
+            .handle_callback(callback(alice(), data))
+            .await
+            .unwrap();
+    }
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+
+    let texts = h.telegram.texts_to(USER_ID as i64);
+    assert_eq!(texts.len(), 5);
+    assert!(texts[1].contains("از این به بعد به این زبان"));
+    assert!(texts[2].contains("تبریک"));
+    assert_eq!(
+        h.service.store.language(USER_ID as i64).unwrap().as_deref(),
+        Some("fa")
+    );
+}
+
+#[tokio::test]
+async fn admin_bans_and_unbans() {
+    let h = Harness::new();
+
+    for text in [
+        "#Ban 1000",
+        "CONFIRM",
+        "#Unban 1000",
+        "#Unban 1000",
+        "#Ban nobody",
+        "CONFIRM",
+    ] {
+        h.service
+            .handle_message(private_message(admin(), Some(text)))
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(
+        h.telegram.texts_to(ADMIN_ID as i64),
+        vec![
+            "⚠️ Send CONFIRM within 60 seconds to run #Ban 1000, or anything else to cancel it",
+            "🚫 User 1000 is now banned",
+            "✅ User 1000 is no longer banned",
+            "ℹ️ User 1000 was not banned",
+            "⚠️ Send CONFIRM within 60 seconds to run #Ban nobody, or anything else to cancel it",
+            "⚠️ Not a valid user id: nobody",
+        ]
+    );
+    assert!(!h.service.store.is_banned(USER_ID as i64).unwrap());
+}
+
+#[tokio::test]
+async fn admin_grants_custom_giftcard() {
+    let h = Harness::new();
+
+    h.service
+        .handle_message(private_message(admin(), Some("#Grant 1000 30")))
+        .await
+        .unwrap();
+
+    assert_eq!(*h.giftcards.requested_days.lock().unwrap(), vec![30]);
+    let to_user = h.telegram.texts_to(USER_ID as i64);
+    assert!(to_user[0].contains("30-day"));
+    assert_eq!(to_user[1], CODE);
+    assert_eq!(
+        h.telegram.texts_to(ADMIN_ID as i64),
+        vec!["🎁 Sent a 30-day giftcard to user 1000"]
+    );
+}
+
+#[tokio::test]
+async fn admin_grant_requires_valid_arguments() {
+    let h = Harness::new();
+
+    h.service
+        .handle_message(private_message(admin(), Some("#Grant 1000 0")))
+        .await
+        .unwrap();
+
+    assert!(h.giftcards.requested_days.lock().unwrap().is_empty());
+    assert_eq!(
+        h.telegram.texts_to(ADMIN_ID as i64),
+        vec!["⚠️ Usage: #Grant <user_id> <days>"]
+    );
+}
+
+#[tokio::test]
+async fn replies_to_group_mentions() {
+    let h = Harness::new();
+
+    h.service
+        .handle_message(group_message("hey @GephGiftcardBot", None))
+        .await
+        .unwrap();
+
+    let sent = h.telegram.sent();
+    assert_eq!(sent.len(), 1);
+    assert_eq!(sent[0].chat_id, ChatId(GROUP_ID));
+    assert_eq!(sent[0].reply_to, Some(MessageId(99)));
+    assert_eq!(sent[0].thread_id, None);
+}
+
+#[tokio::test]
+async fn replies_in_the_mentioning_topic() {
+    let h = Harness::new();
+
+    h.service
+        .handle_message(group_message("@GephGiftcardBot", Some(12)))
+        .await
+        .unwrap();
+
+    let sent = h.telegram.sent();
+    assert_eq!(sent[0].thread_id.map(|t| t.0.0), Some(12));
+}
+
+#[tokio::test(start_paused = true)]
+async fn deletes_group_replies_and_mentions_later() {
+    let h = Harness::with_config("group_replies:\n  delete_after_secs: 60\n  delete_mention: true");
+
+    h.service
+        .handle_message(group_message("hey @GephGiftcardBot", None))
+        .await
+        .unwrap();
+    tokio::time::sleep(std::time::Duration::from_secs(59)).await;
+    assert!(h.telegram.deleted.lock().unwrap().is_empty());
+
+    tokio::time::sleep(std::time::Duration::from_secs(2)).await;
+    assert_eq!(
+        *h.telegram.deleted.lock().unwrap(),
+        vec![
+            (ChatId(GROUP_ID), MessageId(1)),
+            (ChatId(GROUP_ID), MessageId(99))
+        ]
+    );
+}
+
+#[tokio::test]
+async fn ignores_group_chatter() {
+    let h = Harness::new();
+
+    h.service
+        .handle_message(group_message("hello everyone", None))
+        .await
+        .unwrap();
+
+    assert!(h.telegram.sent().is_empty());
+}
+
+#[tokio::test]
+async fn greets_new_members_once_per_interval() {
+    let h = Harness::with_config("greeting:\n  enabled: true\n  delete_after_secs: 0");
+
+    let mut bob = user(2000, Some("bob"));
+    bob["first_name"] = json!("Bob");
+    h.service
+        .handle_message(join_message(vec![alice(), bob]))
+        .await
+        .unwrap();
+    h.service
+        .handle_message(join_message(vec![user(3000, None)]))
+        .await
+        .unwrap();
+
+    let texts = h.telegram.texts_to(GROUP_ID);
+    assert_eq!(texts.len(), 1);
+    assert!(texts[0].starts_with("👋 Welcome Test, Bob!"));
+    assert!(texts[0].contains("3-day"));
+}
+
+#[tokio::test]
+async fn greets_only_when_enabled() {
+    let h = Harness::new();
+
+    h.service
+        .handle_message(join_message(vec![alice()]))
+        .await
+        .unwrap();
+
+    assert!(h.telegram.sent().is_empty());
+}
+
+#[tokio::test]
+async fn does_not_greet_bots() {
+    let h = Harness::with_config("greeting:\n  enabled: true");
+    let mut bot = user(3000, Some("some_bot"));
+    bot["is_bot"] = json!(true);
+
+    h.service
+        .handle_message(join_message(vec![bot]))
+        .await
+        .unwrap();
+
+    assert!(h.telegram.sent().is_empty());
+}
+
+#[tokio::test(start_paused = true)]
+async fn deletes_greeting_later() {
+    let h = Harness::with_config("greeting:\n  enabled: true\n  delete_after_secs: 30");
+
+    h.service
+        .handle_message(join_message(vec![alice()]))
+        .await
+        .unwrap();
+    assert!(h.telegram.deleted.lock().unwrap().is_empty());
+
+    tokio::time::sleep(std::time::Duration::from_secs(31)).await;
+    assert_eq!(
+        *h.telegram.deleted.lock().unwrap(),
+        vec![(ChatId(GROUP_ID), MessageId(1))]
+    );
+}
+
+#[tokio::test]
+async fn scheduled_announcement_goes_to_group() {
+    let h = Harness::new();
+
+    h.service
+        .run_scheduled(&ScheduledAction::Announce(
+            "PM the bot for a {days}-day card".to_owned(),
+        ))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        h.telegram.texts_to(GROUP_ID),
+        vec!["PM the bot for a 3-day card"]
+    );
+}
+
+#[tokio::test]
+async fn admin_commands_have_localized_aliases() {
+    let h = Harness::new();
+
+    for text in ["#封禁 1000", "CONFIRM"] {
+        h.service
+            .handle_message(private_message(admin(), Some(text)))
+            .await
+            .unwrap();
+    }
+
+    assert!(h.service.store.is_banned(USER_ID as i64).unwrap());
+}
+
+#[tokio::test]
+async fn bans_are_only_run_once_confirmed_and_audited() {
+    let dir = tempfile::tempdir().unwrap();
+    let audit_path = dir.path().join("audit.jsonl");
+    let mut h = Harness::new();
+    let audit_config = serde_yaml::from_str(&format!("path: '{}'", audit_path.display())).unwrap();
+    h.service.audit = Arc::new(AuditLog::open(Some(&audit_config)).unwrap());
+
+    // anything but the phrase cancels
+    for text in ["#Ban 1000", "yes"] {
+        h.service
+            .handle_message(private_message(admin(), Some(text)))
+            .await
+            .unwrap();
+    }
+    assert!(!h.service.store.is_banned(USER_ID as i64).unwrap());
+
+    // and so does waiting too long
+    h.service
+        .handle_message(private_message(admin(), Some("#Ban 1000")))
+        .await
+        .unwrap();
+    let store = &h.service.store;
+    let mut state = store.conversation(ADMIN_ID as i64).unwrap().unwrap();
+    state.expires_at = NOW;
+    store.set_conversation(ADMIN_ID as i64, state).unwrap();
+    h.service
+        .handle_message(private_message(admin(), Some("CONFIRM")))
+        .await
+        .unwrap();
+    assert!(!h.service.store.is_banned(USER_ID as i64).unwrap());
+
+    for text in ["#Ban 1000", "CONFIRM"] {
+        h.service
+            .handle_message(private_message(admin(), Some(text)))
+            .await
+            .unwrap();
+    }
+    assert!(h.service.store.is_banned(USER_ID as i64).unwrap());
+    assert_eq!(h.telegram.texts_to(ADMIN_ID as i64)[1], "👌 Cancelled");
+
+    let entries: Vec<Value> = std::fs::read_to_string(&audit_path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let actions: Vec<(&Value, &Value, &Value)> = entries
+        .iter()
+        .map(|entry| {
+            (
+                &entry["outcome"],
+                &entry["checks"]["command"],
+                &entry["checks"]["confirmed"],
+            )
+        })
+        .collect();
+    assert_eq!(
+        actions,
+        vec![
+            (
+                &json!("awaiting_confirmation"),
+                &json!("#Ban 1000"),
+                &Value::Null
+            ),
+            (&json!("not_confirmed"), &json!("#Ban 1000"), &Value::Null),
+            (
+                &json!("awaiting_confirmation"),
+                &json!("#Ban 1000"),
+                &Value::Null
+            ),
+            (
+                &json!("awaiting_confirmation"),
+                &json!("#Ban 1000"),
+                &Value::Null
+            ),
+            (&json!("admin_command"), &json!("#Ban 1000"), &json!(true)),
+        ]
+    );
+    assert!(
+        entries
+            .iter()
+            .all(|entry| entry["user_id"] == json!(ADMIN_ID))
+    );
+}
+
+#[tokio::test]
+async fn the_admin_username_alone_does_not_make_an_admin() {
+    let h = Harness::new();
+
+    for text in ["#Ban 1000", "CONFIRM"] {
+        h.service
+            .handle_message(private_message(user(999, Some("admin")), Some(text)))
+            .await
+            .unwrap();
+    }
+
+    assert!(!h.service.store.is_banned(USER_ID as i64).unwrap());
+    assert!(
+        h.telegram
+            .texts_to(999)
+            .iter()
+            .all(|text| !text.contains("CONFIRM"))
+    );
+
+    // without admin_ids, nobody is an admin
+    let service = BotService {
+        global: Arc::new(Config {
+            admin_ids: vec![],
+            ..(*h.service.global).clone()
+        }),
+        ..h.service
+    };
+    for text in ["#Ban 1000", "CONFIRM"] {
+        service
+            .handle_message(private_message(admin(), Some(text)))
+            .await
+            .unwrap();
+    }
+    assert!(!service.store.is_banned(USER_ID as i64).unwrap());
+}
+
+#[tokio::test]
+async fn admin_commands_are_plain_text_for_users() {
+    let h = Harness::new();
+    h.set_member(USER_ID, true);
+
+    h.service
+        .handle_message(private_message(alice(), Some("#Ban 42")))
+        .await
+        .unwrap();
+
+    assert!(!h.service.store.is_banned(ADMIN_ID as i64).unwrap());
+    assert!(h.service.store.is_redeemed(USER_ID as i64).unwrap());
+}
+
+#[tokio::test]
+async fn admin_grants_step_by_step() {
+    let h = Harness::new();
+
+    for text in ["#Grant", "nobody", "1000", "0", "30"] {
+        h.service
+            .handle_message(private_message(admin(), Some(text)))
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(
+        h.telegram.texts_to(ADMIN_ID as i64),
+        vec![
+            "👤 Which user id should receive the giftcard? Send /cancel to stop.",
+            "⚠️ Not a valid user id: nobody",
+            "📅 How many days should the giftcard last?",
+            "⚠️ Please send a number of days greater than 0",
+            "🎁 Sent a 30-day giftcard to user 1000",
+        ]
+    );
+    assert_eq!(*h.giftcards.requested_days.lock().unwrap(), vec![30]);
+    assert!(
+        h.service
+            .store
+            .conversation(ADMIN_ID as i64)
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[tokio::test]
+async fn conversations_can_be_cancelled() {
+    let h = Harness::new();
+
+    for text in ["#Grant", "/cancel", "#RecipientCount"] {
+        h.service
+            .handle_message(private_message(admin(), Some(text)))
+            .await
+            .unwrap();
+    }
+
+    let texts = h.telegram.texts_to(ADMIN_ID as i64);
+    assert_eq!(texts[1], "👌 Cancelled");
+    assert_eq!(texts[2], "🌸 0 users received giftcards!");
+}
+
+#[tokio::test]
+async fn expired_conversations_are_forgotten() {
+    let h = Harness::new();
+    h.service
+        .store
+        .set_conversation(
+            ADMIN_ID as i64,
+            ConversationState {
+                flow: "grant".to_owned(),
+                step: "days".to_owned(),
+                data: [("user_id".to_owned(), "1000".to_owned())].into(),
+                expires_at: NOW,
+            },
+        )
+        .unwrap();
+
+    h.service
+        .handle_message(private_message(admin(), Some("30")))
+        .await
+        .unwrap();
+
+    assert!(h.giftcards.requested_days.lock().unwrap().is_empty());
+    assert!(
+        h.service
+            .store
+            .conversation(ADMIN_ID as i64)
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[tokio::test]
+async fn member_milestones_are_celebrated_once_with_a_bonus_card() {
+    let h = Harness::with_config("milestones: { every: 1000, bonus_days: 7 }");
+    let store = &h.service.store;
+    // eligible, banned, redeemed too long ago, left the group
+    for (user_id, days_ago) in [(1, 2), (2, 2), (3, 60), (4, 2)] {
+        store
+            .record_redemption(user_id, NOW - days_ago * 86400)
+            .unwrap();
+    }
+    store.ban(2).unwrap();
+    store.flag_left_group(4).unwrap();
+    let check = ScheduledAction::Maintenance(MaintenanceTask::CheckMilestones);
+
+    // the first check only records where the group stands
+    h.telegram.member_count.store(2500, Ordering::SeqCst);
+    h.service.run_scheduled(&check).await.unwrap();
+    assert_eq!(store.last_milestone().unwrap(), Some(2000));
+    assert!(h.telegram.sent().is_empty());
+
+    h.telegram.member_count.store(2999, Ordering::SeqCst);
+    h.service.run_scheduled(&check).await.unwrap();
+    assert!(h.telegram.sent().is_empty());
+
+    h.telegram.member_count.store(3004, Ordering::SeqCst);
+    h.service.run_scheduled(&check).await.unwrap();
+    h.service.run_scheduled(&check).await.unwrap();
+
+    assert_eq!(store.last_milestone().unwrap(), Some(3000));
+    let announcements = h.telegram.texts_to(GROUP_ID);
+    assert_eq!(announcements.len(), 1);
+    assert!(announcements[0].starts_with("🥳 Our group just reached 3000 members!"));
+    assert!(announcements[0].contains("bonus 7-day"));
+    let to_winner = h.telegram.texts_to(1);
+    assert!(to_winner[0].starts_with("🎊 Our group reached 3000 members"));
+    assert_eq!(*h.giftcards.requested_days.lock().unwrap(), vec![7]);
+    for user_id in [2, 3, 4] {
+        assert!(h.telegram.texts_to(user_id).is_empty());
+    }
+    // the winner's first card still decides when they are archived
+    assert!(store.redemptions().unwrap().contains(&(1, NOW - 2 * 86400)));
+}
+
+#[tokio::test]
+async fn milestones_wait_for_the_backend_to_create_the_bonus_card() {
+    let h = Harness::with_config("milestones: { every: 1000 }");
+    let store = &h.service.store;
+    store.record_redemption(1, NOW).unwrap();
+    store.record_milestone(2000).unwrap();
+    h.telegram.member_count.store(3004, Ordering::SeqCst);
+    let check = ScheduledAction::Maintenance(MaintenanceTask::CheckMilestones);
+
+    h.giftcards.fail.store(true, Ordering::SeqCst);
+    assert!(h.service.run_scheduled(&check).await.is_err());
+    assert!(h.telegram.sent().is_empty());
+    assert_eq!(store.last_milestone().unwrap(), Some(2000));
+
+    h.giftcards.fail.store(false, Ordering::SeqCst);
+    h.service.run_scheduled(&check).await.unwrap();
+    assert_eq!(store.last_milestone().unwrap(), Some(3000));
+    assert_eq!(h.telegram.texts_to(GROUP_ID).len(), 1);
+    assert_eq!(h.telegram.texts_to(1)[2], CODE);
+}
+
+#[tokio::test]
+async fn milestone_winners_get_their_card_when_the_announcement_fails() {
+    let h = Harness::with_config("milestones: { every: 1000 }");
+    let store = &h.service.store;
+    store.record_redemption(1, NOW).unwrap();
+    store.record_milestone(2000).unwrap();
+    h.telegram.member_count.store(3004, Ordering::SeqCst);
+    h.telegram
+        .send_failures
+        .lock()
+        .unwrap()
+        .push_back(DeliveryError::Rejected("not enough rights".into()));
+
+    let check = ScheduledAction::Maintenance(MaintenanceTask::CheckMilestones);
+    h.service.run_scheduled(&check).await.unwrap();
+
+    assert_eq!(store.last_milestone().unwrap(), Some(3000));
+    assert!(h.telegram.texts_to(GROUP_ID).is_empty());
+    let to_winner = h.telegram.texts_to(1);
+    assert!(to_winner[0].starts_with("🎊 Our group reached 3000 members"));
+    assert!(to_winner.contains(&CODE.to_owned()));
+}
+
+#[tokio::test(start_paused = true)]
+async fn hung_updates_are_cancelled_and_the_user_asked_to_retry() {
+    let h = Harness::new();
+    let sender = private_message(alice(), Some("hi")).from.unwrap();
+
+    let result = h
+        .service
+        .within_timeout(Some(&sender), std::future::pending())
+        .await;
+
+    assert!(result.unwrap_err().to_string().contains("longer than 60s"));
+    assert_eq!(h.service.timed_out_updates.load(Ordering::Relaxed), 1);
+    assert!(
+        h.telegram.texts_to(USER_ID as i64)[0]
+            .starts_with("⚠️ Sorry, we're having a temporary problem")
+    );
+
+    // handling that finishes in time is untouched
+    h.service
+        .within_timeout(Some(&sender), async { Ok(()) })
+        .await
+        .unwrap();
+    assert_eq!(h.service.timed_out_updates.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test(start_paused = true)]
+async fn slow_delivery_of_a_created_giftcard_is_not_cancelled() {
+    let h = Harness::new();
+    h.set_member(USER_ID, true);
+    *h.telegram.send_delay.lock().unwrap() = Duration::from_secs(50);
+    let msg = private_message(alice(), Some("hi"));
+    let sender = msg.from.clone().unwrap();
+
+    h.service
+        .within_timeout(Some(&sender), h.service.handle_message(msg))
+        .await
+        .unwrap();
+
+    let texts = h.telegram.texts_to(USER_ID as i64);
+    assert_eq!(texts.len(), 3);
+    assert_eq!(texts[1], CODE);
+    assert!(h.service.store.is_redeemed(USER_ID as i64).unwrap());
+    assert_eq!(h.service.timed_out_updates.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn cooldown_refuses_users_given_a_card_recently() {
+    let h =
+        Harness::with_config("eligibility: [blacklist, group_membership, {cooldown: {days: 30}}]");
+    h.set_member(USER_ID, true);
+    // an admin reset the redemption, but the card is remembered
+    h.service
+        .store
+        .record_card(card(USER_ID as i64, "EARLIER", NOW - 10 * 86400))
+        .unwrap();
+
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+
+    assert!(
+        h.telegram.texts_to(USER_ID as i64)[0]
+            .starts_with("⏳ You received a giftcard recently. Please try again in 20 days.")
+    );
+    assert!(h.giftcards.requested_days.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn daily_cap_counts_cards_issued_since_midnight() {
+    let h =
+        Harness::with_config("eligibility: [blacklist, group_membership, {daily_cap: {max: 1}}]");
+    h.set_member(USER_ID, true);
+    h.set_member(USER_ID + 1, true);
+    // yesterday's card doesn't count
+    h.service.store.record_redemption(1, NOW - 86400).unwrap();
+
+    for sender in [alice(), user(USER_ID + 1, Some("bob"))] {
+        h.service
+            .handle_message(private_message(sender, Some("hi")))
+            .await
+            .unwrap();
+    }
+
+    assert_eq!(*h.giftcards.requested_days.lock().unwrap(), vec![3]);
+    assert!(
+        h.telegram.texts_to(USER_ID as i64 + 1)[0]
+            .starts_with("🌙 All of today's giftcards have been given out.")
+    );
+}
+
+#[tokio::test]
+async fn admins_change_days_and_group_link_at_runtime() {
+    let h = Harness::new();
+    h.set_member(USER_ID, false);
+    h.set_member(USER_ID + 1, true);
+
+    for text in [
+        "#Set days 7",
+        "#Set group_link https://t.me/gephnew",
+        "#Set days zero",
+        "#Set colour blue",
+    ] {
+        h.service
+            .handle_message(private_message(admin(), Some(text)))
+            .await
+            .unwrap();
+    }
+    assert_eq!(
+        h.telegram.texts_to(ADMIN_ID as i64),
+        vec![
+            "⚙️ days is now 7",
+            "⚙️ group_link is now https://t.me/gephnew",
+            MSG_SET_USAGE,
+            MSG_SET_USAGE,
+        ]
+    );
+
+    for sender in [alice(), user(USER_ID + 1, Some("bob"))] {
+        h.service
+            .handle_message(private_message(sender, Some("hi")))
+            .await
+            .unwrap();
+    }
+    assert!(h.telegram.texts_to(USER_ID as i64)[0].ends_with("https://t.me/gephnew"));
+    assert_eq!(*h.giftcards.requested_days.lock().unwrap(), vec![7]);
+}
+
+#[tokio::test(start_paused = true)]
+async fn messages_wait_out_flood_control_and_network_errors() {
+    let h = Harness::new();
+    h.set_member(USER_ID, true);
+    h.telegram.send_failures.lock().unwrap().extend([
+        DeliveryError::RetryAfter(Duration::from_secs(5)),
+        DeliveryError::Transient("connection reset".to_owned()),
+    ]);
+    let started = tokio::time::Instant::now();
+
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+
+    assert_eq!(h.telegram.texts_to(USER_ID as i64).len(), 3);
+    // 5 seconds of flood control, then 2 seconds of backoff after the second attempt
+    assert!(started.elapsed() >= Duration::from_secs(7));
+}
+
+#[tokio::test]
+async fn users_who_blocked_the_bot_are_skipped_until_they_write_again() {
+    let h = Harness::new();
+    let store = &h.service.store;
+    store
+        .record_card(card(USER_ID as i64, "FIRST", NOW - 5 * 86400))
+        .unwrap();
+    h.telegram
+        .send_failures
+        .lock()
+        .unwrap()
+        .push_back(DeliveryError::Undeliverable(
+            "bot was blocked by the user".to_owned(),
+        ));
+    let remind = ScheduledAction::Maintenance(MaintenanceTask::RemindUnusedCards);
+
+    h.service.run_scheduled(&remind).await.unwrap();
+    assert_eq!(store.undeliverable_users().unwrap(), vec![USER_ID as i64]);
+
+    store
+        .record_card(card(USER_ID as i64, "SECOND", NOW - 4 * 86400))
+        .unwrap();
+    h.service.run_scheduled(&remind).await.unwrap();
+    assert!(h.telegram.texts_to(USER_ID as i64).is_empty());
+
+    h.set_member(USER_ID, false);
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+    assert!(store.undeliverable_users().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn giftcards_come_with_a_qr_code_when_configured() {
+    let h = Harness::with_config("qr_code: true");
+    h.set_member(USER_ID, true);
+
+    h.service
+        .handle_message(private_message(alice(), Some("hi")))
+        .await
+        .unwrap();
+
+    let photos = h.telegram.photos.lock().unwrap();
+    assert_eq!(photos.len(), 1);
+    assert_eq!(photos[0].0, ChatId(USER_ID as i64));
+    assert!(photos[0].1.starts_with(b"\x89PNG"));
+    assert_eq!(h.telegram.texts_to(USER_ID as i64)[1], CODE);
+}
@@ -0,0 +1,489 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
+
+use acidjson::AcidJson;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+
+use super::{ConversationState, DailyStats, IssuedCard, PendingReview, Storage, WaitlistEntry};
+
+/// The schema version written by this build. Bump it together with a new entry in [`MIGRATIONS`]
+/// when the shape of existing data changes; new fields only need `#[serde(default)]`.
+const STORE_VERSION: u32 = 3;
+
+/// Upgrades a store from the version at its index to the next one.
+type Migration = fn(&mut Value) -> anyhow::Result<()>;
+
+const MIGRATIONS: &[Migration] = &[migrate_v0_to_v1, migrate_v1_to_v2, migrate_v2_to_v3];
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Store {
+    pub version: u32,
+    pub redemptions: BTreeMap<i64, Redemption>,
+    #[serde(default)]
+    pub pending_reviews: BTreeMap<i64, PendingReview>,
+    #[serde(default)]
+    pub banned_users: BTreeSet<i64>,
+    #[serde(default)]
+    pub conversations: BTreeMap<i64, ConversationState>,
+    /// issued giftcards by code
+    #[serde(default)]
+    pub cards: BTreeMap<String, IssuedCard>,
+    /// users by the hash of the phone number they shared
+    #[serde(default)]
+    pub phones: BTreeMap<String, i64>,
+    /// users whose redemptions were moved to the archive
+    #[serde(default)]
+    pub archived_users: BTreeSet<i64>,
+    /// users who left the group soon after redeeming
+    #[serde(default)]
+    pub left_group: BTreeSet<i64>,
+    /// how often users asked again after redeeming
+    #[serde(default)]
+    pub repeat_attempts: BTreeMap<i64, u32>,
+    /// member count milestones the group reached
+    #[serde(default)]
+    pub milestones: BTreeSet<u64>,
+    /// settings changed by admins at runtime, by name
+    #[serde(default)]
+    pub settings: BTreeMap<String, String>,
+    /// users the bot can no longer message
+    #[serde(default)]
+    pub undeliverable: BTreeSet<i64>,
+    /// languages users chose, by user
+    #[serde(default)]
+    pub languages: BTreeMap<i64, String>,
+    /// users waiting for a giftcard, by user
+    #[serde(default)]
+    pub waitlist: BTreeMap<i64, WaitlistEntry>,
+    /// statistics by days since the unix epoch
+    #[serde(default)]
+    pub daily_stats: BTreeMap<u64, DailyStats>,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self {
+            version: STORE_VERSION,
+            redemptions: BTreeMap::new(),
+            pending_reviews: BTreeMap::new(),
+            banned_users: BTreeSet::new(),
+            conversations: BTreeMap::new(),
+            cards: BTreeMap::new(),
+            phones: BTreeMap::new(),
+            archived_users: BTreeSet::new(),
+            left_group: BTreeSet::new(),
+            repeat_attempts: BTreeMap::new(),
+            milestones: BTreeSet::new(),
+            settings: BTreeMap::new(),
+            undeliverable: BTreeSet::new(),
+            languages: BTreeMap::new(),
+            waitlist: BTreeMap::new(),
+            daily_stats: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Redemption {
+    /// unix timestamp, or 0 for redemptions made before timestamps were recorded
+    pub redeemed_at: u64,
+}
+
+/// [`Storage`] backed by a single json file.
+pub struct JsonStorage(AcidJson<Store>);
+
+impl JsonStorage {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        migrate_store(Path::new(path))?;
+        let store = AcidJson::open_or_else(Path::new(path), Store::default)
+            .with_context(|| format!("cannot open store at {path}"))?;
+        Ok(Self(store))
+    }
+
+    /// Opens an existing store as it is, refusing one that [`Self::open`] would migrate.
+    pub fn open_read_only(path: &str) -> anyhow::Result<Self> {
+        let raw = std::fs::read(path).with_context(|| format!("cannot read store at {path}"))?;
+        let store: Value = serde_json::from_slice(&raw).context("store is not valid json")?;
+        let version = stored_version(&store);
+        anyhow::ensure!(
+            version == STORE_VERSION,
+            "store has version {version}, but this build reads version {STORE_VERSION}; start the \
+             bot once to migrate it"
+        );
+        let store = AcidJson::open(Path::new(path))
+            .with_context(|| format!("cannot open store at {path}"))?;
+        Ok(Self(store))
+    }
+}
+
+impl Storage for JsonStorage {
+    fn is_redeemed(&self, user_id: i64) -> anyhow::Result<bool> {
+        let store = self.0.read();
+        Ok(store.redemptions.contains_key(&user_id) || store.archived_users.contains(&user_id))
+    }
+
+    fn record_redemption(&self, user_id: i64, redeemed_at: u64) -> anyhow::Result<()> {
+        self.0
+            .write()
+            .redemptions
+            .insert(user_id, Redemption { redeemed_at });
+        Ok(())
+    }
+
+    fn redemption_count(&self) -> anyhow::Result<usize> {
+        let store = self.0.read();
+        Ok(store.redemptions.len() + store.archived_users.len())
+    }
+
+    fn redemptions(&self) -> anyhow::Result<Vec<(i64, u64)>> {
+        Ok(self
+            .0
+            .read()
+            .redemptions
+            .iter()
+            .map(|(user_id, redemption)| (*user_id, redemption.redeemed_at))
+            .collect())
+    }
+
+    fn redemptions_since(&self, since: u64) -> anyhow::Result<usize> {
+        Ok(self
+            .0
+            .read()
+            .redemptions
+            .values()
+            .filter(|redemption| redemption.redeemed_at >= since)
+            .count())
+    }
+
+    fn archive_redemptions(&self, user_ids: &[i64]) -> anyhow::Result<()> {
+        let mut store = self.0.write();
+        for user_id in user_ids {
+            if store.redemptions.remove(user_id).is_some() {
+                store.archived_users.insert(*user_id);
+            }
+        }
+        Ok(())
+    }
+
+    fn has_pending_review(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self.0.read().pending_reviews.contains_key(&user_id))
+    }
+
+    fn add_pending_review(&self, user_id: i64, review: PendingReview) -> anyhow::Result<()> {
+        self.0.write().pending_reviews.insert(user_id, review);
+        Ok(())
+    }
+
+    fn take_pending_review(&self, user_id: i64) -> anyhow::Result<Option<PendingReview>> {
+        Ok(self.0.write().pending_reviews.remove(&user_id))
+    }
+
+    fn pending_reviews(&self) -> anyhow::Result<Vec<(i64, PendingReview)>> {
+        Ok(self
+            .0
+            .read()
+            .pending_reviews
+            .iter()
+            .map(|(user_id, review)| (*user_id, review.clone()))
+            .collect())
+    }
+
+    fn is_banned(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self.0.read().banned_users.contains(&user_id))
+    }
+
+    fn ban(&self, user_id: i64) -> anyhow::Result<()> {
+        self.0.write().banned_users.insert(user_id);
+        Ok(())
+    }
+
+    fn unban(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self.0.write().banned_users.remove(&user_id))
+    }
+
+    fn banned_users(&self) -> anyhow::Result<Vec<i64>> {
+        Ok(self.0.read().banned_users.iter().copied().collect())
+    }
+
+    fn conversation(&self, user_id: i64) -> anyhow::Result<Option<ConversationState>> {
+        Ok(self.0.read().conversations.get(&user_id).cloned())
+    }
+
+    fn set_conversation(&self, user_id: i64, state: ConversationState) -> anyhow::Result<()> {
+        self.0.write().conversations.insert(user_id, state);
+        Ok(())
+    }
+
+    fn clear_conversation(&self, user_id: i64) -> anyhow::Result<()> {
+        self.0.write().conversations.remove(&user_id);
+        Ok(())
+    }
+
+    fn record_card(&self, card: IssuedCard) -> anyhow::Result<()> {
+        self.0.write().cards.insert(card.code.clone(), card);
+        Ok(())
+    }
+
+    fn cards(&self) -> anyhow::Result<Vec<IssuedCard>> {
+        let mut cards: Vec<IssuedCard> = self.0.read().cards.values().cloned().collect();
+        cards.sort_by_key(|card| card.issued_at);
+        Ok(cards)
+    }
+
+    fn last_card_issued_at(&self, user_id: i64) -> anyhow::Result<Option<u64>> {
+        Ok(self
+            .0
+            .read()
+            .cards
+            .values()
+            .filter(|card| card.user_id == user_id)
+            .map(|card| card.issued_at)
+            .max())
+    }
+
+    fn update_card(&self, card: IssuedCard) -> anyhow::Result<()> {
+        self.record_card(card)
+    }
+
+    fn phone_owner(&self, phone_hash: &str) -> anyhow::Result<Option<i64>> {
+        Ok(self.0.read().phones.get(phone_hash).copied())
+    }
+
+    fn has_phone(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self.0.read().phones.values().any(|owner| *owner == user_id))
+    }
+
+    fn record_phone(&self, phone_hash: &str, user_id: i64) -> anyhow::Result<()> {
+        self.0.write().phones.insert(phone_hash.to_owned(), user_id);
+        Ok(())
+    }
+
+    fn record_repeat_attempt(&self, user_id: i64) -> anyhow::Result<u32> {
+        let mut store = self.0.write();
+        let attempts = store.repeat_attempts.entry(user_id).or_default();
+        *attempts += 1;
+        Ok(*attempts)
+    }
+
+    fn repeat_attempts(&self) -> anyhow::Result<Vec<(i64, u32)>> {
+        let mut attempts: Vec<(i64, u32)> = self
+            .0
+            .read()
+            .repeat_attempts
+            .iter()
+            .map(|(user_id, attempts)| (*user_id, *attempts))
+            .collect();
+        attempts.sort_by_key(|(_, attempts)| std::cmp::Reverse(*attempts));
+        Ok(attempts)
+    }
+
+    fn last_milestone(&self) -> anyhow::Result<Option<u64>> {
+        Ok(self.0.read().milestones.last().copied())
+    }
+
+    fn record_milestone(&self, milestone: u64) -> anyhow::Result<()> {
+        self.0.write().milestones.insert(milestone);
+        Ok(())
+    }
+
+    fn setting(&self, name: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.0.read().settings.get(name).cloned())
+    }
+
+    fn set_setting(&self, name: &str, value: &str) -> anyhow::Result<()> {
+        self.0
+            .write()
+            .settings
+            .insert(name.to_owned(), value.to_owned());
+        Ok(())
+    }
+
+    fn language(&self, user_id: i64) -> anyhow::Result<Option<String>> {
+        Ok(self.0.read().languages.get(&user_id).cloned())
+    }
+
+    fn set_language(&self, user_id: i64, language: &str) -> anyhow::Result<()> {
+        self.0
+            .write()
+            .languages
+            .insert(user_id, language.to_owned());
+        Ok(())
+    }
+
+    fn join_waitlist(&self, user_id: i64, entry: WaitlistEntry) -> anyhow::Result<()> {
+        self.0.write().waitlist.entry(user_id).or_insert(entry);
+        Ok(())
+    }
+
+    fn waitlist(&self) -> anyhow::Result<Vec<(i64, WaitlistEntry)>> {
+        let mut waitlist: Vec<_> = self
+            .0
+            .read()
+            .waitlist
+            .iter()
+            .map(|(user_id, entry)| (*user_id, entry.clone()))
+            .collect();
+        waitlist.sort_by_key(|(user_id, entry)| (entry.joined_at, *user_id));
+        Ok(waitlist)
+    }
+
+    fn leave_waitlist(&self, user_id: i64) -> anyhow::Result<()> {
+        if self.0.read().waitlist.contains_key(&user_id) {
+            self.0.write().waitlist.remove(&user_id);
+        }
+        Ok(())
+    }
+
+    fn add_daily_stats(&self, day: u64, stats: DailyStats) -> anyhow::Result<()> {
+        self.0
+            .write()
+            .daily_stats
+            .entry(day)
+            .or_default()
+            .add(stats);
+        Ok(())
+    }
+
+    fn daily_stats(&self, first_day: u64) -> anyhow::Result<Vec<(u64, DailyStats)>> {
+        Ok(self
+            .0
+            .read()
+            .daily_stats
+            .range(first_day..)
+            .map(|(day, stats)| (*day, *stats))
+            .collect())
+    }
+
+    fn flag_left_group(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self.0.write().left_group.insert(user_id))
+    }
+
+    fn left_group_users(&self) -> anyhow::Result<Vec<i64>> {
+        Ok(self.0.read().left_group.iter().copied().collect())
+    }
+
+    fn flag_undeliverable(&self, user_id: i64) -> anyhow::Result<bool> {
+        Ok(self.0.write().undeliverable.insert(user_id))
+    }
+
+    fn unflag_undeliverable(&self, user_id: i64) -> anyhow::Result<bool> {
+        if !self.0.read().undeliverable.contains(&user_id) {
+            return Ok(false);
+        }
+        Ok(self.0.write().undeliverable.remove(&user_id))
+    }
+
+    fn undeliverable_users(&self) -> anyhow::Result<Vec<i64>> {
+        Ok(self.0.read().undeliverable.iter().copied().collect())
+    }
+
+    fn reset_user(&self, user_id: i64) -> anyhow::Result<bool> {
+        let mut store = self.0.write();
+        store.conversations.remove(&user_id);
+        store.left_group.remove(&user_id);
+        store.repeat_attempts.remove(&user_id);
+        store.phones.retain(|_, owner| *owner != user_id);
+        let archived = store.archived_users.remove(&user_id);
+        let redeemed = store.redemptions.remove(&user_id).is_some() || archived;
+        let pending = store.pending_reviews.remove(&user_id).is_some();
+        let banned = store.banned_users.remove(&user_id);
+        Ok(redeemed || pending || banned)
+    }
+
+    fn snapshot(&self, path: &Path) -> anyhow::Result<()> {
+        let store = serde_json::to_vec(&*self.0.read())?;
+        std::fs::write(path, store).with_context(|| format!("cannot write snapshot {path:?}"))
+    }
+}
+
+/// Brings an existing store file up to [`STORE_VERSION`], keeping a backup of the original.
+fn migrate_store(path: &Path) -> anyhow::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    let raw = std::fs::read(path).with_context(|| format!("cannot read store at {path:?}"))?;
+    let mut store: Value = serde_json::from_slice(&raw).context("store is not valid json")?;
+
+    let version = stored_version(&store);
+    anyhow::ensure!(
+        version <= STORE_VERSION,
+        "store has version {version}, but this build only understands up to {STORE_VERSION}"
+    );
+    if version == STORE_VERSION {
+        return Ok(());
+    }
+
+    let backup = path.with_extension(format!("v{version}.bak"));
+    std::fs::write(&backup, &raw).with_context(|| format!("cannot write backup {backup:?}"))?;
+    eprintln!("backed up store to {backup:?}, migrating from version {version} to {STORE_VERSION}");
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(&mut store).with_context(|| format!("migration from version {from} failed"))?;
+        store["version"] = json!(from + 1);
+    }
+
+    let tmp = path.with_extension("migrating");
+    std::fs::write(&tmp, serde_json::to_vec(&store)?)?;
+    std::fs::rename(&tmp, path)?;
+
+    Ok(())
+}
+
+fn stored_version(store: &Value) -> u32 {
+    // stores written before versioning was introduced have no version field
+    store.get("version").and_then(Value::as_u64).unwrap_or(0) as u32
+}
+
+fn migrate_v0_to_v1(store: &mut Value) -> anyhow::Result<()> {
+    let store = store
+        .as_object_mut()
+        .context("store is not a json object")?;
+    store.entry("pending_reviews").or_insert_with(|| json!({}));
+    store.entry("rejected_users").or_insert_with(|| json!([]));
+    Ok(())
+}
+
+/// Replaces the set of redeemed users with redemption records that carry a timestamp.
+fn migrate_v1_to_v2(store: &mut Value) -> anyhow::Result<()> {
+    let store = store
+        .as_object_mut()
+        .context("store is not a json object")?;
+    let redeemed_users = store
+        .remove("redeemed_users")
+        .context("store has no redeemed_users")?;
+    let redemptions: serde_json::Map<String, Value> = redeemed_users
+        .as_array()
+        .context("redeemed_users is not an array")?
+        .iter()
+        .map(|user_id| (user_id.to_string(), json!({ "redeemed_at": 0 })))
+        .collect();
+    store.insert("redemptions".to_owned(), Value::Object(redemptions));
+    Ok(())
+}
+
+/// Users rejected in manual review share the blacklist with users banned by an admin.
+fn migrate_v2_to_v3(store: &mut Value) -> anyhow::Result<()> {
+    let store = store
+        .as_object_mut()
+        .context("store is not a json object")?;
+    let rejected_users = store.remove("rejected_users").unwrap_or_else(|| json!([]));
+    store.insert("banned_users".to_owned(), rejected_users);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn behaves_like_the_other_backends() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.json");
+        let store = JsonStorage::open(path.to_str().unwrap()).unwrap();
+        crate::store::check_storage(&store);
+    }
+}
@@ -0,0 +1,380 @@
+mod json;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, TryLockError},
+    path::Path,
+};
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use teloxide::types::User;
+
+use crate::config::{BotConfig, StoreBackend};
+
+/// Persistent state of one bot, independent of how it is stored.
+pub trait Storage: Send + Sync {
+    fn is_redeemed(&self, user_id: i64) -> anyhow::Result<bool>;
+    fn record_redemption(&self, user_id: i64, redeemed_at: u64) -> anyhow::Result<()>;
+    /// How many users redeemed, including those whose redemptions were archived.
+    fn redemption_count(&self) -> anyhow::Result<usize>;
+    /// Every redemption still in the store as `(user_id, redeemed_at)`, ordered by user id.
+    fn redemptions(&self) -> anyhow::Result<Vec<(i64, u64)>>;
+    /// How many redemptions still in the store happened at `since` or later.
+    fn redemptions_since(&self, since: u64) -> anyhow::Result<usize>;
+    /// Drops the redemption records of these users, once they were written to the archive, while
+    /// still counting the users as redeemed.
+    fn archive_redemptions(&self, user_ids: &[i64]) -> anyhow::Result<()>;
+
+    fn has_pending_review(&self, user_id: i64) -> anyhow::Result<bool>;
+    fn add_pending_review(&self, user_id: i64, review: PendingReview) -> anyhow::Result<()>;
+    /// Removes and returns the pending review, so that only one caller can act on it.
+    fn take_pending_review(&self, user_id: i64) -> anyhow::Result<Option<PendingReview>>;
+    fn pending_reviews(&self) -> anyhow::Result<Vec<(i64, PendingReview)>>;
+
+    fn is_banned(&self, user_id: i64) -> anyhow::Result<bool>;
+    fn ban(&self, user_id: i64) -> anyhow::Result<()>;
+    /// Returns whether the user was banned.
+    fn unban(&self, user_id: i64) -> anyhow::Result<bool>;
+    fn banned_users(&self) -> anyhow::Result<Vec<i64>>;
+
+    /// The user's conversation, whether or not it has expired.
+    fn conversation(&self, user_id: i64) -> anyhow::Result<Option<ConversationState>>;
+    fn set_conversation(&self, user_id: i64, state: ConversationState) -> anyhow::Result<()>;
+    fn clear_conversation(&self, user_id: i64) -> anyhow::Result<()>;
+
+    /// Remembers a code given to a user, so that whether it was used can be checked later.
+    fn record_card(&self, card: IssuedCard) -> anyhow::Result<()>;
+    /// Every remembered code, ordered by when it was issued.
+    fn cards(&self) -> anyhow::Result<Vec<IssuedCard>>;
+    /// When the user was last given one of the remembered codes, if ever.
+    fn last_card_issued_at(&self, user_id: i64) -> anyhow::Result<Option<u64>>;
+    /// Replaces the remembered card with the same code.
+    fn update_card(&self, card: IssuedCard) -> anyhow::Result<()>;
+
+    /// The user whose account shared the phone number with this hash, if any.
+    fn phone_owner(&self, phone_hash: &str) -> anyhow::Result<Option<i64>>;
+    fn has_phone(&self, user_id: i64) -> anyhow::Result<bool>;
+    fn record_phone(&self, phone_hash: &str, user_id: i64) -> anyhow::Result<()>;
+
+    /// Counts another giftcard request from a user who already redeemed, returning how many they
+    /// made so far.
+    fn record_repeat_attempt(&self, user_id: i64) -> anyhow::Result<u32>;
+    /// Users who asked again after redeeming and how often, most persistent first.
+    fn repeat_attempts(&self) -> anyhow::Result<Vec<(i64, u32)>>;
+
+    /// The highest member count milestone the group reached, if any was recorded.
+    fn last_milestone(&self) -> anyhow::Result<Option<u64>>;
+    fn record_milestone(&self, milestone: u64) -> anyhow::Result<()>;
+
+    /// A setting an admin changed at runtime with `#Set`, overriding the config.
+    fn setting(&self, name: &str) -> anyhow::Result<Option<String>>;
+    fn set_setting(&self, name: &str, value: &str) -> anyhow::Result<()>;
+
+    /// The language the user chose with `/language`, overriding the one telegram reports.
+    fn language(&self, user_id: i64) -> anyhow::Result<Option<String>>;
+    fn set_language(&self, user_id: i64, language: &str) -> anyhow::Result<()>;
+
+    /// Adds the user to the waitlist, keeping their place if they already are on it.
+    fn join_waitlist(&self, user_id: i64, entry: WaitlistEntry) -> anyhow::Result<()>;
+    /// Everyone on the waitlist, ordered by when they joined it.
+    fn waitlist(&self) -> anyhow::Result<Vec<(i64, WaitlistEntry)>>;
+    fn leave_waitlist(&self, user_id: i64) -> anyhow::Result<()>;
+
+    /// Adds to the statistics of a day, counted in days since the unix epoch.
+    fn add_daily_stats(&self, day: u64, stats: DailyStats) -> anyhow::Result<()>;
+    /// The statistics of the days from `first_day` on that have any, ordered by day.
+    fn daily_stats(&self, first_day: u64) -> anyhow::Result<Vec<(u64, DailyStats)>>;
+
+    /// Flags a user who left the group after redeeming. Returns whether they weren't flagged yet.
+    fn flag_left_group(&self, user_id: i64) -> anyhow::Result<bool>;
+    fn left_group_users(&self) -> anyhow::Result<Vec<i64>>;
+
+    /// Flags a user the bot can no longer message, e.g. because they blocked it. Returns whether
+    /// they weren't flagged yet.
+    fn flag_undeliverable(&self, user_id: i64) -> anyhow::Result<bool>;
+    /// Returns whether the user was flagged.
+    fn unflag_undeliverable(&self, user_id: i64) -> anyhow::Result<bool>;
+    fn undeliverable_users(&self) -> anyhow::Result<Vec<i64>>;
+
+    /// Forgets the user's redemption, pending review and ban. Returns whether there was any.
+    fn reset_user(&self, user_id: i64) -> anyhow::Result<bool>;
+
+    /// Reclaims the space left by deleted data. The json store rewrites its file on every
+    /// change, so only some backends have anything to do here.
+    fn compact(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Writes a consistent copy of the store to a new file, in the backend's own format, so that
+    /// it can be opened in place of the store.
+    fn snapshot(&self, path: &Path) -> anyhow::Result<()>;
+}
+
+/// a giftcard request waiting for an admin to approve or reject it
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PendingReview {
+    pub chat_id: i64,
+    pub reasons: Vec<String>,
+    pub requested_at: u64,
+}
+
+/// a giftcard code given to a user
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct IssuedCard {
+    pub user_id: i64,
+    pub code: String,
+    pub issued_at: u64,
+    /// whether the backend reported the code as redeemed in the app
+    #[serde(default)]
+    pub used: bool,
+    /// whether the user was reminded of the unused code
+    #[serde(default)]
+    pub reminded: bool,
+}
+
+/// a user waiting for a giftcard until the bot can give them out again, see `waitlist`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WaitlistEntry {
+    /// the user as telegram described them, so that their request can be checked like a new one
+    pub user: User,
+    pub joined_at: u64,
+}
+
+/// how giftcard requests ended on one day, see `#Chart`
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct DailyStats {
+    /// users given giftcards, including granted and approved ones, however many codes each got
+    pub issued: u64,
+    /// requests refused by a rule or from users who already redeemed
+    pub denied: u64,
+    /// requests that failed, e.g. because the backend did
+    pub errors: u64,
+}
+
+impl DailyStats {
+    pub fn add(&mut self, other: DailyStats) {
+        self.issued += other.issued;
+        self.denied += other.denied;
+        self.errors += other.errors;
+    }
+}
+
+/// where a user is in a multi-message flow, see [`crate::conversation`]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ConversationState {
+    pub flow: String,
+    pub step: String,
+    /// answers collected by earlier steps
+    #[serde(default)]
+    pub data: BTreeMap<String, String>,
+    pub expires_at: u64,
+}
+
+pub fn open_storage(config: &BotConfig) -> anyhow::Result<Box<dyn Storage>> {
+    match config.store_backend {
+        StoreBackend::Json => Ok(Box::new(json::JsonStorage::open(&config.store_path)?)),
+        #[cfg(feature = "sqlite")]
+        StoreBackend::Sqlite => Ok(Box::new(sqlite::SqliteStorage::open(&config.store_path)?)),
+        #[cfg(not(feature = "sqlite"))]
+        StoreBackend::Sqlite => anyhow::bail!("this build does not include the sqlite backend"),
+    }
+}
+
+/// Opens an existing store for commands that only read it. Unlike [`open_storage`], it never
+/// migrates the store, so it refuses one written by an older build.
+pub fn open_storage_read_only(config: &BotConfig) -> anyhow::Result<Box<dyn Storage>> {
+    match config.store_backend {
+        StoreBackend::Json => Ok(Box::new(json::JsonStorage::open_read_only(
+            &config.store_path,
+        )?)),
+        #[cfg(feature = "sqlite")]
+        StoreBackend::Sqlite => Ok(Box::new(sqlite::SqliteStorage::open_read_only(
+            &config.store_path,
+        )?)),
+        #[cfg(not(feature = "sqlite"))]
+        StoreBackend::Sqlite => anyhow::bail!("this build does not include the sqlite backend"),
+    }
+}
+
+/// Marks the bot's store as in use until the returned file is dropped, failing if it already is.
+/// The bot holds it while running, and commands that write to the store take it too, since the
+/// bot would overwrite their changes with its own copy of the store.
+pub fn lock_store(config: &BotConfig) -> anyhow::Result<File> {
+    let path = format!("{}.lock", config.store_path);
+    let file = File::create(&path).with_context(|| format!("cannot create {path}"))?;
+    match file.try_lock() {
+        Ok(()) => Ok(file),
+        Err(TryLockError::WouldBlock) => anyhow::bail!(
+            "the store at {} is in use, stop the bot first",
+            config.store_path
+        ),
+        Err(TryLockError::Error(err)) => Err(err).with_context(|| format!("cannot lock {path}")),
+    }
+}
+
+/// Runs a backend through every [`Storage`] method, so that the backends behave the same. Expects
+/// an empty store.
+#[cfg(test)]
+pub fn check_storage(store: &dyn Storage) {
+    store.record_redemption(1, 100).unwrap();
+    store.record_redemption(2, 200).unwrap();
+    store.record_redemption(3, 300).unwrap();
+    assert!(store.is_redeemed(1).unwrap());
+    assert!(!store.is_redeemed(4).unwrap());
+    assert_eq!(store.redemptions_since(200).unwrap(), 2);
+    store.archive_redemptions(&[1, 4]).unwrap();
+    assert!(store.is_redeemed(1).unwrap());
+    assert_eq!(store.redemption_count().unwrap(), 3);
+    assert_eq!(store.redemptions().unwrap(), vec![(2, 200), (3, 300)]);
+    assert_eq!(store.redemptions_since(0).unwrap(), 2);
+
+    let review = PendingReview {
+        chat_id: 5,
+        reasons: vec!["new account".into()],
+        requested_at: 100,
+    };
+    store.add_pending_review(5, review).unwrap();
+    assert!(store.has_pending_review(5).unwrap());
+    assert_eq!(store.pending_reviews().unwrap().len(), 1);
+    let review = store.take_pending_review(5).unwrap().unwrap();
+    assert_eq!(
+        (review.chat_id, review.reasons),
+        (5, vec!["new account".into()])
+    );
+    assert!(store.take_pending_review(5).unwrap().is_none());
+    assert!(!store.has_pending_review(5).unwrap());
+
+    store.ban(6).unwrap();
+    store.ban(6).unwrap();
+    assert!(store.is_banned(6).unwrap());
+    assert_eq!(store.banned_users().unwrap(), vec![6]);
+    assert!(store.unban(6).unwrap());
+    assert!(!store.unban(6).unwrap());
+    assert!(!store.is_banned(6).unwrap());
+
+    let state = ConversationState {
+        flow: "survey".into(),
+        step: "age".into(),
+        data: BTreeMap::from([("name".into(), "Test".into())]),
+        expires_at: 500,
+    };
+    store.set_conversation(7, state.clone()).unwrap();
+    assert_eq!(store.conversation(7).unwrap(), Some(state));
+    store.clear_conversation(7).unwrap();
+    assert_eq!(store.conversation(7).unwrap(), None);
+
+    let card = |code: &str, issued_at| IssuedCard {
+        user_id: 8,
+        code: code.into(),
+        issued_at,
+        used: false,
+        reminded: false,
+    };
+    store.record_card(card("LATER", 200)).unwrap();
+    store.record_card(card("EARLIER", 100)).unwrap();
+    store
+        .update_card(IssuedCard {
+            used: true,
+            ..card("LATER", 200)
+        })
+        .unwrap();
+    let cards = store.cards().unwrap();
+    assert_eq!(cards.len(), 2);
+    assert_eq!(cards[0], card("EARLIER", 100));
+    assert!(cards[1].used);
+    assert_eq!(store.last_card_issued_at(8).unwrap(), Some(200));
+    assert_eq!(store.last_card_issued_at(9).unwrap(), None);
+
+    store.record_phone("hash", 10).unwrap();
+    assert_eq!(store.phone_owner("hash").unwrap(), Some(10));
+    assert_eq!(store.phone_owner("other").unwrap(), None);
+    assert!(store.has_phone(10).unwrap());
+    assert!(!store.has_phone(11).unwrap());
+
+    assert_eq!(store.record_repeat_attempt(11).unwrap(), 1);
+    assert_eq!(store.record_repeat_attempt(12).unwrap(), 1);
+    assert_eq!(store.record_repeat_attempt(12).unwrap(), 2);
+    assert_eq!(store.repeat_attempts().unwrap(), vec![(12, 2), (11, 1)]);
+
+    assert_eq!(store.last_milestone().unwrap(), None);
+    store.record_milestone(1000).unwrap();
+    store.record_milestone(100).unwrap();
+    assert_eq!(store.last_milestone().unwrap(), Some(1000));
+
+    assert_eq!(store.setting("paused").unwrap(), None);
+    store.set_setting("paused", "true").unwrap();
+    store.set_setting("paused", "false").unwrap();
+    assert_eq!(store.setting("paused").unwrap().as_deref(), Some("false"));
+
+    store.set_language(13, "en").unwrap();
+    store.set_language(13, "zh").unwrap();
+    assert_eq!(store.language(13).unwrap().as_deref(), Some("zh"));
+    assert_eq!(store.language(14).unwrap(), None);
+
+    let entry = |id: u64, joined_at| WaitlistEntry {
+        user: serde_json::from_value(serde_json::json!({
+            "id": id,
+            "is_bot": false,
+            "first_name": "Test",
+        }))
+        .unwrap(),
+        joined_at,
+    };
+    store.join_waitlist(15, entry(15, 200)).unwrap();
+    store.join_waitlist(16, entry(16, 100)).unwrap();
+    store.join_waitlist(15, entry(15, 300)).unwrap();
+    let waitlist = store.waitlist().unwrap();
+    let order: Vec<(i64, u64)> = waitlist
+        .iter()
+        .map(|(user_id, entry)| (*user_id, entry.joined_at))
+        .collect();
+    assert_eq!(order, vec![(16, 100), (15, 200)]);
+    assert_eq!(waitlist[0].1.user.id.0, 16);
+    store.leave_waitlist(16).unwrap();
+    store.leave_waitlist(16).unwrap();
+    assert_eq!(store.waitlist().unwrap().len(), 1);
+
+    let stats = |issued, denied, errors| DailyStats {
+        issued,
+        denied,
+        errors,
+    };
+    store.add_daily_stats(1, stats(1, 0, 0)).unwrap();
+    store.add_daily_stats(2, stats(1, 2, 3)).unwrap();
+    store.add_daily_stats(2, stats(1, 0, 1)).unwrap();
+    assert_eq!(store.daily_stats(2).unwrap(), vec![(2, stats(2, 2, 4))]);
+
+    assert!(store.flag_left_group(17).unwrap());
+    assert!(!store.flag_left_group(17).unwrap());
+    assert_eq!(store.left_group_users().unwrap(), vec![17]);
+    assert!(store.flag_undeliverable(18).unwrap());
+    assert!(!store.flag_undeliverable(18).unwrap());
+    assert_eq!(store.undeliverable_users().unwrap(), vec![18]);
+    assert!(store.unflag_undeliverable(18).unwrap());
+    assert!(!store.unflag_undeliverable(18).unwrap());
+
+    store.record_redemption(19, 100).unwrap();
+    store.archive_redemptions(&[19]).unwrap();
+    store.ban(19).unwrap();
+    store.record_phone("hash of 19", 19).unwrap();
+    store.flag_left_group(19).unwrap();
+    store.record_repeat_attempt(19).unwrap();
+    assert!(store.reset_user(19).unwrap());
+    assert!(!store.reset_user(19).unwrap());
+    assert!(!store.is_redeemed(19).unwrap());
+    assert!(!store.is_banned(19).unwrap());
+    assert!(!store.has_phone(19).unwrap());
+    assert_eq!(store.left_group_users().unwrap(), vec![17]);
+    assert!(
+        store
+            .repeat_attempts()
+            .unwrap()
+            .iter()
+            .all(|(user_id, _)| *user_id != 19)
+    );
+
+    store.compact().unwrap();
+}
@@ -0,0 +1,802 @@
+use std::{path::Path, sync::Mutex};
+
+use anyhow::Context;
+use rusqlite::{Connection, OpenFlags, OptionalExtension, params};
+
+use super::{ConversationState, DailyStats, IssuedCard, PendingReview, Storage, WaitlistEntry};
+
+/// The schema version written by this build, kept in `PRAGMA user_version`. Bump it together with
+/// a new entry in [`MIGRATIONS`].
+const SCHEMA_VERSION: u32 = 1;
+
+/// Upgrades a database from the version at its index to the next one.
+type Migration = fn(&Connection) -> rusqlite::Result<()>;
+
+const MIGRATIONS: &[Migration] = &[migrate_v0_to_v1];
+
+/// The tables of schema version 1. Later versions change them in new [`MIGRATIONS`], not here.
+const SCHEMA_V1: &str = "
+CREATE TABLE IF NOT EXISTS redemptions (
+    user_id INTEGER PRIMARY KEY,
+    redeemed_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS redemptions_redeemed_at ON redemptions (redeemed_at);
+CREATE TABLE IF NOT EXISTS archived_users (
+    user_id INTEGER PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS pending_reviews (
+    user_id INTEGER PRIMARY KEY,
+    chat_id INTEGER NOT NULL,
+    reasons TEXT NOT NULL,
+    requested_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS pending_reviews_requested_at ON pending_reviews (requested_at);
+CREATE TABLE IF NOT EXISTS banned_users (
+    user_id INTEGER PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS conversations (
+    user_id INTEGER PRIMARY KEY,
+    flow TEXT NOT NULL,
+    step TEXT NOT NULL,
+    data TEXT NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS cards (
+    code TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL,
+    issued_at INTEGER NOT NULL,
+    used INTEGER NOT NULL,
+    reminded INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS cards_issued_at ON cards (issued_at);
+CREATE INDEX IF NOT EXISTS cards_user_id ON cards (user_id);
+CREATE TABLE IF NOT EXISTS phones (
+    phone_hash TEXT PRIMARY KEY,
+    user_id INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS phones_user_id ON phones (user_id);
+CREATE TABLE IF NOT EXISTS left_group_users (
+    user_id INTEGER PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS milestones (
+    reached INTEGER PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS undeliverable_users (
+    user_id INTEGER PRIMARY KEY
+);
+CREATE TABLE IF NOT EXISTS settings (
+    name TEXT PRIMARY KEY,
+    value TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS languages (
+    user_id INTEGER PRIMARY KEY,
+    language TEXT NOT NULL
+);
+CREATE TABLE IF NOT EXISTS waitlist (
+    user_id INTEGER PRIMARY KEY,
+    user TEXT NOT NULL,
+    joined_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS waitlist_joined_at ON waitlist (joined_at);
+CREATE TABLE IF NOT EXISTS daily_stats (
+    day INTEGER PRIMARY KEY,
+    issued INTEGER NOT NULL,
+    denied INTEGER NOT NULL,
+    errors INTEGER NOT NULL
+);
+CREATE TABLE IF NOT EXISTS repeat_attempts (
+    user_id INTEGER PRIMARY KEY,
+    attempts INTEGER NOT NULL
+);
+";
+
+/// [`Storage`] backed by a sqlite database in WAL mode.
+///
+/// Writes go through a single connection, while reads borrow one of a pool of read-only
+/// connections, so they don't wait for each other or for writers.
+pub struct SqliteStorage {
+    path: String,
+    writer: Mutex<Connection>,
+    readers: Mutex<Vec<Connection>>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> anyhow::Result<Self> {
+        let writer =
+            Connection::open(path).with_context(|| format!("cannot open database at {path}"))?;
+        let journal_mode: String =
+            writer.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get(0))?;
+        anyhow::ensure!(
+            journal_mode.eq_ignore_ascii_case("wal"),
+            "cannot enable WAL mode, database is in {journal_mode} mode"
+        );
+        writer.pragma_update(None, "synchronous", "NORMAL")?;
+        writer.busy_timeout(std::time::Duration::from_secs(5))?;
+        migrate(&writer)?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            writer: Mutex::new(writer),
+            readers: Mutex::new(vec![]),
+        })
+    }
+
+    /// Opens an existing database as it is, refusing one that [`Self::open`] would migrate. Writes
+    /// to it fail.
+    pub fn open_read_only(path: &str) -> anyhow::Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )
+        .with_context(|| format!("cannot open database at {path}"))?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        anyhow::ensure!(
+            version == SCHEMA_VERSION,
+            "database has schema version {version}, but this build reads version \
+             {SCHEMA_VERSION}; start the bot once to migrate it"
+        );
+
+        Ok(Self {
+            path: path.to_owned(),
+            writer: Mutex::new(conn),
+            readers: Mutex::new(vec![]),
+        })
+    }
+
+    fn read<T>(&self, f: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> anyhow::Result<T> {
+        let pooled = self.readers.lock().unwrap().pop();
+        let conn = match pooled {
+            Some(conn) => conn,
+            None => {
+                let conn = Connection::open_with_flags(
+                    &self.path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                )?;
+                conn.busy_timeout(std::time::Duration::from_secs(5))?;
+                conn
+            }
+        };
+        let result = f(&conn);
+        self.readers.lock().unwrap().push(conn);
+        Ok(result?)
+    }
+
+    fn write<T>(&self, f: impl FnOnce(&Connection) -> rusqlite::Result<T>) -> anyhow::Result<T> {
+        Ok(f(&self.writer.lock().unwrap())?)
+    }
+}
+
+/// Brings the database up to [`SCHEMA_VERSION`], one transaction per version.
+fn migrate(conn: &Connection) -> anyhow::Result<()> {
+    let version: u32 = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    anyhow::ensure!(
+        version <= SCHEMA_VERSION,
+        "database has schema version {version}, but this build only understands up to {SCHEMA_VERSION}"
+    );
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.unchecked_transaction()?;
+        migration(&tx).with_context(|| format!("migration from version {from} failed"))?;
+        tx.pragma_update(None, "user_version", from as u32 + 1)?;
+        tx.commit()?;
+    }
+    Ok(())
+}
+
+/// Creates the tables, some of which databases from before versioning already have, and moves
+/// users rejected in review to the blacklist they now share with banned users.
+fn migrate_v0_to_v1(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch(SCHEMA_V1)?;
+    let has_rejected_users: bool = conn.query_row(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'rejected_users')",
+        [],
+        |row| row.get(0),
+    )?;
+    if has_rejected_users {
+        conn.execute_batch(
+            "INSERT OR IGNORE INTO banned_users (user_id) SELECT user_id FROM rejected_users;
+             DROP TABLE rejected_users;",
+        )?;
+    }
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn is_redeemed(&self, user_id: i64) -> anyhow::Result<bool> {
+        self.read(|conn| {
+            conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM redemptions WHERE user_id = ?1)
+                    OR EXISTS (SELECT 1 FROM archived_users WHERE user_id = ?1)",
+                params![user_id],
+                |row| row.get(0),
+            )
+        })
+    }
+
+    fn record_redemption(&self, user_id: i64, redeemed_at: u64) -> anyhow::Result<()> {
+        self.write(|conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO redemptions (user_id, redeemed_at) VALUES (?1, ?2)",
+                params![user_id, redeemed_at as i64],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn redemption_count(&self) -> anyhow::Result<usize> {
+        let count: i64 = self.read(|conn| {
+            conn.query_row(
+                "SELECT (SELECT COUNT(*) FROM redemptions) + (SELECT COUNT(*) FROM archived_users)",
+                [],
+                |row| row.get(0),
+            )
+        })?;
+        Ok(count as usize)
+    }
+
+    fn redemptions(&self) -> anyhow::Result<Vec<(i64, u64)>> {
+        self.read(|conn| {
+            conn.prepare("SELECT user_id, redeemed_at FROM redemptions ORDER BY user_id")?
+                .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))?
+                .collect()
+        })
+    }
+
+    fn redemptions_since(&self, since: u64) -> anyhow::Result<usize> {
+        let count: i64 = self.read(|conn| {
+            conn.query_row(
+                "SELECT COUNT(*) FROM redemptions WHERE redeemed_at >= ?1",
+                params![since as i64],
+                |row| row.get(0),
+            )
+        })?;
+        Ok(count as usize)
+    }
+
+    fn archive_redemptions(&self, user_ids: &[i64]) -> anyhow::Result<()> {
+        self.write(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            for user_id in user_ids {
+                if tx.execute(
+                    "DELETE FROM redemptions WHERE user_id = ?1",
+                    params![user_id],
+                )? > 0
+                {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO archived_users (user_id) VALUES (?1)",
+                        params![user_id],
+                    )?;
+                }
+            }
+            tx.commit()
+        })
+    }
+
+    fn has_pending_review(&self, user_id: i64) -> anyhow::Result<bool> {
+        self.read(|conn| {
+            conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM pending_reviews WHERE user_id = ?1)",
+                params![user_id],
+                |row| row.get(0),
+            )
+        })
+    }
+
+    fn add_pending_review(&self, user_id: i64, review: PendingReview) -> anyhow::Result<()> {
+        let reasons = serde_json::to_string(&review.reasons)?;
+        self.write(|conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO pending_reviews (user_id, chat_id, reasons, requested_at)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![user_id, review.chat_id, reasons, review.requested_at as i64],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn take_pending_review(&self, user_id: i64) -> anyhow::Result<Option<PendingReview>> {
+        let row: Option<(i64, String, i64)> = self.write(|conn| {
+            conn.query_row(
+                "DELETE FROM pending_reviews WHERE user_id = ?1
+                 RETURNING chat_id, reasons, requested_at",
+                params![user_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+        })?;
+        row.map(|(chat_id, reasons, requested_at)| {
+            Ok(PendingReview {
+                chat_id,
+                reasons: serde_json::from_str(&reasons)?,
+                requested_at: requested_at as u64,
+            })
+        })
+        .transpose()
+    }
+
+    fn pending_reviews(&self) -> anyhow::Result<Vec<(i64, PendingReview)>> {
+        let rows: Vec<(i64, i64, String, i64)> = self.read(|conn| {
+            conn.prepare(
+                "SELECT user_id, chat_id, reasons, requested_at FROM pending_reviews
+                 ORDER BY user_id",
+            )?
+            .query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect()
+        })?;
+        rows.into_iter()
+            .map(|(user_id, chat_id, reasons, requested_at)| {
+                let review = PendingReview {
+                    chat_id,
+                    reasons: serde_json::from_str(&reasons)?,
+                    requested_at: requested_at as u64,
+                };
+                Ok((user_id, review))
+            })
+            .collect()
+    }
+
+    fn is_banned(&self, user_id: i64) -> anyhow::Result<bool> {
+        self.read(|conn| {
+            conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM banned_users WHERE user_id = ?1)",
+                params![user_id],
+                |row| row.get(0),
+            )
+        })
+    }
+
+    fn ban(&self, user_id: i64) -> anyhow::Result<()> {
+        self.write(|conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO banned_users (user_id) VALUES (?1)",
+                params![user_id],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn unban(&self, user_id: i64) -> anyhow::Result<bool> {
+        let removed = self.write(|conn| {
+            conn.execute(
+                "DELETE FROM banned_users WHERE user_id = ?1",
+                params![user_id],
+            )
+        })?;
+        Ok(removed > 0)
+    }
+
+    fn banned_users(&self) -> anyhow::Result<Vec<i64>> {
+        self.read(|conn| {
+            conn.prepare("SELECT user_id FROM banned_users ORDER BY user_id")?
+                .query_map([], |row| row.get(0))?
+                .collect()
+        })
+    }
+
+    fn conversation(&self, user_id: i64) -> anyhow::Result<Option<ConversationState>> {
+        let row: Option<(String, String, String, i64)> = self.read(|conn| {
+            conn.query_row(
+                "SELECT flow, step, data, expires_at FROM conversations WHERE user_id = ?1",
+                params![user_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()
+        })?;
+        row.map(|(flow, step, data, expires_at)| {
+            Ok(ConversationState {
+                flow,
+                step,
+                data: serde_json::from_str(&data)?,
+                expires_at: expires_at as u64,
+            })
+        })
+        .transpose()
+    }
+
+    fn set_conversation(&self, user_id: i64, state: ConversationState) -> anyhow::Result<()> {
+        let data = serde_json::to_string(&state.data)?;
+        self.write(|conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO conversations (user_id, flow, step, data, expires_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    user_id,
+                    state.flow,
+                    state.step,
+                    data,
+                    state.expires_at as i64
+                ],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn clear_conversation(&self, user_id: i64) -> anyhow::Result<()> {
+        self.write(|conn| {
+            conn.execute(
+                "DELETE FROM conversations WHERE user_id = ?1",
+                params![user_id],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn record_card(&self, card: IssuedCard) -> anyhow::Result<()> {
+        self.write(|conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO cards (code, user_id, issued_at, used, reminded)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    card.code,
+                    card.user_id,
+                    card.issued_at as i64,
+                    card.used,
+                    card.reminded
+                ],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn cards(&self) -> anyhow::Result<Vec<IssuedCard>> {
+        self.read(|conn| {
+            conn.prepare(
+                "SELECT code, user_id, issued_at, used, reminded FROM cards
+                 ORDER BY issued_at",
+            )?
+            .query_map([], |row| {
+                Ok(IssuedCard {
+                    code: row.get(0)?,
+                    user_id: row.get(1)?,
+                    issued_at: row.get::<_, i64>(2)? as u64,
+                    used: row.get(3)?,
+                    reminded: row.get(4)?,
+                })
+            })?
+            .collect()
+        })
+    }
+
+    fn last_card_issued_at(&self, user_id: i64) -> anyhow::Result<Option<u64>> {
+        let issued_at: Option<i64> = self.read(|conn| {
+            conn.query_row(
+                "SELECT MAX(issued_at) FROM cards WHERE user_id = ?1",
+                params![user_id],
+                |row| row.get(0),
+            )
+        })?;
+        Ok(issued_at.map(|issued_at| issued_at as u64))
+    }
+
+    fn update_card(&self, card: IssuedCard) -> anyhow::Result<()> {
+        self.record_card(card)
+    }
+
+    fn phone_owner(&self, phone_hash: &str) -> anyhow::Result<Option<i64>> {
+        self.read(|conn| {
+            conn.query_row(
+                "SELECT user_id FROM phones WHERE phone_hash = ?1",
+                params![phone_hash],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+    }
+
+    fn has_phone(&self, user_id: i64) -> anyhow::Result<bool> {
+        self.read(|conn| {
+            conn.query_row(
+                "SELECT EXISTS (SELECT 1 FROM phones WHERE user_id = ?1)",
+                params![user_id],
+                |row| row.get(0),
+            )
+        })
+    }
+
+    fn record_phone(&self, phone_hash: &str, user_id: i64) -> anyhow::Result<()> {
+        self.write(|conn| {
+            conn.execute(
+                "INSERT OR REPLACE INTO phones (phone_hash, user_id) VALUES (?1, ?2)",
+                params![phone_hash, user_id],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn record_repeat_attempt(&self, user_id: i64) -> anyhow::Result<u32> {
+        self.write(|conn| {
+            conn.query_row(
+                "INSERT INTO repeat_attempts (user_id, attempts) VALUES (?1, 1)
+                    ON CONFLICT (user_id) DO UPDATE SET attempts = attempts + 1
+                    RETURNING attempts",
+                params![user_id],
+                |row| row.get(0),
+            )
+        })
+    }
+
+    fn repeat_attempts(&self) -> anyhow::Result<Vec<(i64, u32)>> {
+        self.read(|conn| {
+            conn.prepare("SELECT user_id, attempts FROM repeat_attempts ORDER BY attempts DESC")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect()
+        })
+    }
+
+    fn last_milestone(&self) -> anyhow::Result<Option<u64>> {
+        let reached: Option<i64> = self.read(|conn| {
+            conn.query_row("SELECT MAX(reached) FROM milestones", [], |row| row.get(0))
+        })?;
+        Ok(reached.map(|reached| reached as u64))
+    }
+
+    fn record_milestone(&self, milestone: u64) -> anyhow::Result<()> {
+        self.write(|conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO milestones (reached) VALUES (?1)",
+                params![milestone as i64],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn flag_undeliverable(&self, user_id: i64) -> anyhow::Result<bool> {
+        let inserted = self.write(|conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO undeliverable_users (user_id) VALUES (?1)",
+                params![user_id],
+            )
+        })?;
+        Ok(inserted > 0)
+    }
+
+    fn unflag_undeliverable(&self, user_id: i64) -> anyhow::Result<bool> {
+        let deleted = self.write(|conn| {
+            conn.execute(
+                "DELETE FROM undeliverable_users WHERE user_id = ?1",
+                params![user_id],
+            )
+        })?;
+        Ok(deleted > 0)
+    }
+
+    fn undeliverable_users(&self) -> anyhow::Result<Vec<i64>> {
+        self.read(|conn| {
+            conn.prepare("SELECT user_id FROM undeliverable_users ORDER BY user_id")?
+                .query_map([], |row| row.get(0))?
+                .collect()
+        })
+    }
+
+    fn setting(&self, name: &str) -> anyhow::Result<Option<String>> {
+        self.read(|conn| {
+            conn.query_row(
+                "SELECT value FROM settings WHERE name = ?1",
+                params![name],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+    }
+
+    fn set_setting(&self, name: &str, value: &str) -> anyhow::Result<()> {
+        self.write(|conn| {
+            conn.execute(
+                "INSERT INTO settings (name, value) VALUES (?1, ?2)
+                 ON CONFLICT (name) DO UPDATE SET value = excluded.value",
+                params![name, value],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn language(&self, user_id: i64) -> anyhow::Result<Option<String>> {
+        self.read(|conn| {
+            conn.query_row(
+                "SELECT language FROM languages WHERE user_id = ?1",
+                params![user_id],
+                |row| row.get(0),
+            )
+            .optional()
+        })
+    }
+
+    fn set_language(&self, user_id: i64, language: &str) -> anyhow::Result<()> {
+        self.write(|conn| {
+            conn.execute(
+                "INSERT INTO languages (user_id, language) VALUES (?1, ?2)
+                 ON CONFLICT (user_id) DO UPDATE SET language = excluded.language",
+                params![user_id, language],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn join_waitlist(&self, user_id: i64, entry: WaitlistEntry) -> anyhow::Result<()> {
+        let user = serde_json::to_string(&entry.user)?;
+        self.write(|conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO waitlist (user_id, user, joined_at) VALUES (?1, ?2, ?3)",
+                params![user_id, user, entry.joined_at as i64],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn waitlist(&self) -> anyhow::Result<Vec<(i64, WaitlistEntry)>> {
+        let rows: Vec<(i64, String, i64)> = self.read(|conn| {
+            conn.prepare(
+                "SELECT user_id, user, joined_at FROM waitlist ORDER BY joined_at, user_id",
+            )?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect()
+        })?;
+        rows.into_iter()
+            .map(|(user_id, user, joined_at)| {
+                let entry = WaitlistEntry {
+                    user: serde_json::from_str(&user)?,
+                    joined_at: joined_at as u64,
+                };
+                Ok((user_id, entry))
+            })
+            .collect()
+    }
+
+    fn leave_waitlist(&self, user_id: i64) -> anyhow::Result<()> {
+        self.write(|conn| {
+            conn.execute("DELETE FROM waitlist WHERE user_id = ?1", params![user_id])
+        })?;
+        Ok(())
+    }
+
+    fn add_daily_stats(&self, day: u64, stats: DailyStats) -> anyhow::Result<()> {
+        self.write(|conn| {
+            conn.execute(
+                "INSERT INTO daily_stats (day, issued, denied, errors) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT (day) DO UPDATE SET
+                     issued = issued + excluded.issued,
+                     denied = denied + excluded.denied,
+                     errors = errors + excluded.errors",
+                params![
+                    day as i64,
+                    stats.issued as i64,
+                    stats.denied as i64,
+                    stats.errors as i64
+                ],
+            )
+        })?;
+        Ok(())
+    }
+
+    fn daily_stats(&self, first_day: u64) -> anyhow::Result<Vec<(u64, DailyStats)>> {
+        let rows: Vec<(i64, i64, i64, i64)> = self.read(|conn| {
+            conn.prepare(
+                "SELECT day, issued, denied, errors FROM daily_stats WHERE day >= ?1 ORDER BY day",
+            )?
+            .query_map(params![first_day as i64], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })?
+            .collect()
+        })?;
+        Ok(rows
+            .into_iter()
+            .map(|(day, issued, denied, errors)| {
+                let stats = DailyStats {
+                    issued: issued as u64,
+                    denied: denied as u64,
+                    errors: errors as u64,
+                };
+                (day as u64, stats)
+            })
+            .collect())
+    }
+
+    fn flag_left_group(&self, user_id: i64) -> anyhow::Result<bool> {
+        let inserted = self.write(|conn| {
+            conn.execute(
+                "INSERT OR IGNORE INTO left_group_users (user_id) VALUES (?1)",
+                params![user_id],
+            )
+        })?;
+        Ok(inserted > 0)
+    }
+
+    fn left_group_users(&self) -> anyhow::Result<Vec<i64>> {
+        self.read(|conn| {
+            conn.prepare("SELECT user_id FROM left_group_users ORDER BY user_id")?
+                .query_map([], |row| row.get(0))?
+                .collect()
+        })
+    }
+
+    fn reset_user(&self, user_id: i64) -> anyhow::Result<bool> {
+        let removed = self.write(|conn| {
+            let tx = conn.unchecked_transaction()?;
+            for table in [
+                "conversations",
+                "phones",
+                "left_group_users",
+                "repeat_attempts",
+            ] {
+                tx.execute(
+                    &format!("DELETE FROM {table} WHERE user_id = ?1"),
+                    params![user_id],
+                )?;
+            }
+            let mut removed = 0;
+            for table in [
+                "redemptions",
+                "archived_users",
+                "pending_reviews",
+                "banned_users",
+            ] {
+                removed += tx.execute(
+                    &format!("DELETE FROM {table} WHERE user_id = ?1"),
+                    params![user_id],
+                )?;
+            }
+            tx.commit()?;
+            Ok(removed)
+        })?;
+        Ok(removed > 0)
+    }
+
+    fn compact(&self) -> anyhow::Result<()> {
+        self.write(|conn| {
+            conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |_| Ok(()))?;
+            conn.execute_batch("VACUUM")
+        })
+    }
+
+    fn snapshot(&self, path: &Path) -> anyhow::Result<()> {
+        let path = path.to_str().context("snapshot path is not valid utf-8")?;
+        // unlike copying the file, this includes what is still only in the write-ahead log
+        self.write(|conn| conn.execute("VACUUM INTO ?1", params![path]))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn behaves_like_the_other_backends() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let store = SqliteStorage::open(path.to_str().unwrap()).unwrap();
+        crate::store::check_storage(&store);
+    }
+
+    #[test]
+    fn migrates_databases_from_before_versioning() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE redemptions (user_id INTEGER PRIMARY KEY, redeemed_at INTEGER NOT NULL);
+             INSERT INTO redemptions VALUES (1, 100);
+             CREATE TABLE rejected_users (user_id INTEGER PRIMARY KEY);
+             INSERT INTO rejected_users VALUES (2), (3);",
+        )
+        .unwrap();
+        drop(conn);
+
+        let store = SqliteStorage::open(path.to_str().unwrap()).unwrap();
+        assert!(store.is_redeemed(1).unwrap());
+        assert_eq!(store.banned_users().unwrap(), vec![2, 3]);
+        drop(store);
+
+        let conn = Connection::open(&path).unwrap();
+        let version: u32 = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, SCHEMA_VERSION);
+        conn.pragma_update(None, "user_version", SCHEMA_VERSION + 1)
+            .unwrap();
+        drop(conn);
+        assert!(SqliteStorage::open(path.to_str().unwrap()).is_err());
+    }
+}
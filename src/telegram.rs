@@ -0,0 +1,412 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use teloxide::{
+    ApiError, RequestError,
+    payloads::SendMessageSetters,
+    prelude::*,
+    types::{
+        ChatId, InputFile, LinkPreviewOptions, MessageId, ParseMode, ReplyMarkup, ReplyParameters,
+        ThreadId, UserId,
+    },
+};
+use tokio::time::Instant;
+
+use crate::{
+    BoxFuture,
+    config::{BotConfig, Config, DeliveryConfig},
+};
+
+/// How long to wait before trying a message again after a network error, multiplied by the
+/// number of attempts so far.
+const RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Creates the bot's client, talking to the configured Bot API server through the configured
+/// proxy, if any.
+pub fn build_bot(config: &BotConfig, global: &Config) -> anyhow::Result<Bot> {
+    let mut client = teloxide::net::default_reqwest_settings();
+    if let Some(proxy) = global.proxy.telegram() {
+        client =
+            client.proxy(teloxide_reqwest::Proxy::all(proxy).context("invalid telegram proxy")?);
+    }
+    let mut bot = Bot::with_client(config.telegram_token.clone(), client.build()?);
+    if let Some(url) = &global.telegram_api_url {
+        bot = bot.set_api_url(url.parse().context("invalid telegram_api_url")?);
+    }
+    Ok(bot)
+}
+
+/// A message the bot wants to send, independent of how it reaches telegram.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutgoingMessage {
+    pub chat_id: ChatId,
+    pub text: String,
+    pub parse_mode: Option<ParseMode>,
+    pub disable_link_preview: bool,
+    pub reply_to: Option<MessageId>,
+    /// the forum topic to post in
+    pub thread_id: Option<ThreadId>,
+    /// buttons under the message, or a keyboard replacing the user's
+    pub keyboard: Option<ReplyMarkup>,
+}
+
+impl OutgoingMessage {
+    pub fn new(chat_id: ChatId, text: impl Into<String>) -> Self {
+        Self {
+            chat_id,
+            text: text.into(),
+            parse_mode: None,
+            disable_link_preview: false,
+            reply_to: None,
+            thread_id: None,
+            keyboard: None,
+        }
+    }
+}
+
+/// Why telegram didn't take a message.
+#[derive(Debug)]
+pub enum DeliveryError {
+    /// flood control wants the bot to wait this long before sending again
+    RetryAfter(Duration),
+    /// the chat can never receive messages from the bot, e.g. because the user blocked it
+    Undeliverable(String),
+    /// telegram refused this particular message, e.g. because it is too long
+    Rejected(String),
+    /// telegram could not be reached, or answered something unexpected
+    Transient(String),
+}
+
+impl DeliveryError {
+    /// Whether sending the same message again later could succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, Self::RetryAfter(_) | Self::Transient(_))
+    }
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RetryAfter(wait) => write!(f, "flood control, retry after {wait:?}"),
+            Self::Undeliverable(message) => write!(f, "chat cannot be messaged: {message}"),
+            Self::Rejected(message) => write!(f, "message refused: {message}"),
+            Self::Transient(message) => write!(f, "cannot reach telegram: {message}"),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+impl From<RequestError> for DeliveryError {
+    fn from(err: RequestError) -> Self {
+        match err {
+            RequestError::RetryAfter(wait) => Self::RetryAfter(wait.duration()),
+            RequestError::Api(
+                err @ (ApiError::BotBlocked
+                | ApiError::UserDeactivated
+                | ApiError::ChatNotFound
+                | ApiError::UserNotFound
+                | ApiError::GroupDeactivated
+                | ApiError::BotKicked
+                | ApiError::BotKickedFromSupergroup
+                | ApiError::CantInitiateConversation
+                | ApiError::CantTalkWithBots),
+            ) => Self::Undeliverable(err.to_string()),
+            RequestError::Api(err) => Self::Rejected(err.to_string()),
+            RequestError::MigrateToChatId(chat_id) => {
+                Self::Rejected(format!("the group moved to {chat_id}"))
+            }
+            err => Self::Transient(err.to_string()),
+        }
+    }
+}
+
+/// The parts of the telegram bot API the bot uses.
+pub trait TelegramApi: Send + Sync {
+    fn send_message(&self, msg: OutgoingMessage) -> BoxFuture<'_, anyhow::Result<MessageId>>;
+
+    /// Uploads a png image to the chat.
+    fn send_photo(&self, chat_id: ChatId, png: Vec<u8>)
+    -> BoxFuture<'_, anyhow::Result<MessageId>>;
+
+    fn is_group_member(
+        &self,
+        group_id: ChatId,
+        user_id: UserId,
+    ) -> BoxFuture<'_, anyhow::Result<bool>>;
+
+    fn member_count(&self, group_id: ChatId) -> BoxFuture<'_, anyhow::Result<u64>>;
+
+    fn answer_callback_query(&self, query_id: String) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    fn edit_message_text(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+    ) -> BoxFuture<'_, anyhow::Result<()>>;
+
+    fn delete_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, anyhow::Result<()>>;
+}
+
+impl TelegramApi for Bot {
+    fn send_message(&self, msg: OutgoingMessage) -> BoxFuture<'_, anyhow::Result<MessageId>> {
+        Box::pin(async move {
+            let mut req = Requester::send_message(self, msg.chat_id, msg.text);
+            if let Some(parse_mode) = msg.parse_mode {
+                req = req.parse_mode(parse_mode);
+            }
+            if msg.disable_link_preview {
+                req = req.link_preview_options(LinkPreviewOptions {
+                    is_disabled: true,
+                    url: None,
+                    prefer_small_media: false,
+                    prefer_large_media: false,
+                    show_above_text: false,
+                });
+            }
+            if let Some(reply_to) = msg.reply_to {
+                req = req
+                    .reply_parameters(ReplyParameters::new(reply_to).allow_sending_without_reply());
+            }
+            if let Some(thread_id) = msg.thread_id {
+                req = req.message_thread_id(thread_id);
+            }
+            if let Some(keyboard) = msg.keyboard {
+                req = req.reply_markup(keyboard);
+            }
+            Ok(req.await.map_err(DeliveryError::from)?.id)
+        })
+    }
+
+    fn send_photo(
+        &self,
+        chat_id: ChatId,
+        png: Vec<u8>,
+    ) -> BoxFuture<'_, anyhow::Result<MessageId>> {
+        Box::pin(async move {
+            let photo = InputFile::memory(png).file_name("giftcard.png");
+            let msg = Requester::send_photo(self, chat_id, photo)
+                .await
+                .map_err(DeliveryError::from)?;
+            Ok(msg.id)
+        })
+    }
+
+    fn is_group_member(
+        &self,
+        group_id: ChatId,
+        user_id: UserId,
+    ) -> BoxFuture<'_, anyhow::Result<bool>> {
+        Box::pin(async move {
+            let member = self
+                .get_chat_member(group_id, user_id)
+                .await
+                .with_context(|| format!("get_chat_member failed for user {}", user_id.0))?;
+            Ok(member.is_present())
+        })
+    }
+
+    fn member_count(&self, group_id: ChatId) -> BoxFuture<'_, anyhow::Result<u64>> {
+        Box::pin(async move {
+            let count = self
+                .get_chat_member_count(group_id)
+                .await
+                .with_context(|| format!("get_chat_member_count failed for group {group_id}"))?;
+            Ok(count.into())
+        })
+    }
+
+    fn answer_callback_query(&self, query_id: String) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            Requester::answer_callback_query(self, query_id).await?;
+            Ok(())
+        })
+    }
+
+    fn edit_message_text(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+    ) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            Requester::edit_message_text(self, chat_id, message_id, text).await?;
+            Ok(())
+        })
+    }
+
+    fn delete_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, anyhow::Result<()>> {
+        Box::pin(async move {
+            Requester::delete_message(self, chat_id, message_id).await?;
+            Ok(())
+        })
+    }
+}
+
+/// Sends messages through another [`TelegramApi`], retrying those that failed for a transient
+/// reason.
+///
+/// Once telegram's flood control asks the bot to slow down, every message queues up behind the
+/// wait rather than only the one that hit it, since sending more meanwhile only extends the
+/// limit. Waits longer than `max_retry_after_secs` are not sat out, so that a user isn't left
+/// hanging for minutes.
+pub struct Outbox {
+    inner: Arc<dyn TelegramApi>,
+    attempts: u32,
+    max_wait: Duration,
+    /// set after a 429, until telegram accepts messages again
+    paused_until: Mutex<Option<Instant>>,
+}
+
+impl Outbox {
+    pub fn new(inner: Arc<dyn TelegramApi>, config: &DeliveryConfig) -> Self {
+        Self {
+            inner,
+            attempts: config.attempts.max(1),
+            max_wait: Duration::from_secs(config.max_retry_after_secs),
+            paused_until: Mutex::new(None),
+        }
+    }
+
+    fn pause(&self, wait: Duration) {
+        let until = Instant::now() + wait;
+        let mut paused_until = self.paused_until.lock().unwrap();
+        *paused_until = Some(paused_until.map_or(until, |paused| paused.max(until)));
+    }
+
+    /// Calls `send` until it succeeds, fails for good or runs out of attempts.
+    async fn deliver<'a>(
+        &'a self,
+        chat_id: ChatId,
+        send: impl Fn() -> BoxFuture<'a, anyhow::Result<MessageId>>,
+    ) -> anyhow::Result<MessageId> {
+        let mut attempt = 1;
+        loop {
+            let paused_until = *self.paused_until.lock().unwrap();
+            if let Some(until) = paused_until {
+                tokio::time::sleep_until(until).await;
+            }
+            let err = match send().await {
+                Ok(message_id) => return Ok(message_id),
+                Err(err) => err,
+            };
+            let wait = match err.downcast_ref::<DeliveryError>() {
+                Some(DeliveryError::RetryAfter(wait)) => {
+                    self.pause(*wait);
+                    *wait
+                }
+                Some(delivery) if delivery.is_transient() => RETRY_BACKOFF * attempt,
+                _ => return Err(err),
+            };
+            if attempt >= self.attempts || wait > self.max_wait {
+                return Err(err);
+            }
+            eprintln!(
+                "sending to chat {chat_id} failed on attempt {attempt} of {}: {err:#}",
+                self.attempts
+            );
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+}
+
+impl TelegramApi for Outbox {
+    fn send_message(&self, msg: OutgoingMessage) -> BoxFuture<'_, anyhow::Result<MessageId>> {
+        Box::pin(async move {
+            self.deliver(msg.chat_id, || self.inner.send_message(msg.clone()))
+                .await
+        })
+    }
+
+    fn send_photo(
+        &self,
+        chat_id: ChatId,
+        png: Vec<u8>,
+    ) -> BoxFuture<'_, anyhow::Result<MessageId>> {
+        Box::pin(async move {
+            self.deliver(chat_id, || self.inner.send_photo(chat_id, png.clone()))
+                .await
+        })
+    }
+
+    fn is_group_member(
+        &self,
+        group_id: ChatId,
+        user_id: UserId,
+    ) -> BoxFuture<'_, anyhow::Result<bool>> {
+        self.inner.is_group_member(group_id, user_id)
+    }
+
+    fn member_count(&self, group_id: ChatId) -> BoxFuture<'_, anyhow::Result<u64>> {
+        self.inner.member_count(group_id)
+    }
+
+    fn answer_callback_query(&self, query_id: String) -> BoxFuture<'_, anyhow::Result<()>> {
+        self.inner.answer_callback_query(query_id)
+    }
+
+    fn edit_message_text(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+        text: String,
+    ) -> BoxFuture<'_, anyhow::Result<()>> {
+        self.inner.edit_message_text(chat_id, message_id, text)
+    }
+
+    fn delete_message(
+        &self,
+        chat_id: ChatId,
+        message_id: MessageId,
+    ) -> BoxFuture<'_, anyhow::Result<()>> {
+        self.inner.delete_message(chat_id, message_id)
+    }
+}
+
+/// Makes sure the bot's token, username and group are what the config says, before it starts
+/// answering users.
+pub async fn check_bot(bot: &Bot, config: &BotConfig) -> anyhow::Result<()> {
+    let me = bot.get_me().await.with_context(|| {
+        format!(
+            "telegram rejected the token of {}, check telegram_token",
+            config.bot_uname
+        )
+    })?;
+    let username = me.username();
+    anyhow::ensure!(
+        username.eq_ignore_ascii_case(&config.bot_uname),
+        "telegram_token belongs to @{username}, but bot_uname is {}",
+        config.bot_uname
+    );
+
+    for group_id in config.required_groups().map(ChatId) {
+        bot.get_chat(group_id).await.with_context(|| {
+            format!(
+                "@{username} cannot see the group {group_id}, check geph_group_id and language_groups and that the bot was added to it"
+            )
+        })?;
+        let member = bot
+            .get_chat_member(group_id, me.id)
+            .await
+            .with_context(|| format!("cannot look up @{username} in the group {group_id}"))?;
+        anyhow::ensure!(
+            member.is_present(),
+            "@{username} is not a member of the group {group_id}, add it so it can check membership"
+        );
+    }
+    Ok(())
+}
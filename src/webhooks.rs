@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use reqwest::Client;
+use ring::hmac;
+use serde_json::{Value, json};
+
+use crate::config::{WebhookConfig, WebhookEventKind};
+
+/// How many times a webhook is tried before its event is dropped.
+const DELIVERY_ATTEMPTS: u32 = 3;
+
+/// Posts events to the configured webhooks, so that analytics or CRM systems can follow the
+/// promotion without reading the store.
+///
+/// Each request carries the unix time in `X-Webhook-Timestamp` and, in `X-Webhook-Signature`,
+/// `sha256=` followed by the hex HMAC-SHA256 of `{timestamp}.{body}` keyed with the webhook's
+/// secret. Receivers should recompute it and reject stale timestamps to prevent replays.
+pub struct Webhooks {
+    client: Client,
+    targets: Vec<WebhookConfig>,
+}
+
+impl Webhooks {
+    pub fn new(targets: &[WebhookConfig]) -> anyhow::Result<Self> {
+        Ok(Self {
+            client: Client::builder().timeout(Duration::from_secs(10)).build()?,
+            targets: targets.to_vec(),
+        })
+    }
+
+    /// Sends the event in the background to every webhook that wants it, so that slow receivers
+    /// never hold up the bot. Failed deliveries are retried a few times, then logged.
+    pub fn send(&self, event: WebhookEventKind, bot: &str, user_id: i64, data: Value) {
+        let targets: Vec<&WebhookConfig> = self
+            .targets
+            .iter()
+            .filter(|target| target.events.is_empty() || target.events.contains(&event))
+            .collect();
+        if targets.is_empty() {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        let timestamp = crate::unix_now();
+        let body = json!({
+            "event": event,
+            "bot": bot,
+            "user_id": user_id,
+            "timestamp": timestamp,
+            "data": data,
+        })
+        .to_string();
+        for target in targets {
+            let request = self
+                .client
+                .post(&target.url)
+                .header("Content-Type", "application/json")
+                .header("X-Webhook-Timestamp", timestamp)
+                .header(
+                    "X-Webhook-Signature",
+                    signature(&target.secret, timestamp, &body),
+                )
+                .body(body.clone());
+            let url = target.url.clone();
+            runtime.spawn(async move {
+                for attempt in 1..=DELIVERY_ATTEMPTS {
+                    let Some(request) = request.try_clone() else {
+                        return;
+                    };
+                    match request.send().await.and_then(|r| r.error_for_status()) {
+                        Ok(_) => return,
+                        Err(err) if attempt == DELIVERY_ATTEMPTS => {
+                            eprintln!("giving up on webhook {url}: {err}")
+                        }
+                        Err(_) => tokio::time::sleep(Duration::from_secs(1 << attempt)).await,
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// The `X-Webhook-Signature` of a request.
+pub fn signature(secret: &str, timestamp: u64, body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret.as_bytes());
+    let tag = hmac::sign(&key, format!("{timestamp}.{body}").as_bytes());
+    let hex: String = tag
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("sha256={hex}")
+}
@@ -0,0 +1,59 @@
+use std::{future::Future, pin::Pin};
+
+use tokio::sync::{mpsc, oneshot};
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A fixed number of worker tasks, each draining its own bounded queue.
+///
+/// Jobs with the same key always go to the same worker, so they run one after another in the
+/// order they were submitted. When a worker's queue is full, submitting waits, which pushes back
+/// on the dispatcher instead of buffering updates without limit.
+pub struct WorkerPool {
+    queues: Vec<mpsc::Sender<Job>>,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize, queue_size: usize) -> Self {
+        let queues = (0..workers.max(1))
+            .map(|_| {
+                let (send, mut recv) = mpsc::channel::<Job>(queue_size.max(1));
+                tokio::spawn(async move {
+                    while let Some(job) = recv.recv().await {
+                        job.await;
+                    }
+                });
+                send
+            })
+            .collect();
+        Self { queues }
+    }
+
+    pub async fn submit(&self, key: u64, job: impl Future<Output = ()> + Send + 'static) {
+        let queue = &self.queues[(key % self.queues.len() as u64) as usize];
+        if queue.send(Box::pin(job)).await.is_err() {
+            eprintln!("worker for key {key} has stopped, dropping job");
+        }
+    }
+
+    /// Waits until the jobs submitted with `key` so far have run, then keeps later ones waiting
+    /// until the returned [`Turn`] is dropped. This puts work that borrows, and so can't be
+    /// submitted, in order with the key's jobs.
+    pub async fn turn(&self, key: u64) -> Turn {
+        let (started, start) = oneshot::channel();
+        let (done, finished) = oneshot::channel();
+        self.submit(key, async move {
+            let _ = started.send(());
+            let _: Result<(), _> = finished.await;
+        })
+        .await;
+        // a stopped worker runs nothing that could get in the way
+        let _ = start.await;
+        Turn { _done: done }
+    }
+}
+
+/// A key's place in its worker, see [`WorkerPool::turn`].
+pub struct Turn {
+    _done: oneshot::Sender<()>,
+}